@@ -1,10 +1,10 @@
 use std::{time::Duration, future::Future, pin::Pin};
 
 use reqwest::{Client, header::{self, HeaderValue, HeaderName}, Url, StatusCode, Response};
-use serde::{de::DeserializeOwned, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use thiserror::Error;
 
-use crate::{AddressingId, TaskRequest, MsgId, TaskResult, ProxyId};
+use crate::{AddressingId, AppOrProxyId, TaskRequest, MsgId, TaskResult, ProxyId};
 #[cfg(feature = "sockets")]
 use crate::SocketTask;
 
@@ -21,6 +21,8 @@ pub enum BeamError {
     ReqwestError(#[from] reqwest::Error),
     #[error("Unexpected status code {0}")]
     UnexpectedStatus(StatusCode),
+    #[error("Unexpected status code {0}: {1}")]
+    UnexpectedStatusWithBody(StatusCode, String),
     #[error("The following receivers had invalid certificates which is why the request has been canceld: {0:?}")]
     InvalidReceivers(Vec<ProxyId>),
     #[error("Other handler specific error: {0}")]
@@ -175,6 +177,98 @@ impl BeamClient {
         }
     }
 
+    /// Upload a result that is stored apart from the task's results, e.g. one with a large artifact as its body.
+    /// Returns the digest the creator of the task can fetch it by with [`Self::get_attachment`], e.g. after reading it from a regular result.
+    /// The broker needs a blob store for this to work.
+    pub async fn put_attachment<T: Serialize + 'static>(&self, attachment: &TaskResult<T>, for_task_id: &MsgId) -> Result<String> {
+        let url = self.beam_proxy_url
+            .join(&format!("/v1/tasks/{for_task_id}/attachments"))
+            .expect("The proxy url is valid");
+        let response = self.client
+            .put(url)
+            .json(attachment)
+            .send().await?
+            .handle_invalid_receivers().await?;
+        if response.status() != StatusCode::CREATED {
+            return Err(BeamError::UnexpectedStatus(response.status()));
+        }
+        response.headers()
+            .get(header::LOCATION)
+            .and_then(|location| location.to_str().ok())
+            .and_then(|location| location.rsplit_once('/'))
+            .map(|(_, digest)| digest.to_string())
+            .ok_or_else(|| BeamError::other("Missing location of the attachment"))
+    }
+
+    /// Fetch an attachment of a task by its digest.
+    pub async fn get_attachment<T: DeserializeOwned + 'static>(&self, task_id: &MsgId, digest: &str) -> Result<TaskResult<T>> {
+        let url = self.beam_proxy_url
+            .join(&format!("/v1/tasks/{task_id}/attachments/{digest}"))
+            .expect("The proxy url is valid");
+        let response = self.client
+            .get(url)
+            .send().await?;
+        match response.status() {
+            StatusCode::OK => Ok(response.json().await?),
+            status => Err(BeamError::UnexpectedStatus(status))
+        }
+    }
+
+    /// Claim a task so it is hidden from other workers' todo listings for the given lease duration.
+    /// The claim has to be a result with status [`crate::WorkStatus::Claimed`], which is stored as this worker's result if the claim succeeds.
+    /// Returns false if another worker holds a lease on the task.
+    pub async fn claim_task<T: Serialize + 'static>(&self, claim: &TaskResult<T>, lease: Option<Duration>) -> Result<bool> {
+        let mut url = self.beam_proxy_url
+            .join(&format!("/v1/tasks/{}/claim", claim.task))
+            .expect("The proxy url is valid");
+        if let Some(lease) = lease {
+            url.set_query(Some(&format!("lease={}", lease.as_secs())));
+        }
+        let response = self.client
+            .post(url)
+            .json(claim)
+            .send().await?
+            .handle_invalid_receivers().await?;
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(true),
+            StatusCode::CONFLICT => Ok(false),
+            status => Err(BeamError::UnexpectedStatus(status))
+        }
+    }
+
+    /// Acknowledge that the result of `worker` for a task created by this app has been received.
+    pub async fn acknowledge_result(&self, task_id: &MsgId, worker: &AppOrProxyId) -> Result<()> {
+        let url = self.beam_proxy_url
+            .join(&format!("/v1/tasks/{task_id}/results/{worker}/ack"))
+            .expect("The proxy url is valid");
+        let response = self.client
+            .post(url)
+            .send().await?;
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(()),
+            status => Err(BeamError::UnexpectedStatus(status))
+        }
+    }
+
+    /// Wait for the creator of the task to acknowledge the result of `worker` using the given blocking options.
+    /// Returns false if it has not been acknowledged in time.
+    pub async fn wait_for_acknowledgement(&self, task_id: &MsgId, worker: &AppOrProxyId, blocking: &BlockingOptions) -> Result<bool> {
+        #[derive(Deserialize)]
+        struct Acknowledgement {
+            acknowledged: bool,
+        }
+        let url = self.beam_proxy_url
+            .join(&format!("/v1/tasks/{task_id}/results/{worker}/ack?{}", blocking.to_query()))
+            .expect("The proxy url is valid");
+        let response = self.client
+            .get(url)
+            .send().await?;
+        match response.status() {
+            StatusCode::OK => Ok(response.json::<Acknowledgement>().await?.acknowledged),
+            status => Err(BeamError::UnexpectedStatus(status))
+        }
+    }
+
     /// For low level beam request where full control of the request is required.
     /// This will return a [`reqwest::RequestBuilder`] with a url relative to the given path.
     pub fn raw_beam_request(&self, method: reqwest::Method, relative_path: &str) -> reqwest::RequestBuilder {
@@ -243,6 +337,21 @@ impl BeamClient {
         }
     }
 
+    /// End the relay of a connected socket by its socket task id, closing the connections of both parties
+    #[cfg(feature = "sockets")]
+    pub async fn cancel_socket(&self, socket_task_id: &MsgId) -> Result<()> {
+        let url = self.beam_proxy_url
+            .join(&format!("/v1/sockets/{socket_task_id}"))
+            .expect("The proxy url is valid");
+        let response = self.client
+            .delete(url)
+            .send().await?;
+        match response.status() {
+            StatusCode::NO_CONTENT => Ok(()),
+            status => Err(BeamError::UnexpectedStatus(status))
+        }
+    }
+
     /// Connect to a socket by its socket task id
     #[cfg(feature = "sockets")]
     pub async fn connect_socket(&self, socket_task_id: &MsgId) -> Result<reqwest::Upgraded> {
@@ -268,10 +377,16 @@ impl BeamClient {
 impl HandleInvalidReceiversExt for Response {
     fn handle_invalid_receivers(self) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>> {
         async fn handle_invalid_receivers(res: Response) -> Result<Response> {
-            if res.status() == StatusCode::FAILED_DEPENDENCY {
-                Err(BeamError::InvalidReceivers(res.json().await?))
-            } else {
-                Ok(res)
+            // The proxy checks the receivers' certificates (424) and the broker may check them as well (422)
+            if !matches!(res.status(), StatusCode::FAILED_DEPENDENCY | StatusCode::UNPROCESSABLE_ENTITY) {
+                return Ok(res);
+            }
+            let status = res.status();
+            let body = res.bytes().await?;
+            // Other 422s, e.g. for a result status the task's failure strategy rejects, come with a message instead
+            match serde_json::from_slice(&body) {
+                Ok(receivers) => Err(BeamError::InvalidReceivers(receivers)),
+                Err(_) => Err(BeamError::UnexpectedStatusWithBody(status, String::from_utf8_lossy(&body).into_owned())),
             }
         }
         Box::pin(handle_invalid_receivers(self))
@@ -281,3 +396,24 @@ impl HandleInvalidReceiversExt for Response {
 trait HandleInvalidReceiversExt: Sized {
     fn handle_invalid_receivers(self) -> Pin<Box<dyn Future<Output = Result<Response>> + Send>>;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(status: StatusCode, body: &'static str) -> Response {
+        http::Response::builder().status(status).body(body).unwrap().into()
+    }
+
+    #[tokio::test]
+    async fn only_receiver_lists_are_invalid_receivers() {
+        crate::set_broker_id("broker.samply.de".to_string());
+        let invalid = response(StatusCode::UNPROCESSABLE_ENTITY, r#"["proxy2.broker.samply.de"]"#).handle_invalid_receivers().await;
+        assert!(matches!(invalid, Err(BeamError::InvalidReceivers(proxies)) if proxies == [ProxyId::new_unchecked("proxy2.broker.samply.de")]));
+
+        let rejected = response(StatusCode::UNPROCESSABLE_ENTITY, "Status tempfailed is not accepted").handle_invalid_receivers().await;
+        assert!(matches!(rejected, Err(BeamError::UnexpectedStatusWithBody(StatusCode::UNPROCESSABLE_ENTITY, body)) if body == "Status tempfailed is not accepted"));
+
+        assert!(response(StatusCode::CREATED, "").handle_invalid_receivers().await.is_ok());
+    }
+}
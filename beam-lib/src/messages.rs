@@ -3,7 +3,7 @@ use serde_json::Value;
 use uuid::Uuid;
 use crate::AddressingId;
 
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Deserialize, Serialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Deserialize, Serialize)]
 pub struct MsgId(Uuid);
 
 impl MsgId {
@@ -18,7 +18,17 @@ impl std::fmt::Display for MsgId {
     }
 }
 
+impl std::str::FromStr for MsgId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        s.parse().map(Self)
+    }
+}
+
+/// Construct with [`TaskRequest::new`] and set the optional fields afterwards, so new optional fields don't break callers
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct TaskRequest<T> {
     pub id: MsgId,
     pub from: AddressingId,
@@ -30,10 +40,38 @@ pub struct TaskRequest<T> {
     pub body: T,
     pub ttl: String,
     pub failure_strategy: FailureStrategy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_policy: Option<CompletionPolicy>,
+    /// RFC 3339 timestamp after which the broker rejects results, e.g. `2024-01-01T12:00:00Z`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<String>,
+    /// Earlier attempt of the same logical task that this task retries
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_task: Option<MsgId>,
     pub metadata: Value,
 }
 
+impl<T> TaskRequest<T> {
+    /// A task without completion policy, deadline or parent task
+    pub fn new(id: MsgId, from: AddressingId, to: Vec<AddressingId>, body: T, ttl: String, failure_strategy: FailureStrategy, metadata: Value) -> Self {
+        Self {
+            id,
+            from,
+            to,
+            body,
+            ttl,
+            failure_strategy,
+            completion_policy: None,
+            deadline: None,
+            parent_task: None,
+            metadata,
+        }
+    }
+}
+
+/// Construct with [`TaskResult::new`] and set the optional fields afterwards, so new optional fields don't break callers
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct TaskResult<T> {
     pub from: AddressingId,
     pub to: Vec<AddressingId>,
@@ -45,10 +83,30 @@ pub struct TaskResult<T> {
     )]
     pub body: T,
     pub metadata: Value,
+    /// Increasing number of this worker's updates to the result. The broker rejects updates older than the stored result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
+}
+
+impl<T> TaskResult<T> {
+    /// A result without sequence number
+    pub fn new(from: AddressingId, to: Vec<AddressingId>, task: MsgId, status: WorkStatus, body: T, metadata: Value) -> Self {
+        Self {
+            from,
+            to,
+            task,
+            status,
+            body,
+            metadata,
+            seq: None,
+        }
+    }
 }
 
+/// Construct with [`SocketTask::new`] and set the optional fields afterwards, so new optional fields don't break callers
 #[cfg(feature = "sockets")]
 #[derive(Debug, Serialize, Deserialize)]
+#[non_exhaustive]
 pub struct SocketTask {
     pub from: AddressingId,
     pub to: Vec<AddressingId>,
@@ -56,6 +114,24 @@ pub struct SocketTask {
     pub id: MsgId,
     #[serde(default)]
     pub metadata: Value,
+    /// The task of the socket's creator the socket belongs to. The proxy of the worker on the other end records how the socket ended as its result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<MsgId>,
+}
+
+#[cfg(feature = "sockets")]
+impl SocketTask {
+    /// A socket that doesn't belong to a task
+    pub fn new(from: AddressingId, to: Vec<AddressingId>, ttl: String, id: MsgId, metadata: Value) -> Self {
+        Self {
+            from,
+            to,
+            ttl,
+            id,
+            metadata,
+            task: None,
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
@@ -68,6 +144,44 @@ pub enum FailureStrategy {
     },
 }
 
+impl FailureStrategy {
+    /// Retrying needs at least one try and a pause between tries
+    pub fn validate(&self) -> Result<(), &'static str> {
+        match self {
+            Self::Retry { max_tries: 0, .. } => Err("Retry failure strategy needs max_tries > 0"),
+            Self::Retry { backoff_millisecs: 0, .. } => Err("Retry failure strategy needs a non-zero backoff"),
+            _ => Ok(()),
+        }
+    }
+
+    /// A temporary failure asks for another try, which only makes sense if the task is retried
+    pub fn accepts(&self, status: WorkStatus) -> Result<(), &'static str> {
+        match (self, status) {
+            (Self::Discard, WorkStatus::TempFailed) => Err("Task does not retry failures, so it only accepts permanent ones; please use the status permfailed"),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Where a proxy runs as advertised by the proxy itself, so apps can choose a nearby proxy or address the proxies of a zone
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct ProxyMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub zone: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub region: Option<String>,
+}
+
+/// Decides when the broker considers a task complete
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum CompletionPolicy {
+    /// Complete once all recipients have succeeded
+    All,
+    /// Complete once any recipient has succeeded
+    Any,
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone, Copy, Eq, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum WorkStatus {
@@ -77,6 +191,13 @@ pub enum WorkStatus {
     PermFailed,
 }
 
+impl WorkStatus {
+    /// Whether the worker is done with the task, i.e. it either succeeded or failed permanently
+    pub fn is_terminal(self) -> bool {
+        matches!(self, WorkStatus::Succeeded | WorkStatus::PermFailed)
+    }
+}
+
 #[derive(Debug, Serialize, Deserialize, Clone)]
 pub struct MsgEmpty {
     pub from: AddressingId,
@@ -136,15 +257,7 @@ mod tests {
         #[cfg(feature = "strict-ids")]
         crate::set_broker_id("broker.samply.de".to_string());
         let from = AppId::new_unchecked("test.broker.samply.de").into();
-        let task = TaskRequest {
-            id: MsgId::new(),
-            from,
-            to: vec![],
-            body: <T>::from("asdf"),
-            ttl: "10s".to_string(),
-            failure_strategy: FailureStrategy::Discard,
-            metadata: Value::Null,
-        };
+        let task = TaskRequest::new(MsgId::new(), from, vec![], <T>::from("asdf"), "10s".to_string(), FailureStrategy::Discard, Value::Null);
         assert_eq!(serde_json::from_str::<TaskRequest<T>>(&serde_json::to_string(&task).unwrap()).unwrap().body, task.body);
     }
 
@@ -153,4 +266,22 @@ mod tests {
         test_serialize_and_deserialize::<RawString>();
         test_serialize_and_deserialize::<String>();
     }
+
+    #[test]
+    fn validate_failure_strategy() {
+        assert!(FailureStrategy::Discard.validate().is_ok());
+        assert!(FailureStrategy::Retry { backoff_millisecs: 1000, max_tries: 5 }.validate().is_ok());
+        assert!(FailureStrategy::Retry { backoff_millisecs: 1000, max_tries: 0 }.validate().is_err());
+        assert!(FailureStrategy::Retry { backoff_millisecs: 0, max_tries: 5 }.validate().is_err());
+    }
+
+    #[test]
+    fn failure_strategy_accepts_status() {
+        use WorkStatus::*;
+        let retry = FailureStrategy::Retry { backoff_millisecs: 1000, max_tries: 5 };
+        for status in [Claimed, Succeeded, TempFailed, PermFailed] {
+            assert_eq!(FailureStrategy::Discard.accepts(status).is_ok(), status != TempFailed, "{status:?}");
+            assert!(retry.accepts(status).is_ok(), "{status:?}");
+        }
+    }
 }
@@ -0,0 +1,103 @@
+use std::{fs::{File, OpenOptions}, io::{self, Write}, path::PathBuf, sync::Mutex, time::{Duration, SystemTime}};
+
+use beam_lib::{AppOrProxyId, WorkStatus};
+use serde::Serialize;
+use shared::{expire_map::LazyExpireMap, openssl, EncryptedMsgTaskResult, MsgId, MsgSigned};
+use tokio::time::Instant;
+use tracing::error;
+
+/// Append-only record of every accepted result, so operators can tell who submitted what and when even after a result was overwritten
+#[derive(Default)]
+pub(crate) struct AuditLog {
+    /// Entries by task, kept as long as their task
+    entries: LazyExpireMap<MsgId, Vec<AuditEntry>>,
+    /// File every entry is appended to as a line of JSON. Unlike the entries in memory, it outlives tasks and restarts.
+    file: Option<Mutex<File>>,
+}
+
+#[derive(Serialize, Clone, Debug, PartialEq)]
+pub(crate) struct AuditEntry {
+    pub(crate) task: MsgId,
+    pub(crate) worker: AppOrProxyId,
+    /// When the broker accepted the result, in RFC 3339 format
+    pub(crate) submitted: String,
+    pub(crate) status: WorkStatus,
+    /// Hex encoded SHA-256 digest of the signed result, which identifies its encrypted body without revealing it
+    pub(crate) digest: String,
+}
+
+impl AuditEntry {
+    pub(crate) fn new(result: &MsgSigned<EncryptedMsgTaskResult>) -> Self {
+        Self {
+            task: result.msg.task,
+            worker: result.msg.from.clone(),
+            submitted: humantime::format_rfc3339_millis(SystemTime::now()).to_string(),
+            status: result.msg.status,
+            digest: openssl::sha::sha256(result.jwt.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect(),
+        }
+    }
+}
+
+impl AuditLog {
+    pub(crate) fn new(file: Option<PathBuf>) -> io::Result<Self> {
+        let file = file
+            .map(|path| OpenOptions::new().create(true).append(true).open(path))
+            .transpose()?
+            .map(Mutex::new);
+        Ok(Self { entries: Default::default(), file })
+    }
+
+    /// Records the entry of a result that has been accepted. The entries of a task are kept in memory until `keep_for` after the latest one.
+    pub(crate) fn record(&self, entry: AuditEntry, keep_for: Duration) {
+        if let Some(file) = &self.file {
+            let mut line = serde_json::to_vec(&entry).expect("Audit entries can be serialized");
+            line.push(b'\n');
+            // A poisoned lock only means that another write failed, which has been logged already
+            let mut file = file.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            if let Err(e) = file.write_all(&line) {
+                error!("Unable to append result of {} to task {} to the audit log: {e}", entry.worker, entry.task);
+            }
+        }
+        let keep_until = Instant::now() + keep_for;
+        let mut entries = self.entries.entry(entry.task).or_insert_with(|| (Vec::new(), keep_until));
+        entries.1 = entries.1.max(keep_until);
+        entries.0.push(entry);
+    }
+
+    /// The entries of the task in the order the results were accepted
+    pub(crate) fn get(&self, task_id: &MsgId) -> Vec<AuditEntry> {
+        self.entries.get(task_id).map(|entries| entries.clone()).unwrap_or_default()
+    }
+
+    pub(crate) fn retain_expired(&self) {
+        self.entries.retain_expired();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::test_utils::{app, result};
+
+    use super::*;
+
+    #[test]
+    fn append_to_file() {
+        let path = std::env::temp_dir().join(format!("beam-audit-{}.jsonl", MsgId::new()));
+        let (worker, creator) = (app("app1"), app("app2"));
+        let entry = |status| AuditEntry::new(&MsgSigned {
+            msg: result(MsgId::new(), &worker, &creator, status),
+            jwt: "signed".to_string(),
+        });
+        AuditLog::new(Some(path.clone())).unwrap().record(entry(WorkStatus::Claimed), Duration::from_secs(60));
+        // Reopening appends instead of truncating
+        AuditLog::new(Some(path.clone())).unwrap().record(entry(WorkStatus::Succeeded), Duration::from_secs(60));
+
+        let lines: Vec<serde_json::Value> = std::fs::read_to_string(&path).unwrap().lines().map(|line| serde_json::from_str(line).unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[0]["status"], "claimed");
+        assert_eq!(lines[1]["status"], "succeeded");
+        // printf signed | sha256sum
+        assert_eq!(lines[1]["digest"], "4a3cdfae6f291c8f544daea5b72905cf9e74c1ed427d831ad0d7ca00c73c794d");
+        std::fs::remove_file(path).unwrap();
+    }
+}
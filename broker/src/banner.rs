@@ -1,13 +1,32 @@
+use std::net::SocketAddr;
+
 use axum::{http::{header, HeaderValue}, response::Response};
+use shared::config_broker;
 use tracing::info;
 
-pub(crate) fn print_banner() {
+/// Logs the version this broker was built from. With `no_banner` this is a single structured event for log collectors.
+pub(crate) fn print_banner(config: &config_broker::Config) {
+    log_startup(config.no_banner, &config.bind_addr);
+}
+
+fn log_startup(no_banner: bool, bind_addr: &SocketAddr) {
     let commit = match env!("GIT_DIRTY") {
         "false" => {
             env!("GIT_COMMIT_SHORT")
         }
         _ => "SNAPSHOT",
     };
+    if no_banner {
+        info!(
+            name = env!("CARGO_PKG_NAME"),
+            version = env!("CARGO_PKG_VERSION"),
+            commit,
+            bind_addr = %bind_addr,
+            broker_id = beam_lib::get_broker_id(),
+            "Starting up"
+        );
+        return;
+    }
     info!(
         "🌈 Samply.Beam ({}) v{} (built {} {}, {} with feature(s): {}) starting up ...",
         env!("CARGO_PKG_NAME"),
@@ -28,3 +47,44 @@ pub(crate) async fn set_server_header<B>(mut response: Response<B>) -> Response<
     }
     response
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{io, sync::{Arc, Mutex}};
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Log(Arc<Mutex<Vec<u8>>>);
+
+    impl io::Write for Log {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logged_startup(no_banner: bool) -> String {
+        shared::test_utils::init_broker_id();
+        let log = Log::default();
+        let writer = log.clone();
+        let subscriber = tracing_subscriber::fmt().with_writer(move || writer.clone()).with_ansi(false).finish();
+        tracing::subscriber::with_default(subscriber, || log_startup(no_banner, &"0.0.0.0:8080".parse().unwrap()));
+        let log = log.0.lock().unwrap();
+        String::from_utf8(log.clone()).unwrap()
+    }
+
+    #[test]
+    fn no_banner() {
+        assert!(logged_startup(false).contains("🌈 Samply.Beam"));
+        let log = logged_startup(true);
+        assert!(!log.contains("🌈"), "{log}");
+        assert_eq!(log.lines().count(), 1, "{log}");
+        for field in [concat!("version=\"", env!("CARGO_PKG_VERSION"), "\""), "bind_addr=0.0.0.0:8080", "broker_id=\"broker.samply.de\"", "Starting up"] {
+            assert!(log.contains(field), "{field} is missing in {log}");
+        }
+    }
+}
@@ -0,0 +1,263 @@
+use std::{fs, io::{self, Read, Write}, path::PathBuf, sync::Arc};
+
+use dashmap::DashMap;
+use flate2::{read::DeflateDecoder, write::DeflateEncoder, Compression};
+use shared::{openssl, Encrypted, EncryptedMsgTaskRequest, MsgId, MsgSigned};
+use tracing::{debug, warn};
+
+/// Storage for the signed messages of large tasks so only their metadata is kept in memory.
+/// Its methods block, so they are called outside of the async executor.
+pub(crate) trait BlobStore: Send + Sync {
+    fn put(&self, id: &MsgId, blob: &str) -> io::Result<()>;
+    fn get(&self, id: &MsgId) -> io::Result<String>;
+    fn delete(&self, id: &MsgId) -> io::Result<()>;
+    /// Ids of all stored blobs
+    fn ids(&self) -> io::Result<Vec<MsgId>>;
+}
+
+/// Stores each blob as a file named after the id of its task
+pub(crate) struct FsBlobStore {
+    dir: PathBuf,
+}
+
+impl FsBlobStore {
+    pub(crate) fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    fn path(&self, id: &MsgId) -> PathBuf {
+        self.dir.join(id.to_string())
+    }
+}
+
+impl BlobStore for FsBlobStore {
+    fn put(&self, id: &MsgId, blob: &str) -> io::Result<()> {
+        fs::write(self.path(id), blob)
+    }
+
+    fn get(&self, id: &MsgId) -> io::Result<String> {
+        fs::read_to_string(self.path(id))
+    }
+
+    fn delete(&self, id: &MsgId) -> io::Result<()> {
+        fs::remove_file(self.path(id))
+    }
+
+    fn ids(&self) -> io::Result<Vec<MsgId>> {
+        let mut ids = Vec::new();
+        for entry in fs::read_dir(&self.dir)? {
+            let name = entry?.file_name();
+            // Skip files not written by us
+            if let Some(id) = name.to_str().and_then(|name| name.parse().ok()) {
+                ids.push(id);
+            }
+        }
+        Ok(ids)
+    }
+}
+
+/// Keeps each blob compressed in memory, for brokers without a disk to offload tasks to.
+/// The encrypted bodies compress poorly, but they are base64 encoded twice in a signed message, which compression undoes to a good part.
+#[derive(Default)]
+pub(crate) struct CompressedBlobStore {
+    blobs: DashMap<MsgId, Vec<u8>>,
+}
+
+fn compress(blob: &str) -> io::Result<Vec<u8>> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::fast());
+    encoder.write_all(blob.as_bytes())?;
+    encoder.finish()
+}
+
+fn decompress(compressed: &[u8]) -> io::Result<String> {
+    let mut blob = String::new();
+    DeflateDecoder::new(compressed).read_to_string(&mut blob)?;
+    Ok(blob)
+}
+
+impl BlobStore for CompressedBlobStore {
+    fn put(&self, id: &MsgId, blob: &str) -> io::Result<()> {
+        let compressed = compress(blob)?;
+        debug!("Compressed task {id} from {} to {} bytes", blob.len(), compressed.len());
+        self.blobs.insert(*id, compressed);
+        Ok(())
+    }
+
+    fn get(&self, id: &MsgId) -> io::Result<String> {
+        let compressed = self.blobs.get(id).ok_or(io::ErrorKind::NotFound)?;
+        decompress(&compressed)
+    }
+
+    fn delete(&self, id: &MsgId) -> io::Result<()> {
+        self.blobs.remove(id);
+        Ok(())
+    }
+
+    fn ids(&self) -> io::Result<Vec<MsgId>> {
+        Ok(self.blobs.iter().map(|entry| *entry.key()).collect())
+    }
+}
+
+/// Offloads tasks whose signed message is at least `min_size` bytes long to the blob store
+pub(crate) struct OffloadedTasks {
+    store: Arc<dyn BlobStore>,
+    min_size: usize,
+}
+
+impl OffloadedTasks {
+    pub(crate) fn new(store: impl BlobStore + 'static, min_size: usize) -> Self {
+        Self { store: Arc::new(store), min_size }
+    }
+
+    /// Whether the task is large enough to be moved to the blob store
+    pub(crate) fn should_offload(&self, task: &MsgSigned<EncryptedMsgTaskRequest>) -> bool {
+        task.jwt.len() >= self.min_size
+    }
+
+    /// Writes the signed message of a task to the blob store
+    pub(crate) async fn store(&self, id: MsgId, jwt: String) -> io::Result<()> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || {
+            store.put(&id, &jwt)?;
+            debug!("Offloaded task {id} of {} bytes", jwt.len());
+            Ok(())
+        })
+        .await?
+    }
+
+    /// Deletes the stored signed message of a task
+    pub(crate) async fn delete(&self, id: MsgId) -> io::Result<()> {
+        let store = self.store.clone();
+        tokio::task::spawn_blocking(move || store.delete(&id)).await?
+    }
+
+    /// Drops the encrypted body of a task whose signed message was stored from memory.
+    /// An offloaded task is recognized by its empty JWT.
+    pub(crate) fn strip(task: &mut MsgSigned<EncryptedMsgTaskRequest>) {
+        task.jwt = String::new();
+        task.msg.body = Encrypted::default();
+    }
+
+    /// Returns the JWT of an offloaded task or `None` if it was kept in memory
+    pub(crate) fn load(&self, task: &MsgSigned<EncryptedMsgTaskRequest>) -> io::Result<Option<String>> {
+        if !task.jwt.is_empty() {
+            return Ok(None);
+        }
+        self.store.get(&task.msg.id).map(Some)
+    }
+
+    /// Deletes the blobs of tasks that no longer exist, including those left over from a previous run
+    pub(crate) fn remove_orphans(&self, exists: impl Fn(&MsgId) -> bool) {
+        let ids = match self.store.ids() {
+            Ok(ids) => ids,
+            Err(e) => {
+                warn!("Unable to list offloaded tasks: {e}");
+                return;
+            }
+        };
+        for id in ids.iter().filter(|id| !exists(id)) {
+            if let Err(e) = self.store.delete(id) {
+                warn!("Unable to delete offloaded task {id}: {e}");
+            }
+        }
+    }
+}
+
+/// Signed results stored apart from their task, each under the SHA-256 digest of its signed message.
+/// They are kept in a directory per task so they can be removed together once the task is gone.
+pub(crate) struct Attachments {
+    dir: PathBuf,
+}
+
+impl Attachments {
+    pub(crate) fn new(dir: PathBuf) -> io::Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(Self { dir })
+    }
+
+    /// Stores the signed message and returns its hex encoded digest
+    pub(crate) async fn put(&self, task_id: MsgId, jwt: String) -> io::Result<String> {
+        let task_dir = self.dir.join(task_id.to_string());
+        tokio::task::spawn_blocking(move || {
+            let digest: String = openssl::sha::sha256(jwt.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect();
+            fs::create_dir_all(&task_dir)?;
+            fs::write(task_dir.join(&digest), &jwt)?;
+            debug!("Stored attachment {digest} of {} bytes for task {task_id}", jwt.len());
+            Ok(digest)
+        })
+        .await?
+    }
+
+    /// Returns the signed message or `None` if there is no such attachment
+    pub(crate) async fn get(&self, task_id: &MsgId, digest: &str) -> io::Result<Option<String>> {
+        // The digest comes from the URL, so it must not be able to leave the task's directory
+        if digest.len() != 64 || !digest.bytes().all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b)) {
+            return Ok(None);
+        }
+        match tokio::fs::read_to_string(self.dir.join(task_id.to_string()).join(digest)).await {
+            Ok(jwt) => Ok(Some(jwt)),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    /// Deletes the attachments of tasks that no longer exist, including those left over from a previous run
+    pub(crate) fn remove_orphans(&self, exists: impl Fn(&MsgId) -> bool) {
+        let entries = match fs::read_dir(&self.dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Unable to list attachments: {e}");
+                return;
+            }
+        };
+        for entry in entries.flatten() {
+            let Some(task_id) = entry.file_name().to_str().and_then(|name| name.parse::<MsgId>().ok()) else {
+                continue;
+            };
+            if !exists(&task_id) {
+                if let Err(e) = fs::remove_dir_all(entry.path()) {
+                    warn!("Unable to delete attachments of task {task_id}: {e}");
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fs_round_trip() {
+        let dir = std::env::temp_dir().join(format!("beam-blobs-{}", MsgId::new()));
+        let store = FsBlobStore::new(dir.clone()).unwrap();
+        let (kept, orphan) = (MsgId::new(), MsgId::new());
+        store.put(&kept, "kept").unwrap();
+        store.put(&orphan, "orphan").unwrap();
+        fs::write(dir.join("unrelated"), "").unwrap();
+        assert_eq!(store.get(&kept).unwrap(), "kept");
+
+        let offloaded = OffloadedTasks::new(store, 0);
+        offloaded.remove_orphans(|id| *id == kept);
+        assert!(dir.join(kept.to_string()).exists());
+        assert!(!dir.join(orphan.to_string()).exists());
+        assert!(dir.join("unrelated").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn compressed_round_trip() {
+        let store = CompressedBlobStore::default();
+        let (kept, orphan) = (MsgId::new(), MsgId::new());
+        let blob = format!("header.{}.signature", "eyJib2R5IjoiYWJjIn0".repeat(1000));
+        store.put(&kept, &blob).unwrap();
+        store.put(&orphan, "orphan").unwrap();
+        assert!(store.blobs.get(&kept).unwrap().len() < blob.len() / 10);
+        assert_eq!(store.get(&kept).unwrap(), blob);
+
+        let offloaded = OffloadedTasks::new(store, 0);
+        offloaded.remove_orphans(|id| *id == kept);
+        assert_eq!(offloaded.store.ids().unwrap(), [kept]);
+    }
+}
@@ -0,0 +1,156 @@
+use std::{sync::Mutex, time::{Duration, Instant}};
+
+use serde::Serialize;
+use tokio::sync::watch;
+use tracing::{info, warn};
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq, Default)]
+#[serde(rename_all = "snake_case")]
+pub enum CircuitStatus {
+    /// Requests are passed through
+    #[default]
+    Closed,
+    /// Requests fail fast until the cooldown has passed
+    Open,
+    /// A single probe request is passed through to check for recovery
+    HalfOpen,
+}
+
+enum State {
+    Closed { failures: u32 },
+    Open { until: Instant },
+    HalfOpen { since: Instant },
+}
+
+/// Fails requests to the vault fast after it failed `threshold` times in a row.
+/// After `cooldown`, the next request probes whether it has recovered.
+/// A probe that never reports back, e.g. as its request was cancelled, is replaced by another one after `cooldown`.
+pub struct CircuitBreaker {
+    state: Mutex<State>,
+    threshold: u32,
+    cooldown: Duration,
+    status_sender: watch::Sender<CircuitStatus>,
+}
+
+impl CircuitBreaker {
+    pub fn new(threshold: u32, cooldown: Duration, status_sender: watch::Sender<CircuitStatus>) -> Self {
+        status_sender.send_replace(CircuitStatus::Closed);
+        Self {
+            state: Mutex::new(State::Closed { failures: 0 }),
+            threshold: threshold.max(1),
+            cooldown,
+            status_sender,
+        }
+    }
+
+    /// Whether a request may be sent. Only one request is let through while probing.
+    pub fn allow(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        match *state {
+            State::Closed { .. } => true,
+            State::Open { until } if until <= Instant::now() => {
+                *state = State::HalfOpen { since: Instant::now() };
+                self.status_sender.send_replace(CircuitStatus::HalfOpen);
+                true
+            }
+            State::HalfOpen { since } if since + self.cooldown <= Instant::now() => {
+                *state = State::HalfOpen { since: Instant::now() };
+                true
+            }
+            State::Open { .. } | State::HalfOpen { .. } => false,
+        }
+    }
+
+    pub fn record_success(&self) {
+        let mut state = self.state.lock().unwrap();
+        if !matches!(*state, State::Closed { .. }) {
+            info!("Vault has recovered, closing circuit breaker");
+            self.status_sender.send_replace(CircuitStatus::Closed);
+        }
+        *state = State::Closed { failures: 0 };
+    }
+
+    pub fn record_failure(&self) {
+        let mut state = self.state.lock().unwrap();
+        let failures = match *state {
+            State::Closed { failures } => failures + 1,
+            // A failed probe opens the circuit again
+            State::HalfOpen { .. } => self.threshold,
+            State::Open { .. } => return,
+        };
+        if failures < self.threshold {
+            *state = State::Closed { failures };
+            return;
+        }
+        warn!("Vault failed {failures} times, failing requests to it for {}s", self.cooldown.as_secs());
+        *state = State::Open { until: Instant::now() + self.cooldown };
+        self.status_sender.send_replace(CircuitStatus::Open);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn outage_and_recovery() {
+        let (sender, status) = watch::channel(CircuitStatus::default());
+        let breaker = CircuitBreaker::new(3, Duration::from_millis(50), sender);
+        // Vault goes down
+        for _ in 0..2 {
+            assert!(breaker.allow());
+            breaker.record_failure();
+        }
+        assert_eq!(*status.borrow(), CircuitStatus::Closed);
+        assert!(breaker.allow());
+        breaker.record_failure();
+        assert_eq!(*status.borrow(), CircuitStatus::Open);
+        assert!(!breaker.allow());
+
+        // The probe fails as the vault is still down
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        assert_eq!(*status.borrow(), CircuitStatus::HalfOpen);
+        assert!(!breaker.allow(), "Only a single probe is let through");
+        breaker.record_failure();
+        assert_eq!(*status.borrow(), CircuitStatus::Open);
+        assert!(!breaker.allow());
+
+        // Vault recovers
+        std::thread::sleep(Duration::from_millis(60));
+        assert!(breaker.allow());
+        breaker.record_success();
+        assert_eq!(*status.borrow(), CircuitStatus::Closed);
+        assert!(breaker.allow());
+        // Failures are only counted in a row
+        breaker.record_failure();
+        breaker.record_success();
+        breaker.record_failure();
+        breaker.record_failure();
+        assert!(breaker.allow());
+    }
+
+    #[tokio::test]
+    async fn cancelled_probe_is_replaced() {
+        let (sender, status) = watch::channel(CircuitStatus::default());
+        let breaker = CircuitBreaker::new(1, Duration::from_millis(50), sender);
+        breaker.record_failure();
+        tokio::time::sleep(Duration::from_millis(60)).await;
+
+        // The probe's request is dropped before it reports back
+        let probe = async {
+            assert!(breaker.allow());
+            std::future::pending::<()>().await;
+            breaker.record_success();
+        };
+        assert!(tokio::time::timeout(Duration::from_millis(10), probe).await.is_err());
+        assert_eq!(*status.borrow(), CircuitStatus::HalfOpen);
+        assert!(!breaker.allow());
+
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(breaker.allow(), "Another probe is let through after the cooldown");
+        assert!(!breaker.allow());
+        breaker.record_success();
+        assert_eq!(*status.borrow(), CircuitStatus::Closed);
+    }
+}
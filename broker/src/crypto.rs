@@ -15,12 +15,13 @@ use std::time::Duration;
 use tokio::time::timeout;
 use tracing::{debug, error, warn, info};
 
-use crate::health::{self, VaultStatus};
+use crate::{circuit_breaker::{CircuitBreaker, CircuitStatus}, health::{self, VaultStatus}};
 
 pub struct GetCertsFromPki {
     pki_realm: String,
     hyper_client: SamplyHttpClient,
     health_report_sender: tokio::sync::watch::Sender<health::VaultStatus>,
+    breaker: CircuitBreaker,
 }
 
 #[derive(Debug, Deserialize, Clone, Hash)]
@@ -42,6 +43,7 @@ struct PkiListResponse {
 impl GetCertsFromPki {
     pub(crate) fn new(
         health_report_sender: tokio::sync::watch::Sender<health::VaultStatus>,
+        circuit_sender: tokio::sync::watch::Sender<CircuitStatus>,
     ) -> Result<Self, SamplyBeamError> {
         let mut certs: Vec<String> = Vec::new();
         if let Some(dir) = &config::CONFIG_CENTRAL.tls_ca_certificates_dir {
@@ -64,10 +66,17 @@ impl GetCertsFromPki {
         )?;
         let pki_realm = config::CONFIG_CENTRAL.pki_realm.clone();
 
+        let breaker = CircuitBreaker::new(
+            config::CONFIG_CENTRAL.vault_failure_threshold,
+            config::CONFIG_CENTRAL.vault_cooldown,
+            circuit_sender,
+        );
+
         Ok(Self {
             pki_realm,
             hyper_client,
             health_report_sender,
+            breaker,
         })
     }
 
@@ -137,6 +146,11 @@ impl GetCertsFromPki {
             if tries > 0 {
                 tokio::time::sleep(Duration::from_secs(3)).await;
             }
+            // Don't keep callers waiting on a failing vault; certificates already cached are still served
+            if !self.breaker.allow() {
+                debug!("Samply.PKI: Not requesting {api_path} as the vault keeps failing");
+                return Err(SamplyBeamError::VaultCircuitOpen);
+            }
             let resp = self.hyper_client
                 .request(method.clone(), uri.clone())
                 .header("X-Vault-Token", &config::CONFIG_CENTRAL.pki_token)
@@ -146,14 +160,18 @@ impl GetCertsFromPki {
             let Ok(resp) = resp else {
                 warn!("Samply.PKI: Unable to communicate to vault: {}; retrying (failed attempt #{})", resp.unwrap_err(), tries+2);
                 self.report_vault_health(VaultStatus::Unreachable).await;
+                self.breaker.record_failure();
                 continue;
             };
             match resp.status() {
                 code if code.is_success() => {
                     self.report_vault_health(VaultStatus::Ok).await;
+                    self.breaker.record_success();
                     return Ok(resp);
                 }
                 code if code.is_client_error() || code.is_redirection() => {
+                    // The vault is up but rejected the request
+                    self.breaker.record_success();
                     error!(
                         "Samply.PKI: Vault reported client-side Error (code {}), not retrying. Response was {}",
                         code, resp.text().await.unwrap_or_else(|e| format!("Failed to decode failed response: {e}"))
@@ -165,6 +183,7 @@ impl GetCertsFromPki {
                     )));
                 }
                 code => {
+                    self.breaker.record_failure();
                     match self.check_vault_health().await {
                         Err(SamplyBeamError::VaultSealed) => {
                             warn!(
@@ -269,8 +288,9 @@ impl GetCerts for GetCertsFromPki {
 
 pub(crate) fn build_cert_getter(
     sender: tokio::sync::watch::Sender<VaultStatus>,
+    circuit_sender: tokio::sync::watch::Sender<CircuitStatus>,
 ) -> Result<GetCertsFromPki, SamplyBeamError> {
-    GetCertsFromPki::new(sender)
+    GetCertsFromPki::new(sender, circuit_sender)
 }
 
 pub(crate) fn pki_url_builder(location: &str) -> Url {
@@ -0,0 +1,53 @@
+//! A single snapshot of the broker's internal state for on-call debugging, without scraping all metrics
+
+use std::sync::{Arc, RwLock};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
+use serde_json::{json, Map, Value};
+
+use crate::serve_health::check_admin_auth;
+
+type Source = Box<dyn Fn() -> Value + Send + Sync>;
+
+/// Collects the state of the parts of the broker that registered themselves
+#[derive(Clone, Default)]
+pub(crate) struct Diagnostics(Arc<RwLock<Vec<(&'static str, Source)>>>);
+
+impl Diagnostics {
+    /// Adds the values returned by `source` to the snapshot under `name`
+    pub(crate) fn register(&self, name: &'static str, source: impl Fn() -> Value + Send + Sync + 'static) {
+        self.0.write().unwrap().push((name, Box::new(source)));
+    }
+
+    pub(crate) async fn snapshot(&self) -> Value {
+        let mut snapshot: Map<String, Value> = self.0
+            .read()
+            .unwrap()
+            .iter()
+            .map(|(name, source)| (name.to_string(), source()))
+            .collect();
+        let (verified_tokens, verified_body_tokens) = shared::crypto_jwt::verified_token_cache_sizes();
+        snapshot.insert("caches".into(), json!({
+            "certificates": shared::crypto::certificate_cache_size().await,
+            "verified_tokens": verified_tokens,
+            "verified_body_tokens": verified_body_tokens,
+        }));
+        Value::Object(snapshot)
+    }
+
+    pub(crate) fn router(self) -> Router {
+        Router::new()
+            .route("/v1/admin/diagnostics", get(get_diagnostics))
+            .with_state(self)
+    }
+}
+
+// GET /v1/admin/diagnostics
+async fn get_diagnostics(
+    State(diagnostics): State<Diagnostics>,
+    auth: TypedHeader<Authorization<Basic>>,
+) -> Result<Json<Value>, StatusCode> {
+    check_admin_auth(&auth)?;
+    Ok(Json(diagnostics.snapshot().await))
+}
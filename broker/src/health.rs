@@ -1,8 +1,10 @@
 use std::{fmt::Display, sync::Arc, time::{Duration, SystemTime}, collections::HashMap};
 
 use serde::{Serialize, Deserialize};
-use beam_lib::ProxyId;
-use tokio::sync::RwLock;
+use beam_lib::{ProxyId, ProxyMetadata};
+use tokio::sync::{watch, RwLock};
+
+use crate::circuit_breaker::CircuitStatus;
 use tracing::{info, warn};
 
 #[derive(Serialize)]
@@ -51,6 +53,7 @@ impl Default for InitStatus {
 
 pub struct Health {
     pub vault: VaultStatus,
+    pub vault_circuit: watch::Receiver<CircuitStatus>,
     pub initstatus: InitStatus,
     pub proxies: HashMap<ProxyId, ProxyStatus>
 }
@@ -61,6 +64,9 @@ pub struct ProxyStatus {
     last_disconnect: Option<SystemTime>,
     #[serde(skip)]
     connections: u8,
+    /// As sent by the proxy when it last connected
+    #[serde(flatten)]
+    pub metadata: ProxyMetadata,
 }
 
 impl ProxyStatus {
@@ -73,9 +79,10 @@ impl ProxyStatus {
         self.connections -= 1;
     }
 
-    pub fn connect(&mut self) {
+    pub fn connect(&mut self, metadata: ProxyMetadata) {
         self.connections += 1;
         self.last_connect = SystemTime::now();
+        self.metadata = metadata;
     }
 
     pub fn _last_seen(&self) -> SystemTime {
@@ -88,20 +95,23 @@ impl ProxyStatus {
 }
 
 impl ProxyStatus {
-    pub fn new() -> ProxyStatus {
-        ProxyStatus { last_connect: SystemTime::now(), connections: 1, last_disconnect: None }
+    pub fn new(metadata: ProxyMetadata) -> ProxyStatus {
+        ProxyStatus { last_connect: SystemTime::now(), connections: 1, last_disconnect: None, metadata }
     }
 }
 
 pub struct Senders {
     pub vault: tokio::sync::watch::Sender<VaultStatus>,
+    pub vault_circuit: watch::Sender<CircuitStatus>,
     pub init: tokio::sync::watch::Sender<InitStatus>,
 }
 
 impl Health {
     pub fn make() -> (Senders, Arc<RwLock<Self>>) {
+        let (vault_circuit_tx, vault_circuit_rx) = watch::channel(CircuitStatus::default());
         let health = Health {
             vault: VaultStatus::default(),
+            vault_circuit: vault_circuit_rx,
             initstatus: InitStatus::default(),
             proxies: HashMap::default()
         };
@@ -146,7 +156,7 @@ impl Health {
         };
         tokio::task::spawn(initstatus_watcher);
 
-        let senders = Senders { vault: vault_tx, init: init_tx };
+        let senders = Senders { vault: vault_tx, vault_circuit: vault_circuit_tx, init: init_tx };
         (senders, health)
     }
 }
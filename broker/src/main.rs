@@ -1,15 +1,23 @@
 #![allow(unused_imports)]
 
+mod audit;
 mod banner;
+mod blob_store;
+mod circuit_breaker;
 mod crypto;
+mod diagnostics;
 mod health;
+mod metrics;
+mod proxy_protocol;
 mod serve;
 mod serve_health;
 mod serve_pki;
 mod serve_tasks;
 #[cfg(feature = "sockets")]
 mod serve_sockets;
+mod stream_slots;
 mod task_manager;
+mod webhooks;
 mod compare_client_server_version;
 
 use std::{collections::HashMap, sync::Arc, time::Duration};
@@ -23,10 +31,10 @@ use tracing::{error, info, warn};
 pub async fn main() -> anyhow::Result<()> {
     shared::config::prepare_env();
     shared::logger::init_logger()?;
-    banner::print_banner();
+    banner::print_banner(&CONFIG_CENTRAL);
 
-    let (Senders { init: init_status_sender, vault: vault_status_sender}, health) = health::Health::make();
-    let cert_getter = crypto::build_cert_getter(vault_status_sender)?;
+    let (Senders { init: init_status_sender, vault: vault_status_sender, vault_circuit: vault_circuit_sender }, health) = health::Health::make();
+    let cert_getter = crypto::build_cert_getter(vault_status_sender, vault_circuit_sender)?;
 
     shared::crypto::init_cert_getter(cert_getter);
     tokio::task::spawn(init_broker_ca_chain(init_status_sender));
@@ -36,6 +44,15 @@ pub async fn main() -> anyhow::Result<()> {
     }
 
     let _ = config::CONFIG_CENTRAL.bind_addr; // Initialize config
+    if let Err(err) = check_clock().await {
+        error!("{err}. Refusing to start, please synchronize the system clock");
+        std::process::exit(1);
+    }
+    info!(
+        "Buffering {} new task and at least {} new result notifications per task for waiting clients",
+        CONFIG_CENTRAL.task_broadcast_capacity,
+        CONFIG_CENTRAL.result_broadcast_capacity,
+    );
 
     serve::serve(health).await?;
 
@@ -47,3 +64,25 @@ async fn init_broker_ca_chain(sender: watch::Sender<InitStatus>) {
     shared::crypto::init_ca_chain().await.expect("Failed to init broker ca chain");
     sender.send_replace(health::InitStatus::Done);
 }
+
+/// Checks the system clock against the configured time reference, if any
+async fn check_clock() -> Result<(), SamplyBeamError> {
+    let Some(reference) = &CONFIG_CENTRAL.time_reference else {
+        return Ok(());
+    };
+    if CONFIG_CENTRAL.max_clock_skew.is_zero() {
+        return Ok(());
+    }
+    let client = http_client::build(
+        &config::CONFIG_SHARED.tls_ca_certificates,
+        Some(Duration::from_secs(30)),
+        Some(Duration::from_secs(20)),
+    )?;
+    match reference.now(&client).await {
+        Ok(time) => shared::clock::check_clock(time, CONFIG_CENTRAL.max_clock_skew, CONFIG_CENTRAL.enforce_clock_skew),
+        Err(e) => {
+            warn!("Unable to check the system clock against {reference}: {e}");
+            Ok(())
+        }
+    }
+}
@@ -0,0 +1,32 @@
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, register_int_gauge_vec, Encoder, IntCounterVec, IntGaugeVec, TextEncoder};
+
+/// Label distinguishing the kind of task a [`crate::task_manager::TaskManager`] holds
+const TASK_TYPE_LABEL: &str = "type";
+
+pub static TASKS_OPEN: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("beam_tasks_open", "Number of tasks currently held by the broker", &[TASK_TYPE_LABEL])
+        .expect("Metric is only registered once")
+});
+
+pub static TASKS_DELIVERED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("beam_tasks_delivered_total", "Number of times a task was handed out to a polling client", &[TASK_TYPE_LABEL])
+        .expect("Metric is only registered once")
+});
+
+pub static TASKS_EXPIRED: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!("beam_tasks_expired_total", "Number of tasks removed by the broker because they expired", &[TASK_TYPE_LABEL])
+        .expect("Metric is only registered once")
+});
+
+pub static RESULT_CHANNELS_OPEN: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!("beam_result_channels_open", "Number of channels notifying about new results held by the broker, one per open task", &[TASK_TYPE_LABEL])
+        .expect("Metric is only registered once")
+});
+
+/// Renders all registered metrics in the prometheus text format
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer).expect("Prometheus text format is valid utf8"))
+}
@@ -0,0 +1,241 @@
+//! Support for the PROXY protocol (v1 and v2) of L4 load balancers, which prepend the address
+//! of the real client to each connection.
+//! See <https://www.haproxy.org/download/2.9/doc/proxy-protocol.txt>
+
+use std::{
+    future::Future,
+    io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    time::Duration,
+};
+
+use axum::{extract::ConnectInfo, Extension, Router};
+use hyper_util::{rt::{TokioExecutor, TokioIo}, server::conn::auto, service::TowerToHyperService};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt},
+    net::TcpListener,
+    sync::watch,
+    task::JoinSet,
+};
+use tracing::{debug, error, warn};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Longest possible v1 header including the trailing CRLF
+const V1_MAX_LEN: usize = 107;
+/// Time a load balancer has to send the header after connecting
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Reads the PROXY protocol header from the start of `stream` without consuming anything after it.
+/// Returns the client's address, or `None` if the load balancer did not proxy a client, e.g. for its own health checks.
+pub(crate) async fn read_header<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least 12 bytes long
+    let mut start = [0; 12];
+    stream.read_exact(&mut start).await?;
+    if &start == V2_SIGNATURE {
+        read_v2(stream).await
+    } else if start.starts_with(b"PROXY ") {
+        read_v1(stream, &start).await
+    } else {
+        Err(invalid("Connection does not start with a PROXY protocol header"))
+    }
+}
+
+async fn read_v1<R: AsyncRead + Unpin>(stream: &mut R, start: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() == V1_MAX_LEN {
+            return Err(invalid("PROXY protocol v1 header is too long"));
+        }
+        line.push(stream.read_u8().await?);
+    }
+    let line = std::str::from_utf8(&line[..line.len() - 2]).map_err(|_| invalid("PROXY protocol v1 header is not ASCII"))?;
+    let fields: Vec<&str> = line.split(' ').collect();
+    match fields[..] {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", "TCP4" | "TCP6", src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("Invalid source address in PROXY protocol v1 header"))?;
+            let port: u16 = src_port.parse().map_err(|_| invalid("Invalid source port in PROXY protocol v1 header"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Malformed PROXY protocol v1 header")),
+    }
+}
+
+async fn read_v2<R: AsyncRead + Unpin>(stream: &mut R) -> io::Result<Option<SocketAddr>> {
+    let mut header = [0; 4];
+    stream.read_exact(&mut header).await?;
+    let [version_command, family, len @ ..] = header;
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY protocol version"));
+    }
+    let mut addresses = vec![0; u16::from_be_bytes(len) as usize];
+    stream.read_exact(&mut addresses).await?;
+    let port = |at: usize| u16::from_be_bytes([addresses[at], addresses[at + 1]]);
+    match (version_command & 0x0F, family >> 4) {
+        // LOCAL connections are established by the load balancer itself
+        (0, _) => Ok(None),
+        (1, 1) if addresses.len() >= 12 => {
+            let ip = Ipv4Addr::from(<[u8; 4]>::try_from(&addresses[..4]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(8))))
+        }
+        (1, 2) if addresses.len() >= 36 => {
+            let ip = Ipv6Addr::from(<[u8; 16]>::try_from(&addresses[..16]).unwrap());
+            Ok(Some(SocketAddr::new(ip.into(), port(32))))
+        }
+        // Unix sockets and unspecified families carry no usable address
+        (1, 0 | 3) => Ok(None),
+        _ => Err(invalid("Malformed PROXY protocol v2 header")),
+    }
+}
+
+fn invalid(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+/// Serves `app` on connections that start with a PROXY protocol header, passing the client's address as [`ConnectInfo`].
+/// Connections without a valid header are closed.
+/// Like `axum::serve`, failing to accept a connection only stops serving if the listener itself is unusable.
+/// Once `shutdown` completes, no new connections are accepted and open ones finish their in-flight requests before this returns.
+pub(crate) async fn serve(listener: TcpListener, app: Router, shutdown: impl Future<Output = ()>) -> io::Result<()> {
+    let (stop, stopping) = watch::channel(());
+    let mut connections = JoinSet::new();
+    tokio::pin!(shutdown);
+    let result = loop {
+        let accepted = tokio::select! {
+            accepted = listener.accept() => accepted,
+            () = &mut shutdown => break Ok(()),
+            // Reap finished connections so the set does not grow with every connection served
+            Some(_) = connections.join_next() => continue,
+        };
+        let (mut stream, peer) = match accepted {
+            Ok(conn) => conn,
+            // The client went away before its connection was accepted
+            Err(e) if matches!(e.kind(), io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionAborted | io::ErrorKind::ConnectionReset) => continue,
+            Err(e) if matches!(e.kind(), io::ErrorKind::InvalidInput | io::ErrorKind::Unsupported) => break Err(e),
+            Err(e) => {
+                // E.g. too many open files, which may free up
+                error!("Failed to accept connection: {e}");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let app = app.clone();
+        let mut stopping = stopping.clone();
+        connections.spawn(async move {
+            let header = tokio::select! {
+                header = tokio::time::timeout(HEADER_TIMEOUT, read_header(&mut stream)) => header,
+                // Nothing was requested yet
+                _ = stopping.changed() => return,
+            };
+            let client = match header {
+                Ok(Ok(client)) => client.unwrap_or(peer),
+                Ok(Err(e)) => {
+                    warn!("Closing connection from {peer}: {e}");
+                    return;
+                }
+                Err(_) => {
+                    warn!("Closing connection from {peer}: No PROXY protocol header received");
+                    return;
+                }
+            };
+            let service = TowerToHyperService::new(app.layer(Extension(ConnectInfo(client))));
+            let builder = auto::Builder::new(TokioExecutor::new());
+            let conn = builder.serve_connection_with_upgrades(TokioIo::new(stream), service);
+            tokio::pin!(conn);
+            let res = tokio::select! {
+                res = conn.as_mut() => res,
+                _ = stopping.changed() => {
+                    conn.as_mut().graceful_shutdown();
+                    conn.await
+                }
+            };
+            if let Err(e) = res {
+                debug!("Connection from {client} via {peer} failed: {e}");
+            }
+        });
+    };
+    drop(listener);
+    _ = stop.send(());
+    while connections.join_next().await.is_some() {}
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::routing::get;
+    use tokio::{io::AsyncWriteExt, net::TcpStream};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn parse_headers() {
+        let v1 = b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET /";
+        let mut stream = &v1[..];
+        assert_eq!(read_header(&mut stream).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(stream, b"GET /", "Only the header is consumed");
+
+        let v1 = b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n";
+        assert_eq!(read_header(&mut &v1[..]).await.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+        assert_eq!(read_header(&mut &b"PROXY UNKNOWN\r\n"[..]).await.unwrap(), None);
+        assert!(read_header(&mut &b"GET / HTTP/1.1\r\n\r\n"[..]).await.is_err());
+        assert!(read_header(&mut &[b"PROXY TCP4 ".as_slice(), &[b'1'; 200]].concat()[..]).await.is_err());
+
+        let mut v2 = V2_SIGNATURE.to_vec();
+        v2.extend([0x21, 0x11, 0, 12, 192, 0, 2, 1, 192, 0, 2, 2, 0xDC, 0x04, 0x01, 0xBB]);
+        assert_eq!(read_header(&mut &v2[..]).await.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read_header(&mut &local[..]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn client_address_is_passed_on() {
+        let app = Router::new().route("/", get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.to_string() }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(serve(listener, app, std::future::pending()));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\nHost: broker\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("192.0.2.1:56324"), "{response}");
+
+        // Requests without the header are not served, the connection may also be reset as the request is left unread
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"GET / HTTP/1.1\r\nHost: broker\r\nConnection: close\r\n\r\n").await.unwrap();
+        let mut response = String::new();
+        _ = stream.read_to_string(&mut response).await;
+        assert!(response.is_empty(), "{response}");
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_connections() {
+        let started = std::sync::Arc::new(tokio::sync::Notify::new());
+        let app = Router::new().route("/", get({
+            let started = started.clone();
+            || async move {
+                started.notify_one();
+                tokio::time::sleep(Duration::from_millis(100)).await;
+                "done"
+            }
+        }));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let (shut_down, shutdown) = tokio::sync::oneshot::channel::<()>();
+        let server = tokio::spawn(serve(listener, app, async { _ = shutdown.await; }));
+
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        stream.write_all(b"PROXY TCP4 192.0.2.1 192.0.2.2 56324 443\r\nGET / HTTP/1.1\r\nHost: broker\r\n\r\n").await.unwrap();
+        started.notified().await;
+        shut_down.send(()).unwrap();
+        // The request in flight is answered and the kept-alive connection closed afterwards
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+        assert!(response.starts_with("HTTP/1.1 200"), "{response}");
+        assert!(response.ends_with("done"), "{response}");
+        tokio::time::timeout(Duration::from_secs(1), server).await.unwrap().unwrap().unwrap();
+        assert!(TcpStream::connect(addr).await.is_err(), "No new connections are accepted");
+    }
+}
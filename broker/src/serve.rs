@@ -1,15 +1,16 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc};
+use std::{collections::HashMap, net::{IpAddr, SocketAddr}, sync::Arc};
 
 use axum::{
-    extract::{DefaultBodyLimit, Path, Query},
-    http::{header, StatusCode},
-    response::IntoResponse,
+    extract::{ConnectInfo, DefaultBodyLimit, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
     routing::{get, post},
     Extension, Json, Router,
 };
 use serde::Deserialize;
 use shared::{
-    config, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg,
+    config, ipnet::IpNet, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg,
     MsgEmpty, MsgId, MsgSigned, EMPTY_VEC_APPORPROXYID,
 };
 use tokio::{
@@ -20,26 +21,297 @@ use tokio::{
 };
 use tracing::{debug, info, trace, warn};
 
-use crate::{banner, crypto, health::Health, serve_health, serve_pki, serve_tasks, compare_client_server_version};
+use crate::{banner, crypto, diagnostics::Diagnostics, health::{Health, InitStatus}, serve_health, serve_pki, serve_tasks, compare_client_server_version};
 
 pub(crate) async fn serve(health: Arc<RwLock<Health>>) -> anyhow::Result<()> {
-    let app = serve_tasks::router()
+    let diagnostics = Diagnostics::default();
+    let (tasks_app, tasks_admin_app) = serve_tasks::routers(&diagnostics);
+    let app = tasks_app
         .merge(serve_pki::router())
-        .merge(serve_health::router(health));
+        .merge(serve_health::router(health.clone()));
     #[cfg(feature = "sockets")]
-    let app = app.merge(crate::serve_sockets::router());
+    let app = app.merge(crate::serve_sockets::router(&diagnostics));
+    let app = with_pki_requirement(app, health.clone(), config::CONFIG_CENTRAL.reject_until_pki_ready);
+    let admin_app = serve_health::admin_router(health)
+        .merge(tasks_admin_app)
+        .merge(diagnostics.router());
+    let require_tls = config::CONFIG_CENTRAL.require_tls.then(|| Forwarders {
+        trusted_proxies: config::CONFIG_CENTRAL.trusted_proxies.as_slice().into(),
+        proxy_protocol: config::CONFIG_CENTRAL.proxy_protocol,
+    });
+    let app = with_trusted_proxies(app, &config::CONFIG_CENTRAL.trusted_proxies);
+    let Some(admin_bind_addr) = config::CONFIG_CENTRAL.admin_bind_addr else {
+        let app = with_not_found_fallback(app.merge(admin_app), PUBLIC_ROUTES.to_vec());
+        return serve_app(add_middleware(with_tls_requirement(app, require_tls)), config::CONFIG_CENTRAL.bind_addr, config::CONFIG_CENTRAL.proxy_protocol).await;
+    };
+    let app = with_not_found_fallback(app, PUBLIC_ROUTES.to_vec());
+    let admin_app = with_not_found_fallback(admin_app, Vec::new());
+    // Both servers share the same health state and shut down on the same signal
+    tokio::try_join!(
+        serve_app(add_middleware(with_tls_requirement(app, require_tls)), config::CONFIG_CENTRAL.bind_addr, config::CONFIG_CENTRAL.proxy_protocol),
+        serve_app(add_middleware(admin_app), admin_bind_addr, false),
+    )?;
+    Ok(())
+}
+
+/// Listed to clients requesting unknown paths, the admin endpoints are left out even if they are served on the same port
+const PUBLIC_ROUTES: &[&str] = &[
+    "/v1/tasks",
+    #[cfg(feature = "sockets")]
+    "/v1/sockets",
+    "/v1/groups",
+    "/v1/capabilities",
+    "/v1/pki/certs",
+    "/v1/proxies",
+    "/v1/health",
+];
+
+fn with_not_found_fallback(app: Router, routes: Vec<&'static str>) -> Router {
+    shared::middleware::with_not_found_fallback(app, env!("SAMPLY_USER_AGENT"), routes)
+}
+
+fn add_middleware(app: Router) -> Router {
     // Middleware needs to be set last
-    let app = app
+    app
         .layer(axum::middleware::from_fn(shared::middleware::log))
         .layer(axum::middleware::map_response(banner::set_server_header))
-        .layer(DefaultBodyLimit::disable());
+        .layer(DefaultBodyLimit::disable())
+}
+
+/// The peers whose headers tell how a request reached the broker
+#[derive(Clone)]
+struct Forwarders {
+    trusted_proxies: Arc<[IpNet]>,
+    /// Connections with a PROXY protocol header come from the load balancer, but the address passed on is the client's
+    proxy_protocol: bool,
+}
 
-    info!(
-        "Startup complete. Listening for requests on {}",
-        config::CONFIG_CENTRAL.bind_addr
-    );
-    axum::serve(TcpListener::bind(&config::CONFIG_CENTRAL.bind_addr).await?, app.into_make_service_with_connect_info::<SocketAddr>())
+impl Forwarders {
+    fn forwarded(&self, req: &Request) -> bool {
+        self.proxy_protocol || req.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .is_some_and(|ConnectInfo(peer)| self.trusted_proxies.iter().any(|net| net.contains(&peer.ip())))
+    }
+}
+
+/// The admin port is meant to be reachable from the internal network only, so the requirement only applies to the public port
+fn with_tls_requirement(app: Router, require_tls: Option<Forwarders>) -> Router {
+    if let Some(forwarders) = require_tls {
+        app.layer(axum::middleware::from_fn_with_state(forwarders, require_tls_middleware))
+    } else {
+        app
+    }
+}
+
+/// Rejects requests that reached the TLS-terminating reverse proxy in front of the broker over plain http.
+/// Only trusted proxies can tell, as anyone else could send the headers themselves.
+/// The health check is exempt, as load balancers usually probe it directly.
+async fn require_tls_middleware(State(forwarders): State<Forwarders>, req: Request, next: Next) -> Response {
+    if req.uri().path() == "/v1/health" || (forwarders.forwarded(&req) && forwarded_over_tls(req.headers())) {
+        return next.run(req).await;
+    }
+    warn!("Rejected {} {} as it was not forwarded over https by a trusted proxy", req.method(), req.uri());
+    (StatusCode::UPGRADE_REQUIRED, "This broker only accepts requests over https").into_response()
+}
+
+/// Fails closed while the CA chain is missing, as signatures cannot be verified without it.
+/// Requests without a signature, like the health check which reports the broker as unavailable until then, are let through.
+fn with_pki_requirement(app: Router, health: Arc<RwLock<Health>>, reject_until_pki_ready: bool) -> Router {
+    if reject_until_pki_ready {
+        app.layer(axum::middleware::from_fn_with_state(health, require_pki_middleware))
+    } else {
+        app
+    }
+}
+
+async fn require_pki_middleware(State(health): State<Arc<RwLock<Health>>>, req: Request, next: Next) -> Response {
+    let is_signed = req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .is_some_and(|value| value.starts_with("SamplyJWT "));
+    if !is_signed || matches!(health.read().await.initstatus, InitStatus::Done) {
+        return next.run(req).await;
+    }
+    debug!("Rejected signed request {} {} as the CA chain is not loaded yet", req.method(), req.uri());
+    (
+        StatusCode::SERVICE_UNAVAILABLE,
+        [(header::RETRY_AFTER, "5")],
+        "The broker cannot verify signatures until it has loaded the CA chain; please retry later.",
+    ).into_response()
+}
+
+/// Lets handlers see the client named by trusted reverse proxies in the X-Forwarded-For header instead of the proxy
+fn with_trusted_proxies(app: Router, trusted_proxies: &[IpNet]) -> Router {
+    if trusted_proxies.is_empty() {
+        return app;
+    }
+    app.layer(axum::middleware::from_fn_with_state(Arc::<[IpNet]>::from(trusted_proxies), resolve_client_middleware))
+}
+
+async fn resolve_client_middleware(State(trusted_proxies): State<Arc<[IpNet]>>, mut req: Request, next: Next) -> Response {
+    if let Some(ConnectInfo(peer)) = req.extensions().get::<ConnectInfo<SocketAddr>>().copied() {
+        let client = client_ip(peer.ip(), req.headers(), &trusted_proxies);
+        if client != peer.ip() {
+            // The port of the client's connection is unknown
+            req.extensions_mut().insert(ConnectInfo(SocketAddr::new(client, 0)));
+        }
+    }
+    next.run(req).await
+}
+
+/// Walks the X-Forwarded-For chain from the closest proxy backwards for as long as it was appended by trusted proxies.
+/// Anything before the first untrusted address may have been made up by the client, so that address is the client's.
+fn client_ip(peer: IpAddr, headers: &HeaderMap, trusted_proxies: &[IpNet]) -> IpAddr {
+    let is_trusted = |ip: &IpAddr| trusted_proxies.iter().any(|net| net.contains(ip));
+    let mut client = peer;
+    let forwarded_for = headers
+        .get_all(HeaderName::from_static("x-forwarded-for"))
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .collect::<Vec<_>>();
+    for hop in forwarded_for.into_iter().rev() {
+        if !is_trusted(&client) {
+            break;
+        }
+        let Ok(ip) = hop.trim().parse() else {
+            warn!("Ignoring the rest of the invalid X-Forwarded-For address {hop:?} set by {client}");
+            break;
+        };
+        client = ip;
+    }
+    client
+}
+
+/// Reads the protocol of the client's connection to the first reverse proxy from the X-Forwarded-Proto or Forwarded header
+fn forwarded_over_tls(headers: &HeaderMap) -> bool {
+    let first_value = |name| headers.get(name).and_then(|value| value.to_str().ok()).and_then(|value| value.split(',').next());
+    let x_forwarded_proto = first_value(HeaderName::from_static("x-forwarded-proto"));
+    let forwarded_proto = || first_value(header::FORWARDED)?
+        .split(';')
+        .filter_map(|pair| pair.trim().split_once('='))
+        .find(|(key, _)| key.eq_ignore_ascii_case("proto"))
+        .map(|(_, proto)| proto.trim_matches('"'));
+    x_forwarded_proto
+        .or_else(forwarded_proto)
+        .is_some_and(|proto| proto.trim().eq_ignore_ascii_case("https"))
+}
+
+async fn serve_app(app: Router, bind_addr: SocketAddr, proxy_protocol: bool) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(&bind_addr).await?;
+    info!("Startup complete. Listening for requests on {bind_addr}");
+    if proxy_protocol {
+        info!("Expecting PROXY protocol headers on {bind_addr}");
+        crate::proxy_protocol::serve(listener, app, shared::graceful_shutdown::wait_for_signal()).await?;
+        return Ok(());
+    }
+    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
         .with_graceful_shutdown(shared::graceful_shutdown::wait_for_signal())
         .await?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use shared::http_client::SamplyHttpClient;
+
+    use super::*;
+
+    /// Requests come from 127.0.0.1, which is only trusted with `trusted_proxy` set to a network containing it
+    async fn status_via(trusted_proxy: &str, require_tls: bool, path: &str, headers: &[(&'static str, &str)]) -> StatusCode {
+        let app = Router::new()
+            .route("/v1/health", get(|| async { "healthy" }))
+            .route("/v1/tasks", get(|| async { "tasks" }));
+        let forwarders = Forwarders { trusted_proxies: Arc::new([trusted_proxy.parse().unwrap()]), proxy_protocol: false };
+        let app = with_tls_requirement(app, require_tls.then_some(forwarders));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await });
+        let mut req = SamplyHttpClient::new().get(format!("http://{addr}{path}"));
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.send().await.unwrap().status()
+    }
+
+    async fn status(require_tls: bool, path: &str, headers: &[(&'static str, &str)]) -> StatusCode {
+        status_via("127.0.0.0/8", require_tls, path, headers).await
+    }
+
+    #[tokio::test]
+    async fn require_tls() {
+        assert_eq!(status(false, "/v1/tasks", &[]).await, StatusCode::OK, "Plain http is allowed by default");
+        assert_eq!(status(true, "/v1/tasks", &[]).await, StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(status(true, "/v1/tasks", &[("x-forwarded-proto", "http")]).await, StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(status(true, "/v1/tasks", &[("x-forwarded-proto", "https")]).await, StatusCode::OK);
+        assert_eq!(status(true, "/v1/tasks", &[("x-forwarded-proto", "https, http")]).await, StatusCode::OK);
+        assert_eq!(status(true, "/v1/tasks", &[("forwarded", "for=192.0.2.60;proto=https;by=203.0.113.43")]).await, StatusCode::OK);
+        assert_eq!(status(true, "/v1/tasks", &[("forwarded", "for=192.0.2.60;proto=http")]).await, StatusCode::UPGRADE_REQUIRED);
+        assert_eq!(status(true, "/v1/health", &[]).await, StatusCode::OK, "Load balancers may probe the health check directly");
+        assert_eq!(
+            status_via("10.0.0.0/8", true, "/v1/tasks", &[("x-forwarded-proto", "https")]).await,
+            StatusCode::UPGRADE_REQUIRED,
+            "Clients can't claim to use https themselves"
+        );
+        assert_eq!(status_via("10.0.0.0/8", true, "/v1/tasks", &[("forwarded", "proto=https")]).await, StatusCode::UPGRADE_REQUIRED);
+    }
+
+    #[tokio::test]
+    async fn signed_requests_wait_for_pki() {
+        let (_senders, health) = Health::make();
+        let app = Router::new()
+            .route("/v1/health", get(|| async { "healthy" }))
+            .route("/v1/tasks", get(|| async { "tasks" }));
+        let app = with_pki_requirement(app, health.clone(), true);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let status = |path: &'static str, signed: bool| async move {
+            let mut req = SamplyHttpClient::new().get(format!("http://{addr}{path}"));
+            if signed {
+                req = req.header(header::AUTHORIZATION, "SamplyJWT some.signed.token");
+            }
+            req.send().await.unwrap().status()
+        };
+        assert_eq!(status("/v1/tasks", true).await, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(status("/v1/health", false).await, StatusCode::OK);
+        assert_eq!(status("/v1/tasks", false).await, StatusCode::OK, "Unsigned requests are rejected by the handlers themselves if they need a signature");
+        health.write().await.initstatus = InitStatus::Done;
+        assert_eq!(status("/v1/tasks", true).await, StatusCode::OK);
+    }
+
+    #[test]
+    fn client_ip_from_trusted_proxies() {
+        let trusted: Vec<IpNet> = vec!["10.0.0.0/8".parse().unwrap(), "2001:db8::/32".parse().unwrap()];
+        let client_ip = |peer: &str, forwarded_for: &[&str]| {
+            let mut headers = HeaderMap::new();
+            for value in forwarded_for {
+                headers.append("x-forwarded-for", value.parse().unwrap());
+            }
+            client_ip(peer.parse().unwrap(), &headers, &trusted).to_string()
+        };
+        assert_eq!(client_ip("10.0.0.1", &["192.0.2.1"]), "192.0.2.1");
+        assert_eq!(client_ip("10.0.0.1", &[]), "10.0.0.1");
+        assert_eq!(client_ip("203.0.113.9", &["192.0.2.1"]), "203.0.113.9", "Untrusted peers can't choose their address");
+        assert_eq!(client_ip("10.0.0.1", &["192.0.2.66, 192.0.2.1"]), "192.0.2.1", "Addresses before the first untrusted one may be spoofed");
+        assert_eq!(client_ip("10.0.0.1", &["192.0.2.1, 10.0.0.2"]), "192.0.2.1");
+        assert_eq!(client_ip("10.0.0.1", &["192.0.2.1", "10.0.0.2"]), "192.0.2.1", "Repeated headers form one list");
+        assert_eq!(client_ip("2001:db8::1", &["2001:db9::1"]), "2001:db9::1");
+        assert_eq!(client_ip("10.0.0.1", &["192.0.2.1, unknown"]), "10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn handlers_see_resolved_client() {
+        async fn client(trusted: &str) -> String {
+            let app = Router::new().route("/", get(|ConnectInfo(addr): ConnectInfo<SocketAddr>| async move { addr.ip().to_string() }));
+            let app = with_trusted_proxies(app, &[trusted.parse().unwrap()]);
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await });
+            let res = SamplyHttpClient::new().get(format!("http://{addr}/")).header("x-forwarded-for", "192.0.2.1").send().await.unwrap();
+            res.text().await.unwrap()
+        }
+        assert_eq!(client("127.0.0.0/8").await, "192.0.2.1");
+        assert_eq!(client("10.0.0.0/8").await, "127.0.0.1");
+    }
+}
@@ -1,27 +1,37 @@
-use std::{sync::Arc, time::{Duration, SystemTime}};
+use std::{collections::hash_map::Entry, sync::Arc, time::{Duration, SystemTime}};
 
-use axum::{extract::{State, Path}, http::StatusCode, routing::get, Json, Router, response::Response};
+use axum::{extract::{Query, State, Path}, http::StatusCode, routing::get, Json, Router, response::Response};
 use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
-use beam_lib::ProxyId;
+use beam_lib::{ProxyId, ProxyMetadata};
 use serde::{Serialize, Deserialize};
-use shared::{crypto_jwt::Authorized, Msg, config::CONFIG_CENTRAL};
+use shared::{crypto::constant_time_eq, crypto_jwt::Authorized, Msg, config::CONFIG_CENTRAL};
 use tokio::sync::RwLock;
+use tracing::warn;
 
-use crate::{health::{Health, VaultStatus, Verdict, ProxyStatus, InitStatus}, compare_client_server_version::log_version_mismatch};
+use crate::{circuit_breaker::CircuitStatus, health::{Health, VaultStatus, Verdict, ProxyStatus, InitStatus}, compare_client_server_version::log_version_mismatch, metrics};
 
 #[derive(Serialize)]
 struct HealthOutput {
     summary: Verdict,
     vault: VaultStatus,
+    vault_circuit: CircuitStatus,
     init_status: InitStatus
 }
 
 pub(crate) fn router(health: Arc<RwLock<Health>>) -> Router {
     Router::new()
         .route("/v1/health", get(handler))
+        .route("/v1/control", get(get_control_tasks).layer(axum::middleware::from_fn(log_version_mismatch)))
+        .route("/v1/proxies", get(list_proxies))
         .route("/v1/health/proxies/:proxy_id", get(proxy_health))
         .route("/v1/health/proxies", get(get_all_proxies))
-        .route("/v1/control", get(get_control_tasks).layer(axum::middleware::from_fn(log_version_mismatch)))
+        .with_state(health)
+}
+
+/// Metrics which may be served on a separate admin port
+pub(crate) fn admin_router(health: Arc<RwLock<Health>>) -> Router {
+    Router::new()
+        .route("/metrics", get(get_metrics))
         .with_state(health)
 }
 
@@ -40,6 +50,7 @@ async fn handler(
     let health_as_json = HealthOutput {
         summary,
         vault: state.vault,
+        vault_circuit: *state.vault_circuit.borrow(),
         init_status: state.initstatus
     };
     (statuscode, Json(health_as_json))
@@ -49,18 +60,40 @@ async fn get_all_proxies(State(state): State<Arc<RwLock<Health>>>) -> Json<Vec<P
     Json(state.read().await.proxies.keys().cloned().collect())
 }
 
+#[derive(Serialize, Deserialize)]
+struct ProxyListing {
+    id: ProxyId,
+    online: bool,
+    #[serde(flatten)]
+    metadata: ProxyMetadata,
+}
+
+#[derive(Deserialize)]
+struct ProxyListingFilter {
+    zone: Option<String>,
+}
+
+// GET /v1/proxies
+/// Lists the proxies that have connected to the broker, optionally only those of one zone
+async fn list_proxies(
+    State(state): State<Arc<RwLock<Health>>>,
+    Query(filter): Query<ProxyListingFilter>,
+) -> Json<Vec<ProxyListing>> {
+    let mut proxies: Vec<_> = state.read().await.proxies
+        .iter()
+        .filter(|(_, status)| filter.zone.is_none() || status.metadata.zone == filter.zone)
+        .map(|(id, status)| ProxyListing { id: id.clone(), online: status.online(), metadata: status.metadata.clone() })
+        .collect();
+    proxies.sort_by(|a, b| a.id.as_ref().cmp(b.id.as_ref()));
+    Json(proxies)
+}
+
 async fn proxy_health(
     State(state): State<Arc<RwLock<Health>>>,
     Path(proxy): Path<ProxyId>,
     auth: TypedHeader<Authorization<Basic>>
 ) -> Result<(StatusCode, Json<ProxyStatus>), StatusCode> {
-    let Some(ref monitoring_key) = CONFIG_CENTRAL.monitoring_api_key else {
-        return Err(StatusCode::NOT_IMPLEMENTED);
-    };
-
-    if auth.password() != monitoring_key {
-        return Err(StatusCode::UNAUTHORIZED)
-    }
+    check_monitoring_auth(&auth)?;
 
     if let Some(reported_back) = state.read().await.proxies.get(&proxy) {
         if reported_back.online() {
@@ -73,14 +106,48 @@ async fn proxy_health(
     }
 }
 
+// GET /metrics
+async fn get_metrics(auth: TypedHeader<Authorization<Basic>>) -> Result<String, StatusCode> {
+    check_monitoring_auth(&auth)?;
+    metrics::render().map_err(|e| {
+        warn!("Failed to render metrics: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+pub(crate) fn check_monitoring_auth(auth: &Authorization<Basic>) -> Result<(), StatusCode> {
+    let Some(ref monitoring_key) = CONFIG_CENTRAL.monitoring_api_key else {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    if !constant_time_eq(auth.password().as_bytes(), monitoring_key.as_bytes()) {
+        return Err(StatusCode::UNAUTHORIZED)
+    }
+    Ok(())
+}
+
+/// Admins identify themselves by the user name so their actions can be attributed in the logs
+pub(crate) fn check_admin_auth(auth: &Authorization<Basic>) -> Result<(), StatusCode> {
+    let Some(ref admin_key) = CONFIG_CENTRAL.admin_api_key else {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+
+    if !constant_time_eq(auth.password().as_bytes(), admin_key.as_bytes()) || auth.username().is_empty() {
+        return Err(StatusCode::UNAUTHORIZED)
+    }
+    Ok(())
+}
+
 async fn get_control_tasks(
     State(state): State<Arc<RwLock<Health>>>,
+    // Part of the signed request, so only the proxy itself can set its metadata
+    Query(metadata): Query<ProxyMetadata>,
     proxy_auth: Authorized,
 ) -> StatusCode {
     let proxy_id = proxy_auth.get_from().proxy_id(); 
     // Once this is freed the connection will be removed from the map of connected proxies again
     // This ensures that when the connection is dropped and therefore this response future the status of this proxy will be updated
-    let _connection_remover = ConnectedGuard::connect(&proxy_id, &state).await;
+    let _connection_remover = ConnectedGuard::connect(&proxy_id, &state, metadata).await;
 
     // In the future, this will wait for control tasks for the given proxy
     tokio::time::sleep(Duration::from_secs(60 * 60)).await;
@@ -94,12 +161,12 @@ struct ConnectedGuard<'a> {
 }
 
 impl<'a> ConnectedGuard<'a> {
-    async fn connect(proxy: &'a ProxyId, state: &'a Arc<RwLock<Health>>) -> ConnectedGuard<'a> {
+    async fn connect(proxy: &'a ProxyId, state: &'a Arc<RwLock<Health>>, metadata: ProxyMetadata) -> ConnectedGuard<'a> {
         {
-            state.write().await.proxies
-                .entry(proxy.clone())
-                .and_modify(ProxyStatus::connect)
-                .or_insert(ProxyStatus::new());
+            match state.write().await.proxies.entry(proxy.clone()) {
+                Entry::Occupied(mut status) => status.get_mut().connect(metadata),
+                Entry::Vacant(entry) => { entry.insert(ProxyStatus::new(metadata)); },
+            }
         }
         Self { proxy, state }
     }
@@ -121,3 +188,30 @@ impl<'a> Drop for ConnectedGuard<'a> {
         });
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+    use shared::test_utils::init_broker_id;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn zone_is_listed() {
+        init_broker_id();
+        let (_senders, health) = Health::make();
+        let (eu, us) = (ProxyId::new("proxy1.broker.samply.de").unwrap(), ProxyId::new("proxy2.broker.samply.de").unwrap());
+        let metadata = |uri: &str| Query::<ProxyMetadata>::try_from_uri(&uri.parse().unwrap()).unwrap().0;
+        let _eu = ConnectedGuard::connect(&eu, &health, metadata("/v1/control?zone=eu&region=de")).await;
+        let _us = ConnectedGuard::connect(&us, &health, metadata("/v1/control?zone=us")).await;
+
+        let list = |zone: Option<&str>| list_proxies(State(health.clone()), Query(ProxyListingFilter { zone: zone.map(String::from) }));
+        let Json(all) = list(None).await;
+        assert_eq!(serde_json::to_value(all).unwrap(), json!([
+            {"id": "proxy1.broker.samply.de", "online": true, "zone": "eu", "region": "de"},
+            {"id": "proxy2.broker.samply.de", "online": true, "zone": "us"},
+        ]));
+        let Json(in_zone) = list(Some("us")).await;
+        assert_eq!(in_zone.iter().map(|proxy| &proxy.id).collect::<Vec<_>>(), [&us]);
+    }
+}
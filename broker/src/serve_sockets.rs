@@ -1,76 +1,222 @@
-use std::{sync::Arc, collections::{HashMap, HashSet}, ops::Deref, time::Duration};
+use std::{sync::Arc, collections::{HashMap, HashSet}, future::Future, ops::Deref, time::Duration};
 
-use axum::{extract::{Path, Request, State}, http::{header, request::Parts, HeaderValue, StatusCode}, response::{IntoResponse, Response}, routing::get, RequestExt, Router};
+use axum::{extract::{Path, Query, Request, State}, http::{header, request::Parts, HeaderValue, StatusCode}, response::{IntoResponse, Response}, routing::get, RequestExt, Router};
+use beam_lib::AppOrProxyId;
 use bytes::BufMut;
+use dashmap::{mapref::entry::Entry, DashMap};
 use hyper_util::rt::TokioIo;
-use serde::{Serialize, Serializer, ser::SerializeSeq};
-use shared::{config::{CONFIG_CENTRAL, CONFIG_SHARED}, crypto_jwt::Authorized, expire_map::LazyExpireMap, serde_helpers::DerefSerializer, Encrypted, HasWaitId, HowLongToBlock, Msg, MsgEmpty, MsgId, MsgSigned, MsgSocketRequest};
-use tokio::sync::{RwLock, broadcast::{Sender, self}, oneshot};
-use tracing::{debug, log::error, warn};
+use serde::{Deserialize, Serialize, Serializer, ser::SerializeSeq};
+use shared::{config::{CONFIG_CENTRAL, CONFIG_SHARED}, crypto_jwt::Authorized, expire_map::LazyExpireMap, serde_helpers::JsonArrayStream, Encrypted, HasWaitId, HowLongToBlock, Msg, MsgEmpty, MsgId, MsgSigned, MsgSocketRequest, NEXT_CURSOR_HEADER};
+use tokio::{io::{AsyncRead, AsyncWrite}, sync::{RwLock, broadcast::{Sender, self}, oneshot}, task::AbortHandle};
+use tracing::{debug, info, log::error, warn};
 
-use crate::task_manager::{TaskManager, Task};
+use crate::{diagnostics::Diagnostics, serve_tasks::paginate, task_manager::{Task, TaskManager, TaskManagerDiagnostics}};
 
 
 #[derive(Clone)]
-struct SocketState {
+pub(crate) struct SocketState {
     task_manager: Arc<TaskManager<MsgSocketRequest<Encrypted>>>,
-    waiting_connections: Arc<LazyExpireMap<MsgId, oneshot::Sender<hyper::upgrade::OnUpgrade>>>
+    waiting_connections: Arc<LazyExpireMap<MsgId, oneshot::Sender<hyper::upgrade::OnUpgrade>>>,
+    relays: Relays,
+}
+
+/// Sockets whose parties are both connected and relayed to each other
+#[derive(Clone, Default)]
+struct Relays(Arc<DashMap<MsgId, Relay>>, EndedRelays);
+
+/// Maps sockets whose relay ended to their creator, who may reopen them with the same id for a while
+type EndedRelays = Arc<LazyExpireMap<MsgId, AppOrProxyId>>;
+
+struct Relay {
+    /// The creator and the recipient of the socket request, who may cancel the relay
+    parties: Vec<AppOrProxyId>,
+    abort: AbortHandle,
+}
+
+impl Relays {
+    /// How long the creator of a socket may reopen it after its relay ended
+    const REOPEN_WINDOW: Duration = Duration::from_secs(60 * 60);
+
+    /// Spawns the relay and keeps it until it ends or is cancelled. The parties start with the creator of the socket.
+    fn spawn(&self, task_id: MsgId, parties: Vec<AppOrProxyId>, relay: impl Future<Output = ()> + Send + 'static) {
+        let (relays, ended) = (self.0.clone(), self.1.clone());
+        let creator = parties.first().cloned();
+        // Holding the entry keeps a relay ending right away from removing itself before it is inserted
+        match self.0.entry(task_id) {
+            Entry::Occupied(_) => warn!("Socket {task_id} is already relayed"),
+            Entry::Vacant(entry) => {
+                let handle = tokio::spawn(async move {
+                    relay.await;
+                    relays.remove(&task_id);
+                    if let Some(creator) = creator {
+                        ended.insert_for(Self::REOPEN_WINDOW, task_id, creator);
+                    }
+                });
+                entry.insert(Relay { parties, abort: handle.abort_handle() });
+            }
+        }
+    }
+
+    /// Aborts the relay, which closes the connections to both parties
+    fn cancel(&self, task_id: &MsgId, requester: &AppOrProxyId) -> Result<(), (StatusCode, &'static str)> {
+        let Entry::Occupied(relay) = self.0.entry(*task_id) else {
+            return Err((StatusCode::NOT_FOUND, "Socket is not relayed"));
+        };
+        if !relay.get().parties.contains(requester) {
+            return Err((StatusCode::UNAUTHORIZED, "Not allowed to cancel this socket"));
+        }
+        let relay = relay.remove();
+        relay.abort.abort();
+        if let Some(creator) = relay.parties.into_iter().next() {
+            self.1.insert_for(Self::REOPEN_WINDOW, *task_id, creator);
+        }
+        Ok(())
+    }
+
+    /// Checks whether `requester` may post a socket request with the id of an earlier socket.
+    /// Returns whether the request reopens a socket whose relay ended.
+    fn check_reopen(&self, task_id: &MsgId, requester: &AppOrProxyId) -> Result<bool, (StatusCode, &'static str)> {
+        if self.0.contains_key(task_id) {
+            return Err((StatusCode::CONFLICT, "Socket is still relayed"));
+        }
+        match self.1.get(task_id) {
+            Some(creator) if &*creator != requester => Err((StatusCode::UNAUTHORIZED, "Only the creator may reopen this socket")),
+            Some(_) => Ok(true),
+            None => Ok(false),
+        }
+    }
 }
 
 impl SocketState {
     const WAITING_CONNECTIONS_TIMEOUT: Duration = Duration::from_secs(60);
     const WAITING_CONNECTIONS_CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
-}
 
-impl Default for SocketState {
-    fn default() -> Self {
+    pub(crate) fn new(task_broadcast_capacity: usize, result_broadcast_capacity: usize) -> Self {
         let waiting_connections: Arc<LazyExpireMap<_, _>> = Default::default();
-        let cons = waiting_connections.clone();
+        let relays = Relays::default();
+        let (cons, ended) = (waiting_connections.clone(), relays.1.clone());
         tokio::spawn(async move {
             loop {
                 tokio::time::sleep(Self::WAITING_CONNECTIONS_CLEANUP_INTERVAL).await;
                 cons.retain_expired();
+                ended.retain_expired();
             }
         });
         Self {
-            task_manager: TaskManager::new(),
-            waiting_connections
+            task_manager: TaskManager::new(task_broadcast_capacity, result_broadcast_capacity),
+            waiting_connections,
+            relays,
+        }
+    }
+
+    fn diagnostics(&self) -> SocketDiagnostics {
+        SocketDiagnostics {
+            requests: self.task_manager.diagnostics(),
+            waiting_connections: self.waiting_connections.len(),
+            active_relays: self.relays.0.len(),
+            ended_relays: self.relays.1.len(),
         }
     }
 }
 
-pub(crate) fn router() -> Router {
+#[derive(Serialize)]
+struct SocketDiagnostics {
+    /// Socket requests nobody connected to yet
+    requests: TaskManagerDiagnostics,
+    /// Parties waiting for the other party to connect
+    waiting_connections: usize,
+    active_relays: usize,
+    /// Relays that ended recently and may be reopened
+    ended_relays: usize,
+}
+
+impl Default for SocketState {
+    fn default() -> Self {
+        Self::new(CONFIG_CENTRAL.task_broadcast_capacity, CONFIG_CENTRAL.result_broadcast_capacity)
+    }
+}
+
+pub(crate) fn router(diagnostics: &Diagnostics) -> Router {
+    let state = SocketState::default();
+    let diagnostics_state = state.clone();
+    diagnostics.register("sockets", move || serde_json::to_value(diagnostics_state.diagnostics()).unwrap_or_default());
+    // Connecting is a GET request, so only creating and cancelling socket requests are limited
+    shared::middleware::with_request_timeout(api_router(state), shared::config::CONFIG_CENTRAL.request_timeout)
+}
+
+/// The socket API as proxies see it, without the middleware of the server
+pub(crate) fn api_router(state: SocketState) -> Router {
     Router::new()
         .route("/v1/sockets", get(get_socket_requests).post(post_socket_request))
-        .route("/v1/sockets/:id", get(connect_socket))
-        .with_state(SocketState::default())
+        .route("/v1/sockets/:id", get(connect_socket).delete(cancel_socket))
+        .with_state(state)
 }
 
 
+#[derive(Deserialize)]
+struct Pagination {
+    /// Maximum number of socket requests to return
+    limit: Option<usize>,
+    /// Only return socket requests with an id greater than this one
+    after: Option<MsgId>,
+}
+
 async fn get_socket_requests(
     mut block: HowLongToBlock,
+    Query(pagination): Query<Pagination>,
     state: State<SocketState>,
     msg: MsgSigned<MsgEmpty>,
-) -> Result<DerefSerializer, StatusCode> {
+) -> Result<Response, StatusCode> {
     if block.wait_count.is_none() && block.wait_time.is_none() {
         block.wait_count = Some(1);
     }
+    if pagination.limit == Some(0) {
+        return Err(StatusCode::BAD_REQUEST);
+    }
+    // A page never holds more than `limit` socket requests, so waiting for more would only run into the timeout
+    if let Some(limit) = pagination.limit {
+        block.wait_count = block.wait_count.map(|wait_count| wait_count.min(limit.try_into().unwrap_or(u16::MAX)));
+    }
     let requester = msg.get_from();
-    let filter = |req: &MsgSocketRequest<Encrypted>| req.to.contains(requester);
+    let after = pagination.after;
+    let filter = |req: &MsgSocketRequest<Encrypted>| req.to.contains(requester) && after.is_none_or(|after| req.id > after);
+
+    let socket_req_ids = state.task_manager.wait_for_tasks(&block, filter).await?.map(|req| req.msg.id).collect();
+    let Some(limit) = pagination.limit else {
+        return Ok(stream_socket_requests(&state, socket_req_ids, block.wait_count).into_response());
+    };
+    let (page, next_cursor) = paginate(socket_req_ids, limit);
+    let mut res = stream_socket_requests(&state, page, block.wait_count).into_response();
+    if let Some(next_cursor) = next_cursor {
+        res.headers_mut().insert(NEXT_CURSOR_HEADER, HeaderValue::from_str(&next_cursor.to_string()).expect("MsgId is a valid header value"));
+    }
+    Ok(res)
+}
 
-    let socket_reqs = state.task_manager.wait_for_tasks(&block, filter).await?;
-    DerefSerializer::new(socket_reqs, block.wait_count).map_err(|e| {
-        warn!("Failed to serialize socket tasks: {e}");
-        StatusCode::INTERNAL_SERVER_ERROR
+/// Serializes the socket requests only while the body is sent
+fn stream_socket_requests(state: &SocketState, ids: Vec<MsgId>, wait_count: Option<u16>) -> JsonArrayStream {
+    let task_manager = state.task_manager.clone();
+    JsonArrayStream::new(ids, wait_count, move |id, buf| {
+        let Ok(req) = task_manager.get(&id) else {
+            return Ok(false);
+        };
+        serde_json::to_writer(buf, &*req).map(|()| true)
     })
 }
 
+/// Creates a socket request. Its creator may post it again with the same id once the relay ended to reopen the socket,
+/// e.g. after a flaky network dropped the connection. Nothing of the earlier relay is kept, so both parties connect anew.
 async fn post_socket_request(
     state: State<SocketState>,
     msg: MsgSigned<MsgSocketRequest<Encrypted>>,
-) -> Result<impl IntoResponse, StatusCode> {
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
     let msg_id = msg.wait_id();
+    let reopened = state.relays.check_reopen(&msg_id, msg.get_from())?;
     state.task_manager.post_task(msg)?;
+    if reopened {
+        state.relays.1.remove(&msg_id);
+        debug!("Reopened socket {msg_id}");
+    }
 
     Ok((
         StatusCode::CREATED,
@@ -83,40 +229,38 @@ async fn connect_socket(
     Path(task_id): Path<MsgId>,
     mut parts: Parts,
     body: String,
-    // This Result is just an Either type. An error value does not mean something went wrong
-) -> Result<Response, StatusCode> {
-    let result = shared::crypto_jwt::verify_with_extended_header::<MsgEmpty>(&mut parts, &body).await;
-    let msg = match result {
-        Ok(msg) => msg.msg,
-        Err(e) => return Ok(e.into_response()),
-    };
-    {
+) -> Result<Response, (StatusCode, &'static str)> {
+    let msg = shared::crypto_jwt::verify_with_extended_header::<MsgEmpty>(&mut parts, &body)
+        .await?
+        .msg;
+    let parties = {
         let task = state.task_manager.get(&task_id)?;
         // Allowed to connect are the issuer of the task and the recipient
         if !(task.get_from() == &msg.from || task.get_to().contains(&msg.from)) {
-            return Err(StatusCode::UNAUTHORIZED);
+            return Err((StatusCode::UNAUTHORIZED, "Not allowed to connect to this socket"));
         }
-    }
+        std::iter::once(task.get_from().clone()).chain(task.get_to().iter().cloned()).collect()
+    };
 
     let Some(conn) = parts.extensions.remove::<hyper::upgrade::OnUpgrade>() else {
-        return Err(StatusCode::UPGRADE_REQUIRED);
+        return Err((StatusCode::UPGRADE_REQUIRED, "Request is not upgradable"));
     };
 
     if let Some(req_sender) = state.waiting_connections.remove(&task_id) {
-        if let Err(_) = req_sender.send(conn) {
+        if req_sender.send(conn).is_err() {
             warn!("Error sending socket connection to tunnel. Receiver has been dropped");
-            return Err(StatusCode::INTERNAL_SERVER_ERROR);
+            return Err((StatusCode::INTERNAL_SERVER_ERROR, "Failed to hand over socket connection"));
         }
     } else {
         let (tx, rx) = tokio::sync::oneshot::channel();
         state.waiting_connections.insert_for(SocketState::WAITING_CONNECTIONS_TIMEOUT, task_id, tx);
         let Ok(other_con) = rx.await else {
             debug!("Socket expired because nobody connected");
-            return Err(StatusCode::GONE);
+            return Err((StatusCode::GONE, "Other party never connected to this socket"));
         };
         // We don't care if the task expired by now
         _ = state.task_manager.remove(&task_id);
-        tokio::spawn(async move {
+        state.relays.spawn(task_id, parties, async move {
             let (socket1, socket2) = match tokio::try_join!(conn, other_con) {
                 Ok(sockets) => sockets,
                 Err(e) => {
@@ -125,14 +269,193 @@ async fn connect_socket(
                 },
             };
 
-            let result = tokio::io::copy_bidirectional(&mut TokioIo::new(socket1), &mut TokioIo::new(socket2)).await;
-            if let Err(e) = result {
-                debug!("Relaying socket connection ended: {e}");
+            relay(task_id, TokioIo::new(socket1), TokioIo::new(socket2), CONFIG_CENTRAL.socket_max_lifetime).await;
+        });
+    }
+    Ok(switching_protocols())
+}
+
+/// Copies between both parties until either closes the connection or the socket reached its maximum lifetime
+async fn relay(task_id: MsgId, mut socket1: impl AsyncRead + AsyncWrite + Unpin, mut socket2: impl AsyncRead + AsyncWrite + Unpin, max_lifetime: Option<Duration>) {
+    let copy = tokio::io::copy_bidirectional(&mut socket1, &mut socket2);
+    let result = match max_lifetime {
+        Some(max_lifetime) => match tokio::time::timeout(max_lifetime, copy).await {
+            Ok(result) => result,
+            Err(_) => {
+                info!("Closing socket {task_id} as it reached its maximum lifetime of {max_lifetime:?}");
+                return;
             }
+        },
+        None => copy.await,
+    };
+    if let Err(e) = result {
+        debug!("Relaying socket connection ended: {e}");
+    }
+}
+
+/// Lets a party of the socket forcibly end the relay, e.g. of a compromised or runaway tunnel
+async fn cancel_socket(
+    state: State<SocketState>,
+    Path(task_id): Path<MsgId>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    state.relays.cancel(&task_id, msg.get_from())?;
+    info!("{} cancelled the relay of socket {task_id}", msg.get_from());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+fn switching_protocols() -> Response {
+    (
+        StatusCode::SWITCHING_PROTOCOLS,
+        [
+            (header::UPGRADE, HeaderValue::from_static("tcp")),
+            (header::CONNECTION, HeaderValue::from_static("upgrade")),
+        ],
+    ).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use shared::test_utils::{app, app_id, socket_request};
+
+    use super::*;
+
+    #[test]
+    fn paginate_socket_requests() {
+        let mut ids: Vec<_> = (0..5).map(|_| MsgId::new()).collect();
+        let (page, next_cursor) = paginate(ids.clone(), 2);
+        ids.sort();
+        assert_eq!(page, ids[..2]);
+        assert_eq!(next_cursor, Some(ids[1]));
+
+        let (page, next_cursor) = paginate(ids[2..].to_vec(), 3);
+        assert_eq!(page, ids[2..]);
+        assert_eq!(next_cursor, None);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_count_capped_to_limit() {
+        let (creator, recipient) = (app("app1"), AppOrProxyId::from(app_id("app2", "proxy2")));
+        let state = SocketState { task_manager: TaskManager::new(16, 16), waiting_connections: Default::default(), relays: Relays::default() };
+        for _ in 0..2 {
+            let msg = socket_request(&creator, vec![recipient.clone()]);
+            assert_eq!(post_socket_request(State(state.clone()), MsgSigned { msg, jwt: String::new() }).await.into_response().status(), StatusCode::CREATED);
+        }
+        let started = tokio::time::Instant::now();
+        let block = HowLongToBlock { wait_count: Some(5), wait_time: Some(Duration::from_secs(30)) };
+        let pagination = Pagination { limit: Some(2), after: None };
+        let res = get_socket_requests(block, Query(pagination), State(state), MsgSigned { msg: MsgEmpty { from: recipient }, jwt: String::new() }).await.unwrap();
+        assert_eq!(res.status(), StatusCode::OK, "A full page is all the client waited for");
+        assert!(started.elapsed() < Duration::from_secs(30), "Returned once the page was full");
+    }
+
+    #[tokio::test]
+    async fn cancel_active_relay() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (creator, recipient) = (app("app1"), AppOrProxyId::from(app_id("app2", "proxy2")));
+        let (mut end1, mut socket1) = tokio::io::duplex(64);
+        let (mut end2, mut socket2) = tokio::io::duplex(64);
+        let relays = Relays::default();
+        let task_id = MsgId::new();
+        relays.spawn(task_id, vec![creator.clone(), recipient.clone()], async move {
+            _ = tokio::io::copy_bidirectional(&mut socket1, &mut socket2).await;
+        });
+
+        end1.write_all(b"ping").await.unwrap();
+        let mut buf = [0; 4];
+        end2.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"ping");
+
+        assert_eq!(relays.cancel(&task_id, &app_id("app3", "proxy3").into()).unwrap_err().0, StatusCode::UNAUTHORIZED);
+        relays.cancel(&task_id, &recipient).unwrap();
+        let closed = tokio::time::timeout(Duration::from_secs(1), async {
+            (end1.read(&mut buf).await.unwrap(), end2.read(&mut buf).await.unwrap())
+        });
+        assert_eq!(closed.await.unwrap(), (0, 0), "Both parties are disconnected");
+        assert_eq!(relays.cancel(&task_id, &creator).unwrap_err().0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn reopen_socket_after_relay_ended() {
+        let (creator, recipient) = (app("app1"), AppOrProxyId::from(app_id("app2", "proxy2")));
+        let state = SocketState { task_manager: TaskManager::new(16, 16), waiting_connections: Default::default(), relays: Relays::default() };
+        let task_id = MsgId::new();
+        let post = |from: &AppOrProxyId| {
+            let msg = MsgSocketRequest { id: task_id, ..socket_request(from, vec![recipient.clone()]) };
+            let state = state.clone();
+            async move { post_socket_request(State(state), MsgSigned { msg, jwt: String::new() }).await.into_response().status() }
+        };
+        assert_eq!(post(&creator).await, StatusCode::CREATED);
+        assert_eq!(post(&creator).await, StatusCode::CONFLICT, "Nobody connected yet");
+
+        // Once both parties connected, the request is removed and the relay takes over
+        state.task_manager.remove(&task_id).unwrap();
+        let (end_relay, relay_ended) = oneshot::channel::<()>();
+        state.relays.spawn(task_id, vec![creator.clone(), recipient.clone()], async move {
+            _ = relay_ended.await;
+        });
+        assert_eq!(post(&creator).await, StatusCode::CONFLICT, "Socket is still relayed");
+
+        drop(end_relay);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(post(&recipient).await, StatusCode::UNAUTHORIZED);
+        assert_eq!(post(&creator).await, StatusCode::CREATED);
+        assert!(state.task_manager.get(&task_id).is_ok());
+        assert!(state.relays.1.get(&task_id).is_none());
+    }
+
+    #[tokio::test]
+    async fn diagnostics_count_sockets() {
+        let (creator, recipient) = (app("app1"), AppOrProxyId::from(app_id("app2", "proxy2")));
+        let state = SocketState { task_manager: TaskManager::new(16, 16), waiting_connections: Default::default(), relays: Relays::default() };
+        for _ in 0..2 {
+            let msg = socket_request(&creator, vec![recipient.clone()]);
+            assert_eq!(post_socket_request(State(state.clone()), MsgSigned { msg, jwt: String::new() }).await.into_response().status(), StatusCode::CREATED);
+        }
+        let (_end_relay, relay_ended) = oneshot::channel::<()>();
+        state.relays.spawn(MsgId::new(), vec![creator, recipient], async move {
+            _ = relay_ended.await;
         });
+
+        let diagnostics = state.diagnostics();
+        assert_eq!(diagnostics.requests.tasks, 2);
+        assert_eq!(diagnostics.waiting_connections, 0);
+        assert_eq!(diagnostics.active_relays, 1);
+        assert_eq!(diagnostics.ended_relays, 0);
+    }
+
+    #[tokio::test]
+    async fn relay_closed_at_max_lifetime() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (mut end1, socket1) = tokio::io::duplex(64);
+        let (mut end2, socket2) = tokio::io::duplex(64);
+        let relay = tokio::spawn(relay(MsgId::new(), socket1, socket2, Some(Duration::from_millis(300))));
+        // Keep the tunnel busy past its lifetime
+        let started = tokio::time::Instant::now();
+        let mut buf = [0; 4];
+        while started.elapsed() < Duration::from_secs(1) {
+            if end1.write_all(b"ping").await.is_err() {
+                break;
+            }
+            match tokio::time::timeout(Duration::from_millis(100), end2.read(&mut buf)).await.unwrap() {
+                Ok(0) | Err(_) => break,
+                Ok(_) => tokio::time::sleep(Duration::from_millis(20)).await,
+            }
+        }
+        let lifetime = started.elapsed();
+        assert!(lifetime >= Duration::from_millis(300) && lifetime < Duration::from_millis(600), "Closed after {lifetime:?}");
+        relay.await.unwrap();
+        assert_eq!(end1.read(&mut buf).await.unwrap(), 0, "Both parties are disconnected");
+    }
+
+    #[tokio::test]
+    async fn finished_relay_is_removed() {
+        let relays = Relays::default();
+        let task_id = MsgId::new();
+        relays.spawn(task_id, Vec::new(), async {});
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(relays.0.is_empty());
     }
-    Ok(([
-        (header::UPGRADE, HeaderValue::from_static("tcp")),
-        (header::CONNECTION, HeaderValue::from_static("upgrade"))
-    ], StatusCode::SWITCHING_PROTOCOLS).into_response())
 }
@@ -1,58 +1,454 @@
 use std::{
-    collections::HashMap, convert::Infallible, fmt::Debug, mem::Discriminant, net::SocketAddr,
-    sync::Arc,
+    collections::{HashMap, HashSet}, convert::Infallible, fmt::Debug, mem::Discriminant, net::SocketAddr,
+    ops::Deref, sync::Arc, time::{Duration, SystemTime},
 };
 
 use axum::{
+    async_trait,
+    body::Body,
     extract::ConnectInfo,
-    extract::{Path, Query, State},
-    http::{header, HeaderValue, StatusCode, HeaderMap},
-    response::{sse::Event, IntoResponse, Response, Sse},
-    routing::{get, post, put},
+    extract::{FromRequest, Path, Query, Request, State},
+    http::{header, HeaderMap, HeaderName, HeaderValue, StatusCode},
+    response::{sse::{Event, KeepAlive}, IntoResponse, Response, Sse},
+    routing::{delete, get, post, put},
     Json, Router,
 };
-use beam_lib::AppOrProxyId;
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
+use beam_lib::{AppOrProxyId, FailureStrategy};
+use dashmap::{mapref::entry::Entry, DashMap};
 use futures_core::{stream, Stream};
-use serde::Deserialize;
+use serde::{ser::SerializeStruct, Deserialize, Serialize};
 use beam_lib::WorkStatus;
 use shared::{
-    config, errors::SamplyBeamError, sse_event::SseEventType,
+    config, crypto_jwt, errors::SamplyBeamError, expire_map::LazyExpireMap, sse_event::SseEventType,
     EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HasWaitId, HowLongToBlock, Msg, MsgEmpty,
-    MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, EMPTY_VEC_APPORPROXYID, serde_helpers::DerefSerializer,
+    MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, APPLICATION_NDJSON, EMPTY_VEC_APPORPROXYID, NEXT_CURSOR_HEADER, serde_helpers::{DerefSerializer, JsonArrayStream},
 };
 use tokio::{
     sync::{
         broadcast::{Receiver, Sender},
-        RwLock,
+        OwnedSemaphorePermit, RwLock, Semaphore,
     },
-    time,
+    time::{self, Instant},
 };
 use tracing::{debug, error, info, trace, warn};
 
-use crate::task_manager::TaskManager;
+use crate::{audit::{AuditEntry, AuditLog}, blob_store::{Attachments, CompressedBlobStore, FsBlobStore, OffloadedTasks}, serve_health::{check_admin_auth, check_monitoring_auth}, stream_slots::{StreamSlot, StreamSlots, TooManyStreams}, task_manager::{expects_results, ResultUpdate, Task, TaskManager, TaskManagerDiagnostics, TaskManagerError}, webhooks::Webhooks, diagnostics::Diagnostics};
 
 #[derive(Clone)]
 struct TasksState {
-    task_manager: Arc<TaskManager<EncryptedMsgTaskRequest>>
+    task_manager: Arc<TaskManager<EncryptedMsgTaskRequest>>,
+    /// Maps the sender and its idempotency key to the id of the task it created
+    idempotency_keys: Arc<LazyExpireMap<(AppOrProxyId, String), MsgId>>,
+    /// Maps a task to the worker holding a lease on it
+    claims: Arc<LazyExpireMap<MsgId, AppOrProxyId>>,
+    /// Maps workers sending heartbeats to their heartbeat timeout, expiring once they miss it
+    heartbeats: Arc<LazyExpireMap<AppOrProxyId, Duration>>,
+    /// Recipients that have fetched a task, only tracked if delivery receipts are enabled
+    deliveries: Option<Arc<DashMap<MsgId, HashSet<AppOrProxyId>>>>,
+    /// Maps a task to the time the broker received it
+    created: Arc<LazyExpireMap<MsgId, SystemTime>>,
+    /// Maps a task retrying an earlier one to the first attempt of their logical task
+    lineage: Arc<LazyExpireMap<MsgId, MsgId>>,
+    /// Maps a task being deleted to the workers that may still submit a final result until it is removed
+    deleting: Arc<LazyExpireMap<MsgId, HashSet<AppOrProxyId>>>,
+    /// Permits for requests blocking until tasks or results arrive
+    long_polls: Arc<Semaphore>,
+    /// Streams and long polls each app has open
+    streams: StreamSlots,
+    /// Large tasks kept outside of memory, only if a blob store is configured
+    offloaded: Option<Arc<OffloadedTasks>>,
+    /// Results stored apart from their task, only if a blob store is configured
+    attachments: Option<Arc<Attachments>>,
+    /// Maximum length of a signed result, 0 if unlimited
+    max_result_size: usize,
+    max_task_recipients: usize,
+    /// Ceilings of the retry settings of tasks, 0 for none
+    max_retry_tries: usize,
+    max_retry_backoff_millisecs: usize,
+    /// Whether tasks may only be addressed to proxies with a valid certificate
+    validate_recipients: bool,
+    /// Capabilities advertised by workers, which tasks may address instead of the workers themselves
+    capabilities: Arc<LazyExpireMap<AppOrProxyId, HashSet<String>>>,
+    audit: Arc<AuditLog>,
 }
 
-pub(crate) fn router() -> Router {
+impl TasksState {
+    const IDEMPOTENCY_KEY_RETENTION: Duration = Duration::from_secs(60 * 60);
+    const IDEMPOTENCY_KEY_CAPACITY: usize = 10_000;
+    const DEFAULT_CLAIM_LEASE: Duration = Duration::from_secs(60);
+    const DEFAULT_HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(30);
+    const CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
+    /// Workers have to renew their advertisement within this time to stay addressable by their capabilities
+    const CAPABILITY_TTL: Duration = Duration::from_secs(10 * 60);
+}
+
+/// Returns the task API and the admin router serving the task monitor
+pub(crate) fn routers(diagnostics: &Diagnostics) -> (Router, Router) {
     let state = TasksState::default();
+    let diagnostics_state = state.clone();
+    diagnostics.register("tasks", move || serde_json::to_value(diagnostics_state.diagnostics()).unwrap_or_default());
+    if !config::CONFIG_CENTRAL.webhooks.is_empty() {
+        let client = shared::http_client::build(&config::CONFIG_SHARED.tls_ca_certificates, Some(Duration::from_secs(30)), Some(Duration::from_secs(20)))
+            .expect("Failed to build the HTTP client for webhooks");
+        Webhooks::new(config::CONFIG_CENTRAL.webhooks.clone(), client).spawn(state.task_manager.clone());
+    }
+    let router = shared::middleware::with_request_timeout(api_router(state.clone()), config::CONFIG_CENTRAL.request_timeout);
+    let admin_router = Router::new()
+        .route("/v1/monitor/tasks", get(monitor_tasks))
+        .route("/v1/admin/tasks/purge", post(admin_purge_tasks))
+        .route("/v1/admin/tasks/explain", get(admin_explain_tasks))
+        .route("/v1/admin/tasks/:task_id", delete(admin_delete_task))
+        .route("/v1/admin/tasks/:task_id/audit", get(admin_audit_task))
+        .with_state(state);
+    let admin_router = shared::middleware::with_request_timeout(admin_router, config::CONFIG_CENTRAL.request_timeout);
+    (router, admin_router)
+}
+
+/// The task API as proxies see it, without the middleware of the server
+fn api_router(state: TasksState) -> Router {
     Router::new()
         .route("/v1/tasks", get(get_tasks).post(post_task))
+        .route("/v1/tasks/:task_id/claim", post(claim_task))
+        .route("/v1/tasks/heartbeat", post(heartbeat))
+        .route("/v1/tasks/search", get(search_tasks))
+        .route("/v1/tasks/results/stream", get(stream_all_results))
         .route("/v1/tasks/:task_id/results", get(get_results_for_task))
+        .route("/v1/tasks/:task_id/summary", get(get_task_summary))
         .route("/v1/tasks/:task_id/results/:app_id", put(put_result))
+        .route("/v1/tasks/:task_id/results/:app_id/ack", get(get_acknowledgement).post(acknowledge_result))
+        .route("/v1/tasks/:task_id/attachments", put(put_attachment))
+        .route("/v1/tasks/:task_id/attachments/:digest", get(get_attachment))
+        .route("/v1/groups/:group", get(get_group_members))
+        .route("/v1/capabilities", put(put_capabilities))
+        .route("/v1/capabilities/:capability", get(get_capable_workers))
         .with_state(state)
 }
 
+/// What the task API is built from, read from the broker's configuration outside of tests
+struct TasksConfig {
+    task_broadcast_capacity: usize,
+    result_broadcast_capacity: usize,
+    delivery_receipts: bool,
+    max_long_polls: usize,
+    max_streams_per_app: usize,
+    offloaded: Option<OffloadedTasks>,
+    attachments: Option<Attachments>,
+    max_result_size: usize,
+    max_task_recipients: usize,
+    max_retry_tries: usize,
+    max_retry_backoff_millisecs: usize,
+    validate_recipients: bool,
+    audit: AuditLog,
+}
+
+impl TasksConfig {
+    fn from_central() -> Self {
+        Self {
+            task_broadcast_capacity: config::CONFIG_CENTRAL.task_broadcast_capacity,
+            result_broadcast_capacity: config::CONFIG_CENTRAL.result_broadcast_capacity,
+            delivery_receipts: config::CONFIG_CENTRAL.delivery_receipts,
+            max_long_polls: config::CONFIG_CENTRAL.max_long_polls,
+            max_streams_per_app: config::CONFIG_CENTRAL.max_streams_per_app,
+            offloaded: match &config::CONFIG_CENTRAL.blob_store_dir {
+                Some(dir) => {
+                    let store = FsBlobStore::new(dir.clone()).expect("Unable to create blob store directory");
+                    Some(OffloadedTasks::new(store, config::CONFIG_CENTRAL.blob_store_min_size))
+                }
+                None if config::CONFIG_CENTRAL.compress_tasks => {
+                    Some(OffloadedTasks::new(CompressedBlobStore::default(), config::CONFIG_CENTRAL.blob_store_min_size))
+                }
+                None => None,
+            },
+            attachments: config::CONFIG_CENTRAL.blob_store_dir.as_ref().map(|dir| {
+                Attachments::new(dir.join("attachments")).expect("Unable to create attachment directory")
+            }),
+            max_result_size: config::CONFIG_CENTRAL.max_result_size,
+            max_task_recipients: config::CONFIG_CENTRAL.max_task_recipients,
+            max_retry_tries: config::CONFIG_CENTRAL.max_retry_tries,
+            max_retry_backoff_millisecs: config::CONFIG_CENTRAL.max_retry_backoff_millisecs,
+            validate_recipients: config::CONFIG_CENTRAL.validate_recipients,
+            audit: AuditLog::new(config::CONFIG_CENTRAL.audit_log.clone()).expect("Unable to open audit log"),
+        }
+    }
+}
+
 impl Default for TasksState {
     fn default() -> Self {
-        TasksState {
-            task_manager: TaskManager::new()
+        Self::new(TasksConfig::from_central())
+    }
+}
+
+impl TasksState {
+    fn new(config: TasksConfig) -> Self {
+        let state = TasksState {
+            task_manager: TaskManager::new(config.task_broadcast_capacity, config.result_broadcast_capacity),
+            idempotency_keys: Default::default(),
+            claims: Default::default(),
+            heartbeats: Default::default(),
+            deliveries: config.delivery_receipts.then(Default::default),
+            created: Default::default(),
+            lineage: Default::default(),
+            deleting: Default::default(),
+            long_polls: Arc::new(Semaphore::new(config.max_long_polls)),
+            streams: StreamSlots::new(config.max_streams_per_app),
+            offloaded: config.offloaded.map(Arc::new),
+            attachments: config.attachments.map(Arc::new),
+            max_result_size: config.max_result_size,
+            max_task_recipients: config.max_task_recipients,
+            max_retry_tries: config.max_retry_tries,
+            max_retry_backoff_millisecs: config.max_retry_backoff_millisecs,
+            validate_recipients: config.validate_recipients,
+            capabilities: Default::default(),
+            audit: Arc::new(config.audit),
+        };
+        let expired = state.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(Self::CLEANUP_INTERVAL).await;
+                expired.remove_expired();
+            }
+        });
+        state
+    }
+
+    /// Drops what belongs to expired claims, heartbeats, advertisements and tasks
+    fn remove_expired(&self) {
+        self.claims.retain_expired();
+        self.heartbeats.retain_expired();
+        self.created.retain_expired();
+        self.lineage.retain_expired();
+        self.deleting.retain_expired();
+        self.capabilities.retain_expired();
+        self.audit.retain_expired();
+        let tasks = &self.task_manager;
+        if let Some(deliveries) = &self.deliveries {
+            deliveries.retain(|task_id, _| tasks.get(task_id).is_ok());
+        }
+        let (tasks, offloaded, attachments) = (tasks.clone(), self.offloaded.clone(), self.attachments.clone());
+        // Listing and deleting files blocks
+        tokio::task::spawn_blocking(move || {
+            if let Some(offloaded) = offloaded {
+                offloaded.remove_orphans(|task_id| tasks.get(task_id).is_ok());
+            }
+            if let Some(attachments) = attachments {
+                attachments.remove_orphans(|task_id| tasks.get(task_id).is_ok());
+            }
+        });
+    }
+
+    /// Extends the leases `worker` holds to `timeout` from now, returning the tasks it still holds a lease on
+    fn renew_claims(&self, worker: &AppOrProxyId, timeout: Duration) -> Vec<MsgId> {
+        let now = Instant::now();
+        let mut renewed = Vec::new();
+        for mut claim in self.claims.iter_mut() {
+            let (holder, until) = claim.value_mut();
+            if holder == worker && *until > now {
+                *until = now + timeout;
+                renewed.push(*claim.key());
+            }
+        }
+        renewed
+    }
+
+    /// Replaces the capabilities advertised by `worker`, advertising none withdraws the advertisement
+    fn advertise_capabilities(&self, worker: AppOrProxyId, capabilities: HashSet<String>) {
+        if capabilities.is_empty() {
+            self.capabilities.remove(&worker);
+        } else {
+            self.capabilities.insert_for(Self::CAPABILITY_TTL, worker, capabilities);
+        }
+    }
+
+    /// Workers currently advertising `capability`, ordered by their id so a capability resolves to the same recipients each time
+    fn capable_workers(&self, capability: &str) -> Vec<AppOrProxyId> {
+        let now = Instant::now();
+        let mut workers: Vec<AppOrProxyId> = self.capabilities
+            .iter()
+            .filter(|advertisement| advertisement.1 > now && advertisement.0.contains(capability))
+            .map(|advertisement| advertisement.key().clone())
+            .collect();
+        workers.sort_by_key(ToString::to_string);
+        workers
+    }
+
+    /// Parked requests hold resources until they return, so their number is capped.
+    /// Requests that return immediately don't need a permit.
+    fn long_poll_permit(&self, block: &HowLongToBlock, app: &AppOrProxyId) -> Result<Option<LongPollPermit>, TooManyLongPolls> {
+        if block.wait_count.is_none() && block.wait_time.is_none() {
+            return Ok(None);
+        }
+        let slot = self.streams.acquire(app).map_err(TooManyLongPolls::OfApp)?;
+        let permit = self.long_polls.clone().try_acquire_owned().map_err(|_| {
+            warn!("Rejecting long-polling request as the maximum number of concurrent long polls is reached");
+            TooManyLongPolls::Overall
+        })?;
+        Ok(Some(LongPollPermit { _permit: permit, _slot: slot }))
+    }
+
+    /// Records the creation time before posting the task so clients waiting for new tasks in a time window see it.
+    /// Returns the signed message of a large task, which is only written to the blob store by [`Self::offload`] once the task is posted,
+    /// so neither a conflicting task nor the removal of orphaned blobs gets in the way.
+    fn post_task(&self, task: MsgSigned<EncryptedMsgTaskRequest>) -> Result<Option<PendingOffload>, (StatusCode, &'static str)> {
+        let id = task.msg.id;
+        if self.task_manager.get(&id).is_ok_and(|existing| !self.task_manager.is_expired(&existing.msg)) {
+            return Err(TaskManagerError::Conflict.into());
+        }
+        let parent = task.msg.parent_task;
+        if parent == Some(id) {
+            return Err((StatusCode::BAD_REQUEST, "Task cannot retry itself"));
+        }
+        // The parent may already have expired, in which case its creator is unknown
+        if parent.is_some_and(|parent| self.task_manager.get(&parent).is_ok_and(|parent| parent.get_from() != task.get_from())) {
+            return Err((StatusCode::FORBIDDEN, "Task can only retry tasks of the same creator"));
+        }
+        let offload = self.offloaded
+            .as_ref()
+            .filter(|offloaded| offloaded.should_offload(&task))
+            .map(|_| PendingOffload { id, jwt: task.jwt.clone() });
+        let now = SystemTime::now();
+        let ttl = task.msg.expire.duration_since(now).unwrap_or_default();
+        self.created.insert_for(ttl, id, now);
+        self.task_manager.post_task(task)?;
+        if let Some(parent) = parent {
+            let first_attempt = self.lineage.get(&parent).map_or(parent, |first| *first);
+            self.lineage.insert_for(ttl, id, first_attempt);
+        }
+        Ok(offload)
+    }
+
+    /// Moves the signed message of a posted task to the blob store. The task stays in memory until it is stored and if storing it fails.
+    async fn offload(&self, PendingOffload { id, jwt }: PendingOffload) {
+        let Some(offloaded) = &self.offloaded else {
+            return;
+        };
+        if let Err(e) = offloaded.store(id, jwt.clone()).await {
+            error!("Unable to store task {id} in the blob store, keeping it in memory: {e}");
+            return;
+        }
+        let stripped = self.task_manager.modify(&id, |task| if task.jwt == jwt {
+            OffloadedTasks::strip(task);
+        });
+        // The task is gone already, so nobody removes its blob but us
+        if stripped.is_err() {
+            if let Err(e) = offloaded.delete(id).await {
+                warn!("Unable to delete offloaded task {id}: {e}");
+            }
+        }
+    }
+
+    /// Ids of the stored tasks of `creator` that are attempts of the same logical task as `task_id`, oldest first.
+    /// Empty if the task neither retries another task nor has been retried.
+    fn attempts(&self, task_id: MsgId, creator: &AppOrProxyId) -> Vec<MsgId> {
+        let first_attempt = self.lineage.get(&task_id).map_or(task_id, |first| *first);
+        let mut attempts: Vec<_> = self.lineage
+            .iter()
+            .filter(|entry| entry.value().0 == first_attempt)
+            .map(|entry| *entry.key())
+            .collect();
+        if attempts.is_empty() {
+            return attempts;
+        }
+        attempts.push(first_attempt);
+        attempts.retain(|id| self.task_manager.get(id).is_ok_and(|task| task.get_from() == creator));
+        attempts.sort_by_key(|id| self.created.get(id).map(|created| *created));
+        attempts
+    }
+
+    fn diagnostics(&self) -> TasksDiagnostics {
+        TasksDiagnostics {
+            tasks: self.task_manager.diagnostics(),
+            claims: self.claims.len(),
+            delivery_receipts: self.deliveries.as_ref().map(|deliveries| deliveries.len()),
+            being_deleted: self.deleting.len(),
+            capability_advertisements: self.capabilities.len(),
+            free_long_polls: self.long_polls.available_permits(),
+        }
+    }
+
+    /// Loads the signed message of a task if it was moved to the blob store
+    fn load_task<T: Deref<Target = MsgSigned<EncryptedMsgTaskRequest>>>(&self, task: T) -> Option<Box<LoadedTask<T>>> {
+        let jwt = match self.offloaded.as_ref().map(|offloaded| offloaded.load(&task)).transpose() {
+            Ok(jwt) => jwt.flatten(),
+            Err(e) => {
+                error!("Unable to load task {} from the blob store: {e}", task.msg.id);
+                return None;
+            }
+        };
+        Some(Box::new(LoadedTask { task, jwt }))
+    }
+}
+
+/// A posted task still to be moved to the blob store
+struct PendingOffload {
+    id: MsgId,
+    jwt: String,
+}
+
+#[derive(Serialize)]
+struct TasksDiagnostics {
+    tasks: TaskManagerDiagnostics,
+    /// Leases of workers on tasks, including expired ones not cleaned up yet
+    claims: usize,
+    /// Tasks with recorded deliveries, if delivery receipts are enabled
+    delivery_receipts: Option<usize>,
+    being_deleted: usize,
+    /// Workers with capability advertisements, including expired ones not cleaned up yet
+    capability_advertisements: usize,
+    free_long_polls: usize,
+}
+
+/// A task serialized like its signed message, with the JWT loaded from the blob store if it was offloaded
+struct LoadedTask<T> {
+    task: T,
+    jwt: Option<String>,
+}
+
+impl<T: Deref<Target = MsgSigned<EncryptedMsgTaskRequest>>> Serialize for LoadedTask<T> {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.jwt {
+            Some(jwt) => {
+                let mut signed = serializer.serialize_struct("MsgSigned", 1)?;
+                signed.serialize_field("jwt", jwt)?;
+                signed.end()
+            }
+            None => self.task.serialize(serializer),
+        }
+    }
+}
+
+/// Held by a request while it blocks until tasks or results arrive
+struct LongPollPermit {
+    _permit: OwnedSemaphorePermit,
+    _slot: StreamSlot,
+}
+
+enum TooManyLongPolls {
+    Overall,
+    OfApp(TooManyStreams),
+}
+
+impl IntoResponse for TooManyLongPolls {
+    fn into_response(self) -> Response {
+        match self {
+            TooManyLongPolls::Overall => (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, HeaderValue::from_static("1"))],
+                "Too many concurrent long-polling requests; please retry later.",
+            ).into_response(),
+            TooManyLongPolls::OfApp(e) => e.into_response(),
         }
     }
 }
 
+/// Records that a recipient of the task has fetched it
+fn record_delivery(deliveries: &DashMap<MsgId, HashSet<AppOrProxyId>>, task: &EncryptedMsgTaskRequest, worker: &AppOrProxyId) {
+    if !task.to.contains(worker) || deliveries.get(&task.id).is_some_and(|workers| workers.contains(worker)) {
+        return;
+    }
+    deliveries.entry(task.id).or_default().insert(worker.clone());
+}
+
 async fn get_results_for_task(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<TasksState>,
@@ -71,11 +467,29 @@ async fn get_results_for_task(
         .find(|part| *part == "text/event-stream")
         .is_some();
 
-    if *found {
-        get_results_for_task_stream(addr, state, block, task_id, msg)
+    if *found || shared::accepts(&headers, APPLICATION_NDJSON) {
+        let slot = match state.streams.acquire(msg.get_from()) {
+            Ok(slot) => slot,
+            Err(e) => return e.into_response(),
+        };
+        if !*found {
+            return get_results_for_task_ndjson(addr, state, block, task_id, slot, msg)
+                .await
+                .into_response();
+        }
+        // Resuming a stream skips the results the client has already received
+        let last_event_id = headers
+            .get("last-event-id")
+            .and_then(|id| id.to_str().ok())
+            .and_then(|id| id.parse().ok());
+        get_results_for_task_stream(addr, state, block, task_id, last_event_id, slot, msg)
             .await
             .into_response()
     } else {
+        let _permit = match state.long_poll_permit(&block, msg.get_from()) {
+            Ok(permit) => permit,
+            Err(e) => return e.into_response(),
+        };
         get_results_for_task_nostream(addr, state, block, task_id, msg)
             .await
             .into_response()
@@ -89,7 +503,7 @@ async fn get_results_for_task_nostream(
     block: HowLongToBlock,
     task_id: MsgId,
     msg: MsgSigned<MsgEmpty>,
-) -> Result<DerefSerializer, StatusCode> {
+) -> Result<impl IntoResponse, StatusCode> {
     debug!(
         "get_results_for_task(task={}) called by {} with IP {addr}, wait={:?}",
         task_id.to_string(),
@@ -105,11 +519,26 @@ async fn get_results_for_task_nostream(
         mode: MsgFilterMode::Or,
     };
     let task_with_results = state.task_manager.wait_for_results(&task_id, &block, |m| filter_for_me.matches(&m.msg)).await?;
+    let complete = task_complete_header(&task_with_results.msg);
+    // Tasks without recipients never get the results the client waits for
+    let wait_count = block.wait_count.filter(|_| expects_results(&task_with_results.msg));
     
-    DerefSerializer::new(task_with_results.msg.results.values().filter(|m| filter_for_me.matches(&m.msg)), block.wait_count).map_err(|e| {
+    // Ordered by worker so the proxy serves the same byte ranges of unchanged results between requests
+    let mut results: Vec<_> = task_with_results.msg.results.values().filter(|m| filter_for_me.matches(&m.msg)).collect();
+    results.sort_unstable_by(|a, b| a.msg.from.as_ref().cmp(b.msg.from.as_ref()));
+    let results = DerefSerializer::new(results.into_iter(), wait_count).map_err(|e| {
         warn!("Failed to serialize task results: {e}");
         StatusCode::INTERNAL_SERVER_ERROR
-    })
+    })?;
+    Ok((complete, results))
+}
+
+/// Header telling whether the task is complete according to its completion policy
+const TASK_COMPLETE_HEADER: HeaderName = HeaderName::from_static("task-complete");
+
+fn task_complete_header(task: &EncryptedMsgTaskRequest) -> [(HeaderName, HeaderValue); 1] {
+    let complete = if task.is_complete() { "true" } else { "false" };
+    [(TASK_COMPLETE_HEADER, HeaderValue::from_static(complete))]
 }
 
 // GET /v1/tasks/:task_id/results/stream
@@ -118,13 +547,16 @@ async fn get_results_for_task_stream(
     state: TasksState,
     block: HowLongToBlock,
     task_id: MsgId,
+    last_event_id: Option<u64>,
+    slot: StreamSlot,
     msg: MsgSigned<MsgEmpty>,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
     debug!(
-        "get_results_for_task_stream(task={}) called by {} with IP {addr}, wait={:?}",
+        "get_results_for_task_stream(task={}) called by {} with IP {addr}, wait={:?}, last_event_id={:?}",
         task_id.to_string(),
         msg.get_from(),
-        block
+        block,
+        last_event_id
     );
     let from = msg.get_from().clone();
     if &from != state.task_manager.get(&task_id)?.get_from() {
@@ -135,336 +567,2570 @@ async fn get_results_for_task_stream(
     let stream = state.task_manager.stream_results(
         task_id,
         block,
+        last_event_id,
         move |m| filter.matches(&m.msg)
     );
 
-    Ok(Sse::new(stream))
-}
-
-
-#[derive(Deserialize)]
-struct TaskFilter {
-    from: Option<AppOrProxyId>,
-    to: Option<AppOrProxyId>,
-    filter: Option<FilterParam>,
-}
-
-#[derive(Deserialize)]
-#[serde(rename_all = "lowercase")]
-enum FilterParam {
-    Todo,
+    Ok(Sse::new(slot.hold_during(stream)))
 }
 
-/// GET /v1/tasks
-/// Will retrieve tasks that are at least FROM or TO the supplied parameters.
-async fn get_tasks(
+// GET /v1/tasks/:task_id/results with Accept: application/x-ndjson
+/// Streams the results like [`get_results_for_task_stream`] but as one JSON object per line.
+/// There is no line for the end of the stream, which simply ends once the task is complete, removed or the client stops waiting.
+async fn get_results_for_task_ndjson(
+    addr: SocketAddr,
+    state: TasksState,
     block: HowLongToBlock,
-    Query(taskfilter): Query<TaskFilter>,
-    State(state): State<TasksState>,
+    task_id: MsgId,
+    slot: StreamSlot,
     msg: MsgSigned<MsgEmpty>,
-) -> Result<DerefSerializer, (StatusCode, impl IntoResponse)> {
-    let from = taskfilter.from;
-    let mut to = taskfilter.to;
-    let unanswered_by = match taskfilter.filter {
-        Some(FilterParam::Todo) => {
-            if to.is_none() {
-                to = Some(msg.get_from().clone());
+) -> Result<impl IntoResponse, StatusCode> {
+    debug!(
+        "get_results_for_task_ndjson(task={}) called by {} with IP {addr}, wait={:?}",
+        task_id.to_string(),
+        msg.get_from(),
+        block
+    );
+    let from = msg.get_from().clone();
+    if &from != state.task_manager.get(&task_id)?.get_from() {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+
+    let filter = MsgFilterNoTask { from: None, to: Some(from), mode: MsgFilterMode::Or };
+    let stream = state.task_manager.stream_result_updates(
+        task_id,
+        block,
+        None,
+        move |m| filter.matches(&m.msg),
+        |update| {
+            let ResultUpdate::Result { result, .. } = update else {
+                return None;
+            };
+            match serde_json::to_vec(result) {
+                Ok(mut line) => {
+                    line.push(b'\n');
+                    Some(Ok::<_, Infallible>(line))
+                },
+                Err(e) => {
+                    warn!("Failed to serialize task result: {e}");
+                    None
+                }
             }
-            Some(msg.get_from().clone())
         }
-        None => None,
-    };
-    if from.is_none() && to.is_none() {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Please supply either \"from\" or \"to\" query parameter.",
-        ));
-    }
-    if (from.is_some() && *from.as_ref().unwrap() != msg.msg.from)
-        || (to.is_some() && *to.as_ref().unwrap() != msg.msg.from)
-    {
-        // Rewrite in Rust 1.64: https://github.com/rust-lang/rust/pull/94927
-        return Err((
-            StatusCode::UNAUTHORIZED,
-            "You can only list messages created by you (from) or directed to you (to).",
-        ));
-    }
-    // Step 1: Get initial vector fill from HashMap + receiver for new elements
-    let filter = MsgFilterNoTask {
-        from,
-        to,
-        mode: MsgFilterMode::Or,
-    };
-    let filter = MsgFilterForTask {
-        normal: filter,
-        unanswered_by: unanswered_by.as_ref(),
-        workstatus_is_not: [WorkStatus::Succeeded, WorkStatus::PermFailed, WorkStatus::Claimed]
-            .iter()
-            .map(std::mem::discriminant)
-            .collect(),
-    };
-    let tasks = state.task_manager
-        .wait_for_tasks(&block, move |m| filter.matches(m))
-        .await?;
-    DerefSerializer::new(tasks, block.wait_count).map_err(|e| {
-        warn!("Failed to serialize tasks: {e}");
-        (StatusCode::INTERNAL_SERVER_ERROR, "Failed to serialize tasks")
-    })
+    );
+    Ok(([(header::CONTENT_TYPE, APPLICATION_NDJSON)], Body::from_stream(slot.hold_during(stream))))
 }
 
-trait MsgFilterTrait<M: Msg> {
-    // fn new() -> Self;
-    fn from(&self) -> Option<&AppOrProxyId>;
-    fn to(&self) -> Option<&AppOrProxyId>;
-    fn mode(&self) -> &MsgFilterMode;
-
-    fn matches(&self, msg: &M) -> bool {
-        match self.mode() {
-            MsgFilterMode::Or => self.filter_or(msg),
-            MsgFilterMode::And => self.filter_and(msg),
-        }
-    }
+/// Number of recipients of a task by the status of their result.
+/// The status is not encrypted so this can be answered without transferring the results.
+#[derive(Debug, Default, PartialEq, Serialize)]
+struct TaskSummary {
+    succeeded: usize,
+    failed: usize,
+    /// Recipients that have not answered yet, claimed the task or failed temporarily
+    pending: usize,
+    /// Recipients that have fetched the task, if delivery receipts are enabled
+    #[serde(skip_serializing_if = "Option::is_none")]
+    delivered: Option<usize>,
+    /// Task this one retries
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_task: Option<MsgId>,
+    /// Stored attempts of the same logical task, oldest first, if the task is part of a retry chain
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attempts: Vec<MsgId>,
+}
 
-    /// Returns true iff the from or the to conditions match (or both)
-    fn filter_or(&self, msg: &M) -> bool {
-        if self.from().is_none() && self.to().is_none() {
-            return true;
-        }
-        if let Some(to) = &self.to() {
-            if msg.get_to().contains(to) {
-                return true;
-            }
-        }
-        if let Some(from) = &self.from() {
-            if msg.get_from() == *from {
-                return true;
+impl From<&EncryptedMsgTaskRequest> for TaskSummary {
+    fn from(task: &EncryptedMsgTaskRequest) -> Self {
+        let mut summary = TaskSummary { parent_task: task.parent_task, ..Default::default() };
+        for recipient in &task.to {
+            match task.results.get(recipient).map(|result| result.msg.status) {
+                Some(WorkStatus::Succeeded) => summary.succeeded += 1,
+                Some(WorkStatus::PermFailed) => summary.failed += 1,
+                Some(WorkStatus::Claimed | WorkStatus::TempFailed) | None => summary.pending += 1,
             }
         }
-        false
+        summary
     }
+}
 
-    /// Returns true iff all defined from/to conditions are met.
-    fn filter_and(&self, msg: &M) -> bool {
-        if self.from().is_none() && self.to().is_none() {
-            return true;
-        }
-        if let Some(to) = self.to() {
-            if !msg.get_to().contains(to) {
-                return false;
-            }
-        }
-        if let Some(from) = &self.from() {
-            if msg.get_from() != *from {
-                return false;
-            }
-        }
-        true
+// GET /v1/tasks/:task_id/summary
+async fn get_task_summary(
+    State(state): State<TasksState>,
+    Path(task_id): Path<MsgId>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Json<TaskSummary>, StatusCode> {
+    let task = state.task_manager.get(&task_id)?;
+    if msg.get_from() != task.get_from() {
+        return Err(StatusCode::UNAUTHORIZED);
     }
+    let mut summary = TaskSummary::from(&task.msg);
+    drop(task);
+    summary.delivered = state.deliveries.as_ref().map(|deliveries| deliveries.get(&task_id).map_or(0, |workers| workers.len()));
+    summary.attempts = state.attempts(task_id, msg.get_from());
+    Ok(Json(summary))
 }
 
-#[allow(dead_code)]
-enum MsgFilterMode {
-    Or,
-    And,
+// POST /v1/tasks/:task_id/results/:app_id/ack
+/// Lets the creator of a task confirm that it has received the result of `app_id`
+async fn acknowledge_result(
+    State(state): State<TasksState>,
+    Path((task_id, app_id)): Path<(MsgId, AppOrProxyId)>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    if msg.get_from() != state.task_manager.get(&task_id)?.get_from() {
+        return Err(TaskManagerError::Unauthorized.into());
+    }
+    state.task_manager.acknowledge(&task_id, &app_id).map_err(|e| match e {
+        TaskManagerError::NotFound => (StatusCode::NOT_FOUND, "No result to acknowledge"),
+        e => e.into(),
+    })?;
+    debug!("{} acknowledged the result of {app_id} for task {task_id}", msg.get_from());
+    Ok(StatusCode::NO_CONTENT)
 }
-struct MsgFilterNoTask {
-    from: Option<AppOrProxyId>,
-    to: Option<AppOrProxyId>,
-    mode: MsgFilterMode,
+
+#[derive(Serialize, Deserialize, Debug)]
+struct Acknowledgement {
+    acknowledged: bool,
 }
 
-struct MsgFilterForTask<'a> {
-    normal: MsgFilterNoTask,
-    unanswered_by: Option<&'a AppOrProxyId>,
-    workstatus_is_not: Vec<Discriminant<WorkStatus>>,
+// GET /v1/tasks/:task_id/results/:app_id/ack
+/// Lets workers wait for the creator of the task to acknowledge their result
+async fn get_acknowledgement(
+    State(state): State<TasksState>,
+    block: HowLongToBlock,
+    Path((task_id, app_id)): Path<(MsgId, AppOrProxyId)>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Response {
+    let _permit = match state.long_poll_permit(&block, msg.get_from()) {
+        Ok(permit) => permit,
+        Err(e) => return e.into_response(),
+    };
+    wait_for_acknowledgement(state, block, task_id, app_id, msg)
+        .await
+        .into_response()
 }
 
-impl<'a> MsgFilterForTask<'a> {
-    fn unanswered(&self, msg: &EncryptedMsgTaskRequest) -> bool {
-        if self.unanswered_by.is_none() {
-            debug!("Is {} unanswered? Yes, criterion not defined.", msg.id());
-            return true;
-        }
-        let unanswered = self.unanswered_by.unwrap();
-        for res in msg.results.values() {
-            if res.get_from() == unanswered
-                && self
-                    .workstatus_is_not
-                    .contains(&std::mem::discriminant(&res.msg.status))
-            {
-                debug!("Is {} unanswered? No, answer found.", msg.id());
-                return false;
-            }
-        }
-        debug!("Is {} unanswered? Yes, no matching answer found.", msg.id());
-        true
+async fn wait_for_acknowledgement(
+    state: TasksState,
+    block: HowLongToBlock,
+    task_id: MsgId,
+    app_id: AppOrProxyId,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Json<Acknowledgement>, (StatusCode, &'static str)> {
+    if msg.get_from() != &app_id && msg.get_from() != state.task_manager.get(&task_id)?.get_from() {
+        return Err(TaskManagerError::Unauthorized.into());
     }
+    let acknowledged = state.task_manager.wait_for_acknowledgement(&task_id, &app_id, &block).await?;
+    Ok(Json(Acknowledgement { acknowledged }))
 }
 
-impl<'a> MsgFilterTrait<EncryptedMsgTaskRequest> for MsgFilterForTask<'a> {
-    fn from(&self) -> Option<&AppOrProxyId> {
-        self.normal.from.as_ref()
-    }
+// GET /v1/groups/:group
+/// Proxies resolve recipient groups before encrypting a message to their members
+async fn get_group_members(
+    Path(group): Path<String>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Json<&'static Vec<AppOrProxyId>>, StatusCode> {
+    debug!("{} resolved recipient group {group}", msg.get_from());
+    config::CONFIG_CENTRAL.recipient_groups
+        .get(&group)
+        .map(Json)
+        .ok_or(StatusCode::NOT_FOUND)
+}
 
-    fn to(&self) -> Option<&AppOrProxyId> {
-        self.normal.to.as_ref()
-    }
+/// Capabilities are named like recipient groups so they can be used in the `to` field of messages
+fn is_valid_capability(capability: &str) -> bool {
+    !capability.is_empty() && capability.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+}
 
-    fn matches(&self, msg: &EncryptedMsgTaskRequest) -> bool {
-        MsgFilterNoTask::matches(&self.normal, msg) && self.unanswered(&msg)
-    }
+#[derive(Deserialize)]
+struct CapabilityParams {
+    /// Comma separated capabilities of the worker
+    #[serde(default)]
+    capabilities: String,
+}
 
-    fn mode(&self) -> &MsgFilterMode {
-        &self.normal.mode
+// PUT /v1/capabilities
+/// Workers advertise what they are able to do, replacing their previous advertisement
+async fn put_capabilities(
+    State(state): State<TasksState>,
+    Query(params): Query<CapabilityParams>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    let capabilities: HashSet<String> = params.capabilities
+        .split(',')
+        .filter(|capability| !capability.is_empty())
+        .map(ToString::to_string)
+        .collect();
+    if !capabilities.iter().all(|capability| is_valid_capability(capability)) {
+        return Err((StatusCode::BAD_REQUEST, "Capabilities may only contain ASCII letters, digits and dashes"));
     }
+    debug!("{} advertised capabilities {capabilities:?}", msg.get_from());
+    state.advertise_capabilities(msg.msg.from, capabilities);
+    Ok(StatusCode::NO_CONTENT)
 }
 
-impl<'a, M: Msg> MsgFilterTrait<M> for MsgFilterNoTask {
-    fn from(&self) -> Option<&AppOrProxyId> {
-        self.from.as_ref()
+// GET /v1/capabilities/:capability
+/// Proxies resolve capabilities to the workers advertising them before encrypting a message for each of them
+async fn get_capable_workers(
+    State(state): State<TasksState>,
+    Path(capability): Path<String>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Json<Vec<AppOrProxyId>>, StatusCode> {
+    debug!("{} resolved capability {capability}", msg.get_from());
+    let workers = state.capable_workers(&capability);
+    if workers.is_empty() {
+        return Err(StatusCode::NOT_FOUND);
     }
+    Ok(Json(workers))
+}
 
-    fn to(&self) -> Option<&AppOrProxyId> {
-        self.to.as_ref()
-    }
+// GET /v1/monitor/tasks
+/// Lets operators follow the lifecycle of all tasks. The broker can't decrypt the tasks so their bodies are never part of the events.
+async fn monitor_tasks(
+    State(state): State<TasksState>,
+    auth: TypedHeader<Authorization<Basic>>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, StatusCode> {
+    check_monitoring_auth(&auth)?;
+    Ok(Sse::new(state.task_manager.stream_events()).keep_alive(KeepAlive::default()))
+}
 
-    fn mode(&self) -> &MsgFilterMode {
-        &self.mode
-    }
+#[derive(Deserialize)]
+struct DeleteParams {
+    /// Seconds workers that claimed the task have left to submit a final result
+    grace: Option<u64>,
 }
 
-// POST /v1/tasks
-async fn post_task(
+// DELETE /v1/admin/tasks/:task_id
+/// Removes any task regardless of its creator, e.g. to clear stuck or abusive tasks
+async fn admin_delete_task(
+    State(state): State<TasksState>,
+    Path(task_id): Path<MsgId>,
+    Query(params): Query<DeleteParams>,
+    auth: TypedHeader<Authorization<Basic>>,
+) -> Result<StatusCode, StatusCode> {
+    check_admin_auth(&auth)?;
+    let status = delete_task(&state, task_id, params.grace.map(Duration::from_secs))?;
+    warn!("Admin {} removed task {task_id}", auth.username());
+    Ok(status)
+}
+
+/// Removes the task right away or, if workers have claimed it, once they had `grace` to submit a final result.
+/// In the meantime the task is no longer listed and only accepts terminal results of these workers.
+fn delete_task(state: &TasksState, task_id: MsgId, grace: Option<Duration>) -> Result<StatusCode, TaskManagerError> {
+    let workers: HashSet<AppOrProxyId> = {
+        let task = state.task_manager.get(&task_id)?;
+        let leased = state.claims.get(&task_id).map(|holder| holder.clone());
+        task.msg.results
+            .values()
+            .filter(|result| result.msg.status == WorkStatus::Claimed)
+            .map(|result| result.msg.from.clone())
+            .chain(leased)
+            .collect()
+    };
+    let grace = grace.filter(|grace| !grace.is_zero() && !workers.is_empty());
+    let Some(grace) = grace else {
+        state.task_manager.remove(&task_id)?;
+        return Ok(StatusCode::NO_CONTENT);
+    };
+    debug!("Removing task {task_id} in {grace:?} after {workers:?} submitted their final results");
+    state.deleting.insert_for(grace, task_id, workers);
+    let task_manager = state.task_manager.clone();
+    tokio::spawn(async move {
+        tokio::time::sleep(grace).await;
+        // The task may have expired in the meantime
+        _ = task_manager.remove(&task_id);
+    });
+    Ok(StatusCode::ACCEPTED)
+}
+
+#[derive(Deserialize, Debug)]
+struct PurgeFilter {
+    from: Option<AppOrProxyId>,
+    to: Option<AppOrProxyId>,
+}
+
+impl PurgeFilter {
+    fn matches(&self, task: &EncryptedMsgTaskRequest) -> bool {
+        self.from.as_ref().is_none_or(|from| task.from == *from)
+            && self.to.as_ref().is_none_or(|to| task.to.contains(to))
+    }
+}
+
+// POST /v1/admin/tasks/purge
+/// Removes all tasks created by `from` and/or addressed to `to` and returns their ids
+async fn admin_purge_tasks(
+    State(state): State<TasksState>,
+    auth: TypedHeader<Authorization<Basic>>,
+    Json(filter): Json<PurgeFilter>,
+) -> Result<Json<Vec<MsgId>>, (StatusCode, &'static str)> {
+    check_admin_auth(&auth).map_err(|code| (code, "Unauthorized"))?;
+    let purged = purge_tasks(&state, &filter)?;
+    warn!("Admin {} purged {} tasks matching {filter:?}", auth.username(), purged.len());
+    Ok(Json(purged))
+}
+
+fn purge_tasks(state: &TasksState, filter: &PurgeFilter) -> Result<Vec<MsgId>, (StatusCode, &'static str)> {
+    if filter.from.is_none() && filter.to.is_none() {
+        return Err((StatusCode::BAD_REQUEST, "Please supply either \"from\" or \"to\" to purge tasks."));
+    }
+    let ids: Vec<MsgId> = state.task_manager
+        .get_tasks_by(|task| filter.matches(task))
+        .map(|task| task.msg.id)
+        .collect();
+    // Tasks may expire while purging
+    Ok(ids.into_iter().filter(|id| state.task_manager.remove(id).is_ok()).collect())
+}
+
+// GET /v1/admin/tasks/:task_id/audit
+/// Lists every result accepted for the task, including the ones that have since been overwritten
+async fn admin_audit_task(
+    State(state): State<TasksState>,
+    Path(task_id): Path<MsgId>,
+    auth: TypedHeader<Authorization<Basic>>,
+) -> Result<Json<Vec<AuditEntry>>, StatusCode> {
+    check_admin_auth(&auth)?;
+    Ok(Json(state.audit.get(&task_id)))
+}
+
+#[derive(Deserialize)]
+struct ExplainTarget {
+    /// The app whose listing is explained
+    #[serde(rename = "as")]
+    requester: AppOrProxyId,
+    /// Only explain this task instead of all tasks created by or directed to the app
+    task: Option<MsgId>,
+}
+
+// GET /v1/admin/tasks/explain
+/// Tells why the tasks of an app are (not) part of its listing with the given query parameters, e.g. to debug missing tasks.
+/// Only for admins as it reveals metadata of other apps' tasks.
+async fn admin_explain_tasks(
+    State(state): State<TasksState>,
+    auth: TypedHeader<Authorization<Basic>>,
+    Query(taskfilter): Query<TaskFilter>,
+    Query(target): Query<ExplainTarget>,
+) -> Result<Json<Vec<FilterExplanation>>, (StatusCode, &'static str)> {
+    check_admin_auth(&auth).map_err(|code| (code, "Unauthorized"))?;
+    explain_tasks(&state, &taskfilter, &target).map(Json)
+}
+
+fn explain_tasks(state: &TasksState, taskfilter: &TaskFilter, target: &ExplainTarget) -> Result<Vec<FilterExplanation>, (StatusCode, &'static str)> {
+    let listing = TaskListing::new(taskfilter, &target.requester, state)?;
+    let involved = |task: &EncryptedMsgTaskRequest| match target.task {
+        Some(id) => task.id == id,
+        None => task.from == target.requester || task.to.contains(&target.requester),
+    };
+    Ok(state.task_manager
+        .get_tasks_by(involved)
+        .map(|task| listing.explain(&task.msg))
+        .collect())
+}
+
+// GET /v1/tasks/results/stream
+async fn stream_all_results(
     ConnectInfo(addr): ConnectInfo<SocketAddr>,
     State(state): State<TasksState>,
-    msg: MsgSigned<EncryptedMsgTaskRequest>,
-) -> Result<(StatusCode, impl IntoResponse), StatusCode> {
-        // let id = MsgId::new();
-    // msg.id = id;
-    // TODO: Check if ID is taken
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, TooManyStreams> {
+    debug!("stream_all_results called by {} with IP {addr}", msg.get_from());
+    let from = msg.get_from().clone();
+    let slot = state.streams.acquire(&from)?;
+    let filter = MsgFilterNoTask { from: None, to: Some(from.clone()), mode: MsgFilterMode::Or };
+    let stream = state.task_manager.stream_all_results(
+        from,
+        move |m| filter.matches(&m.msg)
+    );
+    Ok(Sse::new(slot.hold_during(stream)).keep_alive(KeepAlive::default()))
+}
+
+
+#[derive(Deserialize)]
+struct TaskFilter {
+    from: Option<AppOrProxyId>,
+    to: Option<AppOrProxyId>,
+    filter: Option<FilterParam>,
+    #[serde(rename = "match", default)]
+    mode: MsgFilterMode,
+    /// Only tasks created at or after this time, in RFC 3339 format in UTC
+    #[serde(default, deserialize_with = "deserialize_rfc3339")]
+    since: Option<SystemTime>,
+    /// Only tasks created before this time, in RFC 3339 format in UTC
+    #[serde(default, deserialize_with = "deserialize_rfc3339")]
+    until: Option<SystemTime>,
+    /// Only look at the tasks without recording their delivery, e.g. for dashboards
+    #[serde(default)]
+    peek: bool,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct TimeWindow {
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+impl TimeWindow {
+    fn contains(&self, time: SystemTime) -> bool {
+        self.since.is_none_or(|since| since <= time) && self.until.is_none_or(|until| time < until)
+    }
+}
+
+fn deserialize_rfc3339<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<Option<SystemTime>, D::Error> {
+    let time = String::deserialize(deserializer)?;
+    humantime::parse_rfc3339_weak(&time)
+        .map(Some)
+        .map_err(|e| serde::de::Error::custom(format!("Invalid RFC 3339 timestamp {time}: {e}")))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum FilterParam {
+    /// Tasks directed to me that I have not answered yet
+    Todo,
+    /// Tasks that none of the recipients have answered yet
+    NoneAnswered,
+    /// Tasks that not all recipients have answered yet
+    NotAllAnswered,
+}
+
+/// GET /v1/tasks
+/// Will retrieve tasks that are at least FROM or TO the supplied parameters.
+async fn get_tasks(
+    block: HowLongToBlock,
+    Query(taskfilter): Query<TaskFilter>,
+    State(state): State<TasksState>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<JsonArrayStream, Response> {
+    let _permit = state.long_poll_permit(&block, msg.get_from()).map_err(IntoResponse::into_response)?;
+    let listing = TaskListing::new(&taskfilter, msg.get_from(), &state).map_err(IntoResponse::into_response)?;
+    let task_ids = state.task_manager
+        .wait_for_tasks(&block, move |m| listing.matches(m))
+        .await
+        .map_err(|e| <(StatusCode, &str)>::from(e).into_response())?
+        // Any listing returning a task to one of its recipients counts as a delivery unless it is only a peek
+        .inspect(|task| if let (Some(deliveries), false) = (&state.deliveries, taskfilter.peek) {
+            record_delivery(deliveries, &task.msg, &msg.msg.from);
+        })
+        .map(|task| task.msg.id)
+        .collect();
+    // The tasks are only serialized while the body is sent, outside of the executor as offloaded tasks are read from the blob store
+    Ok(JsonArrayStream::new_blocking(task_ids, block.wait_count, move |task_id, buf| {
+        let Some(task) = state.task_manager.get(&task_id).ok().and_then(|task| state.load_task(task)) else {
+            return Ok(false);
+        };
+        serde_json::to_writer(buf, &task).map(|()| true)
+    }))
+}
+
+#[derive(Deserialize)]
+struct TaskSearch {
+    /// Comma separated `key:value` pairs which all have to be in the task's metadata
+    labels: Option<String>,
+    /// Comma separated statuses of which at least one recipient has to be in: `succeeded`, `failed` or `pending`
+    status: Option<String>,
+    /// Maximum number of tasks to return
+    limit: Option<usize>,
+    /// Only return tasks with an id greater than this one
+    after: Option<MsgId>,
+}
+
+/// Default page size of a search, which also caps the `limit` parameter
+const SEARCH_LIMIT: usize = 1000;
+
+/// Conditions of a search on top of the ones of a listing
+struct SearchConditions {
+    /// Metadata fields and their values
+    labels: Vec<(String, String)>,
+    statuses: Vec<SummaryStatus>,
+}
+
+#[derive(Deserialize, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+enum SummaryStatus {
+    Succeeded,
+    Failed,
+    Pending,
+}
+
+impl SearchConditions {
+    fn new(search: &TaskSearch) -> Result<Self, (StatusCode, &'static str)> {
+        let labels = search.labels.iter()
+            .flat_map(|labels| labels.split(','))
+            .map(|label| label.split_once(':').map(|(key, value)| (key.to_string(), value.to_string())))
+            .collect::<Option<_>>()
+            .ok_or((StatusCode::BAD_REQUEST, "Labels have to be key:value pairs"))?;
+        let statuses = search.status.iter()
+            .flat_map(|statuses| statuses.split(','))
+            .map(|status| serde_json::from_value(serde_json::Value::String(status.to_string())))
+            .collect::<Result<_, _>>()
+            .map_err(|_| (StatusCode::BAD_REQUEST, "Status has to be succeeded, failed or pending"))?;
+        Ok(Self { labels, statuses })
+    }
+
+    fn matches(&self, task: &EncryptedMsgTaskRequest) -> bool {
+        self.labels.iter().all(|(key, value)| match task.metadata.get(key) {
+            Some(serde_json::Value::String(s)) => s == value,
+            Some(v @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_))) => serde_json::from_str::<serde_json::Value>(value).is_ok_and(|value| value == *v),
+            _ => false,
+        }) && (self.statuses.is_empty() || {
+            let summary = TaskSummary::from(task);
+            self.statuses.iter().any(|status| match status {
+                SummaryStatus::Succeeded => summary.succeeded > 0,
+                SummaryStatus::Failed => summary.failed > 0,
+                SummaryStatus::Pending => summary.pending > 0,
+            })
+        })
+    }
+}
+
+/// What a search returns of a task, leaving out its body and results
+#[derive(Serialize)]
+struct TaskSearchHit {
+    id: MsgId,
+    from: AppOrProxyId,
+    to: Vec<AppOrProxyId>,
+    metadata: serde_json::Value,
+    #[serde(flatten)]
+    summary: TaskSummary,
+}
+
+/// GET /v1/tasks/search
+/// Finds tasks created by or directed to the requester by their metadata, the status of their recipients and the listing parameters of `get_tasks`.
+/// Without `from` and `to`, both the tasks created by and directed to the requester are searched.
+async fn search_tasks(
+    Query(mut taskfilter): Query<TaskFilter>,
+    Query(search): Query<TaskSearch>,
+    State(state): State<TasksState>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Response, (StatusCode, &'static str)> {
+    let requester = msg.get_from();
+    if taskfilter.from.is_none() && taskfilter.to.is_none() {
+        taskfilter.from = Some(requester.clone());
+        taskfilter.to = Some(requester.clone());
+        taskfilter.mode = MsgFilterMode::Or;
+    }
+    let listing = TaskListing::new(&taskfilter, requester, &state)?;
+    let conditions = SearchConditions::new(&search)?;
+    let limit = search.limit.unwrap_or(SEARCH_LIMIT).min(SEARCH_LIMIT);
+    if limit == 0 {
+        return Err((StatusCode::BAD_REQUEST, "The limit has to be positive"));
+    }
+    let ids = state.task_manager
+        .get_tasks_by(|task| search.after.is_none_or(|after| task.id > after) && listing.matches(task) && conditions.matches(task))
+        .map(|task| task.msg.id)
+        .collect();
+    let (page, next_cursor) = paginate(ids, limit);
+    let hits: Vec<_> = page.into_iter()
+        .filter_map(|id| state.task_manager.get(&id).ok())
+        .map(|task| TaskSearchHit {
+            id: task.msg.id,
+            from: task.msg.from.clone(),
+            to: task.msg.to.clone(),
+            metadata: task.msg.metadata.clone(),
+            summary: TaskSummary::from(&task.msg),
+        })
+        .collect();
+    let mut res = Json(hits).into_response();
+    if let Some(next_cursor) = next_cursor {
+        res.headers_mut().insert(NEXT_CURSOR_HEADER, HeaderValue::from_str(&next_cursor.to_string()).expect("MsgId is a valid header value"));
+    }
+    Ok(res)
+}
+
+/// Sorts the ids and returns the first `limit` of them as well as the cursor for the next page if there are more
+pub(crate) fn paginate(mut ids: Vec<MsgId>, limit: usize) -> (Vec<MsgId>, Option<MsgId>) {
+    ids.sort_unstable();
+    let next_cursor = (ids.len() > limit).then(|| ids[limit - 1]);
+    ids.truncate(limit);
+    (ids, next_cursor)
+}
+
+/// Everything deciding whether a task is part of a listing of tasks
+struct TaskListing<'a> {
+    filter: MsgFilterForTask<'a>,
+    /// Set if tasks claimed by other workers than the requester are hidden
+    claims: Option<(Arc<LazyExpireMap<MsgId, AppOrProxyId>>, AppOrProxyId)>,
+    /// Set if only tasks created in a time window are listed
+    window: Option<(Arc<LazyExpireMap<MsgId, SystemTime>>, TimeWindow)>,
+    /// Tasks being deleted are no longer listed
+    deleting: Arc<LazyExpireMap<MsgId, HashSet<AppOrProxyId>>>,
+}
+
+impl<'a> TaskListing<'a> {
+    fn new(taskfilter: &TaskFilter, requester: &'a AppOrProxyId, state: &TasksState) -> Result<Self, (StatusCode, &'static str)> {
+        let mut from = taskfilter.from.clone();
+        let mut to = taskfilter.to.clone();
+        let unanswered = match taskfilter.filter {
+            Some(FilterParam::Todo) => {
+                if to.is_none() {
+                    to = Some(requester.clone());
+                }
+                Unanswered::By(requester)
+            }
+            Some(FilterParam::NoneAnswered) => {
+                if from.is_none() && to.is_none() {
+                    from = Some(requester.clone());
+                }
+                Unanswered::ByAll
+            }
+            Some(FilterParam::NotAllAnswered) => {
+                if from.is_none() && to.is_none() {
+                    from = Some(requester.clone());
+                }
+                Unanswered::ByAny
+            }
+            None => Unanswered::Always,
+        };
+        if from.is_none() && to.is_none() {
+            return Err((
+                StatusCode::BAD_REQUEST,
+                "Please supply either \"from\" or \"to\" query parameter.",
+            ));
+        }
+        if (from.is_some() && from.as_ref().unwrap() != requester)
+            || (to.is_some() && to.as_ref().unwrap() != requester)
+        {
+            // Rewrite in Rust 1.64: https://github.com/rust-lang/rust/pull/94927
+            return Err((
+                StatusCode::UNAUTHORIZED,
+                "You can only list messages created by you (from) or directed to you (to).",
+            ));
+        }
+        let filter = MsgFilterNoTask {
+            from,
+            to,
+            mode: taskfilter.mode,
+        };
+        let filter = MsgFilterForTask {
+            normal: filter,
+            exclude_complete: matches!(unanswered, Unanswered::By(_)),
+            unanswered,
+            workstatus_is_not: [WorkStatus::Succeeded, WorkStatus::PermFailed, WorkStatus::Claimed]
+                .iter()
+                .map(std::mem::discriminant)
+                .collect(),
+        };
+        let claims = matches!(filter.unanswered, Unanswered::By(_)).then(|| (state.claims.clone(), requester.clone()));
+        let window = TimeWindow { since: taskfilter.since, until: taskfilter.until };
+        let window = (window.since.is_some() || window.until.is_some()).then(|| (state.created.clone(), window));
+        Ok(Self { filter, claims, window, deleting: state.deleting.clone() })
+    }
+
+    fn matches(&self, task: &EncryptedMsgTaskRequest) -> bool {
+        self.filter.matches(task) && self.not_claimed_by_other(task) && self.in_window(task) && self.not_deleted(task)
+    }
+
+    fn not_deleted(&self, task: &EncryptedMsgTaskRequest) -> bool {
+        self.deleting.get(&task.id).is_none()
+    }
+
+    fn not_claimed_by_other(&self, task: &EncryptedMsgTaskRequest) -> bool {
+        !self.claims.as_ref().is_some_and(|(claims, me)| is_claimed_by_other(claims, &task.id, me))
+    }
+
+    fn in_window(&self, task: &EncryptedMsgTaskRequest) -> bool {
+        self.window.as_ref().is_none_or(|(created, window)| created.get(&task.id).is_some_and(|time| window.contains(*time)))
+    }
+
+    /// Evaluates every condition on its own instead of stopping at the first one that fails
+    fn explain(&self, task: &EncryptedMsgTaskRequest) -> FilterExplanation {
+        let applies = |condition: bool, result: bool| condition.then_some(result);
+        FilterExplanation {
+            task_id: task.id,
+            listed: self.matches(task),
+            addressed: MsgFilterNoTask::matches(&self.filter.normal, task),
+            not_complete: applies(self.filter.exclude_complete, !task.is_complete()),
+            unanswered: applies(!matches!(self.filter.unanswered, Unanswered::Always), self.filter.unanswered(task)),
+            not_claimed_by_other: applies(self.claims.is_some(), self.not_claimed_by_other(task)),
+            in_time_window: applies(self.window.is_some(), self.in_window(task)),
+            not_deleted: self.not_deleted(task),
+        }
+    }
+}
+
+/// Which conditions of a listing a task meets. Conditions that don't apply to the listing are null.
+#[derive(Serialize, Deserialize, Debug, PartialEq)]
+pub(crate) struct FilterExplanation {
+    task_id: MsgId,
+    listed: bool,
+    /// The task matches the `from` and `to` parameters
+    addressed: bool,
+    not_complete: Option<bool>,
+    /// The recipients named by the `filter` parameter have not answered yet
+    unanswered: Option<bool>,
+    not_claimed_by_other: Option<bool>,
+    in_time_window: Option<bool>,
+    /// The task is not being deleted
+    not_deleted: bool,
+}
+
+trait MsgFilterTrait<M: Msg> {
+    // fn new() -> Self;
+    fn from(&self) -> Option<&AppOrProxyId>;
+    fn to(&self) -> Option<&AppOrProxyId>;
+    fn mode(&self) -> &MsgFilterMode;
+
+    fn matches(&self, msg: &M) -> bool {
+        match self.mode() {
+            MsgFilterMode::Or => self.filter_or(msg),
+            MsgFilterMode::And => self.filter_and(msg),
+        }
+    }
+
+    /// Returns true iff the from or the to conditions match (or both)
+    fn filter_or(&self, msg: &M) -> bool {
+        if self.from().is_none() && self.to().is_none() {
+            return true;
+        }
+        if let Some(to) = &self.to() {
+            if msg.get_to().contains(to) {
+                return true;
+            }
+        }
+        if let Some(from) = &self.from() {
+            if msg.get_from() == *from {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Returns true iff all defined from/to conditions are met.
+    fn filter_and(&self, msg: &M) -> bool {
+        if self.from().is_none() && self.to().is_none() {
+            return true;
+        }
+        if let Some(to) = self.to() {
+            if !msg.get_to().contains(to) {
+                return false;
+            }
+        }
+        if let Some(from) = &self.from() {
+            if msg.get_from() != *from {
+                return false;
+            }
+        }
+        true
+    }
+}
+
+#[derive(Deserialize, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum MsgFilterMode {
+    #[default]
+    Or,
+    And,
+}
+struct MsgFilterNoTask {
+    from: Option<AppOrProxyId>,
+    to: Option<AppOrProxyId>,
+    mode: MsgFilterMode,
+}
+
+/// Which recipients of a task must not have answered it yet for the task to match
+enum Unanswered<'a> {
+    /// No criterion, every task matches
+    Always,
+    /// The given recipient has not answered
+    By(&'a AppOrProxyId),
+    /// None of the recipients have answered
+    ByAll,
+    /// At least one recipient has not answered
+    ByAny,
+}
+
+struct MsgFilterForTask<'a> {
+    normal: MsgFilterNoTask,
+    unanswered: Unanswered<'a>,
+    /// Do not match tasks that are complete according to their completion policy
+    exclude_complete: bool,
+    /// Results with these statuses count as an answer
+    workstatus_is_not: Vec<Discriminant<WorkStatus>>,
+}
+
+impl<'a> MsgFilterForTask<'a> {
+    fn is_answered_by(&self, msg: &EncryptedMsgTaskRequest, recipient: &AppOrProxyId) -> bool {
+        msg.results.get(recipient).is_some_and(|res| {
+            self.workstatus_is_not
+                .contains(&std::mem::discriminant(&res.msg.status))
+        })
+    }
+
+    fn unanswered(&self, msg: &EncryptedMsgTaskRequest) -> bool {
+        let unanswered = match self.unanswered {
+            Unanswered::Always => {
+                debug!("Is {} unanswered? Yes, criterion not defined.", msg.id());
+                return true;
+            }
+            Unanswered::By(recipient) => !self.is_answered_by(msg, recipient),
+            Unanswered::ByAll => !msg.to.iter().any(|to| self.is_answered_by(msg, to)),
+            Unanswered::ByAny => !msg.to.iter().all(|to| self.is_answered_by(msg, to)),
+        };
+        if unanswered {
+            debug!("Is {} unanswered? Yes, no matching answer found.", msg.id());
+        } else {
+            debug!("Is {} unanswered? No, answer found.", msg.id());
+        }
+        unanswered
+    }
+}
+
+impl<'a> MsgFilterTrait<EncryptedMsgTaskRequest> for MsgFilterForTask<'a> {
+    fn from(&self) -> Option<&AppOrProxyId> {
+        self.normal.from.as_ref()
+    }
+
+    fn to(&self) -> Option<&AppOrProxyId> {
+        self.normal.to.as_ref()
+    }
+
+    fn matches(&self, msg: &EncryptedMsgTaskRequest) -> bool {
+        MsgFilterNoTask::matches(&self.normal, msg)
+            && !(self.exclude_complete && msg.is_complete())
+            && self.unanswered(msg)
+    }
+
+    fn mode(&self) -> &MsgFilterMode {
+        &self.normal.mode
+    }
+}
+
+impl<'a, M: Msg> MsgFilterTrait<M> for MsgFilterNoTask {
+    fn from(&self) -> Option<&AppOrProxyId> {
+        self.from.as_ref()
+    }
+
+    fn to(&self) -> Option<&AppOrProxyId> {
+        self.to.as_ref()
+    }
+
+    fn mode(&self) -> &MsgFilterMode {
+        &self.mode
+    }
+}
+
+const IDEMPOTENCY_KEY_HEADER: HeaderName = HeaderName::from_static("idempotency-key");
+
+// POST /v1/tasks
+async fn post_task(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    State(state): State<TasksState>,
+    headers: HeaderMap,
+    mut msg: MsgSigned<EncryptedMsgTaskRequest>,
+) -> Result<Response, Response> {
     trace!(
         "Client {} with IP {addr} is creating task {:?}",
         msg.msg.from, msg
     );
-    let id = msg.msg.id;
-    state.task_manager.post_task(msg)?;
-    Ok((
-        StatusCode::CREATED,
-        [(header::LOCATION, format!("/v1/tasks/{}", id))],
-    ))
+    check_recipient_count(&msg.msg, state.max_task_recipients).map_err(IntoResponse::into_response)?;
+    msg.msg.failure_strategy.validate().map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+    clamp_retry_ceilings(&mut msg.msg, state.max_retry_tries, state.max_retry_backoff_millisecs);
+    if state.validate_recipients {
+        check_recipients_known(&msg.msg.to).await?;
+    }
+    let (created, offload) = create_task(&state, &headers, msg).map_err(IntoResponse::into_response)?;
+    if let Some(offload) = offload {
+        state.offload(offload).await;
+    }
+    Ok(created.into_response())
+}
+
+fn create_task(
+    state: &TasksState,
+    headers: &HeaderMap,
+    msg: MsgSigned<EncryptedMsgTaskRequest>,
+) -> Result<(impl IntoResponse, Option<PendingOffload>), (StatusCode, &'static str)> {
+    let location = |id: MsgId| (StatusCode::CREATED, [(header::LOCATION, format!("/v1/tasks/{}", id))]);
+    let Some(idempotency_key) = headers.get(IDEMPOTENCY_KEY_HEADER) else {
+        let id = msg.msg.id;
+        let offload = state.post_task(msg)?;
+        return Ok((location(id), offload));
+    };
+    let idempotency_key = idempotency_key
+        .to_str()
+        .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid idempotency key"))?
+        .to_string();
+    state.idempotency_keys.evict_to(TasksState::IDEMPOTENCY_KEY_CAPACITY);
+    match state.idempotency_keys.entry((msg.msg.from.clone(), idempotency_key)) {
+        Entry::Occupied(entry) if entry.get().1 > Instant::now() => {
+            let id = entry.get().0;
+            debug!("Task {id} has already been created with this idempotency key");
+            Ok((location(id), None))
+        },
+        entry => {
+            let id = msg.msg.id;
+            let offload = state.post_task(msg)?;
+            entry.insert((id, Instant::now() + TasksState::IDEMPOTENCY_KEY_RETENTION));
+            Ok((location(id), offload))
+        }
+    }
+}
+
+/// Rejects tasks addressed to proxies without a valid certificate.
+/// Apps can only be checked for their proxy as the broker does not know which apps a proxy serves.
+async fn check_recipients_known(to: &[AppOrProxyId]) -> Result<(), Response> {
+    match shared::crypto::get_proxy_public_keys(to).await {
+        Ok(_) => Ok(()),
+        Err(SamplyBeamError::InvalidReceivers(proxies)) => {
+            debug!("Rejecting task addressed to unknown proxies {proxies:?}");
+            Err((StatusCode::UNPROCESSABLE_ENTITY, Json(proxies)).into_response())
+        },
+        Err(e) => {
+            warn!("Unable to validate task recipients: {e}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Unable to validate task recipients").into_response())
+        }
+    }
+}
+
+/// Every recipient gets its own encrypted key and result slot so their number is capped
+fn check_recipient_count(task: &EncryptedMsgTaskRequest, max: usize) -> Result<(), (StatusCode, &'static str)> {
+    if task.to.len() > max {
+        Err((StatusCode::BAD_REQUEST, "Task has too many recipients"))
+    } else {
+        Ok(())
+    }
+}
+
+/// Lowers retry settings above the broker's ceilings (0 for none) so the broker never retries a task indefinitely.
+/// Only the broker's copy is changed as the signed task cannot be.
+fn clamp_retry_ceilings(task: &mut EncryptedMsgTaskRequest, max_tries: usize, max_backoff_millisecs: usize) {
+    let FailureStrategy::Retry { backoff_millisecs, max_tries: tries } = &mut task.failure_strategy else {
+        return;
+    };
+    if max_tries != 0 && *tries > max_tries {
+        warn!("Lowering max_tries of task {} by {} from {tries} to {max_tries}", task.id, task.from);
+        *tries = max_tries;
+    }
+    if max_backoff_millisecs != 0 && *backoff_millisecs > max_backoff_millisecs {
+        warn!("Lowering backoff_millisecs of task {} by {} from {backoff_millisecs} to {max_backoff_millisecs}", task.id, task.from);
+        *backoff_millisecs = max_backoff_millisecs;
+    }
+}
+
+#[derive(Deserialize)]
+struct ClaimParams {
+    /// Lease duration in seconds
+    lease: Option<u64>,
+}
+
+// POST /v1/tasks/:task_id/claim
+async fn claim_task(
+    Path(task_id): Path<MsgId>,
+    Query(params): Query<ClaimParams>,
+    State(state): State<TasksState>,
+    claim: MsgSigned<EncryptedMsgTaskResult>,
+) -> Result<StatusCode, (StatusCode, &'static str)> {
+    if claim.msg.task != task_id {
+        return Err((StatusCode::BAD_REQUEST, "Task IDs supplied in path and payload do not match."));
+    }
+    if claim.msg.status != WorkStatus::Claimed {
+        return Err((StatusCode::BAD_REQUEST, "A claim needs a result with status claimed."));
+    }
+    let worker = &claim.msg.from.clone();
+    let task = state.task_manager.get(&task_id)?;
+    if !task.msg.to.contains(worker) {
+        return Err(TaskManagerError::Unauthorized.into());
+    }
+    let until_expiry = task.msg.expire.duration_since(SystemTime::now()).unwrap_or_default();
+    // The leases of workers sending heartbeats are kept alive by their heartbeats instead
+    let lease = match state.heartbeats.get(worker) {
+        Some(timeout) => *timeout,
+        None => params.lease.map_or(TasksState::DEFAULT_CLAIM_LEASE, Duration::from_secs),
+    }.min(until_expiry);
+    drop(task);
+    try_claim(&state.claims, task_id, worker, lease)?;
+    // The claimed result tells the creator of the task who is working on it
+    if let Err(e) = state.task_manager.put_result(&task_id, claim) {
+        state.claims.remove_if(&task_id, |_, (holder, _)| holder == worker);
+        return Err(e.into());
+    }
+    debug!("{worker} claimed task {task_id} for {}s", lease.as_secs());
+    Ok(StatusCode::NO_CONTENT)
+}
+
+#[derive(Deserialize)]
+struct HeartbeatParams {
+    /// Seconds without a heartbeat after which the worker's leases end
+    timeout: Option<u64>,
+}
+
+// POST /v1/tasks/heartbeat
+/// Keeps the leases of a worker alive while it is working on its tasks.
+/// If the worker crashes, the tasks it claimed are available to other workers once it misses the timeout, regardless of the leases it asked for.
+async fn heartbeat(
+    Query(params): Query<HeartbeatParams>,
+    State(state): State<TasksState>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Json<Vec<MsgId>> {
+    let worker = msg.get_from();
+    let timeout = params.timeout.map_or(TasksState::DEFAULT_HEARTBEAT_TIMEOUT, Duration::from_secs);
+    state.heartbeats.insert_for(timeout, worker.clone(), timeout);
+    let leased = state.renew_claims(worker, timeout);
+    trace!("Heartbeat of {worker} renewed its leases on {leased:?}");
+    Json(leased)
+}
+
+/// Grants `worker` a lease on the task unless another worker holds a lease on it. Claiming again renews the lease.
+fn try_claim(claims: &LazyExpireMap<MsgId, AppOrProxyId>, task_id: MsgId, worker: &AppOrProxyId, lease: Duration) -> Result<(), (StatusCode, &'static str)> {
+    match claims.entry(task_id) {
+        Entry::Occupied(entry) if entry.get().1 > Instant::now() && &entry.get().0 != worker => {
+            Err((StatusCode::CONFLICT, "Task is claimed by another worker"))
+        },
+        entry => {
+            entry.insert((worker.clone(), Instant::now() + lease));
+            Ok(())
+        }
+    }
+}
+
+fn is_claimed_by_other(claims: &LazyExpireMap<MsgId, AppOrProxyId>, task_id: &MsgId, worker: &AppOrProxyId) -> bool {
+    claims.get(task_id).is_some_and(|holder| &*holder != worker)
+}
+
+// PUT /v1/tasks/:task_id/results/:app_id
+async fn put_result(
+    ConnectInfo(addr): ConnectInfo<SocketAddr>,
+    Path((task_id, app_id)): Path<(MsgId, AppOrProxyId)>,
+    State(state): State<TasksState>,
+    headers: HeaderMap,
+    result: MsgSigned<EncryptedMsgTaskResult>,
+) -> Result<Response, Response> {
+    trace!("Called: Task {:?}, {:?} by {addr}", task_id, result);
+    if task_id != result.msg.task {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "Task IDs supplied in path and payload do not match.",
+        ).into_response());
+    }
+    let worker_id = result.msg.from.clone();
+    if app_id != worker_id {
+        return Err((
+            StatusCode::BAD_REQUEST,
+            "AppID supplied in URL and signed message do not match.",
+        ).into_response());
+    }
+    let expire = {
+        let task = state.task_manager.get(&task_id).map_err(IntoResponse::into_response)?;
+        accepts_result(&state, &task.msg, &result, "result").map_err(IntoResponse::into_response)?;
+        if let Err(reason) = task.msg.failure_strategy.accepts(result.msg.status) {
+            warn!("Rejecting {:?} result of {worker_id} to task {task_id}: {reason}", result.msg.status);
+            return Err((StatusCode::UNPROCESSABLE_ENTITY, reason).into_response());
+        }
+        task.msg.expire
+    };
+
+    let audit_entry = AuditEntry::new(&result);
+    // With `If-None-Match: *` a result is only put if the worker has none yet
+    let status = if headers.get(header::IF_NONE_MATCH).is_some_and(|value| value == "*") {
+        state.task_manager.put_first_result(&task_id, result).map_err(IntoResponse::into_response)?;
+        StatusCode::CREATED
+    } else if state.task_manager.put_result(&task_id, result).map_err(IntoResponse::into_response)? {
+        StatusCode::NO_CONTENT
+    } else {
+        StatusCode::CREATED
+    };
+    state.audit.record(audit_entry, expire.duration_since(SystemTime::now()).unwrap_or_default());
+    // A result ends the lease so other workers can pick the task up if it failed
+    state.claims.remove_if(&task_id, |_, (holder, _)| holder == &worker_id);
+    let complete = task_complete_header(&state.task_manager.get(&task_id).map_err(IntoResponse::into_response)?.msg);
+    Ok((status, complete).into_response())
 }
 
-// PUT /v1/tasks/:task_id/results/:app_id
-async fn put_result(
-    ConnectInfo(addr): ConnectInfo<SocketAddr>,
-    Path((task_id, app_id)): Path<(MsgId, AppOrProxyId)>,
-    State(state): State<TasksState>,
-    result: MsgSigned<EncryptedMsgTaskResult>,
-) -> Result<StatusCode, (StatusCode, &'static str)> {
-    trace!("Called: Task {:?}, {:?} by {addr}", task_id, result);
-    if task_id != result.msg.task {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Task IDs supplied in path and payload do not match.",
-        ));
-    }
-    let worker_id = result.msg.from.clone();
-    if app_id != worker_id {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "AppID supplied in URL and signed message do not match.",
-        ));
+/// Whether the task still takes the result or attachment `kind`, which is not the case while it is being deleted, after its deadline or if the result is too large
+fn accepts_result(
+    state: &TasksState,
+    task: &EncryptedMsgTaskRequest,
+    result: &MsgSigned<EncryptedMsgTaskResult>,
+    kind: &str,
+) -> Result<(), (StatusCode, &'static str)> {
+    if let Some(workers) = state.deleting.get(&task.id) {
+        if !result.msg.status.is_terminal() || !workers.contains(&result.msg.from) {
+            return Err((StatusCode::GONE, "Task is being deleted and only accepts final results of workers that claimed it"));
+        }
+    }
+    if task.deadline.is_some_and(|deadline| SystemTime::now() > deadline) {
+        return Err((StatusCode::GONE, "Task no longer accepts results after its deadline"));
+    }
+    // The signed message is the request body and is stored as is
+    if state.max_result_size != 0 && result.jwt.len() > state.max_result_size {
+        warn!("Rejecting {kind} of {} to task {} as it is {} bytes large", result.msg.from, task.id, result.jwt.len());
+        return Err((StatusCode::PAYLOAD_TOO_LARGE, "Result exceeds the maximum size"));
+    }
+    Ok(())
+}
+
+// PUT /v1/tasks/:task_id/attachments
+async fn put_attachment(
+    Path(task_id): Path<MsgId>,
+    State(state): State<TasksState>,
+    attachment: MsgSigned<EncryptedMsgTaskResult>,
+) -> Result<impl IntoResponse, (StatusCode, &'static str)> {
+    let Some(attachments) = &state.attachments else {
+        return Err((StatusCode::NOT_IMPLEMENTED, "Attachments need a blob store on the broker"));
+    };
+    if task_id != attachment.msg.task {
+        return Err((StatusCode::BAD_REQUEST, "Task IDs supplied in path and payload do not match."));
+    }
+    {
+        let task = state.task_manager.get(&task_id)?;
+        if !task.msg.to.contains(&attachment.msg.from) {
+            return Err(TaskManagerError::Unauthorized.into());
+        }
+        accepts_result(&state, &task.msg, &attachment, "attachment")?;
+    }
+    let from = attachment.msg.from;
+    let digest = attachments.put(task_id, attachment.jwt).await.map_err(|e| {
+        error!("Unable to store attachment of {from} to task {task_id}: {e}");
+        (StatusCode::INTERNAL_SERVER_ERROR, "Unable to store attachment")
+    })?;
+    let location = format!("/v1/tasks/{task_id}/attachments/{digest}");
+    Ok((StatusCode::CREATED, [(header::LOCATION, location)]))
+}
+
+/// Attachments are returned like any other signed message so proxies can verify and decrypt them
+#[derive(Serialize)]
+struct SignedAttachment {
+    jwt: String,
+}
+
+// GET /v1/tasks/:task_id/attachments/:digest
+async fn get_attachment(
+    Path((task_id, digest)): Path<(MsgId, String)>,
+    State(state): State<TasksState>,
+    msg: MsgSigned<MsgEmpty>,
+) -> Result<Json<SignedAttachment>, (StatusCode, &'static str)> {
+    let Some(attachments) = &state.attachments else {
+        return Err((StatusCode::NOT_IMPLEMENTED, "Attachments need a blob store on the broker"));
+    };
+    let requester = msg.get_from();
+    {
+        let task = state.task_manager.get(&task_id)?;
+        if task.get_from() != requester && !task.msg.to.contains(requester) {
+            return Err(TaskManagerError::Unauthorized.into());
+        }
+    }
+    match attachments.get(&task_id, &digest).await {
+        Ok(Some(jwt)) => Ok(Json(SignedAttachment { jwt })),
+        Ok(None) => Err((StatusCode::NOT_FOUND, "Attachment not found")),
+        Err(e) => {
+            error!("Unable to load attachment {digest} of task {task_id}: {e}");
+            Err((StatusCode::INTERNAL_SERVER_ERROR, "Unable to load attachment"))
+        }
+    }
+}
+
+/// Serves the task API of an in-memory broker on a local port, so tests send signed requests like proxies do.
+/// All apps belong to proxies with certificates of a test PKI, see [`shared::test_utils::trusted_proxy`].
+#[cfg(test)]
+pub(crate) mod test_support {
+    use std::{net::SocketAddr, sync::Arc, time::{Duration, SystemTime}};
+
+    use axum::http::{header, HeaderMap, HeaderValue, Method, Request, StatusCode};
+    use beam_lib::{AppOrProxyId, FailureStrategy, MsgId, WorkStatus};
+    use serde_json::Value;
+    use shared::{
+        client::sign_request_with_token, config_shared::ConfigCrypto, crypto_jwt, reqwest, test_utils::{app_id, result, task, trusted_proxy},
+        Encrypted, EncryptedMessage, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, HowLongToBlock, Msg, MsgEmpty, MsgSigned, MsgTaskRequest, MsgTaskResult,
+    };
+
+    use super::{AuditEntry, AuditLog, TasksConfig, TasksState};
+
+    pub(crate) fn block(wait_count: Option<u16>, wait_time: Option<Duration>) -> HowLongToBlock {
+        HowLongToBlock { wait_count, wait_time }
+    }
+
+    fn block_query(block: &HowLongToBlock) -> String {
+        let mut query = String::new();
+        if let Some(wait_count) = block.wait_count {
+            query.push_str(&format!("wait_count={wait_count}&"));
+        }
+        if let Some(wait_time) = block.wait_time {
+            query.push_str(&format!("wait_time={}ms&", wait_time.as_millis()));
+        }
+        query
+    }
+
+    #[derive(Clone)]
+    pub(crate) struct TestBroker {
+        state: TasksState,
+        addr: SocketAddr,
+        client: reqwest::Client,
+    }
+
+    impl TestBroker {
+        pub(crate) async fn new() -> Self {
+            Self::with_config(|_| ()).await
+        }
+
+        /// Starts from small broadcast capacities and no limits, adjusted by `configure`
+        pub(super) async fn with_config(configure: impl FnOnce(&mut TasksConfig)) -> Self {
+            let mut config = TasksConfig {
+                task_broadcast_capacity: 16,
+                result_broadcast_capacity: 16,
+                delivery_receipts: false,
+                max_long_polls: 100,
+                max_streams_per_app: 0,
+                offloaded: None,
+                attachments: None,
+                max_result_size: 0,
+                max_task_recipients: 100,
+                max_retry_tries: 0,
+                max_retry_backoff_millisecs: 0,
+                validate_recipients: false,
+                audit: AuditLog::default(),
+            };
+            configure(&mut config);
+            // Generating the keys of the proxies takes a while, which would count against the timeouts in tests
+            for proxy in ["proxy1", "proxy2"] {
+                trusted_proxy(proxy).await;
+            }
+            let state = TasksState::new(config);
+            let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+            listener.set_nonblocking(true).unwrap();
+            let addr = listener.local_addr().unwrap();
+            let listener = tokio::net::TcpListener::from_std(listener).unwrap();
+            let app = super::api_router(state.clone());
+            #[cfg(feature = "sockets")]
+            let app = app.merge(crate::serve_sockets::api_router(crate::serve_sockets::SocketState::new(16, 16)));
+            let app = app.into_make_service_with_connect_info::<SocketAddr>();
+            tokio::spawn(async move { axum::serve(listener, app).await });
+            Self { state, addr, client: reqwest::Client::new() }
+        }
+
+        /// The key material of the proxy of `sender`
+        async fn crypto(sender: &AppOrProxyId) -> Arc<ConfigCrypto> {
+            let proxy_id = sender.proxy_id();
+            trusted_proxy(proxy_id.as_ref().split('.').next().unwrap()).await
+        }
+
+        /// Signs the message as the proxy of its sender
+        pub(crate) async fn sign(msg: EncryptedMessage) -> String {
+            crypto_jwt::sign_to_jwt(&msg, Some(&*Self::crypto(msg.get_from()).await)).await.unwrap()
+        }
+
+        async fn send(&self, method: Method, path: &str, headers: HeaderMap, msg: EncryptedMessage) -> reqwest::Response {
+            let from = msg.get_from().clone();
+            self.send_signed(method, path, headers, Self::sign(msg).await, &from).await
+        }
+
+        /// Sends a message `from` signed before
+        async fn send_signed(&self, method: Method, path: &str, headers: HeaderMap, token: String, from: &AppOrProxyId) -> reqwest::Response {
+            self.send_signed_with(method, path, headers, token, from, &*Self::crypto(from).await).await
+        }
+
+        async fn send_signed_with(&self, method: Method, path: &str, headers: HeaderMap, token: String, from: &AppOrProxyId, crypto: &ConfigCrypto) -> reqwest::Response {
+            let mut req = Request::builder().method(method).uri(format!("http://{}{path}", self.addr));
+            *req.headers_mut().unwrap() = headers;
+            let (parts, ()) = req.body(()).unwrap().into_parts();
+            let host = HeaderValue::from_str(&self.addr.to_string()).unwrap();
+            let req = sign_request_with_token(token, from, parts, &host, Some(crypto)).await.unwrap();
+            self.client.execute(req).await.unwrap()
+        }
+
+        /// Sends the message signed by the key and certificate of `proxy` instead of the sender's proxy
+        pub(crate) async fn send_signed_by(&self, method: Method, path: &str, msg: EncryptedMessage, proxy: &str) -> reqwest::Response {
+            let crypto = trusted_proxy(proxy).await;
+            let from = msg.get_from().clone();
+            let token = crypto_jwt::sign_to_jwt(&msg, Some(&*crypto)).await.unwrap();
+            self.send_signed_with(method, path, HeaderMap::new(), token, &from, &crypto).await
+        }
+
+        async fn send_empty(&self, method: Method, path: &str, from: &AppOrProxyId) -> reqwest::Response {
+            self.send_empty_with(method, path, HeaderMap::new(), from).await
+        }
+
+        async fn send_empty_with(&self, method: Method, path: &str, headers: HeaderMap, from: &AppOrProxyId) -> reqwest::Response {
+            self.send(method, path, headers, EncryptedMessage::MsgEmpty(MsgEmpty { from: from.clone() })).await
+        }
+
+        /// Whether the signed message of the task is kept in memory
+        pub(crate) fn in_memory(&self, task_id: &MsgId) -> bool {
+            !self.state.task_manager.get(task_id).unwrap().jwt.is_empty()
+        }
+
+        /// The task section of the admin diagnostics
+        pub(crate) fn diagnostics(&self) -> Value {
+            serde_json::to_value(self.state.diagnostics()).unwrap()
+        }
+
+        /// Advertises the comma separated `capabilities` of `worker`
+        pub(crate) async fn advertise(&self, worker: &AppOrProxyId, capabilities: &str) -> StatusCode {
+            self.send_empty(Method::PUT, &format!("/v1/capabilities?capabilities={capabilities}"), worker).await.status()
+        }
+
+        pub(crate) async fn capable_workers(&self, capability: &str, from: &AppOrProxyId) -> Result<Vec<AppOrProxyId>, StatusCode> {
+            let res = self.send_empty(Method::GET, &format!("/v1/capabilities/{capability}"), from).await;
+            match res.status() {
+                StatusCode::OK => Ok(res.json().await.unwrap()),
+                code => Err(code),
+            }
+        }
+
+        pub(crate) async fn post_task(&self, from: &AppOrProxyId, to: Vec<AppOrProxyId>) -> MsgId {
+            self.post_task_with(from, to, |_| ()).await
+        }
+
+        /// Creates a task retrying `parent` and returns its id and the status of the request
+        pub(crate) async fn post_retry(&self, from: &AppOrProxyId, to: Vec<AppOrProxyId>, parent: MsgId) -> (MsgId, StatusCode) {
+            self.try_post_task_with(from, to, |task| task.parent_task = Some(parent)).await
+        }
+
+        pub(crate) async fn post_task_with(&self, from: &AppOrProxyId, to: Vec<AppOrProxyId>, customize: impl FnOnce(&mut EncryptedMsgTaskRequest)) -> MsgId {
+            let (id, status) = self.try_post_task_with(from, to, customize).await;
+            assert_eq!(status, StatusCode::CREATED);
+            id
+        }
+
+        pub(crate) async fn try_post_task_with(&self, from: &AppOrProxyId, to: Vec<AppOrProxyId>, customize: impl FnOnce(&mut EncryptedMsgTaskRequest)) -> (MsgId, StatusCode) {
+            let mut task = task(from, to);
+            customize(&mut task);
+            let id = task.id;
+            let res = self.send(Method::POST, "/v1/tasks", HeaderMap::new(), EncryptedMessage::MsgTaskRequest(task)).await;
+            (id, res.status())
+        }
+
+        /// Returns the ids of the tasks `app` has to work on
+        pub(crate) async fn get_todo_tasks(&self, app: &AppOrProxyId, block: HowLongToBlock) -> Vec<MsgId> {
+            task_ids(self.try_get_todo_tasks(app, block).await).await
+        }
+
+        pub(crate) async fn try_get_todo_tasks(&self, app: &AppOrProxyId, block: HowLongToBlock) -> reqwest::Response {
+            self.send_empty(Method::GET, &format!("/v1/tasks?{}filter=todo", block_query(&block)), app).await
+        }
+
+        /// Returns the ids of the tasks created by `app` matching the query, e.g. `since=2024-01-01T00:00:00Z`
+        pub(crate) async fn get_created_tasks(&self, app: &AppOrProxyId, query: &str) -> Vec<MsgId> {
+            task_ids(self.send_empty(Method::GET, &format!("/v1/tasks?from={app}&{query}"), app).await).await
+        }
+
+        /// Searches the tasks of `app` with the query, e.g. `labels=study:a`, and returns the hits and the next cursor
+        pub(crate) async fn search(&self, app: &AppOrProxyId, query: &str) -> Result<(Vec<Value>, Option<String>), StatusCode> {
+            let res = self.send_empty(Method::GET, &format!("/v1/tasks/search?{query}"), app).await;
+            if res.status() != StatusCode::OK {
+                return Err(res.status());
+            }
+            let next_cursor = res.headers().get(shared::NEXT_CURSOR_HEADER).map(|cursor| cursor.to_str().unwrap().to_string());
+            Ok((res.json().await.unwrap(), next_cursor))
+        }
+
+        /// Returns the ids of the tasks `app` has to work on without recording their delivery
+        pub(crate) async fn peek_todo_tasks(&self, app: &AppOrProxyId) -> Vec<MsgId> {
+            task_ids(self.send_empty(Method::GET, "/v1/tasks?filter=todo&peek=true", app).await).await
+        }
+
+        pub(crate) async fn put_result(&self, task_id: MsgId, from: &AppOrProxyId, to: &AppOrProxyId, status: WorkStatus) -> StatusCode {
+            self.put_signed_result(task_id, from, Self::sign_result(task_id, from, to, status).await).await
+        }
+
+        /// The signed result as the proxy of `from` sends it to the broker
+        pub(crate) async fn sign_result(task_id: MsgId, from: &AppOrProxyId, to: &AppOrProxyId, status: WorkStatus) -> String {
+            Self::sign(EncryptedMessage::MsgTaskResult(result(task_id, from, to, status))).await
+        }
+
+        pub(crate) async fn put_signed_result(&self, task_id: MsgId, from: &AppOrProxyId, token: String) -> StatusCode {
+            self.send_signed(Method::PUT, &format!("/v1/tasks/{task_id}/results/{from}"), HeaderMap::new(), token, from).await.status()
+        }
+
+        /// Puts the result only if `from` has none yet, like the proxy does for sockets ending
+        pub(crate) async fn put_first_result(&self, task_id: MsgId, from: &AppOrProxyId, to: &AppOrProxyId, status: WorkStatus) -> StatusCode {
+            let headers = HeaderMap::from_iter([(header::IF_NONE_MATCH, HeaderValue::from_static("*"))]);
+            let token = Self::sign_result(task_id, from, to, status).await;
+            self.send_signed(Method::PUT, &format!("/v1/tasks/{task_id}/results/{from}"), headers, token, from).await.status()
+        }
+
+        /// Uploads the signed attachment and returns the status code and the location of the attachment
+        pub(crate) async fn put_attachment(&self, task_id: MsgId, from: &AppOrProxyId, token: &str) -> (StatusCode, Option<String>) {
+            let res = self.send_signed(Method::PUT, &format!("/v1/tasks/{task_id}/attachments"), HeaderMap::new(), token.to_string(), from).await;
+            let location = res.headers().get(header::LOCATION).map(|location| location.to_str().unwrap().to_string());
+            (res.status(), location)
+        }
+
+        /// Returns the signed message of the attachment at `location`
+        pub(crate) async fn get_attachment(&self, location: &str, app: &AppOrProxyId) -> Result<String, StatusCode> {
+            let res = self.send_empty(Method::GET, location, app).await;
+            if res.status() != StatusCode::OK {
+                return Err(res.status());
+            }
+            let body: Value = res.json().await.unwrap();
+            Ok(body["jwt"].as_str().unwrap().to_string())
+        }
+
+        /// Removes the attachments of tasks that no longer exist as the periodic cleanup does
+        pub(crate) fn remove_orphaned_attachments(&self) {
+            let tasks = &self.state.task_manager;
+            self.state.attachments.as_ref().unwrap().remove_orphans(|task_id| tasks.get(task_id).is_ok());
+        }
+
+        /// Returns the status code and the number of results
+        pub(crate) async fn get_results(&self, task_id: MsgId, app: &AppOrProxyId, block: HowLongToBlock) -> (StatusCode, usize) {
+            let res = self.request_results(task_id, app, block, "application/json").await;
+            match res.status() {
+                StatusCode::OK => (StatusCode::OK, jwts(res).await.len()),
+                code => (code, 0),
+            }
+        }
+
+        /// Streams the results until the stream ends and returns the raw SSE body
+        pub(crate) async fn stream_results(&self, task_id: MsgId, app: &AppOrProxyId, block: HowLongToBlock, last_event_id: Option<u64>) -> String {
+            let mut headers = HeaderMap::from_iter([(header::ACCEPT, HeaderValue::from_static("text/event-stream"))]);
+            if let Some(last_event_id) = last_event_id {
+                headers.insert("last-event-id", last_event_id.into());
+            }
+            let res = self.request_results_with(task_id, app, block, headers).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            res.text().await.unwrap()
+        }
+
+        /// Streams the results as newline-delimited JSON until the stream ends and returns the received lines
+        pub(crate) async fn stream_results_ndjson(&self, task_id: MsgId, app: &AppOrProxyId, block: HowLongToBlock) -> Vec<Value> {
+            let res = self.request_results(task_id, app, block, super::APPLICATION_NDJSON).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            assert_eq!(res.headers()[header::CONTENT_TYPE], super::APPLICATION_NDJSON);
+            let body = res.bytes().await.unwrap();
+            assert!(body.is_empty() || body.ends_with(b"\n"), "Every line is terminated");
+            body.split(|b| *b == b'\n')
+                .filter(|line| !line.is_empty())
+                .map(|line| serde_json::from_slice(line).unwrap())
+                .collect()
+        }
+
+        /// Requests the results with the given `Accept` header and returns the response without waiting for its body
+        pub(crate) async fn request_results(&self, task_id: MsgId, app: &AppOrProxyId, block: HowLongToBlock, accept: &'static str) -> reqwest::Response {
+            let headers = HeaderMap::from_iter([(header::ACCEPT, HeaderValue::from_static(accept))]);
+            self.request_results_with(task_id, app, block, headers).await
+        }
+
+        /// Requests the results with the given headers and returns the response without waiting for its body
+        pub(crate) async fn request_results_with(&self, task_id: MsgId, app: &AppOrProxyId, block: HowLongToBlock, headers: HeaderMap) -> reqwest::Response {
+            let path = format!("/v1/tasks/{task_id}/results?{}", block_query(&block));
+            self.send_empty_with(Method::GET, path.trim_end_matches(['?', '&']), headers, app).await
+        }
+
+        /// Waits until the broker has received `count` streams or long polls of the app
+        pub(crate) async fn until_open_streams(&self, app: &AppOrProxyId, count: usize) {
+            while self.open_streams(app) < count {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+
+        /// Number of streams and long polls the app has open
+        pub(crate) fn open_streams(&self, app: &AppOrProxyId) -> usize {
+            self.state.streams.open(app)
+        }
+
+        /// Streams the results and returns the ids of the received result events
+        pub(crate) async fn stream_result_ids(&self, task_id: MsgId, app: &AppOrProxyId, block: HowLongToBlock, last_event_id: Option<u64>) -> Vec<u64> {
+            self.stream_results(task_id, app, block, last_event_id)
+                .await
+                .lines()
+                .filter_map(|line| line.strip_prefix("id:"))
+                .map(|id| id.trim().parse().unwrap())
+                .collect()
+        }
+
+        /// Lets the worker hold a lease on the task for a minute without recording a result
+        pub(crate) fn claim(&self, task_id: MsgId, worker: &AppOrProxyId) {
+            super::try_claim(&self.state.claims, task_id, worker, Duration::from_secs(60)).unwrap();
+        }
+
+        /// Claims the task like a worker would, with the default lease
+        pub(crate) async fn claim_task(&self, task_id: MsgId, worker: &AppOrProxyId, creator: &AppOrProxyId) -> StatusCode {
+            let claim = result(task_id, worker, creator, WorkStatus::Claimed);
+            self.send(Method::POST, &format!("/v1/tasks/{task_id}/claim"), HeaderMap::new(), EncryptedMessage::MsgTaskResult(claim)).await.status()
+        }
+
+        /// The failure strategy the broker applies to the task
+        pub(crate) fn failure_strategy(&self, task_id: MsgId) -> FailureStrategy {
+            self.state.task_manager.get(&task_id).unwrap().msg.failure_strategy.clone()
+        }
+
+        pub(crate) fn result_status(&self, task_id: MsgId, worker: &AppOrProxyId) -> Option<WorkStatus> {
+            let task = self.state.task_manager.get(&task_id).ok()?;
+            task.msg.results.get(worker).map(|result| result.msg.status)
+        }
+
+        /// Sends a heartbeat of `worker`, returning the tasks it holds a lease on
+        pub(crate) async fn heartbeat(&self, worker: &AppOrProxyId, timeout: Duration) -> Vec<MsgId> {
+            let res = self.send_empty(Method::POST, &format!("/v1/tasks/heartbeat?timeout={}", timeout.as_secs()), worker).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            res.json().await.unwrap()
+        }
+
+        // The admin API authenticates admins with the key of the broker's configuration, so tests call what it does directly
+
+        /// Explains the listing of `app` with the query as an admin
+        pub(crate) fn explain(&self, app: &AppOrProxyId, query: &str, task: Option<MsgId>) -> Vec<super::FilterExplanation> {
+            let filter = axum::extract::Query::try_from_uri(&format!("/v1/admin/tasks/explain?{query}").parse().unwrap()).unwrap();
+            let target = super::ExplainTarget { requester: app.clone(), task };
+            super::explain_tasks(&self.state, &filter, &target).unwrap()
+        }
+
+        /// Deletes the task as an admin, leaving workers that claimed it `grace` to submit a final result
+        pub(crate) fn delete(&self, task_id: MsgId, grace: Option<Duration>) -> StatusCode {
+            super::delete_task(&self.state, task_id, grace).unwrap_or_else(StatusCode::from)
+        }
+
+        /// Purges the tasks as an admin and returns their ids
+        pub(crate) fn purge(&self, from: Option<&AppOrProxyId>, to: Option<&AppOrProxyId>) -> Result<Vec<MsgId>, StatusCode> {
+            let filter = super::PurgeFilter { from: from.cloned(), to: to.cloned() };
+            super::purge_tasks(&self.state, &filter).map_err(|(code, _)| code)
+        }
+
+        /// The audit entries of the task as an admin would see them
+        pub(crate) fn audit(&self, task_id: MsgId) -> Vec<AuditEntry> {
+            self.state.audit.get(&task_id)
+        }
+
+        pub(crate) async fn acknowledge(&self, task_id: MsgId, worker: &AppOrProxyId, app: &AppOrProxyId) -> StatusCode {
+            self.send_empty(Method::POST, &format!("/v1/tasks/{task_id}/results/{worker}/ack"), app).await.status()
+        }
+
+        /// Returns the status code and whether the result of `worker` has been acknowledged
+        pub(crate) async fn get_acknowledgement(&self, task_id: MsgId, worker: &AppOrProxyId, app: &AppOrProxyId, block: HowLongToBlock) -> (StatusCode, bool) {
+            let path = format!("/v1/tasks/{task_id}/results/{worker}/ack?{}", block_query(&block));
+            let res = self.send_empty(Method::GET, path.trim_end_matches(['?', '&']), app).await;
+            match res.status() {
+                StatusCode::OK => (StatusCode::OK, res.json::<super::Acknowledgement>().await.unwrap().acknowledged),
+                code => (code, false),
+            }
+        }
+
+        pub(crate) async fn get_summary(&self, task_id: MsgId, app: &AppOrProxyId) -> Value {
+            let res = self.send_empty(Method::GET, &format!("/v1/tasks/{task_id}/summary"), app).await;
+            assert_eq!(res.status(), StatusCode::OK);
+            res.json().await.unwrap()
+        }
     }
 
+    async fn jwts(res: reqwest::Response) -> Vec<String> {
+        assert_eq!(res.status(), StatusCode::OK);
+        res.json::<Vec<Value>>()
+            .await
+            .unwrap()
+            .into_iter()
+            .map(|signed| signed["jwt"].as_str().unwrap().to_string())
+            .collect()
+    }
 
-    let status = if state.task_manager.put_result(&task_id, result)? {
-        StatusCode::NO_CONTENT
-    } else {
-        StatusCode::CREATED
-    };
-    Ok(status)
+    /// Verifies the listed tasks like a proxy would and returns their ids
+    async fn task_ids(res: reqwest::Response) -> Vec<MsgId> {
+        let mut ids = Vec::new();
+        for jwt in jwts(res).await {
+            ids.push(MsgSigned::<EncryptedMsgTaskRequest>::verify(&jwt).await.unwrap().msg.id);
+        }
+        ids
+    }
 }
 
-#[cfg(all(test, never))] // Removed until the errors down below are fixed
+#[cfg(test)]
 mod test {
+    use std::{collections::HashMap, time::{Duration, SystemTime}};
+
+    use axum::http::StatusCode;
+    use beam_lib::{AppOrProxyId, CompletionPolicy, FailureStrategy, MsgId, WorkStatus};
     use serde_json::Value;
-    use shared::{
-        beam_id::{AppId, AppOrProxyId, BeamId, BrokerId, ProxyId},
-        EncryptedMsgTaskRequest, Msg, MsgSigned, MsgTaskRequest, MsgTaskResult, WorkStatus,
-    };
+    use shared::{EncryptedMsgTaskRequest, EncryptedMsgTaskResult, MsgSigned, MsgTaskRequest, MsgTaskResult};
 
-    use super::{MsgFilterForTask, MsgFilterMode, MsgFilterNoTask, MsgFilterTrait};
+    use shared::expire_map::LazyExpireMap;
 
-    #[test]
-    fn filter_task() {
-        const BROKER_ID: &str = "broker";
-        BrokerId::set_broker_id(BROKER_ID.into());
-        let broker = BrokerId::new(BROKER_ID).unwrap();
-        let proxy = ProxyId::random(&broker);
-        let app1: AppOrProxyId = AppId::new(&format!("app1.{}", proxy)).unwrap().into();
-        let app2: AppOrProxyId = AppId::new(&format!("app2.{}", proxy)).unwrap().into();
-        let task = MsgTaskRequest::new(
-            app1.clone(),
-            vec![app2.clone()],
-            "Important task".into(),
-            shared::FailureStrategy::Retry {
-                backoff_millisecs: 1000,
-                max_tries: 5,
+    use super::{check_recipient_count, is_claimed_by_other, try_claim, MsgFilterForTask, MsgFilterMode, MsgFilterNoTask, MsgFilterTrait, TaskSummary, TimeWindow, Unanswered};
+    use shared::test_utils::{app, encrypted, result, task};
+
+    fn add_result(task: &mut EncryptedMsgTaskRequest, from: &AppOrProxyId, status: WorkStatus) {
+        let result = result(task.id, from, &task.from, status);
+        task.results.insert(from.clone(), MsgSigned { msg: result, jwt: "Certainly valid".into() });
+    }
+
+    fn filter<'a>(from: Option<&AppOrProxyId>, to: Option<&AppOrProxyId>, unanswered: Unanswered<'a>) -> MsgFilterForTask<'a> {
+        MsgFilterForTask {
+            normal: MsgFilterNoTask {
+                from: from.cloned(),
+                to: to.cloned(),
+                mode: MsgFilterMode::Or,
             },
-            Value::Null,
-        );
-        let result_by_app2 = MsgTaskResult {
-            from: app2.clone(),
-            to: vec![task.get_from().clone()],
-            task: *task.id(),
-            status: WorkStatus::TempFailed,
-            metadata: Value::Null,
-            body: Some("I'd like to retry, please re-send this task".into()),
-        };
-        let result_by_app2 = MsgSigned {
-            msg: result_by_app2,
-            sig: "Certainly valid".into(),
-        };
-        let mut task = MsgSigned {
-            msg: task,
-            sig: "Certainly valid".into(),
-        };
-        // let a = app1.clone();
-        let filter = MsgFilterNoTask {
-            from: None,
-            to: Some(&app2),
-            mode: MsgFilterMode::Or,
-        };
-        let filter = MsgFilterForTask {
-            normal: filter,
-            unanswered_by: Some(&app2),
+            exclude_complete: matches!(unanswered, Unanswered::By(_)),
+            unanswered,
             workstatus_is_not: [WorkStatus::Succeeded, WorkStatus::PermFailed]
                 .iter()
                 .map(std::mem::discriminant)
                 .collect(),
-        };
-        assert_eq!(
+        }
+    }
+
+    #[test]
+    fn filter_task() {
+        let app1 = app("app1");
+        let app2 = app("app2");
+        let mut task = task(&app1, vec![app2.clone()]);
+        let filter = filter(None, Some(&app2), Unanswered::By(&app2));
+        assert!(
             filter.matches(&task),
-            true,
             "There are no results yet, so I should get the task: {:?}",
             task
         );
-        task.msg
-            .results
-            .insert(result_by_app2.get_from().clone(), result_by_app2);
-        assert_eq!(
+        add_result(&mut task, &app2, WorkStatus::TempFailed);
+        assert!(
             filter.matches(&task),
-            true,
             "The only result is TempFailed, so I should still get it: {:?}",
             task
         );
 
-        let result_by_app2 = task.msg.results.get_mut(&app2).unwrap();
+        let result_by_app2 = task.results.get_mut(&app2).unwrap();
         result_by_app2.msg.status = WorkStatus::Succeeded;
+        assert!(!filter.matches(&task), "It's done, so I shouldn't get it");
+    }
+
+    #[test]
+    fn completion_policies() {
+        let app1 = app("app1");
+        let app2 = app("app2");
+        let app3 = app("app3");
+        let mut all = task(&app1, vec![app2.clone(), app3.clone()]);
+        all.completion_policy = Some(CompletionPolicy::All);
+        let mut any = all.clone();
+        any.completion_policy = Some(CompletionPolicy::Any);
+        let mut none = all.clone();
+        none.completion_policy = None;
+        let todo = filter(None, Some(&app3), Unanswered::By(&app3));
+
+        for task in [&mut all, &mut any, &mut none] {
+            add_result(task, &app2, WorkStatus::PermFailed);
+            add_result(task, &app3, WorkStatus::Claimed);
+        }
+        assert!(!all.is_complete());
+        assert!(!any.is_complete(), "Nobody has succeeded yet");
+        assert!(todo.matches(&any));
+
+        for task in [&mut all, &mut any, &mut none] {
+            add_result(task, &app2, WorkStatus::Succeeded);
+        }
+        assert!(!all.is_complete(), "app3 has not succeeded yet");
+        assert!(any.is_complete());
+        assert!(!none.is_complete(), "Tasks without a policy are never complete");
+        assert!(todo.matches(&all));
+        assert!(!todo.matches(&any), "Complete tasks are not todo");
+        assert!(todo.matches(&none));
+
+        for task in [&mut all, &mut any, &mut none] {
+            add_result(task, &app3, WorkStatus::Succeeded);
+        }
+        assert!(all.is_complete());
+        assert!(any.is_complete());
+        assert!(!none.is_complete());
+    }
+
+    #[test]
+    fn filter_modes() {
+        let app1 = app("app1");
+        let app2 = app("app2");
+        let app3 = app("app3");
+        let task_1_to_2 = task(&app1, vec![app2.clone()]);
+        let task_1_to_3 = task(&app1, vec![app3.clone()]);
+        let task_3_to_2 = task(&app3, vec![app2.clone()]);
+        let filter = |mode| MsgFilterNoTask {
+            from: Some(app1.clone()),
+            to: Some(app2.clone()),
+            mode,
+        };
+
+        let or = filter(MsgFilterMode::Or);
+        assert!(or.matches(&task_1_to_2));
+        assert!(or.matches(&task_1_to_3));
+        assert!(or.matches(&task_3_to_2));
+
+        let and = filter(MsgFilterMode::And);
+        assert!(and.matches(&task_1_to_2));
+        assert!(!and.matches(&task_1_to_3));
+        assert!(!and.matches(&task_3_to_2));
+    }
+
+    #[test]
+    fn filter_none_and_not_all_answered() {
+        let app1 = app("app1");
+        let app2 = app("app2");
+        let app3 = app("app3");
+        let mut task = task(&app1, vec![app2.clone(), app3.clone()]);
+        let none_answered = filter(Some(&app1), None, Unanswered::ByAll);
+        let not_all_answered = filter(Some(&app1), None, Unanswered::ByAny);
+        assert!(none_answered.matches(&task));
+        assert!(not_all_answered.matches(&task));
+
+        add_result(&mut task, &app2, WorkStatus::TempFailed);
+        assert!(none_answered.matches(&task), "TempFailed does not count as an answer");
+        assert!(not_all_answered.matches(&task));
+
+        add_result(&mut task, &app2, WorkStatus::Succeeded);
+        assert!(!none_answered.matches(&task), "app2 has answered");
+        assert!(not_all_answered.matches(&task), "app3 has not answered yet");
+
+        add_result(&mut task, &app3, WorkStatus::PermFailed);
+        assert!(!none_answered.matches(&task));
+        assert!(!not_all_answered.matches(&task), "Everyone has answered");
+    }
+
+    #[test]
+    fn recipient_limit() {
+        let to: Vec<_> = (0..3).map(|i| app(&format!("app{i}"))).collect();
+        let task = task(&app("sender"), to);
+        assert!(check_recipient_count(&task, 3).is_ok());
+        assert_eq!(check_recipient_count(&task, 2).unwrap_err().0, axum::http::StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn claims() {
+        let claims = LazyExpireMap::default();
+        let (worker1, worker2) = (app("worker1"), app("worker2"));
+        let task_id = MsgId::new();
+        assert!(try_claim(&claims, task_id, &worker1, Duration::from_secs(60)).is_ok());
+        assert!(is_claimed_by_other(&claims, &task_id, &worker2));
+        assert!(!is_claimed_by_other(&claims, &task_id, &worker1));
+        // Double claims
+        assert_eq!(try_claim(&claims, task_id, &worker2, Duration::from_secs(60)).unwrap_err().0, axum::http::StatusCode::CONFLICT);
+        assert!(try_claim(&claims, task_id, &worker1, Duration::from_secs(60)).is_ok());
+        // Other tasks are unaffected
+        assert!(try_claim(&claims, MsgId::new(), &worker2, Duration::from_secs(60)).is_ok());
+    }
+
+    #[tokio::test]
+    async fn claim_lease_expiry() {
+        let claims = LazyExpireMap::default();
+        let (worker1, worker2) = (app("worker1"), app("worker2"));
+        let task_id = MsgId::new();
+        try_claim(&claims, task_id, &worker1, Duration::from_millis(50)).unwrap();
+        assert!(try_claim(&claims, task_id, &worker2, Duration::from_secs(60)).is_err());
+        tokio::time::sleep(Duration::from_millis(60)).await;
+        assert!(!is_claimed_by_other(&claims, &task_id, &worker2));
+        assert!(try_claim(&claims, task_id, &worker2, Duration::from_secs(60)).is_ok());
+        assert!(is_claimed_by_other(&claims, &task_id, &worker1));
+    }
+
+    #[test]
+    fn task_summary() {
+        let (creator, succeeded, failed, retrying, working, silent) = (app("app1"), app("app2"), app("app3"), app("app4"), app("app5"), app("app6"));
+        let mut task = task(&creator, vec![succeeded.clone(), failed.clone(), retrying.clone(), working.clone(), silent]);
+        add_result(&mut task, &succeeded, WorkStatus::Succeeded);
+        add_result(&mut task, &failed, WorkStatus::PermFailed);
+        add_result(&mut task, &retrying, WorkStatus::TempFailed);
+        add_result(&mut task, &working, WorkStatus::Claimed);
+        assert_eq!(TaskSummary::from(&task), TaskSummary { succeeded: 1, failed: 1, pending: 3, ..Default::default() });
         assert_eq!(
-            filter.matches(&task),
-            false,
-            "It's done, so I shouldn't get it"
+            serde_json::to_value(TaskSummary::from(&task)).unwrap(),
+            serde_json::json!({"succeeded": 1, "failed": 1, "pending": 3})
         );
     }
+
+    #[tokio::test]
+    async fn long_polling_roundtrip() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let (creator, worker) = (app("app1"), app("app2"));
+        let waiting_worker = {
+            let (broker, worker) = (broker.clone(), worker.clone());
+            tokio::spawn(async move { broker.get_todo_tasks(&worker, block(Some(1), Some(Duration::from_secs(5)))).await })
+        };
+        broker.until_open_streams(&worker, 1).await;
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        assert_eq!(waiting_worker.await.unwrap(), [task_id]);
+
+        let waiting_creator = {
+            let (broker, creator) = (broker.clone(), creator.clone());
+            tokio::spawn(async move { broker.get_results(task_id, &creator, block(Some(1), Some(Duration::from_secs(5)))).await })
+        };
+        assert_eq!(broker.get_results(task_id, &worker, block(None, None)).await.0, StatusCode::UNAUTHORIZED);
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Succeeded).await, StatusCode::CREATED);
+        assert_eq!(waiting_creator.await.unwrap(), (StatusCode::OK, 1));
+        // Answered tasks are no longer todo
+        assert!(broker.get_todo_tasks(&worker, block(None, None)).await.is_empty());
+        assert_eq!(broker.put_result(task_id, &creator, &creator, WorkStatus::Succeeded).await, StatusCode::UNAUTHORIZED);
+    }
+
+    #[tokio::test]
+    async fn resume_result_stream() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let (creator, worker1, worker2, worker3) = (app("app1"), app("app2"), app("app3"), app("app4"));
+        let task_id = broker.post_task(&creator, vec![worker1.clone(), worker2.clone(), worker3.clone()]).await;
+        broker.put_result(task_id, &worker1, &creator, WorkStatus::Claimed).await;
+        broker.put_result(task_id, &worker2, &creator, WorkStatus::Succeeded).await;
+        assert_eq!(broker.stream_result_ids(task_id, &creator, block(None, None), None).await, [1, 2]);
+
+        // The client disconnects after the second result and misses an update
+        broker.put_result(task_id, &worker1, &creator, WorkStatus::Succeeded).await;
+        assert_eq!(broker.stream_result_ids(task_id, &creator, block(None, None), Some(2)).await, [3]);
+
+        // Results received before reconnecting count towards wait_count
+        let waiting = {
+            let (broker, creator) = (broker.clone(), creator.clone());
+            tokio::spawn(async move { broker.stream_result_ids(task_id, &creator, block(Some(3), Some(Duration::from_secs(5))), Some(3)).await })
+        };
+        broker.until_open_streams(&creator, 1).await;
+        assert!(!waiting.is_finished());
+        broker.put_result(task_id, &worker3, &creator, WorkStatus::Succeeded).await;
+        assert_eq!(waiting.await.unwrap(), [4]);
+    }
+
+    #[tokio::test]
+    async fn result_stream_completes() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let (creator, worker1, worker2) = (app("app1"), app("app2"), app("app3"));
+        let retry = FailureStrategy::Retry { backoff_millisecs: 1000, max_tries: 5 };
+        let task_id = broker.post_task_with(&creator, vec![worker1.clone(), worker2.clone()], |task| task.failure_strategy = retry).await;
+        let event_types = |body: String| body.lines().filter_map(|line| line.strip_prefix("event:")).map(|event| event.trim().to_string()).collect::<Vec<_>>();
+
+        let waiting = {
+            let (broker, creator) = (broker.clone(), creator.clone());
+            tokio::spawn(async move { broker.stream_results(task_id, &creator, block(None, Some(Duration::from_secs(5))), None).await })
+        };
+        broker.until_open_streams(&creator, 1).await;
+        broker.put_result(task_id, &worker1, &creator, WorkStatus::Succeeded).await;
+        broker.put_result(task_id, &worker2, &creator, WorkStatus::Claimed).await;
+        broker.put_result(task_id, &worker2, &creator, WorkStatus::TempFailed).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished(), "Claimed and temporarily failed results are not terminal");
+        broker.put_result(task_id, &worker2, &creator, WorkStatus::PermFailed).await;
+        let events = tokio::time::timeout(Duration::from_secs(1), waiting).await.expect("Stream ends once complete").unwrap();
+        assert_eq!(event_types(events), ["new_result"; 4].into_iter().chain(["complete"]).collect::<Vec<_>>());
+
+        // Streams of complete tasks replay the results and end right away
+        let events = broker.stream_results(task_id, &creator, block(None, Some(Duration::from_secs(5))), None).await;
+        assert_eq!(event_types(events), ["new_result", "new_result", "complete"]);
+    }
+
+    #[tokio::test]
+    async fn first_result_never_replaces_result() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let (creator, worker) = (app("app1"), app("app2"));
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        let token = TestBroker::sign_result(task_id, &worker, &creator, WorkStatus::Succeeded).await;
+        assert_eq!(broker.put_signed_result(task_id, &worker, token.clone()).await, StatusCode::CREATED);
+        assert_eq!(broker.put_first_result(task_id, &worker, &creator, WorkStatus::PermFailed).await, StatusCode::PRECONDITION_FAILED);
+        let results = broker.request_results(task_id, &creator, block(None, None), "application/json").await.text().await.unwrap();
+        assert!(results.contains(&token), "The worker's result is kept");
+
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        assert_eq!(broker.put_first_result(task_id, &worker, &creator, WorkStatus::Succeeded).await, StatusCode::CREATED);
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::PermFailed).await, StatusCode::NO_CONTENT, "A worker may still replace it");
+    }
+
+    #[tokio::test]
+    async fn result_status_must_fit_failure_strategy() {
+        use super::test_support::TestBroker;
+
+        let broker = TestBroker::new().await;
+        let (creator, worker) = (app("app1"), app("app2"));
+        let retry = FailureStrategy::Retry { backoff_millisecs: 1000, max_tries: 5 };
+        for strategy in [FailureStrategy::Discard, retry] {
+            for status in [WorkStatus::Claimed, WorkStatus::Succeeded, WorkStatus::TempFailed, WorkStatus::PermFailed] {
+                let task_id = broker.post_task_with(&creator, vec![worker.clone()], |task| task.failure_strategy = strategy.clone()).await;
+                let expected = if strategy == FailureStrategy::Discard && status == WorkStatus::TempFailed {
+                    StatusCode::UNPROCESSABLE_ENTITY
+                } else {
+                    StatusCode::CREATED
+                };
+                assert_eq!(broker.put_result(task_id, &worker, &creator, status).await, expected, "{strategy:?} {status:?}");
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn retries_above_ceilings_clamped() {
+        use super::test_support::TestBroker;
+
+        let retry = |backoff_millisecs, max_tries| FailureStrategy::Retry { backoff_millisecs, max_tries };
+        let (creator, worker) = (app("app1"), app("app2"));
+        let broker = TestBroker::with_config(|config| {
+            config.max_retry_tries = 100;
+            config.max_retry_backoff_millisecs = 60_000;
+        }).await;
+        let applied = |strategy| async {
+            let task_id = broker.post_task_with(&creator, vec![worker.clone()], |task| task.failure_strategy = strategy).await;
+            broker.failure_strategy(task_id)
+        };
+        assert_eq!(applied(retry(60_000, 100)).await, retry(60_000, 100));
+        assert_eq!(applied(retry(1000, 1_000_000)).await, retry(1000, 100));
+        assert_eq!(applied(retry(u32::MAX as usize, 5)).await, retry(60_000, 5));
+        assert_eq!(applied(FailureStrategy::Discard).await, FailureStrategy::Discard);
+
+        let unlimited = TestBroker::new().await;
+        let task_id = unlimited.post_task_with(&creator, vec![worker.clone()], |task| task.failure_strategy = retry(u32::MAX as usize, 1_000_000)).await;
+        assert_eq!(unlimited.failure_strategy(task_id), retry(u32::MAX as usize, 1_000_000));
+    }
+
+    #[tokio::test]
+    async fn task_without_recipients_expects_no_results() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let creator = app("app1");
+        let task_id = broker.post_task(&creator, vec![]).await;
+        let waiting = block(Some(3), Some(Duration::from_secs(10)));
+
+        let results = tokio::time::timeout(Duration::from_secs(1), broker.get_results(task_id, &creator, waiting)).await;
+        assert_eq!(results.expect("Returns right away"), (StatusCode::OK, 0));
+        let events = tokio::time::timeout(Duration::from_secs(1), broker.stream_results(task_id, &creator, waiting, None)).await;
+        assert!(events.expect("Ends right away").contains("event: complete"));
+    }
+
+    #[tokio::test]
+    async fn retries_keep_lineage() {
+        use super::test_support::TestBroker;
+
+        let broker = TestBroker::new().await;
+        let (creator, worker, other) = (app("app1"), app("app2"), app("app3"));
+        let first = broker.post_task(&creator, vec![worker.clone()]).await;
+        broker.put_result(first, &worker, &creator, WorkStatus::PermFailed).await;
+        let (second, status) = broker.post_retry(&creator, vec![worker.clone()], first).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let (third, status) = broker.post_retry(&creator, vec![worker.clone()], second).await;
+        assert_eq!(status, StatusCode::CREATED);
+
+        let attempts = serde_json::json!([first, second, third]);
+        let summary = broker.get_summary(third, &creator).await;
+        assert_eq!(summary["parent_task"], second.to_string());
+        assert_eq!(summary["attempts"], attempts);
+        let summary = broker.get_summary(first, &creator).await;
+        assert!(summary.get("parent_task").is_none());
+        assert_eq!(summary["attempts"], attempts, "The first attempt knows its retries");
+        assert!(broker.get_summary(broker.post_task(&creator, vec![worker.clone()]).await, &creator).await.get("attempts").is_none());
+
+        assert_eq!(broker.post_retry(&other, vec![worker.clone()], first).await.1, StatusCode::FORBIDDEN, "Only the creator may retry a task");
+    }
+
+    #[tokio::test]
+    async fn result_stream_ndjson() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let (creator, worker1, worker2) = (app("app1"), app("app2"), app("app3"));
+        let task_id = broker.post_task(&creator, vec![worker1.clone(), worker2.clone()]).await;
+        broker.put_result(task_id, &worker1, &creator, WorkStatus::Succeeded).await;
+
+        let waiting = {
+            let (broker, creator) = (broker.clone(), creator.clone());
+            tokio::spawn(async move { broker.stream_results_ndjson(task_id, &creator, block(None, Some(Duration::from_secs(5)))).await })
+        };
+        broker.until_open_streams(&creator, 1).await;
+        broker.put_result(task_id, &worker2, &creator, WorkStatus::Claimed).await;
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(!waiting.is_finished());
+        broker.put_result(task_id, &worker2, &creator, WorkStatus::Succeeded).await;
+        let lines = tokio::time::timeout(Duration::from_secs(1), waiting).await.expect("Stream ends once complete").unwrap();
+        // The existing result is replayed before the new ones, with no line for the end of the stream
+        assert_eq!(lines.len(), 3);
+        for line in lines {
+            let result = MsgSigned::<EncryptedMsgTaskResult>::verify(line["jwt"].as_str().unwrap()).await.unwrap();
+            assert_eq!(result.msg.task, task_id);
+        }
+
+        // Waiting in vain ends the stream without a line
+        let task_id = broker.post_task(&creator, vec![worker1.clone()]).await;
+        assert!(broker.stream_results_ndjson(task_id, &creator, block(None, Some(Duration::from_millis(50)))).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn stable_result_replay() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let creator = app("app1");
+        let workers: Vec<_> = (2..10).map(|i| app(&format!("app{i}"))).collect();
+        let task_id = broker.post_task(&creator, workers.clone()).await;
+        for worker in workers.iter().rev() {
+            broker.put_result(task_id, worker, &creator, WorkStatus::Succeeded).await;
+        }
+        // Updating a result moves it to the end
+        broker.put_result(task_id, workers.last().unwrap(), &creator, WorkStatus::PermFailed).await;
+        let replay = broker.stream_result_ids(task_id, &creator, block(None, None), None).await;
+        assert_eq!(replay, (2..=9).collect::<Vec<_>>());
+        assert_eq!(broker.stream_result_ids(task_id, &creator, block(None, None), None).await, replay);
+    }
+
+    #[tokio::test]
+    async fn result_size_limit() {
+        use super::test_support::{block, TestBroker};
+
+        let (creator, worker) = (app("app1"), app("app2"));
+        let task_id = MsgId::new();
+        let signed = TestBroker::sign_result(task_id, &worker, &creator, WorkStatus::Succeeded).await;
+        let broker = TestBroker::with_config(|config| config.max_result_size = signed.len() - 1).await;
+        broker.try_post_task_with(&creator, vec![worker.clone()], |task| task.id = task_id).await;
+        assert_eq!(broker.put_signed_result(task_id, &worker, signed.clone()).await, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(broker.get_results(task_id, &creator, block(None, None)).await, (StatusCode::OK, 0));
+
+        let broker = TestBroker::with_config(|config| config.max_result_size = signed.len()).await;
+        broker.try_post_task_with(&creator, vec![worker.clone()], |task| task.id = task_id).await;
+        assert_eq!(broker.put_signed_result(task_id, &worker, signed).await, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn delivery_receipts() {
+        use super::test_support::{block, TestBroker};
+
+        let (creator, worker1, worker2) = (app("app1"), app("app2"), app("app3"));
+        let broker = TestBroker::new().await;
+        let task_id = broker.post_task(&creator, vec![worker1.clone()]).await;
+        broker.get_todo_tasks(&worker1, block(None, None)).await;
+        assert!(broker.get_summary(task_id, &creator).await.get("delivered").is_none());
+
+        let broker = TestBroker::with_config(|config| config.delivery_receipts = true).await;
+        let task_id = broker.post_task(&creator, vec![worker1.clone(), worker2.clone()]).await;
+        assert_eq!(broker.get_summary(task_id, &creator).await["delivered"], 0);
+        // Only recipients fetching the task count as delivered
+        assert_eq!(broker.get_results(task_id, &creator, block(None, None)).await.0, StatusCode::OK);
+        assert_eq!(broker.get_todo_tasks(&worker1, block(None, None)).await, [task_id]);
+        assert_eq!(broker.get_summary(task_id, &creator).await["delivered"], 1);
+        // Repeated fetches are only counted once
+        broker.get_todo_tasks(&worker1, block(None, None)).await;
+        assert_eq!(broker.get_summary(task_id, &creator).await["delivered"], 1);
+        broker.get_todo_tasks(&worker2, block(None, None)).await;
+        assert_eq!(broker.get_summary(task_id, &creator).await, serde_json::json!({"succeeded": 0, "failed": 0, "pending": 2, "delivered": 2}));
+    }
+
+    #[tokio::test]
+    async fn peek_does_not_deliver() {
+        use super::test_support::{block, TestBroker};
+
+        let (creator, worker) = (app("app1"), app("app2"));
+        let broker = TestBroker::with_config(|config| config.delivery_receipts = true).await;
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        assert_eq!(broker.peek_todo_tasks(&worker).await, [task_id]);
+        assert_eq!(broker.get_summary(task_id, &creator).await["delivered"], 0);
+        assert_eq!(broker.get_todo_tasks(&worker, block(None, None)).await, [task_id]);
+        assert_eq!(broker.get_summary(task_id, &creator).await["delivered"], 1);
+    }
+
+    #[tokio::test]
+    async fn acknowledge_results() {
+        use super::test_support::{block, TestBroker};
+
+        let (creator, worker, other) = (app("app1"), app("app2"), app("app3"));
+        let broker = TestBroker::new().await;
+        let task_id = broker.post_task(&creator, vec![worker.clone(), other.clone()]).await;
+        assert_eq!(broker.acknowledge(task_id, &worker, &creator).await, StatusCode::NOT_FOUND, "There is no result yet");
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Succeeded).await, StatusCode::CREATED);
+        assert_eq!(broker.get_acknowledgement(task_id, &worker, &worker, block(None, None)).await, (StatusCode::OK, false));
+        assert_eq!(broker.get_acknowledgement(task_id, &worker, &other, block(None, None)).await.0, StatusCode::UNAUTHORIZED);
+
+        let waiter = {
+            let broker = broker.clone();
+            let worker = worker.clone();
+            tokio::spawn(async move { broker.get_acknowledgement(task_id, &worker, &worker, block(None, Some(Duration::from_secs(5)))).await })
+        };
+        broker.until_open_streams(&worker, 1).await;
+        assert_eq!(broker.acknowledge(task_id, &worker, &worker).await, StatusCode::UNAUTHORIZED, "Only the creator may acknowledge");
+        assert_eq!(broker.acknowledge(task_id, &worker, &creator).await, StatusCode::NO_CONTENT);
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap(), (StatusCode::OK, true));
+        assert_eq!(broker.acknowledge(task_id, &worker, &creator).await, StatusCode::NO_CONTENT, "Acknowledging is idempotent");
+
+        let waiter = {
+            let broker = broker.clone();
+            let other = other.clone();
+            tokio::spawn(async move { broker.get_acknowledgement(task_id, &other, &other, block(None, Some(Duration::from_secs(5)))).await })
+        };
+        broker.until_open_streams(&other, 1).await;
+        broker.purge(Some(&creator), None).unwrap();
+        assert_eq!(tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap().0, StatusCode::GONE, "Removing the task ends the wait");
+        assert_eq!(broker.get_acknowledgement(task_id, &worker, &worker, block(None, None)).await.0, StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn explain_filtered_out_task() {
+        use super::test_support::TestBroker;
+        use super::FilterExplanation;
+
+        let (creator, worker, other) = (app("app1"), app("app2"), app("app3"));
+        let broker = TestBroker::new().await;
+        let task_id = broker.post_task(&creator, vec![worker.clone(), other.clone()]).await;
+        broker.post_task(&creator, vec![other.clone()]).await;
+        broker.claim(task_id, &other);
+        let explanation = FilterExplanation {
+            task_id,
+            listed: false,
+            addressed: true,
+            not_complete: Some(true),
+            unanswered: Some(true),
+            not_claimed_by_other: Some(false),
+            in_time_window: None,
+            not_deleted: true,
+        };
+        assert_eq!(broker.explain(&worker, "filter=todo", None), [explanation], "Only the tasks of the app are explained");
+
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Succeeded).await, StatusCode::CREATED);
+        let [explanation] = &broker.explain(&worker, "filter=todo&since=2100-01-01T00:00:00Z", Some(task_id))[..] else {
+            panic!("Expected an explanation of the task");
+        };
+        assert!(!explanation.listed);
+        assert_eq!(explanation.unanswered, Some(false));
+        assert_eq!(explanation.in_time_window, Some(false));
+
+        let [explanation] = &broker.explain(&creator, "from=app1.proxy1.broker.samply.de", Some(task_id))[..] else {
+            panic!("Expected an explanation of the task");
+        };
+        assert!(explanation.listed && explanation.addressed);
+        assert_eq!((explanation.unanswered, explanation.not_claimed_by_other), (None, None), "The conditions don't apply to this listing");
+    }
+
+    #[tokio::test]
+    async fn admin_purge() {
+        use super::test_support::{block, TestBroker};
+
+        let (creator, abuser, worker) = (app("app1"), app("app2"), app("app3"));
+        let broker = TestBroker::new().await;
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        let abusive = [broker.post_task(&abuser, vec![worker.clone()]).await, broker.post_task(&abuser, vec![creator.clone()]).await];
+        let waiter = {
+            let broker = broker.clone();
+            let abuser = abuser.clone();
+            tokio::spawn(async move { broker.get_results(abusive[0], &abuser, block(Some(1), Some(Duration::from_secs(10)))).await })
+        };
+        broker.until_open_streams(&abuser, 1).await;
+
+        assert_eq!(broker.purge(None, None), Err(StatusCode::BAD_REQUEST));
+        let mut purged = broker.purge(Some(&abuser), None).unwrap();
+        purged.sort();
+        let mut expected = abusive.to_vec();
+        expected.sort();
+        assert_eq!(purged, expected);
+        // Clients waiting for results of a purged task are released
+
+        let (code, _) = tokio::time::timeout(Duration::from_secs(1), waiter).await.unwrap().unwrap();
+        assert_eq!(code, StatusCode::GONE);
+        assert_eq!(broker.get_todo_tasks(&worker, block(None, None)).await, [task_id]);
+        assert_eq!(broker.purge(None, Some(&worker)).unwrap(), [task_id]);
+    }
+
+    #[tokio::test]
+    async fn audit_keeps_overwritten_results() {
+        use super::test_support::TestBroker;
+
+        let (creator, worker) = (app("app1"), app("app2"));
+        let broker = TestBroker::new().await;
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Claimed).await, StatusCode::CREATED);
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Succeeded).await, StatusCode::NO_CONTENT);
+        // Rejected results are not recorded
+        assert_eq!(broker.put_result(task_id, &creator, &creator, WorkStatus::Succeeded).await, StatusCode::UNAUTHORIZED);
+
+        let entries = broker.audit(task_id);
+        assert_eq!(entries.iter().map(|entry| entry.status).collect::<Vec<_>>(), [WorkStatus::Claimed, WorkStatus::Succeeded]);
+        assert!(entries.iter().all(|entry| entry.worker == worker && entry.task == task_id));
+        assert!(broker.audit(MsgId::new()).is_empty());
+    }
+
+    #[tokio::test]
+    async fn offloaded_tasks() {
+        use super::test_support::{block, TestBroker};
+        use super::{FsBlobStore, OffloadedTasks};
+
+        let (creator, worker) = (app("app1"), app("app2"));
+        let dir = std::env::temp_dir().join(format!("beam-blobs-{}", MsgId::new()));
+        let broker = TestBroker::with_config(|config| config.offloaded = Some(OffloadedTasks::new(FsBlobStore::new(dir.clone()).unwrap(), 0))).await;
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        assert!(!broker.in_memory(&task_id));
+        let blob = std::fs::read_to_string(dir.join(task_id.to_string())).unwrap();
+        assert_eq!(broker.get_todo_tasks(&worker, block(None, None)).await, [task_id]);
+        // A conflicting task with the same id leaves the stored task alone
+        let (_, status) = broker.try_post_task_with(&creator, vec![worker.clone()], |task| {
+            task.id = task_id;
+            task.metadata = "conflicting".into();
+        }).await;
+        assert_eq!(status, StatusCode::CONFLICT);
+        assert_eq!(std::fs::read_to_string(dir.join(task_id.to_string())).unwrap(), blob);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn mismatched_from_rejected_by_every_endpoint() {
+        use axum::http::Method;
+        use shared::{test_utils::{result, task}, EncryptedMessage, MsgEmpty};
+
+        use super::test_support::TestBroker;
+        use super::Attachments;
+
+        /// Only the proxy of the sender, proxy1, may sign its messages, not a proxy whose id is a suffix of it or another proxy
+        async fn assert_only_sender_proxy(broker: &TestBroker, method: Method, path: &str, msg: impl Fn() -> EncryptedMessage, accepted: StatusCode) {
+            for forger in ["oxy1", "proxy2"] {
+                let res = broker.send_signed_by(method.clone(), path, msg(), forger).await;
+                assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{method} {path} rejects messages signed by {forger}");
+                assert!(res.text().await.unwrap().contains("does not match your certificate"));
+            }
+            let res = broker.send_signed_by(method.clone(), path, msg(), "proxy1").await;
+            assert_eq!(res.status(), accepted, "{method} {path} accepts messages signed by the sender's proxy");
+        }
+
+        let dir = std::env::temp_dir().join(format!("beam-blobs-{}", MsgId::new()));
+        let broker = TestBroker::with_config(|config| config.attachments = Some(Attachments::new(dir.join("attachments")).unwrap())).await;
+        let (creator, worker) = (app("app1"), app("app2"));
+        let empty = |from: &AppOrProxyId| { let from = from.clone(); move || EncryptedMessage::MsgEmpty(MsgEmpty { from: from.clone() }) };
+        let task = task(&creator, vec![worker.clone()]);
+        let task_id = task.id;
+        let result = || EncryptedMessage::MsgTaskResult(result(task_id, &worker, &creator, WorkStatus::Succeeded));
+
+        assert_only_sender_proxy(&broker, Method::GET, &format!("/v1/tasks?from={creator}"), empty(&creator), StatusCode::OK).await;
+        // Forged tasks are not created, so the task is new to the broker once its creator's proxy signs it
+        assert_only_sender_proxy(&broker, Method::POST, "/v1/tasks", || EncryptedMessage::MsgTaskRequest(task.clone()), StatusCode::CREATED).await;
+        assert_only_sender_proxy(&broker, Method::GET, &format!("/v1/tasks/{task_id}/results"), empty(&creator), StatusCode::OK).await;
+        assert_only_sender_proxy(&broker, Method::PUT, &format!("/v1/tasks/{task_id}/results/{worker}"), result, StatusCode::CREATED).await;
+        assert_only_sender_proxy(&broker, Method::PUT, &format!("/v1/tasks/{task_id}/attachments"), result, StatusCode::CREATED).await;
+        #[cfg(feature = "sockets")]
+        {
+            let socket_request = shared::test_utils::socket_request(&creator, vec![worker.clone()]);
+            let socket_id = socket_request.id;
+            assert_only_sender_proxy(&broker, Method::POST, "/v1/sockets", || EncryptedMessage::MsgSocketRequest(socket_request.clone()), StatusCode::CREATED).await;
+            assert_only_sender_proxy(&broker, Method::DELETE, &format!("/v1/sockets/{socket_id}"), empty(&creator), StatusCode::NOT_FOUND).await;
+        }
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn compressed_tasks() {
+        use super::test_support::{block, TestBroker};
+        use super::{CompressedBlobStore, OffloadedTasks};
+
+        let (creator, worker) = (app("app1"), app("app2"));
+        let broker = TestBroker::with_config(|config| config.offloaded = Some(OffloadedTasks::new(CompressedBlobStore::default(), 0))).await;
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        assert!(!broker.in_memory(&task_id));
+        // The signature of the task still verifies once it is decompressed
+        assert_eq!(broker.get_todo_tasks(&worker, block(None, None)).await, [task_id]);
+    }
+
+    #[tokio::test]
+    async fn result_attachments() {
+        use super::test_support::TestBroker;
+        use super::Attachments;
+
+        let (creator, worker, other) = (app("app1"), app("app2"), app("app3"));
+        let dir = std::env::temp_dir().join(format!("beam-blobs-{}", MsgId::new()));
+        let broker = TestBroker::with_config(|config| config.attachments = Some(Attachments::new(dir.join("attachments")).unwrap())).await;
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        let artifact = TestBroker::sign_result(task_id, &worker, &creator, WorkStatus::Succeeded).await;
+
+        let (status, location) = broker.put_attachment(task_id, &worker, &artifact).await;
+        assert_eq!(status, StatusCode::CREATED);
+        let location = location.unwrap();
+        assert_eq!(broker.put_attachment(task_id, &worker, &artifact).await.1.unwrap(), location, "Attachments are addressed by their content");
+        let forged = TestBroker::sign_result(task_id, &other, &creator, WorkStatus::Succeeded).await;
+        assert_eq!(broker.put_attachment(task_id, &other, &forged).await.0, StatusCode::UNAUTHORIZED);
+
+        assert_eq!(broker.get_attachment(&location, &creator).await.unwrap(), artifact);
+        assert_eq!(broker.get_attachment(&location, &worker).await.unwrap(), artifact);
+        assert_eq!(broker.get_attachment(&location, &other).await, Err(StatusCode::UNAUTHORIZED));
+        assert_eq!(broker.get_attachment(&format!("/v1/tasks/{task_id}/attachments/{}", "0".repeat(64)), &creator).await, Err(StatusCode::NOT_FOUND));
+        assert_eq!(broker.get_attachment(&format!("/v1/tasks/{task_id}/attachments/../{task_id}"), &creator).await, Err(StatusCode::NOT_FOUND));
+
+        // Attachments are removed along with their task
+        broker.remove_orphaned_attachments();
+        assert!(dir.join("attachments").join(task_id.to_string()).exists());
+        assert_eq!(broker.purge(Some(&creator), None).unwrap(), [task_id]);
+        broker.remove_orphaned_attachments();
+        assert!(!dir.join("attachments").join(task_id.to_string()).exists());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn attachments_limited_like_results() {
+        use super::test_support::TestBroker;
+        use super::Attachments;
+
+        let (creator, worker) = (app("app1"), app("app2"));
+        let dir = std::env::temp_dir().join(format!("beam-blobs-{}", MsgId::new()));
+        let task_id = MsgId::new();
+        let claimed = TestBroker::sign_result(task_id, &worker, &creator, WorkStatus::Claimed).await;
+        let succeeded = TestBroker::sign_result(task_id, &worker, &creator, WorkStatus::Succeeded).await;
+        let broker = TestBroker::with_config(|config| {
+            config.attachments = Some(Attachments::new(dir.clone()).unwrap());
+            config.max_result_size = succeeded.len() - 1;
+        }).await;
+        broker.try_post_task_with(&creator, vec![worker.clone()], |task| task.id = task_id).await;
+        assert_eq!(broker.put_attachment(task_id, &worker, &succeeded).await.0, StatusCode::PAYLOAD_TOO_LARGE);
+        assert_eq!(broker.put_attachment(task_id, &worker, &claimed).await.0, StatusCode::CREATED);
+
+        // While the task is being deleted, only workers that claimed it may still upload their final attachments
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Claimed).await, StatusCode::CREATED);
+        assert_eq!(broker.delete(task_id, Some(Duration::from_secs(2))), StatusCode::ACCEPTED);
+        assert_eq!(broker.put_attachment(task_id, &worker, &claimed).await.0, StatusCode::GONE);
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn deletion_grace() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let (creator, worker, other) = (app("app1"), app("app2"), app("app3"));
+        let unclaimed = broker.post_task(&creator, vec![worker.clone()]).await;
+        assert_eq!(broker.delete(unclaimed, Some(Duration::from_secs(1))), StatusCode::NO_CONTENT, "Nobody needs a grace");
+        assert_eq!(broker.delete(unclaimed, None), StatusCode::NOT_FOUND);
+
+        let task_id = broker.post_task(&creator, vec![worker.clone(), other.clone()]).await;
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Claimed).await, StatusCode::CREATED);
+        assert_eq!(broker.delete(task_id, Some(Duration::from_secs(2))), StatusCode::ACCEPTED);
+        assert!(broker.peek_todo_tasks(&other).await.is_empty(), "Tasks being deleted are not advertised");
+
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Claimed).await, StatusCode::GONE);
+        assert_eq!(broker.put_result(task_id, &other, &creator, WorkStatus::Succeeded).await, StatusCode::GONE);
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Succeeded).await, StatusCode::NO_CONTENT);
+        assert_eq!(broker.get_results(task_id, &creator, block(None, None)).await.1, 1);
+
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(broker.delete(task_id, None), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn result_after_deadline() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let (creator, worker) = (app("app1"), app("app2"));
+        let task_id = broker.post_task_with(&creator, vec![worker.clone()], |task| task.deadline = Some(SystemTime::now() + Duration::from_secs(1))).await;
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Claimed).await, StatusCode::CREATED);
+
+        tokio::time::sleep(Duration::from_millis(1200)).await;
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Succeeded).await, StatusCode::GONE);
+        assert_eq!(broker.get_results(task_id, &creator, block(None, None)).await.1, 1, "The task itself has not expired");
+
+        let task_id = broker.post_task_with(&creator, vec![worker.clone()], |task| task.deadline = Some(SystemTime::now() + Duration::from_secs(60))).await;
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Succeeded).await, StatusCode::CREATED);
+    }
+
+    #[tokio::test]
+    async fn claim_records_claimed_result() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::new().await;
+        let (creator, worker, other) = (app("app1"), app("app2"), app("app3"));
+        let task_id = broker.post_task(&creator, vec![worker.clone(), other.clone()]).await;
+        assert_eq!(broker.claim_task(task_id, &worker, &creator).await, StatusCode::NO_CONTENT);
+        assert_eq!(broker.claim_task(task_id, &other, &creator).await, StatusCode::CONFLICT);
+        assert_eq!(broker.get_results(task_id, &creator, block(None, None)).await, (StatusCode::OK, 1), "Only the successful claim is recorded");
+        assert_eq!(broker.result_status(task_id, &worker), Some(WorkStatus::Claimed));
+        assert!(broker.peek_todo_tasks(&other).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn missed_heartbeat_requeues_task() {
+        use super::test_support::TestBroker;
+
+        let broker = TestBroker::new().await;
+        let (creator, worker, other) = (app("app1"), app("app2"), app("app3"));
+        let task_id = broker.post_task(&creator, vec![worker.clone(), other.clone()]).await;
+        let timeout = Duration::from_secs(3);
+        assert!(broker.heartbeat(&worker, timeout).await.is_empty());
+        assert_eq!(broker.claim_task(task_id, &worker, &creator).await, StatusCode::NO_CONTENT);
+        assert!(broker.peek_todo_tasks(&other).await.is_empty());
+
+        // Heartbeats keep the lease alive beyond the timeout
+        tokio::time::sleep(Duration::from_secs(2)).await;
+        assert_eq!(broker.heartbeat(&worker, timeout).await, [task_id]);
+        tokio::time::sleep(Duration::from_millis(1500)).await;
+        assert!(broker.peek_todo_tasks(&other).await.is_empty());
+
+        // The worker crashed, so the task is requeued long before the default lease of 60 seconds ends
+        tokio::time::sleep(Duration::from_millis(2500)).await;
+        assert_eq!(broker.peek_todo_tasks(&other).await, [task_id]);
+        assert_eq!(broker.claim_task(task_id, &other, &creator).await, StatusCode::NO_CONTENT);
+        assert!(broker.heartbeat(&worker, timeout).await.is_empty(), "The lease was lost");
+    }
+
+    #[tokio::test]
+    async fn capability_matching() {
+        use super::test_support::TestBroker;
+
+        let broker = TestBroker::new().await;
+        let (creator, ocr, gpu_ocr, proxy) = (app("creator"), app("ocr"), app("gpu-ocr"), AppOrProxyId::new("proxy2.broker.samply.de").unwrap());
+        assert_eq!(broker.capable_workers("ocr", &creator).await, Err(StatusCode::NOT_FOUND));
+
+        assert_eq!(broker.advertise(&ocr, "ocr").await, StatusCode::NO_CONTENT);
+        assert_eq!(broker.advertise(&gpu_ocr, "gpu,ocr").await, StatusCode::NO_CONTENT);
+        assert_eq!(broker.advertise(&proxy, "gpu").await, StatusCode::NO_CONTENT);
+        assert_eq!(broker.advertise(&creator, "group:ocr").await, StatusCode::BAD_REQUEST);
+        assert_eq!(broker.capable_workers("ocr", &creator).await.unwrap(), [gpu_ocr.clone(), ocr.clone()]);
+        assert_eq!(broker.capable_workers("gpu", &creator).await.unwrap(), [gpu_ocr.clone(), proxy.clone()]);
+        assert_eq!(broker.capable_workers("oc", &creator).await, Err(StatusCode::NOT_FOUND), "Capabilities match exactly");
+
+        // A new advertisement replaces the previous one
+        assert_eq!(broker.advertise(&gpu_ocr, "gpu").await, StatusCode::NO_CONTENT);
+        assert_eq!(broker.capable_workers("ocr", &creator).await.unwrap(), [ocr.clone()].as_slice());
+        assert_eq!(broker.advertise(&ocr, "").await, StatusCode::NO_CONTENT);
+        assert_eq!(broker.capable_workers("ocr", &creator).await, Err(StatusCode::NOT_FOUND));
+    }
+
+    #[tokio::test]
+    async fn diagnostics_count_tasks() {
+        use super::test_support::TestBroker;
+
+        let broker = TestBroker::new().await;
+        let (creator, worker, other) = (app("app1"), app("app2"), app("app3"));
+        assert_eq!(broker.diagnostics()["tasks"]["tasks"], 0);
+        let task_id = broker.post_task(&creator, vec![worker.clone(), other.clone()]).await;
+        broker.post_task(&creator, vec![worker.clone()]).await;
+        assert_eq!(broker.put_result(task_id, &worker, &creator, WorkStatus::Claimed).await, StatusCode::CREATED);
+        assert_eq!(broker.put_result(task_id, &other, &creator, WorkStatus::Succeeded).await, StatusCode::CREATED);
+
+        let diagnostics = broker.diagnostics();
+        assert_eq!(diagnostics["tasks"]["tasks"], 2);
+        assert_eq!(diagnostics["tasks"]["results"], 2);
+        assert_eq!(diagnostics["being_deleted"], 0);
+        assert_eq!(diagnostics["free_long_polls"], 100);
+        assert!(diagnostics["delivery_receipts"].is_null(), "Delivery receipts are disabled");
+        assert_eq!(broker.delete(task_id, Some(Duration::from_secs(60))), StatusCode::ACCEPTED);
+        assert_eq!(broker.diagnostics()["being_deleted"], 1);
+    }
+
+    #[test]
+    fn time_window_bounds() {
+        let start = SystemTime::UNIX_EPOCH + Duration::from_secs(1_700_000_000);
+        let end = start + Duration::from_secs(60);
+        let window = TimeWindow { since: Some(start), until: Some(end) };
+        assert!(window.contains(start));
+        assert!(window.contains(end - Duration::from_millis(1)));
+        assert!(!window.contains(end));
+        assert!(!window.contains(start - Duration::from_millis(1)));
+        assert!(TimeWindow { since: None, until: Some(end) }.contains(SystemTime::UNIX_EPOCH));
+        assert!(TimeWindow { since: Some(start), until: None }.contains(end + Duration::from_secs(3600)));
+    }
+
+    #[tokio::test]
+    async fn list_tasks_in_time_window() {
+        use super::test_support::TestBroker;
+
+        let broker = TestBroker::new().await;
+        let (creator, worker) = (app("app1"), app("app2"));
+        let before = SystemTime::now() - Duration::from_secs(1);
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        let after = SystemTime::now() + Duration::from_secs(1);
+        let rfc3339 = |time| humantime::format_rfc3339_millis(time);
+        assert_eq!(broker.get_created_tasks(&creator, &format!("since={}", rfc3339(before))).await, [task_id]);
+        assert_eq!(broker.get_created_tasks(&creator, &format!("since={}&until={}", rfc3339(before), rfc3339(after))).await, [task_id]);
+        assert!(broker.get_created_tasks(&creator, &format!("since={}", rfc3339(after))).await.is_empty());
+        assert!(broker.get_created_tasks(&creator, &format!("until={}", rfc3339(before))).await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn search_tasks() {
+        use super::test_support::TestBroker;
+        use serde_json::json;
+
+        let broker = TestBroker::new().await;
+        let (creator, worker1, worker2, outsider) = (app("app1"), app("app2"), app("app3"), app("app4"));
+        let study_a = broker.post_task_with(&creator, vec![worker1.clone(), worker2.clone()], |task| task.metadata = json!({"study": "a", "priority": 1})).await;
+        let study_b = broker.post_task_with(&creator, vec![worker1.clone()], |task| task.metadata = json!({"study": "b", "priority": 1})).await;
+        let unlabeled = broker.post_task(&creator, vec![worker2.clone()]).await;
+        let by_worker = broker.post_task_with(&worker1, vec![worker2.clone()], |task| task.metadata = json!({"study": "a"})).await;
+        assert_eq!(broker.put_result(study_a, &worker1, &creator, WorkStatus::Succeeded).await, StatusCode::CREATED);
+        assert_eq!(broker.put_result(study_b, &worker1, &creator, WorkStatus::PermFailed).await, StatusCode::CREATED);
+
+        let ids = |hits: &[Value]| {
+            let mut ids: Vec<MsgId> = hits.iter().map(|hit| hit["id"].as_str().unwrap().parse().unwrap()).collect();
+            ids.sort();
+            ids
+        };
+        let sorted = |mut expected: Vec<MsgId>| {
+            expected.sort();
+            expected
+        };
+        let search = |app: &AppOrProxyId, query: &str| {
+            let (broker, app, query) = (broker.clone(), app.clone(), query.to_string());
+            async move { ids(&broker.search(&app, &query).await.unwrap().0) }
+        };
+
+        // Without from and to, tasks created by and directed to the requester are searched
+        assert_eq!(search(&creator, "").await, sorted(vec![study_a, study_b, unlabeled]));
+        assert_eq!(search(&worker1, "").await, sorted(vec![study_a, study_b, by_worker]));
+        assert!(search(&outsider, "").await.is_empty(), "Tasks of others are never found");
+        assert_eq!(broker.search(&outsider, &format!("from={creator}")).await.unwrap_err(), StatusCode::UNAUTHORIZED);
+
+        assert_eq!(search(&creator, "labels=study:a").await, [study_a]);
+        assert_eq!(search(&worker1, "labels=study:a").await, sorted(vec![study_a, by_worker]));
+        assert_eq!(search(&worker1, &format!("labels=study:a&from={worker1}")).await, [by_worker]);
+        assert_eq!(search(&creator, "labels=priority:1").await, sorted(vec![study_a, study_b]), "Numbers match their JSON text");
+        assert!(search(&creator, "labels=study:a,priority:2").await.is_empty(), "All labels have to match");
+        assert_eq!(broker.search(&creator, "labels=study").await.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        assert_eq!(search(&creator, "status=succeeded").await, [study_a]);
+        assert_eq!(search(&creator, "status=failed,succeeded").await, sorted(vec![study_a, study_b]));
+        assert_eq!(search(&creator, "status=pending").await, sorted(vec![study_a, unlabeled]), "worker2 has not answered these");
+        assert_eq!(search(&creator, "status=pending&labels=study:a").await, [study_a]);
+        assert_eq!(broker.search(&creator, "status=done").await.unwrap_err(), StatusCode::BAD_REQUEST);
+        assert_eq!(search(&worker2, "filter=todo&labels=study:a").await, sorted(vec![study_a, by_worker]));
+
+        let future = humantime::format_rfc3339_millis(SystemTime::now() + Duration::from_secs(60));
+        assert!(search(&creator, &format!("since={future}&labels=study:a")).await.is_empty());
+        assert_eq!(search(&creator, &format!("until={future}&status=failed")).await, [study_b]);
+
+        let (hits, cursor) = broker.search(&creator, "limit=2").await.unwrap();
+        assert_eq!(hits.len(), 2);
+        let (rest, last_cursor) = broker.search(&creator, &format!("limit=2&after={}", cursor.unwrap())).await.unwrap();
+        assert_eq!(last_cursor, None);
+        let mut all = ids(&hits);
+        all.extend(ids(&rest));
+        assert_eq!(all, sorted(vec![study_a, study_b, unlabeled]));
+        assert_eq!(broker.search(&creator, "limit=0").await.unwrap_err(), StatusCode::BAD_REQUEST);
+
+        let hit = &broker.search(&creator, "labels=study:a").await.unwrap().0[0];
+        assert_eq!(hit["metadata"], json!({"study": "a", "priority": 1}));
+        assert_eq!((hit["succeeded"].as_u64(), hit["failed"].as_u64(), hit["pending"].as_u64()), (Some(1), Some(0), Some(1)));
+        assert!(hit.get("body").is_none() && hit.get("results").is_none(), "Hits only carry a summary of the task");
+    }
+
+    #[tokio::test]
+    async fn stream_limit_per_app() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::with_config(|config| config.max_streams_per_app = 2).await;
+        let (creator, other, worker) = (app("app1"), app("app2"), app("app3"));
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        let wait = || block(None, Some(Duration::from_secs(5)));
+
+        let first = broker.request_results(task_id, &creator, wait(), "text/event-stream").await;
+        let second = broker.request_results(task_id, &creator, wait(), "application/x-ndjson").await;
+        assert_eq!((first.status(), second.status()), (StatusCode::OK, StatusCode::OK));
+        assert_eq!(broker.open_streams(&creator), 2);
+        assert_eq!(broker.request_results(task_id, &creator, wait(), "text/event-stream").await.status(), StatusCode::TOO_MANY_REQUESTS);
+        // Long polls share the limit while requests returning immediately are not limited
+        assert_eq!(broker.request_results(task_id, &creator, wait(), "application/json").await.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert!(broker.request_results(task_id, &creator, block(None, None), "application/json").await.status().is_success());
+        // Other apps have slots of their own
+        assert!(broker.try_get_todo_tasks(&other, block(Some(1), Some(Duration::from_millis(10)))).await.status().is_success());
+
+        // Dropping a stream, as when the client disconnects, frees its slot once the broker notices
+        drop(first);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(broker.open_streams(&creator), 1);
+        let third = broker.request_results(task_id, &creator, wait(), "text/event-stream").await;
+        assert_eq!(third.status(), StatusCode::OK);
+        drop((second, third));
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(broker.open_streams(&creator), 0);
+    }
+
+    #[tokio::test]
+    async fn long_poll_limit() {
+        use super::test_support::{block, TestBroker};
+
+        let broker = TestBroker::with_config(|config| config.max_long_polls = 1).await;
+        let (creator, worker) = (app("app1"), app("app2"));
+        let waiting_worker = {
+            let (broker, worker) = (broker.clone(), worker.clone());
+            tokio::spawn(async move { broker.get_todo_tasks(&worker, block(Some(1), Some(Duration::from_secs(5)))).await })
+        };
+        broker.until_open_streams(&worker, 1).await;
+        let rejected = broker.try_get_todo_tasks(&worker, block(Some(1), Some(Duration::from_secs(5)))).await;
+        assert_eq!(rejected.status(), StatusCode::SERVICE_UNAVAILABLE);
+        assert!(rejected.headers().contains_key(axum::http::header::RETRY_AFTER));
+        // Requests returning immediately are not limited
+        assert!(broker.get_todo_tasks(&worker, block(None, None)).await.is_empty());
+
+        let task_id = broker.post_task(&creator, vec![worker.clone()]).await;
+        assert_eq!(waiting_worker.await.unwrap(), [task_id]);
+        // The permit is released once the request returns
+        assert_eq!(broker.try_get_todo_tasks(&worker, block(Some(1), Some(Duration::from_millis(10)))).await.status(), StatusCode::OK);
+    }
 }
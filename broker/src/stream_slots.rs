@@ -0,0 +1,76 @@
+use std::sync::Arc;
+
+use axum::{http::StatusCode, response::{IntoResponse, Response}};
+use beam_lib::AppOrProxyId;
+use dashmap::DashMap;
+use futures_core::Stream;
+use tracing::warn;
+
+/// Counts the streams and long polls each app keeps open, so a single app cannot tie up the broker's resources
+#[derive(Clone, Default)]
+pub(crate) struct StreamSlots {
+    /// 0 for no limit
+    max_per_app: usize,
+    /// Only apps with open streams are kept
+    open: Arc<DashMap<AppOrProxyId, usize>>,
+}
+
+impl StreamSlots {
+    pub(crate) fn new(max_per_app: usize) -> Self {
+        Self { max_per_app, open: Default::default() }
+    }
+
+    /// Takes one of the app's slots, which is freed once the returned [`StreamSlot`] is dropped
+    pub(crate) fn acquire(&self, app: &AppOrProxyId) -> Result<StreamSlot, TooManyStreams> {
+        let mut open = self.open.entry(app.clone()).or_default();
+        if self.max_per_app != 0 && *open >= self.max_per_app {
+            warn!("Rejecting stream of {app} as it already has {} open", *open);
+            return Err(TooManyStreams);
+        }
+        *open += 1;
+        Ok(StreamSlot { app: app.clone(), open: self.open.clone() })
+    }
+
+    /// Number of streams the app has open
+    #[cfg(test)]
+    pub(crate) fn open(&self, app: &AppOrProxyId) -> usize {
+        self.open.get(app).map(|open| *open).unwrap_or_default()
+    }
+}
+
+pub(crate) struct StreamSlot {
+    app: AppOrProxyId,
+    open: Arc<DashMap<AppOrProxyId, usize>>,
+}
+
+impl StreamSlot {
+    /// Keeps the slot until the stream ends or is dropped, e.g. because the client disconnected
+    pub(crate) fn hold_during<S: Stream<Item: Send> + Send + 'static>(self, stream: S) -> impl Stream<Item = S::Item> + Send + 'static {
+        async_stream::stream! {
+            let _slot = self;
+            for await item in stream {
+                yield item;
+            }
+        }
+    }
+}
+
+impl Drop for StreamSlot {
+    fn drop(&mut self) {
+        self.open.remove_if_mut(&self.app, |_, open| {
+            *open -= 1;
+            *open == 0
+        });
+    }
+}
+
+pub(crate) struct TooManyStreams;
+
+impl IntoResponse for TooManyStreams {
+    fn into_response(self) -> Response {
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            "Too many concurrent streams and long-polling requests of this app; please close one first.",
+        ).into_response()
+    }
+}
@@ -1,30 +1,38 @@
 use std::{
     borrow::Cow,
     ops::Deref,
-    time::{Duration, SystemTime}, collections::HashMap, sync::Arc, convert::Infallible,
+    time::{Duration, SystemTime}, collections::{HashMap, HashSet}, sync::Arc, convert::Infallible,
 };
 
-use axum::{response::{IntoResponse, sse::Event, Sse}, Json, http::StatusCode};
-use dashmap::DashMap;
+use axum::{response::{IntoResponse, sse::Event, Sse}, Json, http::{header, HeaderValue, StatusCode}};
+use dashmap::{mapref::entry::Entry, DashMap};
 use futures_core::Stream;
 use once_cell::sync::Lazy;
 use serde::Serialize;
 use serde_json::json;
 use beam_lib::{AppOrProxyId, MsgEmpty, MsgId, WorkStatus};
 use shared::{
-    HasWaitId, HowLongToBlock, Msg, MsgSigned,
+    clock::{Clock, SystemClock}, HasWaitId, HowLongToBlock, Msg, MsgSigned,
     MsgState, MsgTaskRequest, MsgTaskResult, sse_event::SseEventType,
 };
-use tokio::{sync::broadcast, time::Instant};
-use tracing::{warn, error};
+use tokio::{sync::{broadcast, mpsc}, task::JoinSet, time::Instant};
+use tracing::{debug, warn, error};
+
+use crate::metrics;
 
 pub trait Task {
     type Result;
+    /// Distinguishes the type of task in metrics
+    const TYPE: &'static str;
 
     fn get_results(&self) -> &HashMap<AppOrProxyId, Self::Result>;
     /// Returns true if the value as been updated and false if it was a result from a new app
     fn insert_result(&mut self, result: Self::Result) -> bool;
-    fn is_expired(&self) -> bool;
+    fn expires_at(&self) -> SystemTime;
+    /// Returns true if a newer version of the result has already been inserted
+    fn is_outdated(&self, _result: &Self::Result) -> bool {
+        false
+    }
 }
 
 pub trait HasStatus {
@@ -33,6 +41,7 @@ pub trait HasStatus {
 
 impl<State: MsgState> Task for MsgTaskRequest<State> {
     type Result = MsgSigned<MsgTaskResult<State>>;
+    const TYPE: &'static str = "task";
 
     fn insert_result(&mut self, result: Self::Result) -> bool {
         self.results.insert(result.get_from().clone(), result).is_some()
@@ -42,8 +51,14 @@ impl<State: MsgState> Task for MsgTaskRequest<State> {
         &self.results
     }
 
-    fn is_expired(&self) -> bool {
-        self.expire < SystemTime::now()
+    fn expires_at(&self) -> SystemTime {
+        self.expire
+    }
+
+    /// Results without a sequence number always replace the stored one
+    fn is_outdated(&self, result: &Self::Result) -> bool {
+        let stored = self.results.get(result.get_from()).and_then(|stored| stored.msg.seq);
+        matches!((stored, result.msg.seq), (Some(stored), Some(new)) if new < stored)
     }
 }
 
@@ -54,6 +69,7 @@ static EMPTY_MAP: Lazy<HashMap<AppOrProxyId, ()>> = Lazy::new(|| {
 #[cfg(feature = "sockets")]
 impl<State: MsgState> Task for shared::MsgSocketRequest<State> {
     type Result = ();
+    const TYPE: &'static str = "socket";
 
     fn get_results(&self) -> &HashMap<AppOrProxyId, Self::Result> {
         &EMPTY_MAP
@@ -61,8 +77,8 @@ impl<State: MsgState> Task for shared::MsgSocketRequest<State> {
 
     fn insert_result(&mut self, _result: Self::Result) -> bool { false }
 
-    fn is_expired(&self) -> bool {
-        self.expire < SystemTime::now()
+    fn expires_at(&self) -> SystemTime {
+        self.expire
     }
 }
 
@@ -78,33 +94,121 @@ impl<T: HasStatus + Msg> HasStatus for MsgSigned<T> {
     }
 }
 
+/// Lifecycle events of tasks for monitoring the broker.
+/// They only contain the parts of the signed messages that are not encrypted.
+#[derive(Debug, Clone, Serialize)]
+#[serde(untagged)]
+pub enum TaskEvent {
+    Created { task_id: MsgId, from: AppOrProxyId, to: Vec<AppOrProxyId> },
+    ResultAdded { task_id: MsgId, from: AppOrProxyId, status: WorkStatus, updated: bool },
+    Expired { task_id: MsgId },
+    Deleted { task_id: MsgId },
+    /// The creator of the task has received the result of `from`
+    Acknowledged { task_id: MsgId, from: AppOrProxyId },
+}
+
+impl TaskEvent {
+    fn event_type(&self) -> SseEventType {
+        match self {
+            TaskEvent::Created { .. } => SseEventType::NewTask,
+            TaskEvent::ResultAdded { updated: false, .. } => SseEventType::NewResult,
+            TaskEvent::ResultAdded { updated: true, .. } => SseEventType::UpdatedResult,
+            TaskEvent::Expired { .. } => SseEventType::ExpiredTask,
+            TaskEvent::Deleted { .. } => SseEventType::DeletedTask,
+            TaskEvent::Acknowledged { .. } => SseEventType::Acknowledged,
+        }
+    }
+}
+
+#[derive(Debug, Serialize)]
+pub struct TaskManagerDiagnostics {
+    pub tasks: usize,
+    pub results: usize,
+    pub result_channels: usize,
+    /// Clients waiting for new tasks
+    pub new_task_subscribers: usize,
+    /// Clients following the task monitor or webhooks
+    pub event_subscribers: usize,
+}
+
+/// How long results are pushed back for a waiting client that does not catch up, after which it is left to lag behind
+const MAX_PUSH_BACK: Duration = Duration::from_secs(30);
+
+/// Notifies about new results of a task and numbers them so SSE clients can resume after reconnecting
+struct ResultChannel {
+    sender: broadcast::Sender<AppOrProxyId>,
+    capacity: usize,
+    /// Incremented for every inserted or updated result of the task
+    last_event_id: u64,
+    /// Event id of the latest version of each result
+    event_ids: HashMap<AppOrProxyId, u64>,
+    /// Workers whose result the creator of the task has acknowledged
+    acknowledged: HashSet<AppOrProxyId>,
+    /// Since when results have been pushed back because the channel is full
+    backlogged_since: Option<SystemTime>,
+}
+
+impl ResultChannel {
+    fn new(capacity: usize) -> Self {
+        let (sender, _) = broadcast::channel(capacity);
+        Self { sender, capacity, last_event_id: 0, event_ids: HashMap::new(), acknowledged: HashSet::new(), backlogged_since: None }
+    }
+
+    /// Whether to push back another result as a waiting client has not yet seen as many results as the channel buffers, so another one would make it lag.
+    /// A stalled client must not block all workers of the task, so results are only pushed back for [`MAX_PUSH_BACK`].
+    fn push_back(&mut self, now: SystemTime) -> bool {
+        if self.sender.len() < self.capacity {
+            self.backlogged_since = None;
+            return false;
+        }
+        let since = *self.backlogged_since.get_or_insert(now);
+        now.duration_since(since).unwrap_or_default() < MAX_PUSH_BACK
+    }
+}
+
+/// Tasks addressed to no one are fire-and-forget: they get no result channel and waiting for their results ends right away
+pub fn expects_results(task: &impl Msg) -> bool {
+    !task.get_to().is_empty()
+}
+
 pub struct TaskManager<T: HasWaitId<MsgId> + Task + Msg> {
     tasks: DashMap<MsgId, MsgSigned<T>>,
     new_tasks: broadcast::Sender<MsgId>,
-    /// Send the index at which the new result for the given Task was inserted
-    new_results: DashMap<MsgId, broadcast::Sender<AppOrProxyId>>,
+    /// Lifecycle events of all tasks for monitoring
+    events: broadcast::Sender<TaskEvent>,
+    /// Send the index at which the new result for the given Task was inserted.
+    /// There is exactly one channel per task expecting results as they are inserted and removed while holding the task's entry.
+    new_results: DashMap<MsgId, ResultChannel>,
+    result_capacity: usize,
+    /// Decides which tasks have expired
+    clock: Arc<dyn Clock>,
 }
 
 impl<T: HasWaitId<MsgId> + Task + Msg + Send + Sync + 'static> TaskManager<T> {
     const EXPIRE_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
-    pub fn new() -> Arc<Self> {
-        let (new_tasks, _) = broadcast::channel(256);
+    /// Waiting clients that miss more than `task_capacity` new tasks or more than `result_capacity` new results
+    /// of a task have to recount them. Every open task preallocates a buffer of at least `result_capacity` results.
+    pub fn new(task_capacity: usize, result_capacity: usize) -> Arc<Self> {
+        Self::with_clock(task_capacity, result_capacity, Arc::new(SystemClock))
+    }
+
+    pub fn with_clock(task_capacity: usize, result_capacity: usize, clock: Arc<dyn Clock>) -> Arc<Self> {
+        let (new_tasks, _) = broadcast::channel(task_capacity);
+        let (events, _) = broadcast::channel(task_capacity);
         let task_manager = Arc::new(Self {
             tasks: Default::default(),
             new_tasks,
+            events,
             new_results: Default::default(),
+            result_capacity,
+            clock,
         });
         let tm = Arc::clone(&task_manager);
         std::thread::spawn(move || {
             loop {
                 std::thread::sleep(Self::EXPIRE_CHECK_INTERVAL);
-                tm.tasks.retain(|_, task| if task.msg.is_expired() {
-                    tm.new_results.remove(&task.msg.wait_id());
-                    false
-                } else {
-                    true
-                });
+                tm.remove_expired();
                 // If the memory footprint of the Dashmap will get too large we might need to consider calling DashMap::shrink_to_fit or find a better solution as
                 // this would need to lock the whole map making it inaccessible until everything is reallocated
             }
@@ -116,19 +220,80 @@ impl<T: HasWaitId<MsgId> + Task + Msg + Send + Sync + 'static> TaskManager<T> {
 
 impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T> {
 
+    /// Counts the tasks and their results as well as the clients waiting for them
+    pub fn diagnostics(&self) -> TaskManagerDiagnostics {
+        TaskManagerDiagnostics {
+            tasks: self.tasks.len(),
+            results: self.tasks.iter().map(|task| task.msg.get_results().len()).sum(),
+            result_channels: self.new_results.len(),
+            new_task_subscribers: self.new_tasks.receiver_count(),
+            event_subscribers: self.events.receiver_count(),
+        }
+    }
+
+    pub fn is_expired(&self, task: &T) -> bool {
+        task.expires_at() < self.clock.now()
+    }
+
+    fn remove_expired(&self) {
+        self.tasks.retain(|_, task| if self.is_expired(&task.msg) {
+            self.new_results.remove(&task.msg.wait_id());
+            _ = self.events.send(TaskEvent::Expired { task_id: task.msg.wait_id() });
+            metrics::TASKS_OPEN.with_label_values(&[T::TYPE]).dec();
+            metrics::TASKS_EXPIRED.with_label_values(&[T::TYPE]).inc();
+            false
+        } else {
+            true
+        });
+        self.remove_orphaned_result_channels();
+        metrics::RESULT_CHANNELS_OPEN.with_label_values(&[T::TYPE]).set(self.new_results.len() as i64);
+    }
+
+    /// Removes result channels whose task no longer exists, which would be leaked otherwise.
+    /// This only happens if a code path removes a task without its channel.
+    fn remove_orphaned_result_channels(&self) {
+        // Collect the ids first as the channels must not be locked before the tasks
+        let ids: Vec<MsgId> = self.new_results.iter().map(|channel| *channel.key()).collect();
+        let mut orphaned = 0;
+        for id in ids {
+            let task = self.tasks.entry(id);
+            if matches!(task, Entry::Vacant(_)) && self.new_results.remove(&id).is_some() {
+                orphaned += 1;
+            }
+        }
+        if orphaned > 0 {
+            warn!("Removed {orphaned} result channels of tasks that no longer exist");
+        }
+    }
+
     pub fn get(&self, task_id: &MsgId) -> Result<impl Deref<Target = MsgSigned<T>> + '_, TaskManagerError> {
         self.tasks.get(task_id).ok_or(TaskManagerError::NotFound)
     }
 
+    /// Changes a stored task in place, e.g. to drop its body from memory once it is stored elsewhere
+    pub fn modify(&self, task_id: &MsgId, f: impl FnOnce(&mut MsgSigned<T>)) -> Result<(), TaskManagerError> {
+        let mut task = self.tasks.get_mut(task_id).ok_or(TaskManagerError::NotFound)?;
+        f(&mut task);
+        Ok(())
+    }
+
     pub fn remove(&self, task_id: &MsgId) -> Result<MsgSigned<T>, TaskManagerError> {
-        self.tasks.remove(task_id).ok_or(TaskManagerError::NotFound).map(|v| v.1)
+        let Entry::Occupied(task) = self.tasks.entry(*task_id) else {
+            return Err(TaskManagerError::NotFound);
+        };
+        // Closing the results channel wakes up clients waiting for results
+        self.new_results.remove(task_id);
+        let task = task.remove();
+        metrics::TASKS_OPEN.with_label_values(&[T::TYPE]).dec();
+        _ = self.events.send(TaskEvent::Deleted { task_id: *task_id });
+        Ok(task)
     }
 
     pub fn get_tasks_by(&self, filter: impl Fn(&T) -> bool) -> impl Iterator<Item = impl Deref<Target = MsgSigned<T>> + '_> {
         self.tasks
             .iter()
             .filter(move |entry| filter(&entry.msg))
-            .filter(|entry| !entry.msg.is_expired())
+            .filter(|entry| !self.is_expired(&entry.msg))
     }
 
     // Once async iterators are stabilized this should be one
@@ -143,46 +308,45 @@ impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T> {
         let (max_elements, wait_until) = decide_blocking_conditions(block);
         let mut new_tasks = self.new_tasks.subscribe();
 
-        let mut num_of_tasks = self.get_tasks_by(&filter).count();
-        while num_of_tasks < max_elements && Instant::now() < wait_until {
-            tokio::select! {
-                _ = tokio::time::sleep_until(wait_until) => {
-                    break;
-                },
-                result = new_tasks.recv() => {
-                    match result {
-                        Ok(id) => {
-                            if let Ok(task) = self.get(&id) {
-                                if filter(&task.msg) {
-                                    num_of_tasks += 1;
-                                }
-                            }
-                        },
-                        Err(e) => {
-                            warn!("new_tasks channel lagged: {e}");
-                            return Err(TaskManagerError::BroadcastBufferOverflow);
-                        }
-                    }
-                },
-            }
-        }
-        Ok(self.get_tasks_by(filter))
+        let num_of_tasks = self.get_tasks_by(&filter).count();
+        wait_for_count(
+            &mut new_tasks,
+            num_of_tasks,
+            max_elements,
+            wait_until,
+            |id| Ok(self.get(&id).is_ok_and(|task| filter(&task.msg))),
+            || Ok(self.get_tasks_by(&filter).count()),
+        ).await?;
+        let delivered = metrics::TASKS_DELIVERED.with_label_values(&[T::TYPE]);
+        Ok(self.get_tasks_by(filter).inspect(move |_| delivered.inc()))
     }
 
     pub fn post_task(&self, task: MsgSigned<T>) -> Result<(), TaskManagerError> {
         let id = task.wait_id();
-        if let Some(task) = self.tasks.get(&id) {
+        let channel = expects_results(&task).then(|| ResultChannel::new(self.result_capacity.max(task.get_to().len())));
+        let insert_channel = || match channel {
+            Some(channel) => _ = self.new_results.insert(id, channel),
+            None => _ = self.new_results.remove(&id),
+        };
+        let created = TaskEvent::Created { task_id: id, from: task.get_from().clone(), to: task.get_to().clone() };
+        match self.tasks.entry(id) {
             // We only have a conflict if the conflicting task has not yet expired
-            if !task.msg.is_expired() {
-                return Err(TaskManagerError::Conflict);
+            Entry::Occupied(existing) if !self.is_expired(&existing.get().msg) => return Err(TaskManagerError::Conflict),
+            Entry::Occupied(mut expired) => {
+                // Replaced a task that expired but was not yet removed
+                insert_channel();
+                expired.insert(task);
+                metrics::TASKS_EXPIRED.with_label_values(&[T::TYPE]).inc();
+            }
+            Entry::Vacant(vacant) => {
+                insert_channel();
+                vacant.insert(task);
+                metrics::TASKS_OPEN.with_label_values(&[T::TYPE]).inc();
             }
         }
-        let max_receivers = task.get_to().len();
-        self.tasks.insert(id.clone(), task);
-        let (results_sender, _) = broadcast::channel(1.max(max_receivers));
-        self.new_results.insert(id.clone(), results_sender);
         // We dont care if noone is listening
         _ = self.new_tasks.send(id);
+        _ = self.events.send(created);
         Ok(())
     }
 }
@@ -200,6 +364,69 @@ fn decide_blocking_conditions(block: &HowLongToBlock) -> (usize, Instant) {
     }
 }
 
+/// Whether every recipient of the task has sent a result passing the filter that it won't change anymore
+fn all_results_terminal<T: Task + Msg>(task: &T, filter: impl Fn(&T::Result) -> bool) -> bool
+where
+    T::Result: HasStatus,
+{
+    task.get_to()
+        .iter()
+        .all(|to| task.get_results().get(to).is_some_and(|res| filter(res) && res.get_status().is_terminal()))
+}
+
+/// What woke up a task waiting on a broadcast channel with a deadline
+#[derive(Debug, PartialEq)]
+enum Wakeup<K> {
+    Deadline,
+    Received(K),
+    Lagged(u64),
+    Closed,
+}
+
+async fn recv_until<K: Clone>(receiver: &mut broadcast::Receiver<K>, wait_until: Instant) -> Wakeup<K> {
+    tokio::select! {
+        _ = tokio::time::sleep_until(wait_until) => Wakeup::Deadline,
+        result = receiver.recv() => match result {
+            Ok(key) => Wakeup::Received(key),
+            Err(broadcast::error::RecvError::Lagged(n)) => Wakeup::Lagged(n),
+            Err(broadcast::error::RecvError::Closed) => Wakeup::Closed,
+        }
+    }
+}
+
+/// Waits on `receiver` until `count` reaches `max_elements` or `wait_until` has passed.
+/// `is_match` is called for every received key and decides whether it counts towards `max_elements`.
+/// If messages were missed or the channel was closed `recount` is used to determine the count from scratch.
+/// Returns the final count.
+async fn wait_for_count<K: Clone>(
+    receiver: &mut broadcast::Receiver<K>,
+    mut count: usize,
+    max_elements: usize,
+    wait_until: Instant,
+    mut is_match: impl FnMut(K) -> Result<bool, TaskManagerError>,
+    mut recount: impl FnMut() -> Result<usize, TaskManagerError>,
+) -> Result<usize, TaskManagerError> {
+    while count < max_elements && Instant::now() < wait_until {
+        match recv_until(receiver, wait_until).await {
+            Wakeup::Deadline => break,
+            Wakeup::Received(key) => {
+                if is_match(key)? {
+                    count += 1;
+                }
+            },
+            Wakeup::Lagged(n) => {
+                debug!("Broadcast channel lagged by {n} messages; recounting");
+                count = recount()?;
+            },
+            Wakeup::Closed => {
+                // Nothing will be sent anymore so the current state is final
+                return recount();
+            },
+        }
+    }
+    Ok(count)
+}
+
 impl<T: HasWaitId<MsgId> + Task + Msg> TaskManager<T>
 where
     T::Result: Msg + HasStatus,
@@ -212,43 +439,37 @@ where
         filter: impl Fn(&T::Result) -> bool,
     ) -> Result<impl Deref<Target = MsgSigned<T>> + '_, TaskManagerError> {
         let (max_elements, wait_until) = decide_blocking_conditions(block);
-        let mut num_of_results = self
-            .get(task_id)?
-            .msg
+        let count_results = |task: &MsgSigned<T>| task.msg
             .get_results()
             .values()
             .filter(|result| filter(result) && result.get_status() != WorkStatus::Claimed)
             .count();
+        let task = self.get(task_id)?;
+        if !expects_results(&*task) {
+            return Ok(task);
+        }
+        let num_of_results = count_results(&task);
+        drop(task);
         let mut new_results = self
             .new_results
             .get(task_id)
-            .expect("Found task but no corresponding results channel")
+            // The task has been removed in the meantime
+            .ok_or(TaskManagerError::Gone)?
+            .sender
             .subscribe();
-        while num_of_results < max_elements && Instant::now() < wait_until {
-            tokio::select! {
-                _ = tokio::time::sleep_until(wait_until) => {
-                    break;
-                },
-                result = new_results.recv() => {
-                    match result {
-                        Ok(key) => {
-                            if let Ok(task) = self.get(task_id) {
-                                let result = &task.msg.get_results()[&key];
-                                if filter(result) && result.get_status() != WorkStatus::Claimed {
-                                    num_of_results += 1;
-                                }
-                            } else {
-                                return Err(TaskManagerError::Gone);
-                            }
-                        },
-                        Err(e) => {
-                            warn!("new_results channel lagged: {e}");
-                            return Err(TaskManagerError::BroadcastBufferOverflow);
-                        }
-                    }
-                },
-            }
-        }
+        wait_for_count(
+            &mut new_results,
+            num_of_results,
+            max_elements,
+            wait_until,
+            |key| {
+                let task = self.get(task_id).map_err(|_| TaskManagerError::Gone)?;
+                let result = &task.msg.get_results()[&key];
+                Ok(filter(result) && result.get_status() != WorkStatus::Claimed)
+            },
+            // The task may have expired while we missed messages
+            || Ok(count_results(&*self.get(task_id).map_err(|_| TaskManagerError::Gone)?)),
+        ).await?;
 
         // Somehow mapping this task to its results creates lifetime issues that I failed to solve.
         // So the caller needs to get the results himself which is not to bad I guess.
@@ -256,105 +477,373 @@ where
         self.get(task_id).map_err(|_| TaskManagerError::Gone)
     }
 
+    /// Streams the results of a task as SSE events whose id is the position of the result in the task's history.
+    /// Existing results are replayed in this order, so reconnecting clients see the same stream, followed by new results as they arrive.
+    /// Clients resuming with `last_event_id` only get the results that were inserted or updated since,
+    /// while all of them still count towards `block.wait_count`.
+    /// Once every recipient has sent a terminal result, a `complete` event ends the stream.
     pub fn stream_results(
         self: Arc<Self>,
         task_id: MsgId,
         block: HowLongToBlock,
+        last_event_id: Option<u64>,
         filter: impl Fn(&T::Result) -> bool + 'static + Send + Sync
     ) -> impl Stream<Item = Result<Event, Infallible>> + 'static + Send
         where
             T::Result: Serialize + Sync + Send,
             T: Send + Sync + 'static
+    {
+        self.stream_result_updates(task_id, block, last_event_id, filter, move |update| Some(Ok(update.into_event(task_id))))
+    }
+
+    /// Waits for the results of a task like [`TaskManager::stream_results`] but leaves it to `to_item` how to present each update.
+    /// Updates for which `to_item` returns `None` are left out of the stream. As results are only borrowed, `to_item` is called while the task is locked.
+    pub fn stream_result_updates<I: Send + 'static>(
+        self: Arc<Self>,
+        task_id: MsgId,
+        block: HowLongToBlock,
+        last_event_id: Option<u64>,
+        filter: impl Fn(&T::Result) -> bool + 'static + Send + Sync,
+        to_item: impl Fn(ResultUpdate<'_, T::Result>) -> Option<I> + 'static + Send + Sync,
+    ) -> impl Stream<Item = I> + 'static + Send
+        where
+            T::Result: Sync + Send,
+            T: Send + Sync + 'static
     {
         async_stream::stream! {
             let Ok(task) = self.get(&task_id) else {
-                yield Ok(to_event("Did not find task", SseEventType::Error));
+                if let Some(item) = to_item(ResultUpdate::NotFound) {
+                    yield item;
+                }
+                return;
+            };
+            if !expects_results(&*task) {
+                drop(task);
+                if let Some(item) = to_item(ResultUpdate::Complete) {
+                    yield item;
+                }
+                return;
+            }
+            // Subscribe while holding the task so no result is missed between replaying and waiting
+            let Some((mut new_results, event_ids)) = self.new_results
+                .get(&task_id)
+                .map(|channel| (channel.sender.subscribe(), channel.event_ids.clone()))
+            else {
+                if let Some(item) = to_item(ResultUpdate::Deleted) {
+                    yield item;
+                }
                 return;
             };
             let (max_elements, wait_until) = decide_blocking_conditions(&block);
-            let ready_results = task.msg
+            let mut ready_results: Vec<_> = task.msg
                 .get_results()
-                .values()
-                .filter(|result| filter(result));
+                .iter()
+                .filter(|(_, result)| filter(result))
+                .map(|(from, result)| (event_ids.get(from).copied().unwrap_or_default(), result))
+                .collect();
+            // Replaying in order of the event ids also lets clients resume after any of the replayed events
+            ready_results.sort_unstable_by_key(|(event_id, _)| *event_id);
             let mut num_of_results = 0;
-            let mut events = Vec::with_capacity(task.msg.get_results().len());
-            for res in ready_results {
+            let mut items = Vec::with_capacity(ready_results.len());
+            let mut complete = all_results_terminal(&task.msg, &filter);
+            for (event_id, res) in ready_results {
                 if res.get_status() != WorkStatus::Claimed {
                     num_of_results += 1;
                 }
-                events.push(to_event(res, SseEventType::NewResult));
+                if last_event_id.is_none_or(|last| event_id > last) {
+                    items.extend(to_item(ResultUpdate::Result { event_id, result: res }));
+                }
                 // Only break when wait_count was actually set otherwise we want all the tasks that are present
                 if num_of_results >= max_elements && max_elements != 0 {
+                    // The client did not ask for all results, so it is not told that they are complete
+                    complete = false;
                     break;
                 }
             }
             // Drop lock before doing async stuff
             drop(task);
-            for event in events {
-                yield Ok(event);
+            for item in items {
+                yield item;
+            }
+            if complete {
+                if let Some(item) = to_item(ResultUpdate::Complete) {
+                    yield item;
+                }
+                return;
             }
-            let mut new_results = self
-                .new_results
-                .get(&task_id)
-                .expect("Found task but no corresponding results channel")
-                .subscribe();
             while num_of_results < max_elements && Instant::now() < wait_until {
-                tokio::select! {
-                    _ = tokio::time::sleep_until(wait_until) => {
-                        yield Ok(to_event((), SseEventType::WaitExpired));
+                match recv_until(&mut new_results, wait_until).await {
+                    Wakeup::Deadline => {
+                        if let Some(item) = to_item(ResultUpdate::Deadline) {
+                            yield item;
+                        }
                         break;
                     },
-                    result = new_results.recv() => {
-                        match result {
-                            Ok(key) => {
-                                if let Ok(task) = self.get(&task_id) {
-                                    let new_result = &task.msg.get_results()[&key];
-                                    if filter(new_result) {
-                                        if new_result.get_status() != WorkStatus::Claimed {
-                                            num_of_results += 1;
-                                        }
-                                        let event = to_event(new_result, SseEventType::NewResult);
-                                        drop(task);
-                                        yield Ok(event);
-                                    };
-                                } else {
-                                    yield Ok(to_event(json!({"task_id": task_id}), SseEventType::DeletedTask));
+                    Wakeup::Received(key) => {
+                        if let Ok(task) = self.get(&task_id) {
+                            let new_result = &task.msg.get_results()[&key];
+                            if filter(new_result) {
+                                if new_result.get_status() != WorkStatus::Claimed {
+                                    num_of_results += 1;
+                                }
+                                let event_id = self.new_results
+                                    .get(&task_id)
+                                    .and_then(|channel| channel.event_ids.get(&key).copied())
+                                    .unwrap_or_default();
+                                let item = to_item(ResultUpdate::Result { event_id, result: new_result });
+                                let complete = all_results_terminal(&task.msg, &filter);
+                                drop(task);
+                                if let Some(item) = item {
+                                    yield item;
+                                }
+                                if complete {
+                                    if let Some(item) = to_item(ResultUpdate::Complete) {
+                                        yield item;
+                                    }
+                                    break;
                                 }
-                            },
-                            Err(broadcast::error::RecvError::Lagged(n)) => {
-                                warn!("new_results channel lagged by: {n} results.");
-                                yield Ok(to_event("Internal server error", SseEventType::Error));
-                            },
-                            Err(broadcast::error::RecvError::Closed) => {
-                                yield Ok(to_event("Task expired", SseEventType::WaitExpired));
-                                break;
+                            };
+                        } else if let Some(item) = to_item(ResultUpdate::Deleted) {
+                            yield item;
+                        }
+                    },
+                    Wakeup::Lagged(n) => {
+                        if self.get(&task_id).is_err() {
+                            if let Some(item) = to_item(ResultUpdate::Deleted) {
+                                yield item;
                             }
+                            break;
+                        }
+                        warn!("new_results channel lagged by: {n} results.");
+                        if let Some(item) = to_item(ResultUpdate::Lagged) {
+                            yield item;
                         }
                     },
+                    Wakeup::Closed => {
+                        if let Some(item) = to_item(ResultUpdate::Expired) {
+                            yield item;
+                        }
+                        break;
+                    }
                 }
             }
         }
     }
 
+    /// Subscribes to the lifecycle events of all tasks
+    pub fn subscribe_events(&self) -> broadcast::Receiver<TaskEvent> {
+        self.events.subscribe()
+    }
+
+    /// Streams the lifecycle events of all tasks. Events missed by slow clients are reported as errors.
+    pub fn stream_events(&self) -> impl Stream<Item = Result<Event, Infallible>> + 'static + Send {
+        let mut events = self.events.subscribe();
+        async_stream::stream! {
+            loop {
+                match events.recv().await {
+                    Ok(event) => yield Ok(to_event(&event, event.event_type())),
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Task monitor lagged by {n} events");
+                        yield Ok(to_event(json!({"error": format!("Missed {n} events")}), SseEventType::Error));
+                    },
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        }
+    }
+
+    /// Streams every new result to tasks created by `owner`, including tasks created after subscribing.
+    /// The per task result channels are forwarded into a single channel by tasks that are aborted once the stream is dropped.
+    pub fn stream_all_results(
+        self: Arc<Self>,
+        owner: AppOrProxyId,
+        filter: impl Fn(&T::Result) -> bool + 'static + Send + Sync
+    ) -> impl Stream<Item = Result<Event, Infallible>> + 'static + Send
+        where
+            T::Result: Serialize + Sync + Send,
+            T: Send + Sync + 'static
+    {
+        async_stream::stream! {
+            let (results_tx, mut results_rx) = mpsc::channel(self.result_capacity);
+            let mut forwarders = JoinSet::new();
+            let mut subscribed = HashSet::new();
+            let mut new_tasks = self.new_tasks.subscribe();
+            self.forward_new_results(&owner, &mut subscribed, &mut forwarders, &results_tx);
+            loop {
+                tokio::select! {
+                    Some((task_id, wakeup)) = results_rx.recv() => match wakeup {
+                        Wakeup::Received(key) => {
+                            let Ok(task) = self.get(&task_id) else {
+                                continue;
+                            };
+                            let new_result = &task.msg.get_results()[&key];
+                            if filter(new_result) {
+                                let event = to_event(new_result, SseEventType::NewResult);
+                                drop(task);
+                                yield Ok(event);
+                            }
+                        },
+                        Wakeup::Lagged(n) => {
+                            warn!("new_results channel of task {task_id} lagged by: {n} results.");
+                            yield Ok(to_event(json!({"task_id": task_id, "error": "Internal server error"}), SseEventType::Error));
+                        },
+                        Wakeup::Closed | Wakeup::Deadline => {
+                            subscribed.remove(&task_id);
+                            yield Ok(to_event(json!({"task_id": task_id}), SseEventType::DeletedTask));
+                        },
+                    },
+                    new_task = new_tasks.recv() => match new_task {
+                        Ok(task_id) => {
+                            if self.get(&task_id).is_ok_and(|task| task.get_from() == &owner) && subscribed.insert(task_id) {
+                                self.forward_task_results(task_id, &mut forwarders, &results_tx);
+                            }
+                        },
+                        Err(broadcast::error::RecvError::Lagged(n)) => {
+                            debug!("new_tasks channel lagged by {n} tasks; resubscribing to all tasks of {owner}");
+                            self.forward_new_results(&owner, &mut subscribed, &mut forwarders, &results_tx);
+                        },
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                }
+            }
+        }
+    }
+
+    /// Forwards the results of all tasks of `owner` that are not yet `subscribed` to
+    fn forward_new_results(
+        &self,
+        owner: &AppOrProxyId,
+        subscribed: &mut HashSet<MsgId>,
+        forwarders: &mut JoinSet<()>,
+        results_tx: &mpsc::Sender<(MsgId, Wakeup<AppOrProxyId>)>,
+    ) {
+        let new_tasks: Vec<_> = self
+            .get_tasks_by(|task| task.get_from() == owner)
+            .map(|task| task.wait_id())
+            .filter(|id| !subscribed.contains(id))
+            .collect();
+        for task_id in new_tasks {
+            subscribed.insert(task_id);
+            self.forward_task_results(task_id, forwarders, results_tx);
+        }
+    }
+
+    fn forward_task_results(
+        &self,
+        task_id: MsgId,
+        forwarders: &mut JoinSet<()>,
+        results_tx: &mpsc::Sender<(MsgId, Wakeup<AppOrProxyId>)>,
+    ) {
+        let Some(mut new_results) = self.new_results.get(&task_id).map(|channel| channel.sender.subscribe()) else {
+            return;
+        };
+        let results_tx = results_tx.clone();
+        forwarders.spawn(async move {
+            loop {
+                let wakeup = match new_results.recv().await {
+                    Ok(key) => Wakeup::Received(key),
+                    Err(broadcast::error::RecvError::Lagged(n)) => Wakeup::Lagged(n),
+                    Err(broadcast::error::RecvError::Closed) => Wakeup::Closed,
+                };
+                let closed = matches!(wakeup, Wakeup::Closed);
+                if results_tx.send((task_id, wakeup)).await.is_err() || closed {
+                    break;
+                }
+            }
+        });
+    }
+
+    /// Records that the creator of the task has received the result of `worker`.
+    /// Acknowledgements are kept until the task is removed and survive updates of the result.
+    pub fn acknowledge(&self, task_id: &MsgId, worker: &AppOrProxyId) -> Result<(), TaskManagerError> {
+        let task = self.get(task_id)?;
+        if !task.msg.get_results().contains_key(worker) {
+            return Err(TaskManagerError::NotFound);
+        }
+        let mut channel = self.new_results.get_mut(task_id).ok_or(TaskManagerError::Gone)?;
+        if channel.acknowledged.insert(worker.clone()) {
+            _ = self.events.send(TaskEvent::Acknowledged { task_id: *task_id, from: worker.clone() });
+        }
+        Ok(())
+    }
+
+    pub fn is_acknowledged(&self, task_id: &MsgId, worker: &AppOrProxyId) -> Result<bool, TaskManagerError> {
+        let task = self.get(task_id)?;
+        if !expects_results(&*task) {
+            return Ok(false);
+        }
+        let channel = self.new_results.get(task_id).ok_or(TaskManagerError::Gone)?;
+        Ok(channel.acknowledged.contains(worker))
+    }
+
+    /// Waits until the result of `worker` is acknowledged or `block.wait_time` has passed and returns whether it was acknowledged.
+    /// Fails with [`TaskManagerError::Gone`] if the task expires or is removed while waiting.
+    pub async fn wait_for_acknowledgement(&self, task_id: &MsgId, worker: &AppOrProxyId, block: &HowLongToBlock) -> Result<bool, TaskManagerError> {
+        let (_, wait_until) = decide_blocking_conditions(&HowLongToBlock { wait_count: None, ..*block });
+        let mut events = self.events.subscribe();
+        if self.is_acknowledged(task_id, worker)? {
+            return Ok(true);
+        }
+        while Instant::now() < wait_until {
+            let recheck = match recv_until(&mut events, wait_until).await {
+                Wakeup::Deadline => break,
+                Wakeup::Received(TaskEvent::Acknowledged { task_id: id, from }) => id == *task_id && from == *worker,
+                Wakeup::Received(TaskEvent::Expired { task_id: id } | TaskEvent::Deleted { task_id: id }) => id == *task_id,
+                Wakeup::Received(_) => false,
+                Wakeup::Lagged(_) | Wakeup::Closed => true,
+            };
+            if recheck {
+                return self.is_acknowledged(task_id, worker).map_err(|_| TaskManagerError::Gone);
+            }
+        }
+        Ok(false)
+    }
+
     /// This will push the result to the given task by its id.
-    /// Returns true if the given result was an update to an existing result
+    /// Returns true if the given result was an update to an existing result.
+    /// Fails with [`TaskManagerError::Backlogged`] while a client waiting for the results is too slow to take another one, for at most [`MAX_PUSH_BACK`].
     pub fn put_result(&self, task_id: &MsgId, result: T::Result) -> Result<bool, TaskManagerError> {
+        self.insert_result(task_id, result, false)
+    }
+
+    /// Like [`Self::put_result`] but fails with [`TaskManagerError::Exists`] instead of replacing a result of the same sender.
+    pub fn put_first_result(&self, task_id: &MsgId, result: T::Result) -> Result<(), TaskManagerError> {
+        self.insert_result(task_id, result, true).map(|_| ())
+    }
+
+    fn insert_result(&self, task_id: &MsgId, result: T::Result, only_first: bool) -> Result<bool, TaskManagerError> {
         let Some(mut task) = self.tasks.get_mut(task_id) else {
             return Err(TaskManagerError::NotFound);
         };
         if !task.get_to().contains(result.get_from()) {
             return Err(TaskManagerError::Unauthorized);
         }
+        if only_first && task.msg.get_results().contains_key(result.get_from()) {
+            return Err(TaskManagerError::Exists);
+        }
+        if task.msg.is_outdated(&result) {
+            return Err(TaskManagerError::Outdated);
+        }
+        if self.new_results.get_mut(task_id).is_some_and(|mut channel| channel.push_back(self.clock.now())) {
+            debug!("Pushing back result of {} to task {task_id} as a waiting client has not caught up", result.get_from());
+            return Err(TaskManagerError::Backlogged);
+        }
         let sender = result.get_from().clone();
+        let status = result.get_status();
         let is_updated = task.msg.insert_result(result);
+        _ = self.events.send(TaskEvent::ResultAdded { task_id: *task_id, from: sender.clone(), status, updated: is_updated });
         // We dont care if noone is listening
-        _ = self
+        let mut channel = self
             .new_results
-            .get(task_id)
+            .get_mut(task_id)
             .expect(
                 "This task id must be present because it is present at the start of the function",
-            )
-            .send(sender);
+            );
+        channel.last_event_id += 1;
+        let event_id = channel.last_event_id;
+        channel.event_ids.insert(sender.clone(), event_id);
+        // We dont care if noone is listening
+        _ = channel.sender.send(sender);
         Ok(is_updated)
     }
 }
@@ -365,7 +854,9 @@ pub enum TaskManagerError {
     Conflict,
     Unauthorized,
     Gone,
-    BroadcastBufferOverflow,
+    Outdated,
+    Backlogged,
+    Exists,
 }
 
 impl TaskManagerError {
@@ -374,8 +865,10 @@ impl TaskManagerError {
             TaskManagerError::NotFound => "Task not found",
             TaskManagerError::Conflict => "Task already exists",
             TaskManagerError::Unauthorized => "Unauthorized to access this task",
-            TaskManagerError::Gone => "Task expired while waiting on it",
-            TaskManagerError::BroadcastBufferOverflow => "Internal server error",
+            TaskManagerError::Gone => "Task expired or was removed while waiting on it",
+            TaskManagerError::Outdated => "A newer version of this result has already been submitted",
+            TaskManagerError::Backlogged => "The creator of this task has not caught up with its results yet; please retry later",
+            TaskManagerError::Exists => "A result of this worker has already been submitted",
         }
     }
 }
@@ -387,14 +880,62 @@ impl From<TaskManagerError> for (StatusCode, &'static str) {
     }
 }
 
+impl IntoResponse for TaskManagerError {
+    fn into_response(self) -> axum::response::Response {
+        match self {
+            // The creator catches up on its own, so a worker can retry soon
+            TaskManagerError::Backlogged => (
+                StatusCode::TOO_MANY_REQUESTS,
+                [(header::RETRY_AFTER, HeaderValue::from_static("1"))],
+                self.error_msg(),
+            ).into_response(),
+            _ => <(StatusCode, &'static str)>::from(self).into_response(),
+        }
+    }
+}
+
 impl From<TaskManagerError> for StatusCode {
     fn from(value: TaskManagerError) -> Self {
         match value {
             TaskManagerError::NotFound => StatusCode::NOT_FOUND,
             TaskManagerError::Conflict => StatusCode::CONFLICT,
-            TaskManagerError::BroadcastBufferOverflow => StatusCode::INTERNAL_SERVER_ERROR,
             TaskManagerError::Unauthorized => StatusCode::UNAUTHORIZED,
             TaskManagerError::Gone => StatusCode::GONE,
+            TaskManagerError::Outdated => StatusCode::CONFLICT,
+            TaskManagerError::Backlogged => StatusCode::TOO_MANY_REQUESTS,
+            TaskManagerError::Exists => StatusCode::PRECONDITION_FAILED,
+        }
+    }
+}
+
+/// What happened while waiting for the results of a task
+pub enum ResultUpdate<'a, R> {
+    /// A result that is new to the client, along with its position in the task's history
+    Result { event_id: u64, result: &'a R },
+    /// Every recipient has sent a terminal result
+    Complete,
+    /// The client stopped waiting before enough results arrived
+    Deadline,
+    /// The task expired while waiting
+    Expired,
+    /// The task was removed, e.g. by an admin
+    Deleted,
+    /// The task did not exist in the first place
+    NotFound,
+    /// Results were missed as the client fell behind
+    Lagged,
+}
+
+impl<R: Serialize> ResultUpdate<'_, R> {
+    fn into_event(self, task_id: MsgId) -> Event {
+        match self {
+            ResultUpdate::Result { event_id, result } => to_event(result, SseEventType::NewResult).id(event_id.to_string()),
+            ResultUpdate::Complete => to_event(json!({"task_id": task_id}), SseEventType::Complete),
+            ResultUpdate::Deadline => to_event((), SseEventType::WaitExpired),
+            ResultUpdate::Expired => to_event("Task expired", SseEventType::WaitExpired),
+            ResultUpdate::Deleted => to_event(json!({"task_id": task_id}), SseEventType::DeletedTask),
+            ResultUpdate::NotFound => to_event("Did not find task", SseEventType::Error),
+            ResultUpdate::Lagged => to_event("Internal server error", SseEventType::Error),
         }
     }
 }
@@ -407,3 +948,346 @@ fn to_event(json: impl Serialize, event_type: impl AsRef<str>) -> Event {
             .data("Internal error: Unable to serialize message.")
     })
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::Value;
+    use shared::{clock::MockClock, test_utils::{app, result, task}, EncryptedMsgTaskRequest};
+
+    use super::*;
+
+    #[derive(Serialize)]
+    struct TestTask {
+        id: MsgId,
+        from: AppOrProxyId,
+        to: Vec<AppOrProxyId>,
+        expired: bool,
+    }
+
+    impl Msg for TestTask {
+        fn get_from(&self) -> &AppOrProxyId {
+            &self.from
+        }
+
+        fn get_to(&self) -> &Vec<AppOrProxyId> {
+            &self.to
+        }
+
+        fn get_metadata(&self) -> &Value {
+            &Value::Null
+        }
+    }
+
+    impl HasWaitId<MsgId> for TestTask {
+        fn wait_id(&self) -> MsgId {
+            self.id
+        }
+    }
+
+    impl Task for TestTask {
+        type Result = ();
+        const TYPE: &'static str = "test";
+
+        fn get_results(&self) -> &HashMap<AppOrProxyId, Self::Result> {
+            &EMPTY_MAP
+        }
+
+        fn insert_result(&mut self, _result: Self::Result) -> bool { false }
+
+        fn expires_at(&self) -> SystemTime {
+            if self.expired { SystemTime::UNIX_EPOCH } else { SystemTime::now() + Duration::from_secs(60) }
+        }
+    }
+
+    fn test_task(expired: bool) -> MsgSigned<TestTask> {
+        let app = app("app");
+        MsgSigned {
+            msg: TestTask { id: MsgId::new(), from: app.clone(), to: vec![app], expired },
+            jwt: "Certainly valid".into(),
+        }
+    }
+
+    #[tokio::test]
+    async fn task_metrics() {
+        let open = metrics::TASKS_OPEN.with_label_values(&[TestTask::TYPE]);
+        let delivered = metrics::TASKS_DELIVERED.with_label_values(&[TestTask::TYPE]);
+        let expired = metrics::TASKS_EXPIRED.with_label_values(&[TestTask::TYPE]);
+        let channels = metrics::RESULT_CHANNELS_OPEN.with_label_values(&[TestTask::TYPE]);
+        let task_manager = TaskManager::<TestTask>::new(16, 1);
+        let live_task = test_task(false);
+        let live_id = live_task.wait_id();
+        task_manager.post_task(live_task).unwrap();
+        let expired_task = test_task(true);
+        let expired_id = expired_task.wait_id();
+        task_manager.post_task(expired_task).unwrap();
+        assert_eq!(open.get(), 2);
+        assert_eq!(task_manager.new_results.len(), 2);
+        let with_id = |id, expired| MsgSigned { msg: TestTask { id, ..test_task(expired).msg }, jwt: String::new() };
+        assert!(matches!(task_manager.post_task(with_id(live_id, false)), Err(TaskManagerError::Conflict)));
+        // Replacing a task that expired replaces its results channel
+        task_manager.post_task(with_id(expired_id, true)).unwrap();
+        assert_eq!(expired.get(), 1);
+        assert_eq!(task_manager.new_results.len(), 2);
+
+        let block = HowLongToBlock { wait_time: None, wait_count: None };
+        assert_eq!(task_manager.wait_for_tasks(&block, |_| true).await.unwrap().count(), 1);
+        assert_eq!(delivered.get(), 1);
+
+        task_manager.remove_expired();
+        assert_eq!(open.get(), 1);
+        assert_eq!(expired.get(), 2);
+        assert!(task_manager.new_results.contains_key(&live_id));
+        assert_eq!(channels.get(), 1);
+
+        task_manager.remove(&live_id).unwrap();
+        assert_eq!(open.get(), 0);
+        assert_eq!(expired.get(), 2);
+        assert!(task_manager.new_results.is_empty());
+
+        // Channels left behind by a bug are cleaned up as well
+        task_manager.new_results.insert(MsgId::new(), ResultChannel::new(1));
+        task_manager.remove_expired();
+        assert!(task_manager.new_results.is_empty());
+        assert_eq!(channels.get(), 0);
+    }
+
+    #[tokio::test]
+    async fn wait_for_count_stops_at_max_elements() {
+        let (tx, mut rx) = broadcast::channel(16);
+        for i in 0..5 {
+            tx.send(i).unwrap();
+        }
+        let wait_until = Instant::now() + Duration::from_secs(10);
+        let count = wait_for_count(&mut rx, 0, 2, wait_until, |i| Ok(i % 2 == 0), || unreachable!()).await.unwrap();
+        assert_eq!(count, 2);
+        // 0 and 2 matched so 3 is the next message
+        assert_eq!(rx.try_recv().unwrap(), 3);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn wait_for_count_respects_deadline() {
+        let (_tx, mut rx) = broadcast::channel::<u32>(16);
+        let wait_until = Instant::now() + Duration::from_millis(50);
+        let count = wait_for_count(&mut rx, 1, 10, wait_until, |_| Ok(true), || unreachable!()).await.unwrap();
+        assert_eq!(count, 1);
+        assert!(Instant::now() >= wait_until);
+    }
+
+    #[tokio::test]
+    async fn wait_for_count_recounts() {
+        let (tx, mut rx) = broadcast::channel(1);
+        tx.send(1).unwrap();
+        tx.send(2).unwrap();
+        let wait_until = Instant::now() + Duration::from_secs(10);
+        // Lagging replaces the count, then the receiver catches up to 2
+        let count = wait_for_count(&mut rx, 0, 6, wait_until, |_| Ok(true), || Ok(5)).await.unwrap();
+        assert_eq!(count, 6);
+
+        tx.send(3).unwrap();
+        let res = wait_for_count(&mut rx, 0, 10, wait_until, |_| Err(TaskManagerError::Gone), || Ok(0)).await;
+        assert!(matches!(res, Err(TaskManagerError::Gone)));
+
+        drop(tx);
+        let res = wait_for_count(&mut rx, 0, 10, wait_until, |_| Ok(true), || Err(TaskManagerError::Gone)).await;
+        assert!(matches!(res, Err(TaskManagerError::Gone)));
+    }
+
+    fn expiring_task(expire: SystemTime) -> MsgSigned<EncryptedMsgTaskRequest> {
+        let app = app("app");
+        MsgSigned {
+            msg: MsgTaskRequest { expire, ..task(&app, vec![app.clone()]) },
+            jwt: "Certainly valid".into(),
+        }
+    }
+
+    async fn next_event<S: Stream + Unpin>(stream: &mut S) -> Option<S::Item> {
+        std::future::poll_fn(|cx| std::pin::Pin::new(&mut *stream).poll_next(cx)).await
+    }
+
+    #[tokio::test]
+    async fn stream_all_results_follows_new_tasks() {
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(16, 1);
+        let expire = SystemTime::now() + Duration::from_secs(60);
+        let old_task = expiring_task(expire);
+        let owner = old_task.get_from().clone();
+        let old_id = old_task.wait_id();
+        task_manager.post_task(old_task).unwrap();
+        let mut stream = Box::pin(task_manager.clone().stream_all_results(owner.clone(), |_| true));
+        // Poll once so the stream subscribes before the next task is created
+        assert!(tokio::time::timeout(Duration::from_millis(50), next_event(&mut stream)).await.is_err());
+
+        let new_task = expiring_task(expire);
+        let new_id = new_task.wait_id();
+        task_manager.post_task(new_task).unwrap();
+        assert!(tokio::time::timeout(Duration::from_millis(50), next_event(&mut stream)).await.is_err());
+        for task_id in [new_id, old_id] {
+            let result = MsgSigned {
+                msg: result(task_id, &owner, &owner, WorkStatus::Succeeded),
+                // The task id is part of the signed result
+                jwt: task_id.to_string(),
+            };
+            task_manager.put_result(&task_id, result).unwrap();
+            let event = tokio::time::timeout(Duration::from_secs(1), next_event(&mut stream)).await.unwrap().unwrap().unwrap();
+            let event = format!("{event:?}");
+            assert!(event.contains("new_result") && event.contains(&task_id.to_string()), "{event}");
+        }
+    }
+
+    #[test]
+    fn slow_consumer_pushes_back_results() {
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::with_clock(16, 2, clock.clone());
+        let task = expiring_task(SystemTime::now() + Duration::from_secs(60));
+        let (task_id, worker) = (task.wait_id(), task.get_from().clone());
+        task_manager.post_task(task).unwrap();
+        let result = |status| MsgSigned {
+            msg: result(task_id, &worker, &worker, status),
+            jwt: task_id.to_string(),
+        };
+        // Without waiting clients results are never pushed back
+        for _ in 0..3 {
+            task_manager.put_result(&task_id, result(WorkStatus::Claimed)).unwrap();
+        }
+        let mut parked = task_manager.new_results.get(&task_id).unwrap().sender.subscribe();
+        task_manager.put_result(&task_id, result(WorkStatus::Claimed)).unwrap();
+        task_manager.put_result(&task_id, result(WorkStatus::Claimed)).unwrap();
+        let pushed_back = task_manager.put_result(&task_id, result(WorkStatus::Succeeded));
+        assert!(matches!(pushed_back, Err(TaskManagerError::Backlogged)));
+        let res = TaskManagerError::Backlogged.into_response();
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()[header::RETRY_AFTER], "1");
+        // Once the client has caught up, the result is accepted
+        parked.try_recv().unwrap();
+        task_manager.put_result(&task_id, result(WorkStatus::Succeeded)).unwrap();
+
+        // A client that does not catch up only holds results back for a while and then lags behind
+        assert!(matches!(task_manager.put_result(&task_id, result(WorkStatus::Succeeded)), Err(TaskManagerError::Backlogged)));
+        clock.advance(MAX_PUSH_BACK - Duration::from_secs(1));
+        assert!(matches!(task_manager.put_result(&task_id, result(WorkStatus::Succeeded)), Err(TaskManagerError::Backlogged)));
+        clock.advance(Duration::from_secs(1));
+        task_manager.put_result(&task_id, result(WorkStatus::Succeeded)).unwrap();
+        assert!(matches!(parked.try_recv(), Err(broadcast::error::TryRecvError::Lagged(1))));
+    }
+
+    #[tokio::test]
+    async fn task_events() {
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::with_clock(16, 1, clock.clone());
+        async fn next<S: Stream<Item = Result<Event, Infallible>> + Unpin>(events: &mut S) -> String {
+            format!("{:?}", tokio::time::timeout(Duration::from_secs(1), next_event(events)).await.unwrap().unwrap().unwrap())
+        }
+        let mut events = Box::pin(task_manager.stream_events());
+        let task = expiring_task(clock.now() + Duration::from_secs(60));
+        let (task_id, app) = (task.wait_id(), task.get_from().clone());
+        task_manager.post_task(task).unwrap();
+        let event = next(&mut events).await;
+        assert!(event.contains("new_task") && event.contains(&task_id.to_string()), "{event}");
+        // Bodies are never part of the events
+        assert!(!event.contains("body"), "{event}");
+        let result = MsgSigned {
+            msg: result(task_id, &app, &app, WorkStatus::TempFailed),
+            jwt: task_id.to_string(),
+        };
+        task_manager.put_result(&task_id, result.clone()).unwrap();
+        let event = next(&mut events).await;
+        assert!(event.contains("new_result") && event.contains("tempfailed"), "{event}");
+        task_manager.put_result(&task_id, result).unwrap();
+        assert!(next(&mut events).await.contains("updated_result"));
+        clock.advance(Duration::from_secs(61));
+        task_manager.remove_expired();
+        let event = next(&mut events).await;
+        assert!(event.contains("expired_task") && event.contains(&task_id.to_string()), "{event}");
+    }
+
+    #[test]
+    fn out_of_order_results() {
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::new(16, 1);
+        let task = expiring_task(SystemTime::now() + Duration::from_secs(60));
+        let (task_id, app) = (task.wait_id(), task.get_from().clone());
+        task_manager.post_task(task).unwrap();
+        let result = |seq, status| MsgSigned {
+            msg: MsgTaskResult { seq, ..result(task_id, &app, &app, status) },
+            jwt: task_id.to_string(),
+        };
+        let stored_status = || task_manager.get(&task_id).unwrap().msg.results[&app].msg.status;
+        task_manager.put_result(&task_id, result(Some(2), WorkStatus::Succeeded)).unwrap();
+        assert!(matches!(task_manager.put_result(&task_id, result(Some(1), WorkStatus::Claimed)), Err(TaskManagerError::Outdated)));
+        assert_eq!(stored_status(), WorkStatus::Succeeded);
+        // Retrying the same update is fine
+        assert!(task_manager.put_result(&task_id, result(Some(2), WorkStatus::Succeeded)).unwrap());
+        assert!(task_manager.put_result(&task_id, result(Some(3), WorkStatus::TempFailed)).unwrap());
+        assert!(task_manager.put_result(&task_id, result(None, WorkStatus::PermFailed)).unwrap());
+        assert_eq!(stored_status(), WorkStatus::PermFailed);
+    }
+
+    #[tokio::test]
+    async fn mass_expiry_wakes_result_waiters() {
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::with_clock(16, 1, clock.clone());
+        let expire = clock.now() + Duration::from_secs(60);
+        let ids: Vec<_> = (0..500).map(|_| {
+            let task = expiring_task(expire);
+            let id = task.wait_id();
+            task_manager.post_task(task).unwrap();
+            id
+        }).collect();
+
+        let waiter = {
+            let task_manager = task_manager.clone();
+            let id = ids[250];
+            tokio::spawn(async move {
+                let block = HowLongToBlock { wait_time: Some(Duration::from_secs(10)), wait_count: Some(1) };
+                task_manager.wait_for_results(&id, &block, |_| true).await.map(|_| ())
+            })
+        };
+        // Let the waiter subscribe before the tasks expire
+        tokio::task::yield_now().await;
+        clock.advance(Duration::from_secs(61));
+        task_manager.remove_expired();
+        let res = tokio::time::timeout(Duration::from_secs(1), waiter).await.expect("Waiter should wake up").unwrap();
+        assert!(matches!(res, Err(TaskManagerError::Gone)));
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn expiry_follows_clock() {
+        let clock = Arc::new(MockClock::new(SystemTime::now()));
+        let task_manager = TaskManager::<EncryptedMsgTaskRequest>::with_clock(16, 1, clock.clone());
+        let task = expiring_task(clock.now() + Duration::from_secs(60));
+        let task_id = task.wait_id();
+        task_manager.post_task(task.clone()).unwrap();
+        let waiter = {
+            let task_manager = task_manager.clone();
+            tokio::spawn(async move {
+                let block = HowLongToBlock { wait_time: Some(Duration::from_secs(30)), wait_count: Some(2) };
+                task_manager.wait_for_tasks(&block, |_| true).await.map(|tasks| tasks.count())
+            })
+        };
+
+        clock.advance(Duration::from_secs(59));
+        assert_eq!(task_manager.get_tasks_by(|_| true).count(), 1);
+        assert!(matches!(task_manager.post_task(task.clone()), Err(TaskManagerError::Conflict)));
+        // The waiter gives up after its wait time, which passes on tokio's clock without actually waiting
+        assert_eq!(waiter.await.unwrap().unwrap(), 1);
+
+        clock.advance(Duration::from_secs(2));
+        assert_eq!(task_manager.get_tasks_by(|_| true).count(), 0);
+        // Expired tasks can be replaced before they are removed
+        task_manager.post_task(task).unwrap();
+        assert!(task_manager.get(&task_id).is_ok());
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn recv_until_wakeups() {
+        let (tx, mut rx) = broadcast::channel(1);
+        let wait_until = Instant::now() + Duration::from_millis(50);
+        tx.send(1).unwrap();
+        assert_eq!(recv_until(&mut rx, wait_until).await, Wakeup::Received(1));
+        tx.send(2).unwrap();
+        tx.send(3).unwrap();
+        assert_eq!(recv_until(&mut rx, wait_until).await, Wakeup::Lagged(1));
+        assert_eq!(recv_until(&mut rx, wait_until).await, Wakeup::Received(3));
+        assert_eq!(recv_until(&mut rx, wait_until).await, Wakeup::Deadline);
+        drop(tx);
+        assert_eq!(recv_until(&mut rx, Instant::now() + Duration::from_secs(10)).await, Wakeup::Closed);
+    }
+}
@@ -0,0 +1,243 @@
+//! Pushes notifications about results and completed tasks to the webhooks configured for proxies,
+//! so their apps don't have to poll. Notifications only name the task and the apps involved, never the encrypted bodies.
+
+use std::{collections::{HashMap, HashSet}, sync::Arc, time::Duration};
+
+use beam_lib::{AppOrProxyId, MsgId, ProxyId};
+use serde::Serialize;
+use shared::{
+    config_broker::Webhook,
+    http_client::SamplyHttpClient,
+    openssl::{hash::MessageDigest, pkey::PKey, sign::Signer},
+    reqwest::StatusCode,
+    sse_event::SseEventType,
+    EncryptedMsgTaskRequest,
+};
+use tokio::sync::{broadcast, mpsc};
+use tracing::{debug, warn};
+
+use crate::task_manager::{TaskEvent, TaskManager};
+
+/// Header carrying the hex encoded HMAC-SHA256 of the body, keyed with the webhook's secret
+pub(crate) const SIGNATURE_HEADER: &str = "x-beam-signature";
+
+#[derive(Debug, Serialize)]
+pub(crate) struct Notification {
+    task_id: MsgId,
+    event: String,
+    from: AppOrProxyId,
+    to: Vec<AppOrProxyId>,
+}
+
+pub(crate) struct Webhooks {
+    webhooks: HashMap<ProxyId, Webhook>,
+    client: SamplyHttpClient,
+    max_attempts: u32,
+    /// Wait before the first retry, doubled for every further one
+    backoff: Duration,
+}
+
+impl Webhooks {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+    /// Notifications waiting for delivery to a single webhook, further ones are dropped
+    const QUEUE_SIZE: usize = 1024;
+
+    pub(crate) fn new(webhooks: HashMap<ProxyId, Webhook>, client: SamplyHttpClient) -> Self {
+        Self { webhooks, client, max_attempts: 5, backoff: Duration::from_secs(1) }
+    }
+
+    /// Notifies the proxy of the task's creator about every result and once the task is complete
+    pub(crate) fn spawn(self, task_manager: Arc<TaskManager<EncryptedMsgTaskRequest>>) {
+        let webhooks = Arc::new(self);
+        // Each webhook has its own queue, so notifications reach it in order and a slow webhook does not hold up the others
+        let queues: HashMap<ProxyId, mpsc::Sender<Notification>> = webhooks.webhooks.keys()
+            .map(|proxy| (proxy.clone(), webhooks.clone().spawn_worker(proxy.clone())))
+            .collect();
+        let mut events = task_manager.subscribe_events();
+        tokio::spawn(async move {
+            // Tasks whose completion has been notified already
+            let mut completed = HashSet::new();
+            loop {
+                let (task_id, worker, status, updated) = match events.recv().await {
+                    Ok(TaskEvent::ResultAdded { task_id, from, status, updated }) => (task_id, from, status, updated),
+                    Ok(TaskEvent::Expired { task_id } | TaskEvent::Deleted { task_id }) => {
+                        completed.remove(&task_id);
+                        continue;
+                    }
+                    Ok(_) => continue,
+                    Err(broadcast::error::RecvError::Lagged(n)) => {
+                        warn!("Missed {n} task events, their webhook notifications are lost");
+                        continue;
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                };
+                let Ok(task) = task_manager.get(&task_id) else {
+                    continue;
+                };
+                let (creator, recipients, complete) = (task.msg.from.clone(), task.msg.to.clone(), task.msg.is_complete());
+                drop(task);
+                let proxy = creator.proxy_id();
+                let Some(queue) = queues.get(&proxy) else {
+                    continue;
+                };
+                let event = if updated { SseEventType::UpdatedResult } else { SseEventType::NewResult };
+                let mut notifications = vec![Notification { task_id, event: event.to_string(), from: worker, to: vec![creator.clone()] }];
+                // Only a final result completes a task, even if later results have already completed it by now
+                if complete && status.is_terminal() && completed.insert(task_id) {
+                    notifications.push(Notification { task_id, event: SseEventType::Complete.to_string(), from: creator, to: recipients });
+                }
+                for notification in notifications {
+                    if let Err(mpsc::error::TrySendError::Full(notification)) = queue.try_send(notification) {
+                        warn!("Too many pending notifications for the webhook of {proxy}, dropping {} of task {task_id}", notification.event);
+                    }
+                }
+            }
+        });
+    }
+
+    /// Delivers the notifications queued for the webhook of `proxy` one after another in the background
+    fn spawn_worker(self: Arc<Self>, proxy: ProxyId) -> mpsc::Sender<Notification> {
+        let (queue, mut notifications) = mpsc::channel(Self::QUEUE_SIZE);
+        tokio::spawn(async move {
+            let webhook = &self.webhooks[&proxy];
+            while let Some(notification) = notifications.recv().await {
+                self.deliver(webhook, &notification).await;
+            }
+        });
+        queue
+    }
+
+    async fn deliver(&self, webhook: &Webhook, notification: &Notification) {
+        let body = serde_json::to_vec(notification).expect("Notifications are serializable");
+        let signature = sign(&webhook.secret, &body);
+        let mut backoff = self.backoff;
+        for attempt in 1..=self.max_attempts {
+            let res = self.client
+                .post(webhook.url.clone())
+                .header(shared::reqwest::header::CONTENT_TYPE, "application/json")
+                .header(SIGNATURE_HEADER, &signature)
+                .body(body.clone())
+                .timeout(Self::TIMEOUT)
+                .send()
+                .await;
+            match res {
+                Ok(res) if res.status().is_success() => {
+                    debug!("Notified {} about {} of task {}", webhook.url, notification.event, notification.task_id);
+                    return;
+                }
+                // The webhook refuses the notification, so trying again won't help
+                Ok(res) if res.status().is_client_error() && res.status() != StatusCode::TOO_MANY_REQUESTS => {
+                    warn!("Webhook {} rejected notification about task {} with {}", webhook.url, notification.task_id, res.status());
+                    return;
+                }
+                Ok(res) => warn!("Webhook {} answered notification about task {} with {} (attempt {attempt})", webhook.url, notification.task_id, res.status()),
+                Err(e) => warn!("Failed to notify webhook {} about task {} (attempt {attempt}): {e}", webhook.url, notification.task_id),
+            }
+            if attempt < self.max_attempts {
+                tokio::time::sleep(backoff).await;
+                backoff *= 2;
+            }
+        }
+        warn!("Giving up notifying webhook {} about task {}", webhook.url, notification.task_id);
+    }
+}
+
+/// Signs the body with HMAC-SHA256 so the receiver can tell that the notification comes from the broker
+pub(crate) fn sign(secret: &str, body: &[u8]) -> String {
+    let key = PKey::hmac(secret.as_bytes()).expect("Any key is a valid HMAC key");
+    let mut signer = Signer::new(MessageDigest::sha256(), &key).expect("SHA-256 is supported");
+    let mac = signer.sign_oneshot_to_vec(body).expect("Signing with HMAC does not fail");
+    let hex: String = mac.iter().map(|byte| format!("{byte:02x}")).collect();
+    format!("sha256={hex}")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::Mutex, time::SystemTime};
+
+    use axum::{extract::State, http::HeaderMap, routing::post, Router};
+    use beam_lib::{CompletionPolicy, FailureStrategy, WorkStatus};
+    use serde_json::Value;
+    use shared::{Encrypted, MsgSigned, MsgTaskRequest, MsgTaskResult};
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use shared::test_utils::{app, encrypted};
+
+    type Received = mpsc::UnboundedSender<Value>;
+
+    /// Fails the first request and records the following ones if their signature is valid
+    async fn receive(State((received, attempts)): State<(Received, Arc<Mutex<u32>>)>, headers: HeaderMap, body: axum::body::Bytes) -> StatusCode {
+        *attempts.lock().unwrap() += 1;
+        if *attempts.lock().unwrap() == 1 {
+            return StatusCode::SERVICE_UNAVAILABLE;
+        }
+        if headers.get(SIGNATURE_HEADER).and_then(|sig| sig.to_str().ok()) != Some(&sign("secret", &body)) {
+            return StatusCode::UNAUTHORIZED;
+        }
+        received.send(serde_json::from_slice(&body).unwrap()).unwrap();
+        StatusCode::NO_CONTENT
+    }
+
+    async fn next(notifications: &mut mpsc::UnboundedReceiver<Value>) -> Value {
+        tokio::time::timeout(Duration::from_secs(5), notifications.recv()).await.expect("Webhook is notified in time").unwrap()
+    }
+
+    #[tokio::test]
+    async fn notifies_webhook_with_retries() {
+        let (creator, worker) = (app("app1"), app("app2"));
+        let other = AppOrProxyId::new("app1.proxy2.broker.samply.de").unwrap();
+        let (received, mut notifications) = mpsc::unbounded_channel();
+        let receiver = Router::new().route("/webhook", post(receive)).with_state((received, Default::default()));
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/webhook", listener.local_addr().unwrap()).parse().unwrap();
+        tokio::spawn(async move { axum::serve(listener, receiver).await });
+
+        let task_manager = TaskManager::new(16, 16);
+        let webhook = Webhook { url, secret: "secret".into() };
+        let mut webhooks = Webhooks::new(HashMap::from([(creator.proxy_id(), webhook)]), SamplyHttpClient::new());
+        webhooks.backoff = Duration::from_millis(50);
+        webhooks.spawn(task_manager.clone());
+
+        let task = MsgTaskRequest {
+            id: MsgId::new(),
+            from: creator.clone(),
+            to: vec![worker.clone()],
+            body: encrypted(),
+            expire: SystemTime::now() + Duration::from_secs(60),
+            failure_strategy: FailureStrategy::Discard,
+            completion_policy: Some(CompletionPolicy::All),
+            deadline: None,
+            parent_task: None,
+            results: Default::default(),
+            metadata: Value::Null,
+        };
+        let task_id = task.id;
+        task_manager.post_task(MsgSigned { msg: task, jwt: String::new() }).unwrap();
+        let result = |status| MsgSigned {
+            msg: MsgTaskResult { from: worker.clone(), to: vec![creator.clone()], task: task_id, status, body: encrypted(), metadata: Value::Null, seq: None },
+            jwt: "encrypted result".into(),
+        };
+        // The first notification is retried while the others wait for it
+        task_manager.put_result(&task_id, result(WorkStatus::Claimed)).unwrap();
+        task_manager.put_result(&task_id, result(WorkStatus::Succeeded)).unwrap();
+        let mut received = Vec::new();
+        for _ in 0..3 {
+            received.push(next(&mut notifications).await);
+        }
+        let events: Vec<_> = received.iter().map(|notification| (notification["event"].as_str().unwrap(), notification["task_id"].as_str().unwrap().to_string())).collect();
+        assert_eq!(events, [("new_result", task_id.to_string()), ("updated_result", task_id.to_string()), ("complete", task_id.to_string())], "Notifications arrive in order");
+        assert_eq!(received[0]["from"], worker.to_string());
+        assert_eq!(received[2]["to"], serde_json::json!([worker.to_string()]));
+        assert!(received.iter().all(|notification| !notification.to_string().contains("encrypted")), "Bodies are never sent");
+
+        // Tasks of proxies without a webhook are not notified
+        let unwatched = MsgTaskRequest { id: MsgId::new(), from: other.clone(), to: vec![worker.clone()], ..task_manager.get(&task_id).unwrap().msg.clone() };
+        let unwatched_id = unwatched.id;
+        task_manager.post_task(MsgSigned { msg: unwatched, jwt: String::new() }).unwrap();
+        task_manager.put_result(&unwatched_id, MsgSigned { msg: MsgTaskResult { task: unwatched_id, to: vec![other.clone()], ..result(WorkStatus::Succeeded).msg }, jwt: String::new() }).unwrap();
+        task_manager.put_result(&task_id, result(WorkStatus::PermFailed)).unwrap();
+        assert_eq!(next(&mut notifications).await["event"], "updated_result");
+        assert!(notifications.try_recv().is_err());
+    }
+}
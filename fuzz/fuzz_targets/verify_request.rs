@@ -0,0 +1,67 @@
+//! Verifies requests like the broker does for requests received from proxies
+#![no_main]
+
+use axum::http::{header, HeaderValue, Request};
+use beam_fuzz::{Token, PROXY_ID, SIGNER};
+use beam_lib::AppOrProxyId;
+use libfuzzer_sys::{arbitrary::{self, Arbitrary}, fuzz_target};
+use shared::{crypto_jwt::{fuzzing, make_extra_fields_digest}, EncryptedMessage};
+
+#[derive(Debug, Arbitrary)]
+struct Input<'a> {
+    method: &'a str,
+    uri: &'a str,
+    date: &'a str,
+    body: Token<'a>,
+    header: HeaderToken<'a>,
+}
+
+#[derive(Debug, Arbitrary)]
+enum HeaderToken<'a> {
+    Token(Token<'a>),
+    /// The signed digest of the request and body token like the proxy creates it
+    Digest(Sender<'a>),
+}
+
+#[derive(Debug, Arbitrary)]
+enum Sender<'a> {
+    Proxy,
+    App,
+    Other(&'a str),
+}
+
+fuzz_target!(|input: Input| {
+    let Some(body) = input.body.to_jwt() else {
+        return;
+    };
+    let Ok(req) = Request::builder()
+        .method(input.method)
+        .uri(input.uri)
+        .header(header::DATE, input.date)
+        .body(())
+    else {
+        return;
+    };
+    let (mut parts, ()) = req.into_parts();
+    let header = match input.header {
+        HeaderToken::Token(token) => token.to_jwt(),
+        HeaderToken::Digest(sender) => {
+            let sender = match sender {
+                Sender::Proxy => PROXY_ID.to_string(),
+                Sender::App => format!("app1.{PROXY_ID}"),
+                Sender::Other(id) => id.to_string(),
+            };
+            let (Ok(sender), Some((_, sig))) = (AppOrProxyId::new(&sender), body.rsplit_once('.')) else {
+                return;
+            };
+            make_extra_fields_digest(&parts.method, &parts.uri, &parts.headers, sig, &sender)
+                .ok()
+                .and_then(|digest| SIGNER.sign(digest))
+        }
+    };
+    let Some(header) = header.and_then(|token| HeaderValue::from_str(&format!("SamplyJWT {token}")).ok()) else {
+        return;
+    };
+    parts.headers.insert(header::AUTHORIZATION, header);
+    let _ = fuzzing::verify_request::<EncryptedMessage>(&parts, &body, &SIGNER.public);
+});
@@ -0,0 +1,12 @@
+//! Verifies tokens like the proxy does for messages received from the broker
+#![no_main]
+
+use beam_fuzz::{Token, SIGNER};
+use libfuzzer_sys::fuzz_target;
+use shared::{crypto_jwt::fuzzing, EncryptedMessage};
+
+fuzz_target!(|token: Token| {
+    if let Some(token) = token.to_jwt() {
+        let _ = fuzzing::verify_token::<EncryptedMessage>(&token, &SIGNER.public);
+    }
+});
@@ -0,0 +1,72 @@
+//! Setup shared by the fuzz targets: the certificate of a proxy to verify tokens with and its key,
+//! which signs arbitrary claims so the fuzzer also gets past the signature checks.
+
+use std::sync::LazyLock;
+
+use beam_lib::ProxyId;
+use jwt_simple::prelude::{Claims, Duration, RS256KeyPair, RSAKeyPairLike};
+use libfuzzer_sys::arbitrary::{self, Arbitrary};
+use serde_json::Value;
+use shared::{
+    crypto::CryptoPublicPortion,
+    openssl::{asn1::Asn1Time, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::{X509NameBuilder, X509}},
+};
+
+pub const PROXY_ID: &str = "proxy1.broker.samply.de";
+
+pub static SIGNER: LazyLock<Signer> = LazyLock::new(Signer::new);
+
+pub struct Signer {
+    pub public: CryptoPublicPortion,
+    key: RS256KeyPair,
+}
+
+impl Signer {
+    fn new() -> Self {
+        beam_lib::set_broker_id("broker.samply.de".to_string());
+        let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+        let mut name = X509NameBuilder::new().unwrap();
+        name.append_entry_by_text("CN", PROXY_ID).unwrap();
+        let name = name.build();
+        let mut cert = X509::builder().unwrap();
+        cert.set_version(2).unwrap();
+        cert.set_subject_name(&name).unwrap();
+        cert.set_issuer_name(&name).unwrap();
+        cert.set_pubkey(&key).unwrap();
+        cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+        cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+        cert.sign(&key, MessageDigest::sha256()).unwrap();
+        let private_pem = key.private_key_to_pem_pkcs8().unwrap();
+        Self {
+            public: CryptoPublicPortion {
+                beam_id: ProxyId::new(PROXY_ID).unwrap(),
+                cert: cert.build(),
+                pubkey: String::from_utf8(key.public_key_to_pem().unwrap()).unwrap(),
+            },
+            key: RS256KeyPair::from_pem(std::str::from_utf8(&private_pem).unwrap()).unwrap(),
+        }
+    }
+
+    /// Signs the claims like the proxy does. Fails for claims that are not a JSON object.
+    pub fn sign(&self, claims: impl serde::Serialize) -> Option<String> {
+        let claims = serde_json::to_value(claims).ok()?;
+        self.key.sign(Claims::with_custom_claims(claims, Duration::from_hours(1))).ok()
+    }
+}
+
+#[derive(Debug, Arbitrary)]
+pub enum Token<'a> {
+    /// Arbitrary bytes, which hardly ever carry a valid signature
+    Raw(&'a str),
+    /// Arbitrary JSON claims with a valid signature
+    Signed(&'a str),
+}
+
+impl Token<'_> {
+    pub fn to_jwt(&self) -> Option<String> {
+        match self {
+            Token::Raw(token) => Some(token.to_string()),
+            Token::Signed(claims) => SIGNER.sign(serde_json::from_str::<Value>(claims).ok()?),
+        }
+    }
+}
@@ -1,55 +1,218 @@
-use std::collections::HashMap;
+use std::{collections::HashMap, time::Duration};
 
 use axum::{
     async_trait,
     extract::{FromRequest, FromRequestParts},
-    http::{header::{self, HeaderName}, request::Parts, Request, StatusCode},
+    http::{header::{self, HeaderName}, request::Parts, HeaderMap, Request, StatusCode},
 };
 use beam_lib::{AppId, AppOrProxyId};
+use once_cell::sync::Lazy;
+use serde_json::json;
 use shared::{
-    config, config_proxy
+    config, config_proxy::{self, ApiKey}, http_client::{self, SamplyHttpClient}, reqwest::Url,
 };
 
 use tracing::{debug, Span, debug_span, warn};
 
-pub(crate) struct AuthenticatedApp(pub(crate) AppId);
+use crate::crypto_pool::CRYPTO_POOL;
 
+/// Decides whether an app may use the proxy with the API key it presented
 #[async_trait]
-impl<S: Send + Sync> FromRequestParts<S> for AuthenticatedApp {
-    type Rejection = (StatusCode, [(HeaderName, &'static str); 1]);
+pub(crate) trait AppAuthenticator: Send + Sync {
+    async fn authenticate(&self, app_id: &AppId, presented_key: &str) -> bool;
+}
 
-    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
-        const SCHEME: &str = "ApiKey";
-        const UNAUTH_ERR: (StatusCode, [(HeaderName, &str); 1]) = (
-            StatusCode::UNAUTHORIZED,
-            [(header::WWW_AUTHENTICATE, SCHEME)],
-        );
-        if let Some(auth) = parts.headers.get(header::AUTHORIZATION) {
-            let auth_str = auth.to_str().map_err(|_| UNAUTH_ERR)?;
-            let mut auth = auth_str.split(' ');
-            if auth.next() != Some(SCHEME) {
-                warn!(auth_str, "Invalid auth scheme");
-                return Err(UNAUTH_ERR);
+/// The API keys configured with `APP_<name>_KEY`
+pub(crate) struct StaticApiKeys(pub(crate) HashMap<AppId, ApiKey>);
+
+#[async_trait]
+impl AppAuthenticator for StaticApiKeys {
+    async fn authenticate(&self, app_id: &AppId, presented_key: &str) -> bool {
+        let Some(api_key_actual) = self.0.get(app_id) else {
+            warn!("App {app_id} not registered in proxy");
+            return false;
+        };
+        let valid = match api_key_actual {
+            ApiKey::Plain(_) => api_key_actual.verify(presented_key),
+            ApiKey::Hashed(_) => {
+                let (api_key_actual, presented_key) = (api_key_actual.clone(), presented_key.to_owned());
+                CRYPTO_POOL.run(move || api_key_actual.verify(&presented_key)).await.unwrap_or_else(|e| {
+                    warn!("Unable to check the api key of app {app_id}: {e}");
+                    false
+                })
             }
-            let Some(client_id) = auth.next().and_then(|s| AppId::new(s).ok()) else {
-                warn!(auth_str, "Invalid app id");
-                return Err(UNAUTH_ERR);
-            };
-            let Some(api_key_actual) = config::CONFIG_PROXY.api_keys.get(&client_id) else {
-                warn!("App {client_id} not registered in proxy");
-                return Err(UNAUTH_ERR);
-            };
-            let api_key_claimed = auth.next().ok_or(UNAUTH_ERR)?;
-            if api_key_claimed != api_key_actual {
-                warn!("App {client_id} provided the wrong api key");
-                return Err(UNAUTH_ERR);
+        };
+        if !valid {
+            warn!("App {app_id} provided the wrong api key");
+            return false;
+        }
+        true
+    }
+}
+
+/// Asks an external service like an existing secret store whether the API key of an app is valid
+pub(crate) struct ExternalAuthenticator {
+    url: Url,
+    client: SamplyHttpClient,
+}
+
+impl ExternalAuthenticator {
+    pub(crate) fn new(url: Url, client: SamplyHttpClient) -> Self {
+        Self { url, client }
+    }
+}
+
+#[async_trait]
+impl AppAuthenticator for ExternalAuthenticator {
+    async fn authenticate(&self, app_id: &AppId, presented_key: &str) -> bool {
+        let res = self.client
+            .post(self.url.clone())
+            .json(&json!({"app_id": app_id, "api_key": presented_key}))
+            .send()
+            .await;
+        match res {
+            Ok(res) if res.status().is_success() => true,
+            Ok(res) => {
+                warn!("Auth service rejected the api key of app {app_id} with status {}", res.status());
+                false
             }
-            debug!("Request authenticated (ClientID {})", client_id);
-            Span::current().record("from", AppOrProxyId::App(client_id.clone()).hide_broker());
-            Ok(Self(client_id))
+            Err(e) => {
+                warn!("Unable to reach auth service to authenticate app {app_id}: {e}");
+                false
+            }
+        }
+    }
+}
+
+/// Asks the static API keys first and the external service only for apps without a static key
+struct StaticThenExternal(StaticApiKeys, ExternalAuthenticator);
+
+#[async_trait]
+impl AppAuthenticator for StaticThenExternal {
+    async fn authenticate(&self, app_id: &AppId, presented_key: &str) -> bool {
+        if self.0.0.contains_key(app_id) {
+            self.0.authenticate(app_id, presented_key).await
         } else {
-            warn!("No auth header provided");
-            Err(UNAUTH_ERR)
+            self.1.authenticate(app_id, presented_key).await
         }
     }
 }
+
+static AUTHENTICATOR: Lazy<Box<dyn AppAuthenticator>> = Lazy::new(|| {
+    let static_keys = StaticApiKeys(config::CONFIG_PROXY.api_keys.clone());
+    let Some(url) = config::CONFIG_PROXY.auth_url.clone() else {
+        return Box::new(static_keys);
+    };
+    let client = http_client::build(&config::CONFIG_SHARED.tls_ca_certificates, Some(Duration::from_secs(10)), None)
+        .expect("Building the same client as for the broker succeeded at startup");
+    Box::new(StaticThenExternal(static_keys, ExternalAuthenticator::new(url, client)))
+});
+
+pub(crate) struct AuthenticatedApp(pub(crate) AppId);
+
+type Rejection = (StatusCode, [(HeaderName, &'static str); 1]);
+
+#[async_trait]
+impl<S: Send + Sync> FromRequestParts<S> for AuthenticatedApp {
+    type Rejection = Rejection;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        authenticate(AUTHENTICATOR.as_ref(), &parts.headers).await.map(Self)
+    }
+}
+
+/// Checks the `Authorization: ApiKey <app_id> <api_key>` header of a request with the given authenticator
+async fn authenticate(authenticator: &dyn AppAuthenticator, headers: &HeaderMap) -> Result<AppId, Rejection> {
+    const SCHEME: &str = "ApiKey";
+    const UNAUTH_ERR: Rejection = (
+        StatusCode::UNAUTHORIZED,
+        [(header::WWW_AUTHENTICATE, SCHEME)],
+    );
+    if let Some(auth) = headers.get(header::AUTHORIZATION) {
+        let auth_str = auth.to_str().map_err(|_| UNAUTH_ERR)?;
+        let mut auth = auth_str.split(' ');
+        if auth.next() != Some(SCHEME) {
+            warn!(auth_str, "Invalid auth scheme");
+            return Err(UNAUTH_ERR);
+        }
+        let Some(client_id) = auth.next().and_then(|s| AppId::new(s).ok()) else {
+            warn!(auth_str, "Invalid app id");
+            return Err(UNAUTH_ERR);
+        };
+        let api_key_claimed = auth.next().ok_or(UNAUTH_ERR)?;
+        if !authenticator.authenticate(&client_id, api_key_claimed).await {
+            return Err(UNAUTH_ERR);
+        }
+        debug!("Request authenticated (ClientID {})", client_id);
+        Span::current().record("from", AppOrProxyId::App(client_id.clone()).hide_broker());
+        Ok(client_id)
+    } else {
+        warn!("No auth header provided");
+        Err(UNAUTH_ERR)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::HeaderValue, routing::post, Json, Router};
+    use serde_json::Value;
+    use shared::test_utils::app_id;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    /// Accepts only app1 with the key "secret"
+    struct MockAuthenticator;
+
+    #[async_trait]
+    impl AppAuthenticator for MockAuthenticator {
+        async fn authenticate(&self, app_id: &AppId, presented_key: &str) -> bool {
+            app_id.to_string().starts_with("app1.") && presented_key == "secret"
+        }
+    }
+
+    fn auth_header(value: &str) -> HeaderMap {
+        HeaderMap::from_iter([(header::AUTHORIZATION, HeaderValue::from_str(value).unwrap())])
+    }
+
+    #[tokio::test]
+    async fn authenticate_with_mock() {
+        let app1 = app_id("app1", "proxy1");
+        let authenticated = authenticate(&MockAuthenticator, &auth_header(&format!("ApiKey {app1} secret"))).await;
+        assert_eq!(authenticated.unwrap(), app1);
+        for rejected in [
+            format!("ApiKey {app1} wrong"),
+            format!("ApiKey {} secret", app_id("app2", "proxy1")),
+            format!("ApiKey {app1}"),
+            format!("Bearer {app1} secret"),
+            "ApiKey not-an-app secret".to_string(),
+        ] {
+            assert!(authenticate(&MockAuthenticator, &auth_header(&rejected)).await.is_err(), "{rejected} should be rejected");
+        }
+        assert!(authenticate(&MockAuthenticator, &HeaderMap::new()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn external_authenticator() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/auth", listener.local_addr().unwrap()).parse().unwrap();
+        let service = Router::new().route("/auth", post(|Json(req): Json<Value>| async move {
+            if req["api_key"] == "secret" && req["app_id"].as_str().is_some_and(|id| id.starts_with("app2.")) {
+                StatusCode::NO_CONTENT
+            } else {
+                StatusCode::FORBIDDEN
+            }
+        }));
+        tokio::spawn(async move { axum::serve(listener, service).await });
+
+        let authenticator = StaticThenExternal(
+            StaticApiKeys(HashMap::from([(app_id("app1", "proxy1"), ApiKey::Plain("static".to_string()))])),
+            ExternalAuthenticator::new(url, SamplyHttpClient::new()),
+        );
+        assert!(authenticator.authenticate(&app_id("app1", "proxy1"), "static").await);
+        assert!(authenticator.authenticate(&app_id("app2", "proxy1"), "secret").await);
+        assert!(!authenticator.authenticate(&app_id("app2", "proxy1"), "static").await);
+        // Apps with a static key are never passed on to the external service
+        assert!(!authenticator.authenticate(&app_id("app1", "proxy1"), "secret").await);
+    }
+}
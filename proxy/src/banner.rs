@@ -1,13 +1,27 @@
 use axum::{http::{HeaderValue, header}, response::Response};
+use shared::config_proxy;
 use tracing::info;
 
-pub(crate) fn print_banner() {
+/// Logs the version this proxy was built from. With `no_banner` this is a single structured event for log collectors.
+pub(crate) fn print_banner(config: &config_proxy::Config) {
     let commit = match env!("GIT_DIRTY") {
         "false" => {
             env!("GIT_COMMIT_SHORT")
         }
         _ => "SNAPSHOT",
     };
+    if config.no_banner {
+        info!(
+            name = env!("CARGO_PKG_NAME"),
+            version = env!("CARGO_PKG_VERSION"),
+            commit,
+            bind_addr = %config.bind_addr,
+            broker_id = beam_lib::get_broker_id(),
+            proxy_id = %config.proxy_id,
+            "Starting up"
+        );
+        return;
+    }
     info!(
         "🌈 Samply.Beam ({}) v{} (built {} {}, {} with feature(s): {}) starting up ...",
         env!("CARGO_PKG_NAME"),
@@ -0,0 +1,202 @@
+use std::{error::Error, io, sync::atomic::{AtomicUsize, Ordering}};
+
+use once_cell::sync::Lazy;
+use shared::{config::CONFIG_PROXY, http_client::SamplyHttpClient, reqwest::{self, header::{self, HeaderValue}, Url}};
+use tracing::{debug, info, warn};
+
+/// The configured broker upstreams, starting with the primary broker
+pub(crate) static BROKERS: Lazy<Brokers> = Lazy::new(|| {
+    let mut uris = vec![CONFIG_PROXY.broker_uri.clone()];
+    uris.extend(CONFIG_PROXY.fallback_broker_uris.iter().cloned());
+    Brokers::new(uris)
+});
+
+/// A list of interchangeable broker upstreams serving the same broker identity.
+/// Requests are sent to the broker that answered last and move on to the next one if it cannot be connected to.
+/// All requests are built against the primary broker's URL and are sent with the `Host` header of the broker they go to.
+/// Signatures only cover the path and query, which all brokers share, so they stay valid at each of them.
+pub(crate) struct Brokers {
+    uris: Vec<Url>,
+    current: AtomicUsize,
+}
+
+impl Brokers {
+    pub(crate) fn new(uris: Vec<Url>) -> Self {
+        assert!(!uris.is_empty(), "At least one broker has to be configured");
+        assert!(uris.iter().all(|uri| uri.path() == uris[0].path()), "Brokers have to share their path");
+        Self { uris, current: AtomicUsize::new(0) }
+    }
+
+    /// The broker that is currently considered healthy
+    pub(crate) fn current(&self) -> &Url {
+        &self.uris[self.current.load(Ordering::Relaxed)]
+    }
+
+    /// Sends a request built against the primary broker to the current broker, failing over to the other brokers on connection errors
+    pub(crate) async fn execute(&self, client: &SamplyHttpClient, mut req: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+        let start = self.current.load(Ordering::Relaxed);
+        let mut last_err = None;
+        for offset in 0..self.uris.len() {
+            let index = (start + offset) % self.uris.len();
+            let is_last = offset + 1 == self.uris.len();
+            // Streaming bodies can't be retried so they only get one attempt
+            let mut attempt = match req.try_clone() {
+                Some(attempt) if !is_last => attempt,
+                _ => {
+                    self.rebase(&mut req, index);
+                    let res = send(client, req).await;
+                    if res.is_ok() {
+                        self.mark_healthy(start, index);
+                    }
+                    return res;
+                }
+            };
+            self.rebase(&mut attempt, index);
+            match send(client, attempt).await {
+                Err(e) if e.is_connect() => {
+                    warn!("Unable to connect to broker {}: {e}", self.uris[index]);
+                    last_err = Some(e);
+                },
+                res => {
+                    self.mark_healthy(start, index);
+                    return res;
+                }
+            }
+        }
+        Err(last_err.expect("Loop returns on the last broker"))
+    }
+
+    fn mark_healthy(&self, previous: usize, index: usize) {
+        if previous != index {
+            info!("Failing over to broker {}", self.uris[index]);
+            self.current.store(index, Ordering::Relaxed);
+        }
+    }
+
+    /// Points `req` at the broker with the given index. The brokers only differ in scheme, host and port.
+    fn rebase(&self, req: &mut reqwest::Request, index: usize) {
+        let broker = &self.uris[index];
+        let url = req.url_mut();
+        url.set_scheme(broker.scheme()).expect("Broker URLs are validated to be http(s)");
+        url.set_host(broker.host_str()).expect("Broker URLs are validated to have a host");
+        url.set_port(broker.port()).expect("Broker URLs are validated to be http(s)");
+        let host = HeaderValue::from_str(url.authority()).expect("Broker URLs are validated to have a host");
+        req.headers_mut().insert(header::HOST, host);
+    }
+}
+
+/// Sends the request, retrying it once if it went over a pooled connection that had already been closed while idle,
+/// e.g. by a firewall. Only idempotent requests are retried as the broker may have received them before the connection broke.
+async fn send(client: &SamplyHttpClient, req: reqwest::Request) -> reqwest::Result<reqwest::Response> {
+    let retry = req.method().is_idempotent().then(|| req.try_clone()).flatten();
+    match (client.execute(req).await, retry) {
+        (Err(e), Some(retry)) if is_stale_connection(&e) => {
+            debug!("Retrying request on a new connection: {e}");
+            client.execute(retry).await
+        }
+        (res, _) => res,
+    }
+}
+
+/// Whether the connection broke after sending the request, which is what a stale pooled connection looks like
+fn is_stale_connection(e: &reqwest::Error) -> bool {
+    if e.is_connect() || e.is_timeout() {
+        return false;
+    }
+    let mut source = e.source();
+    while let Some(err) = source {
+        if err.downcast_ref::<hyper::Error>().is_some_and(hyper::Error::is_incomplete_message)
+            || err.downcast_ref::<io::Error>().is_some_and(|e| matches!(e.kind(), io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted | io::ErrorKind::BrokenPipe))
+        {
+            return true;
+        }
+        source = err.source();
+    }
+    false
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+    use tokio::{io::{AsyncReadExt, AsyncWriteExt}, net::TcpListener};
+
+    use super::*;
+
+    async fn mock_broker(name: &'static str) -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let host = get(|headers: axum::http::HeaderMap| async move { headers[header::HOST].to_str().unwrap().to_string() });
+            axum::serve(listener, Router::new().route("/v1/health", get(move || async move { name })).route("/host", host)).await
+        });
+        format!("http://{addr}/").parse().unwrap()
+    }
+
+    async fn unreachable_broker() -> Url {
+        // Bind and drop a listener so nothing listens on the port
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap()
+    }
+
+    async fn ask(brokers: &Brokers, client: &SamplyHttpClient) -> reqwest::Result<String> {
+        let req = client.get(brokers.uris[0].join("v1/health").unwrap()).build().unwrap();
+        brokers.execute(client, req).await?.text().await
+    }
+
+    #[tokio::test]
+    async fn fails_over_between_brokers() {
+        let client = SamplyHttpClient::new();
+        let primary = unreachable_broker().await;
+        let fallback = mock_broker("fallback").await;
+        let brokers = Brokers::new(vec![primary.clone(), fallback.clone()]);
+
+        assert_eq!(ask(&brokers, &client).await.unwrap(), "fallback");
+        assert_eq!(brokers.current(), &fallback);
+        // Sticks with the healthy broker
+        assert_eq!(ask(&brokers, &client).await.unwrap(), "fallback");
+        assert_eq!(brokers.current(), &fallback);
+        // Signed requests carry the primary broker's host, but each broker gets its own
+        let req = client.get(primary.join("host").unwrap()).header(header::HOST, primary.authority()).build().unwrap();
+        assert_eq!(brokers.execute(&client, req).await.unwrap().text().await.unwrap(), fallback.authority());
+    }
+
+    /// Answers the first request of every connection and closes it on the second one without answering, like a firewall dropping idle connections
+    async fn closing_broker() -> Url {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut conn, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buf = [0; 4096];
+                    assert!(conn.read(&mut buf).await.unwrap() > 0);
+                    conn.write_all(b"HTTP/1.1 200 OK\r\nContent-Length: 2\r\n\r\nok").await.unwrap();
+                    let _ = conn.read(&mut buf).await;
+                });
+            }
+        });
+        format!("http://{addr}/").parse().unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_on_stale_connections() {
+        let client = SamplyHttpClient::new();
+        let brokers = Brokers::new(vec![closing_broker().await]);
+        for _ in 0..3 {
+            assert_eq!(ask(&brokers, &client).await.unwrap(), "ok");
+        }
+        // The broker may have already processed other requests
+        let client = SamplyHttpClient::new();
+        let post = || client.post(brokers.uris[0].join("v1/tasks").unwrap()).build().unwrap();
+        brokers.execute(&client, post()).await.unwrap();
+        assert!(brokers.execute(&client, post()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn fails_when_no_broker_is_reachable() {
+        let client = SamplyHttpClient::new();
+        let brokers = Brokers::new(vec![unreachable_broker().await, unreachable_broker().await]);
+        assert!(ask(&brokers, &client).await.unwrap_err().is_connect());
+        assert_eq!(brokers.current(), &brokers.uris[0]);
+    }
+}
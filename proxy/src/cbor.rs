@@ -0,0 +1,126 @@
+use axum::{
+    body::{to_bytes, Body},
+    http::{header, HeaderMap, HeaderValue},
+    response::{IntoResponse, Response},
+};
+use serde_json::Value;
+use tracing::warn;
+
+use crate::serve_tasks::ERR_UPSTREAM;
+
+/// Apps may send and receive messages as CBOR instead of JSON.
+/// Messages are converted to JSON at the Proxy, so they are signed and sent to the Broker the same way regardless of the App's encoding.
+pub(crate) const APPLICATION_CBOR: &str = "application/cbor";
+
+pub(crate) fn is_cbor(headers: &HeaderMap) -> bool {
+    headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|v| v.to_str().ok())
+        .is_some_and(|v| v.split(';').next().unwrap_or_default().trim() == APPLICATION_CBOR)
+}
+
+pub(crate) fn accepts_cbor(headers: &HeaderMap) -> bool {
+    headers
+        .get_all(header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|part| part.split(';').next().unwrap_or_default().trim() == APPLICATION_CBOR)
+}
+
+/// CBOR byte strings have no JSON equivalent and are rejected
+pub(crate) fn to_json(cbor: &[u8]) -> Result<Value, String> {
+    ciborium::from_reader(cbor).map_err(|e| e.to_string())
+}
+
+pub(crate) fn from_json(json: &Value) -> Vec<u8> {
+    let mut cbor = Vec::new();
+    ciborium::into_writer(json, &mut cbor).expect("JSON values can always be encoded as CBOR");
+    cbor
+}
+
+/// Encodes a successful JSON response as CBOR. As the whole body is needed for this, arrays are no longer streamed.
+pub(crate) async fn encode_response(res: Response) -> Response {
+    let is_json = res
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"application/json"));
+    if !res.status().is_success() || !is_json {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    let bytes = match to_bytes(body, usize::MAX).await {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            warn!("Unable to read response to encode it as CBOR: {e}");
+            return ERR_UPSTREAM.into_response();
+        }
+    };
+    let Ok(json) = serde_json::from_slice::<Value>(&bytes) else {
+        return Response::from_parts(parts, Body::from(bytes));
+    };
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(APPLICATION_CBOR));
+    Response::from_parts(parts, Body::from(from_json(&json)))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::StatusCode, Json};
+    use serde_json::json;
+    use shared::{test_utils::app, MsgTaskRequest};
+
+    use super::*;
+
+    fn task() -> Value {
+        json!({
+            "id": "70c0aa90-bfcf-4312-a6af-42cbd57dc0b8",
+            "from": app("app1"),
+            "to": [app("app2")],
+            "body": "What is the answer?",
+            "failure_strategy": {"retry": {"backoff_millisecs": 1000, "max_tries": 5}},
+            "ttl": "60s",
+            "metadata": {"nested": [1, -2.5, null, true, "text"]},
+        })
+    }
+
+    #[test]
+    fn round_trip() {
+        let task = task();
+        let cbor = from_json(&task);
+        assert!(cbor.len() < serde_json::to_vec(&task).unwrap().len());
+        assert_eq!(to_json(&cbor).unwrap(), task);
+        assert!(to_json(b"\xff").is_err());
+        // Byte strings have no JSON equivalent
+        assert!(to_json(&[0x43, 1, 2, 3]).is_err());
+    }
+
+    /// Messages are signed in their JSON form, which must not depend on how the App encoded them
+    #[test]
+    fn same_message_for_both_encodings() {
+        let from_json: MsgTaskRequest = serde_json::from_slice(&serde_json::to_vec(&task()).unwrap()).unwrap();
+        let from_cbor: MsgTaskRequest = serde_json::from_value(to_json(&super::from_json(&task())).unwrap()).unwrap();
+        assert_eq!(serde_json::to_vec(&from_json).unwrap(), serde_json::to_vec(&from_cbor).unwrap());
+    }
+
+    #[test]
+    fn negotiation() {
+        let headers = |name, value| HeaderMap::from_iter([(name, HeaderValue::from_static(value))]);
+        assert!(is_cbor(&headers(header::CONTENT_TYPE, "application/cbor")));
+        assert!(!is_cbor(&headers(header::CONTENT_TYPE, "application/json")));
+        assert!(accepts_cbor(&headers(header::ACCEPT, "application/json;q=0.5, application/cbor")));
+        assert!(!accepts_cbor(&headers(header::ACCEPT, "application/json")));
+        assert!(!accepts_cbor(&HeaderMap::new()));
+    }
+
+    #[tokio::test]
+    async fn encodes_json_responses() {
+        let res = encode_response(Json(task()).into_response()).await;
+        assert_eq!(res.headers()[header::CONTENT_TYPE], APPLICATION_CBOR);
+        let body = to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        assert_eq!(to_json(&body).unwrap(), task());
+
+        let error = encode_response((StatusCode::BAD_REQUEST, Json(json!(["unknown"]))).into_response()).await;
+        assert_eq!(error.headers()[header::CONTENT_TYPE], "application/json");
+    }
+}
@@ -5,7 +5,7 @@ use shared::{
 };
 use tracing::{debug, info, warn, error};
 
-use crate::serve_tasks::sign_request;
+use crate::{brokers::BROKERS, serve_tasks::sign_request};
 
 pub(crate) struct GetCertsFromBroker {
     client: SamplyHttpClient,
@@ -36,7 +36,7 @@ impl GetCertsFromBroker {
         let req = sign_request(body, parts, &self.config, Some(&self.crypto_conf))
             .await
             .map_err(|(_, msg)| SamplyBeamError::SignEncryptError(msg.into()))?;
-        Ok(self.client.execute(req).await?.into())
+        Ok(BROKERS.execute(&self.client, req).await?)
     }
 
     async fn query(&self, path: &str) -> Result<String, SamplyBeamError> {
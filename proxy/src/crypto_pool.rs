@@ -0,0 +1,101 @@
+use std::sync::Arc;
+
+use once_cell::sync::Lazy;
+use shared::{config::CONFIG_PROXY, errors::SamplyBeamError};
+use tokio::sync::Semaphore;
+
+/// Pool used to decrypt messages received from the broker
+pub(crate) static CRYPTO_POOL: Lazy<CryptoPool> = Lazy::new(|| CryptoPool::new(CONFIG_PROXY.crypto_concurrency));
+
+/// Runs CPU heavy crypto operations on the blocking thread pool so they don't stall the async runtime.
+/// At most `concurrency` jobs run at the same time and at most `concurrency * QUEUED_JOBS_PER_WORKER` further jobs wait for a free slot.
+pub(crate) struct CryptoPool {
+    workers: Arc<Semaphore>,
+    jobs: Arc<Semaphore>,
+}
+
+impl CryptoPool {
+    const QUEUED_JOBS_PER_WORKER: usize = 16;
+
+    pub(crate) fn new(concurrency: usize) -> Self {
+        let concurrency = concurrency.max(1);
+        Self {
+            workers: Arc::new(Semaphore::new(concurrency)),
+            jobs: Arc::new(Semaphore::new(concurrency * (1 + Self::QUEUED_JOBS_PER_WORKER))),
+        }
+    }
+
+    /// Returns an error if no further jobs can be queued right now
+    pub(crate) fn check_capacity(&self) -> Result<(), SamplyBeamError> {
+        if self.jobs.available_permits() == 0 {
+            Err(SamplyBeamError::Overloaded("Too many pending decryptions"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Fails with [`SamplyBeamError::Overloaded`] without running `job` if too many jobs are queued already
+    pub(crate) async fn run<T: Send + 'static>(&self, job: impl FnOnce() -> T + Send + 'static) -> Result<T, SamplyBeamError> {
+        let _job = self.jobs
+            .clone()
+            .try_acquire_owned()
+            .map_err(|_| SamplyBeamError::Overloaded("Too many pending decryptions"))?;
+        let _worker = self.workers
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("Semaphore is never closed");
+        tokio::task::spawn_blocking(job)
+            .await
+            .map_err(|e| SamplyBeamError::InternalSynchronizationError(format!("Crypto job failed: {e}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{sync::{mpsc, Mutex}, time::Duration};
+
+    use super::*;
+
+    fn heavy_job() {
+        std::thread::sleep(Duration::from_millis(50));
+    }
+
+    #[tokio::test(flavor = "current_thread")]
+    async fn runtime_stays_responsive() {
+        let pool = Arc::new(CryptoPool::new(2));
+        let (unblock, blocked) = mpsc::channel::<()>();
+        let blocked = Arc::new(Mutex::new(blocked));
+        let jobs: Vec<_> = (0..20).map(|_| {
+            let pool = pool.clone();
+            let blocked = blocked.clone();
+            tokio::spawn(async move { pool.run(move || blocked.lock().unwrap().recv().unwrap()).await })
+        }).collect();
+
+        // The jobs block until this task unblocks them, which deadlocks if they ran on the runtime's only thread
+        tokio::task::yield_now().await;
+        for _ in &jobs {
+            unblock.send(()).unwrap();
+        }
+        for job in jobs {
+            job.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn rejects_when_saturated() {
+        let pool = Arc::new(CryptoPool::new(1));
+        let capacity = 1 + CryptoPool::QUEUED_JOBS_PER_WORKER;
+        let jobs: Vec<_> = (0..capacity).map(|_| {
+            let pool = pool.clone();
+            tokio::spawn(async move { pool.run(heavy_job).await })
+        }).collect();
+        // Let all jobs get queued
+        tokio::time::sleep(Duration::from_millis(10)).await;
+        assert!(matches!(pool.run(|| ()).await, Err(SamplyBeamError::Overloaded(_))));
+        for job in jobs {
+            job.await.unwrap().unwrap();
+        }
+        assert!(pool.run(|| ()).await.is_ok());
+    }
+}
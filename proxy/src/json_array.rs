@@ -0,0 +1,164 @@
+use bytes::{Bytes, BytesMut};
+
+#[derive(Debug, PartialEq)]
+pub(crate) struct InvalidJsonArray(&'static str);
+
+impl std::fmt::Display for InvalidJsonArray {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Invalid JSON array: {}", self.0)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum ArrayPosition {
+    Before,
+    Inside,
+    After,
+}
+
+/// Splits a JSON array arriving in chunks into its top level elements without parsing them.
+/// The elements themselves are not validated, this is left to the JSON parser consuming them.
+pub(crate) struct JsonArraySplitter {
+    buf: BytesMut,
+    /// Index into `buf` up to which all bytes have been scanned
+    scanned: usize,
+    /// Index into `buf` at which the current element starts
+    element_start: Option<usize>,
+    position: ArrayPosition,
+    depth: usize,
+    in_string: bool,
+    escaped: bool,
+}
+
+impl Default for JsonArraySplitter {
+    fn default() -> Self {
+        Self {
+            buf: BytesMut::new(),
+            scanned: 0,
+            element_start: None,
+            position: ArrayPosition::Before,
+            depth: 0,
+            in_string: false,
+            escaped: false,
+        }
+    }
+}
+
+impl JsonArraySplitter {
+    pub(crate) fn push(&mut self, chunk: &[u8]) {
+        self.buf.extend_from_slice(chunk);
+    }
+
+    /// Returns the next complete element or `None` if more input is needed or the array has ended
+    pub(crate) fn next_element(&mut self) -> Result<Option<Bytes>, InvalidJsonArray> {
+        while self.scanned < self.buf.len() {
+            let byte = self.buf[self.scanned];
+            self.scanned += 1;
+            match self.position {
+                ArrayPosition::Before if byte.is_ascii_whitespace() => {},
+                ArrayPosition::Before if byte == b'[' => self.position = ArrayPosition::Inside,
+                ArrayPosition::Before => return Err(InvalidJsonArray("Expected '['")),
+                ArrayPosition::After if byte.is_ascii_whitespace() => {},
+                ArrayPosition::After => return Err(InvalidJsonArray("Trailing characters after ']'")),
+                ArrayPosition::Inside if self.in_string => {
+                    if self.escaped {
+                        self.escaped = false;
+                    } else if byte == b'\\' {
+                        self.escaped = true;
+                    } else if byte == b'"' {
+                        self.in_string = false;
+                    }
+                },
+                ArrayPosition::Inside if self.depth == 0 && (byte == b',' || byte == b']') => {
+                    if byte == b']' {
+                        self.position = ArrayPosition::After;
+                    }
+                    match self.element_start.take() {
+                        Some(start) => {
+                            let mut element = self.buf.split_to(self.scanned);
+                            self.scanned = 0;
+                            // Drop the delimiter and everything before the element
+                            element.truncate(element.len() - 1);
+                            let mut element = element.split_off(start);
+                            element.truncate(element.trim_ascii_end().len());
+                            return Ok(Some(element.freeze()));
+                        },
+                        None if byte == b',' => return Err(InvalidJsonArray("Missing element before ','")),
+                        None => {},
+                    }
+                },
+                ArrayPosition::Inside if byte.is_ascii_whitespace() => {},
+                ArrayPosition::Inside => {
+                    if self.element_start.is_none() {
+                        self.element_start = Some(self.scanned - 1);
+                    }
+                    match byte {
+                        b'"' => self.in_string = true,
+                        b'[' | b'{' => self.depth += 1,
+                        b']' | b'}' => self.depth = self.depth
+                            .checked_sub(1)
+                            .ok_or(InvalidJsonArray("Unbalanced brackets"))?,
+                        _ => {},
+                    }
+                },
+            }
+        }
+        Ok(None)
+    }
+
+    /// Returns an error if the array has not been closed
+    pub(crate) fn finish(&self) -> Result<(), InvalidJsonArray> {
+        if self.position == ArrayPosition::After {
+            Ok(())
+        } else {
+            Err(InvalidJsonArray("Unexpected end of input"))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn split(chunks: &[&str]) -> Result<Vec<String>, InvalidJsonArray> {
+        let mut splitter = JsonArraySplitter::default();
+        let mut elements = Vec::new();
+        for chunk in chunks {
+            splitter.push(chunk.as_bytes());
+            while let Some(element) = splitter.next_element()? {
+                elements.push(String::from_utf8(element.to_vec()).unwrap());
+            }
+        }
+        splitter.finish()?;
+        Ok(elements)
+    }
+
+    #[test]
+    fn split_elements() {
+        assert_eq!(split(&["[]"]).unwrap(), Vec::<String>::new());
+        assert_eq!(split(&[" [ 1 , true,\"a\" ] "]).unwrap(), ["1", "true", "\"a\""]);
+        assert_eq!(
+            split(&[r#"[{"jwt": "a,]"}, {"jwt": "b\"}"}, [1, [2]]]"#]).unwrap(),
+            [r#"{"jwt": "a,]"}"#, r#"{"jwt": "b\"}"}"#, "[1, [2]]"]
+        );
+    }
+
+    #[test]
+    fn split_chunked() {
+        let json = r#"[{"jwt": "a,\\"}, {"jwt": "b"}]"#;
+        let expected = [r#"{"jwt": "a,\\"}"#, r#"{"jwt": "b"}"#];
+        for i in 0..json.len() {
+            let (first, second) = json.split_at(i);
+            assert_eq!(split(&[first, second]).unwrap(), expected);
+        }
+    }
+
+    #[test]
+    fn invalid_arrays() {
+        assert!(split(&["{}"]).is_err());
+        assert!(split(&["[1,,2]"]).is_err());
+        assert!(split(&["[1, 2"]).is_err());
+        assert!(split(&["[1] 2"]).is_err());
+        assert!(split(&["[1}]"]).is_err());
+    }
+}
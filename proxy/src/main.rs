@@ -1,12 +1,13 @@
 #![allow(unused_imports)]
 
 use std::future::Future;
-use std::time::Duration;
+use std::time::{Duration, SystemTime};
 
 use axum::http::{header, HeaderValue, StatusCode};
-use beam_lib::AppOrProxyId;
+use beam_lib::{AppOrProxyId, ProxyMetadata};
 use futures::future::Ready;
 use shared::{reqwest, EncryptedMessage, MsgEmpty, PlainMessage};
+use shared::clock;
 use shared::crypto::CryptoPublicPortion;
 use shared::errors::SamplyBeamError;
 use shared::http_client::{self, SamplyHttpClient};
@@ -15,11 +16,17 @@ use tokio::time::Instant;
 use tracing::{debug, error, info, warn};
 use tryhard::{backoff_strategies::ExponentialBackoff, RetryFuture, RetryFutureConfig};
 
-use crate::serve_tasks::sign_request;
+use crate::{brokers::BROKERS, serve_tasks::sign_request};
 
 mod auth;
 mod banner;
+mod brokers;
+mod cbor;
 mod crypto;
+mod crypto_pool;
+mod json_array;
+mod metrics;
+mod response_cache;
 mod serve;
 mod serve_health;
 mod serve_tasks;
@@ -32,22 +39,32 @@ pub(crate) const PROXY_TIMEOUT: u64 = 120;
 pub async fn main() -> anyhow::Result<()> {
     shared::config::prepare_env();
     shared::logger::init_logger()?;
-    banner::print_banner();
+    banner::print_banner(&config::CONFIG_PROXY);
 
     let config = config::CONFIG_PROXY.clone();
-    let client = http_client::build(
+    let client = http_client::build_with_pool(
         &config::CONFIG_SHARED.tls_ca_certificates,
         Some(Duration::from_secs(PROXY_TIMEOUT)),
         Some(Duration::from_secs(20)),
+        config.broker_connection_pool,
     )?;
 
-    if let Err(err) = retry_notify(|| get_broker_health(&config, &client), |err, dur| {
+    let broker_time = match retry_notify(|| get_broker_health(&config, &client), |err, dur| {
         warn!("Still trying to reach Broker: {err}. Retrying in {}s", dur.as_secs());
     }).await {
-        error!("Giving up reaching Broker: {err}");
+        Err(err) => {
+            error!("Giving up reaching Broker: {err}");
+            std::process::exit(1);
+        }
+        Ok(broker_time) => {
+            info!("Connected to Broker: {}", BROKERS.current());
+            broker_time
+        }
+    };
+
+    if let Err(err) = check_clock(&config, &client, broker_time).await {
+        error!("{err}. Refusing to start, please synchronize the system clock");
         std::process::exit(1);
-    } else {
-        info!("Connected to Broker: {}", &config.broker_uri);
     }
 
     if let Err(err) = retry_notify(|| init_crypto(config.clone(), client.clone()), |err, dur| {
@@ -107,21 +124,22 @@ async fn init_crypto(config: Config, client: SamplyHttpClient) -> Result<(), Sam
     Ok(())
 }
 
+/// Returns the broker's time from the Date header of its response if it sent one
 async fn get_broker_health(
     config: &Config,
     client: &SamplyHttpClient,
-) -> Result<(), SamplyBeamError> {
+) -> Result<Option<SystemTime>, SamplyBeamError> {
     let uri = config.broker_uri
         .join("/v1/health")
         .expect("Uri to be constructed correctly");
-    let resp = client
-        .get(uri.clone())
+    let req = client
+        .get(uri)
         .header(header::USER_AGENT, HeaderValue::from_static(env!("SAMPLY_USER_AGENT")))
-        .send()
-        .await?;
+        .build()?;
+    let resp = BROKERS.execute(client, req).await?;
 
     match resp.status() {
-        StatusCode::OK => Ok(()),
+        StatusCode::OK => Ok(clock::date_header(resp.headers())),
         _ => Err(SamplyBeamError::InternalSynchronizationError(format!(
             "Unexpected reply from Broker, received status code {}",
             resp.status()
@@ -129,8 +147,45 @@ async fn get_broker_health(
     }
 }
 
+/// Checks the system clock against the configured time reference or else the broker's time
+async fn check_clock(
+    config: &Config,
+    client: &SamplyHttpClient,
+    broker_time: Option<SystemTime>,
+) -> Result<(), SamplyBeamError> {
+    if config.max_clock_skew.is_zero() {
+        return Ok(());
+    }
+    let reference_time = match &config.time_reference {
+        Some(reference) => reference.now(client).await
+            .map_err(|e| warn!("Unable to check the system clock against {reference}: {e}"))
+            .ok(),
+        None => broker_time.or_else(|| {
+            warn!("Unable to check the system clock as the Broker did not send its time");
+            None
+        }),
+    };
+    match reference_time {
+        Some(time) => clock::check_clock(time, config.max_clock_skew, config.enforce_clock_skew),
+        None => Ok(()),
+    }
+}
+
+/// The broker takes the proxy's zone and region from the signed request for control tasks
+fn control_url(config: &Config) -> reqwest::Url {
+    let mut url = config.broker_uri.join("v1/control").expect("Broker URL is a valid base");
+    let ProxyMetadata { zone, region } = &config.metadata;
+    for (key, value) in [("zone", zone), ("region", region)] {
+        if let Some(value) = value {
+            url.query_pairs_mut().append_pair(key, value);
+        }
+    }
+    url
+}
+
 fn spawn_controller_polling(client: SamplyHttpClient, config: Config) {
     const RETRY_INTERVAL: Duration = Duration::from_secs(60);
+    let control_url = control_url(&config);
     tokio::spawn(async move {
         let mut retries_this_min = 0;
         let mut reset_interval = std::pin::pin!(tokio::time::sleep(Duration::from_secs(60)));
@@ -138,7 +193,7 @@ fn spawn_controller_polling(client: SamplyHttpClient, config: Config) {
             let body = EncryptedMessage::MsgEmpty(MsgEmpty {
                 from: AppOrProxyId::Proxy(config.proxy_id.clone()),
             });
-            let (parts, body) = axum::http::Request::get(format!("{}v1/control", config.broker_uri))
+            let (parts, body) = axum::http::Request::get(control_url.as_str())
                 .header(header::USER_AGENT, env!("SAMPLY_USER_AGENT"))
                 .body(body)
                 .expect("To build request successfully")
@@ -146,7 +201,7 @@ fn spawn_controller_polling(client: SamplyHttpClient, config: Config) {
 
             let req = sign_request(body, parts, &config, None).await.expect("Unable to sign request; this should always work");
             // In the future this will poll actual control related tasks
-            match client.execute(req).await {
+            match BROKERS.execute(&client, req).await {
                 Ok(res) => {
                     match res.status() {
                         StatusCode::OK => {
@@ -0,0 +1,44 @@
+use beam_lib::AppOrProxyId;
+use once_cell::sync::Lazy;
+use prometheus::{register_int_counter_vec, Encoder, IntCounterVec, TextEncoder};
+
+pub static DECRYPTION_FAILURES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "beam_proxy_decryption_failures_total",
+        "Number of messages from the broker that failed verification or decryption by their claimed sender's proxy",
+        &["sender", "reason"]
+    )
+    .expect("Metric is only registered once")
+});
+
+/// Why a message could not be decrypted
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum FailureReason {
+    /// The signature or certificate of the message could not be verified
+    Signature,
+    /// The message was signed correctly but could not be decrypted, e.g. because it was encrypted for another key
+    Decryption,
+}
+
+impl FailureReason {
+    fn as_str(self) -> &'static str {
+        match self {
+            FailureReason::Signature => "signature",
+            FailureReason::Decryption => "decryption",
+        }
+    }
+}
+
+/// Counts a failed message by the proxy of its sender, as apps of a proxy share its keys.
+/// Messages whose sender can't even be read are counted as `unknown`.
+pub(crate) fn record_decryption_failure(sender: Option<&AppOrProxyId>, reason: FailureReason) {
+    let sender = sender.map_or_else(|| "unknown".to_string(), |sender| sender.proxy_id().to_string());
+    DECRYPTION_FAILURES.with_label_values(&[&sender, reason.as_str()]).inc();
+}
+
+/// Renders all registered metrics in the prometheus text format
+pub fn render() -> Result<String, prometheus::Error> {
+    let mut buffer = Vec::new();
+    TextEncoder::new().encode(&prometheus::gather(), &mut buffer)?;
+    Ok(String::from_utf8(buffer).expect("Prometheus text format is valid utf8"))
+}
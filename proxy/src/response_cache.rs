@@ -0,0 +1,285 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use axum::{
+    body::{Body, Bytes},
+    extract::Request,
+    http::{header, response::Parts, Method, StatusCode},
+    response::{IntoResponse, Response},
+};
+use beam_lib::AppId;
+use futures::{stream, StreamExt};
+use tokio::time::Instant;
+use tracing::{debug, error};
+
+use crate::serve_tasks::ERR_UPSTREAM;
+
+/// Most replies the cache holds before evicting the least recently used one
+const MAX_ENTRIES: usize = 1000;
+/// Most bytes of bodies the cache holds before evicting the least recently used reply
+const MAX_BYTES: usize = 64 * 1024 * 1024;
+/// Larger replies are passed through without being cached
+const MAX_ENTRY_BYTES: usize = 1024 * 1024;
+
+/// Keeps the decrypted replies to non-blocking GET requests for a short time,
+/// so apps polling the status of their tasks neither hit the broker nor the crypto pool on every poll.
+/// Disabled by default.
+#[derive(Clone, Default)]
+pub(crate) struct ResponseCache {
+    inner: Option<Arc<Inner>>,
+}
+
+struct Inner {
+    ttl: Duration,
+    limits: Limits,
+    entries: Mutex<Entries>,
+}
+
+#[derive(Clone, Copy)]
+struct Limits {
+    entries: usize,
+    bytes: usize,
+    entry_bytes: usize,
+}
+
+#[derive(Default)]
+struct Entries {
+    map: HashMap<CacheKey, CachedResponse>,
+    /// Total size of the cached bodies
+    bytes: usize,
+}
+
+/// Apps may only see their own replies, so the app is part of the key
+type CacheKey = (AppId, String);
+
+struct CachedResponse {
+    parts: Parts,
+    body: Bytes,
+    expires: Instant,
+    last_used: Instant,
+}
+
+impl ResponseCache {
+    pub(crate) fn new(ttl: Option<Duration>) -> Self {
+        Self::with_limits(ttl, Limits { entries: MAX_ENTRIES, bytes: MAX_BYTES, entry_bytes: MAX_ENTRY_BYTES })
+    }
+
+    fn with_limits(ttl: Option<Duration>, limits: Limits) -> Self {
+        Self {
+            inner: ttl.map(|ttl| Arc::new(Inner { ttl, limits, entries: Mutex::default() })),
+        }
+    }
+
+    /// Serves the reply from the cache if the request is cacheable and its reply has been cached within the TTL.
+    /// Otherwise the reply is fetched and cached if it was successful and is not too large.
+    /// Mutating requests remove all replies cached for the app, so it sees the effect of its own changes.
+    pub(crate) async fn get_or_fetch<F: Future<Output = Response>>(
+        &self,
+        sender: &AppId,
+        req: Request,
+        fetch: impl FnOnce(Request) -> F,
+    ) -> Response {
+        let Some(inner) = &self.inner else {
+            return fetch(req).await;
+        };
+        if req.method() != Method::GET {
+            inner.entries.lock().unwrap().retain(|(app, _), _| app != sender);
+            return fetch(req).await;
+        }
+        if is_blocking(&req) || is_partial_or_conditional(&req) {
+            return fetch(req).await;
+        }
+        let key = (sender.clone(), req.uri().path_and_query().map_or(req.uri().path(), |pq| pq.as_str()).to_string());
+        if let Some(cached) = inner.get(&key) {
+            debug!("Serving {} for {} from the response cache", key.1, key.0);
+            return cached;
+        }
+        let res = fetch(req).await;
+        if res.status() != StatusCode::OK {
+            return res;
+        }
+        // Decrypted arrays are streamed, so the whole reply has to be received before it can be cached
+        let (parts, body) = res.into_parts();
+        let mut body = body.into_data_stream();
+        let mut received = Vec::new();
+        let mut len = 0;
+        while let Some(chunk) = body.next().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(e) => {
+                    error!("Failed to receive reply for the response cache: {e}");
+                    return ERR_UPSTREAM.into_response();
+                }
+            };
+            len += chunk.len();
+            received.push(chunk);
+            if len > inner.limits.entry_bytes {
+                debug!("Not caching {} for {} as it is larger than {} bytes", key.1, key.0, inner.limits.entry_bytes);
+                let body = stream::iter(received.into_iter().map(Ok)).chain(body);
+                return Response::from_parts(parts, Body::from_stream(body));
+            }
+        }
+        let body = Bytes::from(received.concat());
+        inner.insert(key, parts.clone(), body.clone());
+        Response::from_parts(parts, Body::from(body))
+    }
+}
+
+impl Inner {
+    fn get(&self, key: &CacheKey) -> Option<Response> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let cached = entries.map.get_mut(key).filter(|cached| cached.expires > now)?;
+        cached.last_used = now;
+        Some(Response::from_parts(cached.parts.clone(), Body::from(cached.body.clone())))
+    }
+
+    fn insert(&self, key: CacheKey, parts: Parts, body: Bytes) {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        entries.retain(|_, cached| cached.expires > now);
+        entries.bytes += body.len();
+        let replaced = entries.map.insert(key, CachedResponse { parts, body, expires: now + self.ttl, last_used: now });
+        entries.bytes -= replaced.map_or(0, |cached| cached.body.len());
+        while entries.map.len() > self.limits.entries || entries.bytes > self.limits.bytes {
+            let Some(lru) = entries.map.iter().min_by_key(|(_, cached)| cached.last_used).map(|(key, _)| key.clone()) else {
+                break;
+            };
+            let evicted = entries.map.remove(&lru).expect("Key was just found");
+            entries.bytes -= evicted.body.len();
+        }
+    }
+}
+
+impl Entries {
+    fn retain(&mut self, mut keep: impl FnMut(&CacheKey, &CachedResponse) -> bool) {
+        let bytes = &mut self.bytes;
+        self.map.retain(|key, cached| {
+            let kept = keep(key, cached);
+            if !kept {
+                *bytes -= cached.body.len();
+            }
+            kept
+        });
+    }
+}
+
+/// Replies to range and conditional requests depend on headers that are not part of the key
+fn is_partial_or_conditional(req: &Request) -> bool {
+    [header::RANGE, header::IF_RANGE, header::IF_NONE_MATCH, header::IF_MODIFIED_SINCE, header::IF_MATCH, header::IF_UNMODIFIED_SINCE]
+        .iter()
+        .any(|name| req.headers().contains_key(name))
+}
+
+/// Long polls wait for changes, so they must always reach the broker
+fn is_blocking(req: &Request) -> bool {
+    req.uri()
+        .query()
+        .unwrap_or_default()
+        .split('&')
+        .filter_map(|param| param.split('=').next())
+        .any(|name| name == "wait_time" || name == "wait_count")
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use shared::test_utils::app_id;
+
+    use super::*;
+
+    fn request(method: Method, uri: &str) -> Request {
+        Request::builder().method(method).uri(uri).body(Body::empty()).unwrap()
+    }
+
+    /// Polls through the cache and returns the body. The upstream reply is the number of upstream calls so far.
+    async fn poll(cache: &ResponseCache, upstream: &AtomicUsize, app: &AppId, method: Method, uri: &str) -> String {
+        let res = cache.get_or_fetch(app, request(method, uri), |_| async {
+            (upstream.fetch_add(1, Ordering::SeqCst) + 1).to_string().into_response()
+        }).await;
+        String::from_utf8(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn second_poll_is_served_from_cache() {
+        let cache = ResponseCache::new(Some(Duration::from_millis(200)));
+        let upstream = AtomicUsize::new(0);
+        let (app1, app2) = (app_id("app1", "proxy1"), app_id("app2", "proxy1"));
+        let uri = "/v1/tasks/8db76400-e2d9-4d9c-b2a8-bb2b8b5f6a1c/results";
+
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, uri).await, "1");
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, uri).await, "1", "Second poll within TTL skips the broker");
+        assert_eq!(poll(&cache, &upstream, &app2, Method::GET, uri).await, "2", "Other apps don't share the cache");
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, &format!("{uri}?wait_count=1")).await, "3", "Long polls bypass the cache");
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, uri).await, "1");
+
+        assert_eq!(poll(&cache, &upstream, &app1, Method::PUT, &format!("{uri}/{app1}")).await, "4");
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, uri).await, "5", "Mutating requests invalidate the app's cache");
+        assert_eq!(poll(&cache, &upstream, &app2, Method::GET, uri).await, "2");
+
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        assert_eq!(poll(&cache, &upstream, &app2, Method::GET, uri).await, "6", "Replies expire after the TTL");
+    }
+
+    #[tokio::test]
+    async fn range_and_conditional_requests_bypass_cache() {
+        let cache = ResponseCache::new(Some(Duration::from_secs(60)));
+        let upstream = AtomicUsize::new(0);
+        let app1 = app_id("app1", "proxy1");
+        let uri = "/v1/tasks/8db76400-e2d9-4d9c-b2a8-bb2b8b5f6a1c/attachments/0";
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, uri).await, "1");
+        for (name, value) in [(header::RANGE, "bytes=0-1"), (header::IF_RANGE, "\"etag\""), (header::IF_NONE_MATCH, "\"etag\"")] {
+            let mut req = request(Method::GET, uri);
+            req.headers_mut().insert(name.clone(), value.parse().unwrap());
+            let res = cache.get_or_fetch(&app1, req, |_| async { "upstream".into_response() }).await;
+            assert_eq!(axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap(), "upstream", "{name} bypasses the cache");
+        }
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, uri).await, "1", "Bypassing requests are not cached");
+    }
+
+    #[tokio::test]
+    async fn cache_is_bounded() {
+        let cache = ResponseCache::with_limits(Some(Duration::from_secs(60)), Limits { entries: 2, bytes: 10, entry_bytes: 4 });
+        let upstream = AtomicUsize::new(0);
+        let app1 = app_id("app1", "proxy1");
+        // The reply is as long as the path, split into single bytes like a streamed array
+        let fetch = |uri: &'static str| cache.get_or_fetch(&app1, request(Method::GET, uri), |_| async {
+            upstream.fetch_add(1, Ordering::SeqCst);
+            Body::from_stream(stream::iter(uri.bytes().map(|byte| Ok::<_, std::io::Error>(vec![byte])))).into_response()
+        });
+        let body = |res: Response| async { axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap() };
+
+        assert_eq!(body(fetch("/large").await).await, "/large", "Large replies are passed through in full");
+        assert_eq!(body(fetch("/large").await).await, "/large");
+        assert_eq!(upstream.load(Ordering::SeqCst), 2, "Large replies are not cached");
+
+        for uri in ["/a", "/b", "/a", "/c", "/a", "/b"] {
+            fetch(uri).await;
+        }
+        assert_eq!(upstream.load(Ordering::SeqCst), 6, "/b was evicted as the least recently used reply once /c was cached");
+
+        let cache = ResponseCache::with_limits(Some(Duration::from_secs(60)), Limits { entries: 10, bytes: 5, entry_bytes: 4 });
+        let upstream = AtomicUsize::new(0);
+        for uri in ["/aa", "/bb", "/aa"] {
+            cache.get_or_fetch(&app1, request(Method::GET, uri), |_| async {
+                upstream.fetch_add(1, Ordering::SeqCst);
+                uri.into_response()
+            }).await;
+        }
+        assert_eq!(upstream.load(Ordering::SeqCst), 3, "Caching /bb evicted /aa to stay within the byte limit");
+    }
+
+    #[tokio::test]
+    async fn disabled_cache() {
+        let cache = ResponseCache::default();
+        let upstream = AtomicUsize::new(0);
+        let app1 = app_id("app1", "proxy1");
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, "/v1/tasks").await, "1");
+        assert_eq!(poll(&cache, &upstream, &app1, Method::GET, "/v1/tasks").await, "2");
+    }
+}
@@ -1,10 +1,14 @@
 use std::{fmt::Write, net::SocketAddr};
 
-use axum::extract::DefaultBodyLimit;
+use axum::{
+    extract::DefaultBodyLimit,
+    http::{header, HeaderName, HeaderValue, Method},
+};
 use shared::{
     config, config_proxy, config_shared, errors::SamplyBeamError, http_client::SamplyHttpClient,
 };
 use tokio::net::TcpListener;
+use tower_http::cors::{AllowOrigin, CorsLayer};
 use tracing::{debug, error, info, warn};
 
 use crate::{banner, serve_health, serve_tasks};
@@ -21,11 +25,17 @@ pub(crate) async fn serve(
 
     #[cfg(feature = "sockets")]
     let app = app.merge(crate::serve_sockets::router(client));
+    let app = shared::middleware::with_not_found_fallback(app, env!("SAMPLY_USER_AGENT"), ROUTES.to_vec());
     // Middleware needs to be set last
     let app = app
         .layer(axum::middleware::from_fn(shared::middleware::log))
         .layer(axum::middleware::map_response(banner::set_server_header))
         .layer(DefaultBodyLimit::disable());
+    // Needs to be the outermost layer to answer preflight requests before they are authenticated
+    let app = match cors_layer(&config.cors_allowed_origins) {
+        Some(cors) => app.layer(cors),
+        None => app,
+    };
 
     let mut apps_joined = String::new();
     config.api_keys.keys().for_each(|k| {
@@ -46,3 +56,76 @@ pub(crate) async fn serve(
 
     Ok(())
 }
+
+/// Listed to apps requesting unknown paths
+const ROUTES: &[&str] = &[
+    "/v1/tasks",
+    #[cfg(feature = "sockets")]
+    "/v1/sockets",
+    "/v1/capabilities",
+    "/v1/health",
+];
+
+/// Lets browser apps from the given origins call the proxy. Returns `None` if no origins are allowed.
+fn cors_layer(origins: &[HeaderValue]) -> Option<CorsLayer> {
+    if origins.is_empty() {
+        return None;
+    }
+    let allow_origin = if origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        AllowOrigin::list(origins.iter().cloned())
+    };
+    Some(CorsLayer::new()
+        .allow_origin(allow_origin)
+        .allow_methods([Method::GET, Method::POST, Method::PUT])
+        .allow_headers([
+            header::AUTHORIZATION,
+            header::CONTENT_TYPE,
+            header::ACCEPT,
+            HeaderName::from_static("beam-repoll"),
+        ])
+        .expose_headers([header::LOCATION, HeaderName::from_static("task-complete")]))
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{routing::get, Router};
+
+    use super::*;
+
+    async fn preflight(origins: &[HeaderValue], origin: &str) -> axum::http::HeaderMap {
+        let app = Router::new().route("/v1/tasks", get(|| async { "tasks" }));
+        let app = match cors_layer(origins) {
+            Some(cors) => app.layer(cors),
+            None => app,
+        };
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        SamplyHttpClient::new()
+            .request(Method::OPTIONS, format!("http://{addr}/v1/tasks"))
+            .header(header::ORIGIN, origin)
+            .header(header::ACCESS_CONTROL_REQUEST_METHOD, "POST")
+            .header(header::ACCESS_CONTROL_REQUEST_HEADERS, "authorization")
+            .send()
+            .await
+            .unwrap()
+            .headers()
+            .clone()
+    }
+
+    #[tokio::test]
+    async fn cors_preflight() {
+        let allowed = [HeaderValue::from_static("https://app.samply.de")];
+        let headers = preflight(&allowed, "https://app.samply.de").await;
+        assert_eq!(headers[header::ACCESS_CONTROL_ALLOW_ORIGIN], "https://app.samply.de");
+        assert!(headers[header::ACCESS_CONTROL_ALLOW_HEADERS].to_str().unwrap().contains("authorization"));
+
+        let headers = preflight(&allowed, "https://evil.example.com").await;
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+        // No CORS by default
+        let headers = preflight(&[], "https://app.samply.de").await;
+        assert!(!headers.contains_key(header::ACCESS_CONTROL_ALLOW_ORIGIN));
+    }
+}
@@ -1,9 +1,91 @@
-use axum::{http::StatusCode, routing::get, Router};
+use std::{sync::Arc, time::Duration};
+
+use axum::{extract::State, http::StatusCode, routing::get, Json, Router};
+use axum_extra::{headers::{authorization::Basic, Authorization}, TypedHeader};
+use beam_lib::{AppOrProxyId, FailureStrategy};
+use serde::Serialize;
+use serde_json::Value;
+use shared::{config::CONFIG_PROXY, crypto::{self, constant_time_eq}, errors::SamplyBeamError, EncryptableMsg, MsgTaskRequest};
+use tokio::{sync::Mutex, time::Instant};
+use tracing::warn;
+
+use crate::{crypto_pool::CRYPTO_POOL, metrics, serve_tasks::decrypt_msg};
+
+/// The last self test and when it was run
+type SelftestCache = Arc<Mutex<Option<(Instant, SelftestReport)>>>;
+
+/// Self tests are expensive so their result is reused for this long
+const SELFTEST_MIN_INTERVAL: Duration = Duration::from_secs(60);
 
 pub(crate) fn router() -> Router {
-    Router::new().route("/v1/health", get(handler_health))
+    Router::new()
+        .route("/v1/health", get(handler_health))
+        .route("/v1/health/selftest", get(handler_selftest))
+        .route("/metrics", get(get_metrics))
+        .with_state(SelftestCache::default())
 }
 
 async fn handler_health() -> StatusCode {
     StatusCode::OK
 }
+
+// GET /metrics
+async fn get_metrics(auth: TypedHeader<Authorization<Basic>>) -> Result<String, StatusCode> {
+    let Some(ref monitoring_key) = CONFIG_PROXY.monitoring_api_key else {
+        return Err(StatusCode::NOT_IMPLEMENTED);
+    };
+    if !constant_time_eq(auth.password().as_bytes(), monitoring_key.as_bytes()) {
+        return Err(StatusCode::UNAUTHORIZED);
+    }
+    metrics::render().map_err(|e| {
+        warn!("Failed to render metrics: {e}");
+        StatusCode::INTERNAL_SERVER_ERROR
+    })
+}
+
+#[derive(Serialize, Clone)]
+struct SelftestReport {
+    success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<String>,
+    latency_ms: u128,
+}
+
+// GET /v1/health/selftest
+async fn handler_selftest(State(cache): State<SelftestCache>) -> (StatusCode, Json<SelftestReport>) {
+    let mut cache = cache.lock().await;
+    let report = match &*cache {
+        Some((last_run, report)) if last_run.elapsed() < SELFTEST_MIN_INTERVAL => report.clone(),
+        _ => {
+            let start = Instant::now();
+            let result = crypto_selftest().await;
+            let report = SelftestReport {
+                success: result.is_ok(),
+                error: result.err().map(|e| {
+                    warn!("Crypto self test failed: {e}");
+                    e.to_string()
+                }),
+                latency_ms: start.elapsed().as_millis(),
+            };
+            *cache = Some((start, report.clone()));
+            report
+        }
+    };
+    let status = if report.success { StatusCode::OK } else { StatusCode::SERVICE_UNAVAILABLE };
+    (status, Json(report))
+}
+
+/// Encrypts a message to this proxy using the public key from its certificate and decrypts it again using its private key
+async fn crypto_selftest() -> Result<(), SamplyBeamError> {
+    const BODY: &str = "Beam self test";
+    let me = AppOrProxyId::Proxy(CONFIG_PROXY.proxy_id.clone());
+    let msg = MsgTaskRequest::new(me.clone(), vec![me.clone()], BODY.to_string(), FailureStrategy::Discard, Value::Null);
+    let public_keys = crypto::get_proxy_public_keys([&me]).await?;
+    let encrypted = msg.encrypt(&public_keys)?;
+    let decrypted = CRYPTO_POOL.run(move || decrypt_msg(encrypted)).await??;
+    if decrypted.body.body.as_deref() == Some(BODY) {
+        Ok(())
+    } else {
+        Err(SamplyBeamError::SignEncryptError("Decrypted message does not match the original".into()))
+    }
+}
@@ -25,10 +25,10 @@ use futures::{stream::IntoAsyncRead, FutureExt, SinkExt, StreamExt, TryStreamExt
 use hyper_util::rt::TokioIo;
 use rsa::rand_core::RngCore;
 use serde::{Deserialize, Serialize};
-use serde_json::Value;
-use beam_lib::AppOrProxyId;
+use serde_json::{json, Value};
+use beam_lib::{AppId, AppOrProxyId, WorkStatus};
 use shared::{
-    config, ct_codecs::{self, Base64UrlSafeNoPadding, Decoder as B64Decoder, Encoder as B64Encoder}, expire_map::LazyExpireMap, http_client::SamplyHttpClient, reqwest, MessageType, MsgEmpty, MsgId, MsgSocketRequest, Plain
+    config, ct_codecs::{self, Base64UrlSafeNoPadding, Decoder as B64Decoder, Encoder as B64Encoder}, expire_map::LazyExpireMap, http_client::SamplyHttpClient, reqwest, MessageType, MsgEmpty, MsgId, MsgSocketRequest, MsgTaskResult, Plain, NEXT_CURSOR_HEADER
 };
 use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, ReadBuf, ReadHalf, WriteHalf};
 use tokio_util::{
@@ -39,10 +39,18 @@ use tracing::{warn, debug};
 
 use crate::{
     auth::AuthenticatedApp,
+    response_cache::ResponseCache,
     serve_tasks::{forward_request, handler_task, TasksState, validate_and_decrypt, to_server_error},
 };
 
-type MsgSecretMap = Arc<LazyExpireMap<MsgId, SocketEncKey>>;
+type MsgSecretMap = Arc<LazyExpireMap<MsgId, SocketSecret>>;
+
+/// The key of a socket and, if the socket belongs to a task and was sent to its worker, that task and the creator of the task
+#[derive(Debug, Clone)]
+struct SocketSecret {
+    key: SocketEncKey,
+    task: Option<(MsgId, AppOrProxyId)>,
+}
 const TASK_SECRET_CLEANUP_INTERVAL: Duration = Duration::from_secs(5 * 60);
 
 pub(crate) fn router(client: SamplyHttpClient) -> Router {
@@ -50,6 +58,8 @@ pub(crate) fn router(client: SamplyHttpClient) -> Router {
     let state = TasksState {
         client: client.clone(),
         config,
+        // Listing socket tasks hands out their secrets, so the replies must not be cached
+        response_cache: ResponseCache::default(),
     };
     let task_secret_map: MsgSecretMap = Default::default();
     let map = task_secret_map.clone();
@@ -62,7 +72,7 @@ pub(crate) fn router(client: SamplyHttpClient) -> Router {
 
     Router::new()
         .route("/v1/sockets", get(get_tasks))
-        .route("/v1/sockets/:app_or_id", post(create_socket_con).get(connect_socket))
+        .route("/v1/sockets/:app_or_id", post(create_socket_con).get(connect_socket).delete(cancel_socket))
         .with_state(state)
         .layer(Extension(task_secret_map))
 }
@@ -72,11 +82,12 @@ async fn get_tasks(
     state: State<TasksState>,
     Extension(task_secret_map): Extension<MsgSecretMap>,
     req: Request
-) -> Result<Json<Vec<MsgSocketRequest<Plain>>>, Response> {
+) -> Result<Response, Response> {
     let res = forward_request(req, &state.config, &sender, &state.client).await?;
     if res.status() != StatusCode::OK {
         return Err(http::Response::from(res).map(axum::body::Body::new));
     }
+    let next_cursor = res.headers().get(NEXT_CURSOR_HEADER).cloned();
     let enc_json = res.json().await.map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
     let plain_json = to_server_error(validate_and_decrypt(enc_json).await).map_err(IntoResponse::into_response)?;
     let tasks: Vec<MessageType<Plain>> = serde_json::from_value(plain_json).map_err(|_| StatusCode::INTERNAL_SERVER_ERROR.into_response())?;
@@ -91,7 +102,8 @@ async fn get_tasks(
             let Ok(ttl) = socket_task.expire.duration_since(SystemTime::now()) else {
                 continue;
             };
-            task_secret_map.insert_for(ttl, socket_task.id, key);
+            let task = socket_task.task.map(|task| (task, socket_task.from.clone()));
+            task_secret_map.insert_for(ttl, socket_task.id, SocketSecret { key, task });
             socket_task.secret.body = None;
             out.push(socket_task);
         } else {
@@ -99,7 +111,11 @@ async fn get_tasks(
         }
     }
 
-    Ok(Json(out))
+    let mut res = Json(out).into_response();
+    if let Some(next_cursor) = next_cursor {
+        res.headers_mut().insert(NEXT_CURSOR_HEADER, next_cursor);
+    }
+    Ok(res)
 }
 
 async fn create_socket_con(
@@ -109,13 +125,24 @@ async fn create_socket_con(
     state: State<TasksState>,
     mut req: Request,
 ) -> Response {
-    let task_id = MsgId::new();
+    // Reusing the id of an earlier socket reopens it once its relay ended
+    let task_id = match req.headers_mut().remove("socket-id").map(|id| id.to_str().ok()?.parse::<MsgId>().ok()) {
+        None => MsgId::new(),
+        Some(Some(id)) => id,
+        Some(None) => return (StatusCode::BAD_REQUEST, "Invalid socket-id header").into_response(),
+    };
+    // Ties the socket to a task so that the proxy of its worker, the other party, records how the socket ended
+    let task = match req.headers_mut().remove("task-id").map(|id| id.to_str().ok()?.parse::<MsgId>().ok()) {
+        None => None,
+        Some(Some(id)) => Some(id),
+        Some(None) => return (StatusCode::BAD_REQUEST, "Invalid task-id header").into_response(),
+    };
     let secret = SocketEncKey::generate();
     let Ok(secret_encoded) = secret.to_b64_str() else {
         return StatusCode::INTERNAL_SERVER_ERROR.into_response();
     };
     const TTL: Duration = Duration::from_secs(60);
-    task_secret_map.insert_for(TTL, task_id.clone(), secret);
+    task_secret_map.insert_for(TTL, task_id.clone(), SocketSecret { key: secret, task: None });
     let metadata = req
         .headers_mut()
         .remove("metadata")
@@ -127,7 +154,8 @@ async fn create_socket_con(
         expire: SystemTime::now() + TTL,
         id: task_id,
         secret: Plain::from(secret_encoded),
-        metadata
+        metadata,
+        task,
     };
 
     let Ok(body) = serde_json::to_vec(&socket_req) else {
@@ -162,6 +190,21 @@ async fn create_socket_con(
     connect_socket(AuthenticatedApp(sender), state, Extension(task_secret_map), Path(task_id), req).await
 }
 
+/// Asks the broker to end the relay of the socket
+async fn cancel_socket(
+    AuthenticatedApp(sender): AuthenticatedApp,
+    state: State<TasksState>,
+    req: Request,
+) -> Response {
+    match forward_request(req, &state.config, &sender, &state.client).await {
+        Ok(res) => http::Response::from(res).map(axum::body::Body::new),
+        Err(err) => {
+            warn!("Failed to cancel socket: {err:?}");
+            err
+        }
+    }
+}
+
 async fn connect_socket(
     AuthenticatedApp(sender): AuthenticatedApp,
     state: State<TasksState>,
@@ -173,7 +216,7 @@ async fn connect_socket(
         return StatusCode::UPGRADE_REQUIRED.into_response();
     };
 
-    let Some(key) = task_secret_map.get(&task_id).map(|v| v.clone()) else {
+    let Some(SocketSecret { key, task }) = task_secret_map.get(&task_id).map(|v| v.clone()) else {
         return StatusCode::UNAUTHORIZED.into_response();
     };
 
@@ -230,9 +273,12 @@ async fn connect_socket(
         };
 
         let result = tokio::io::copy_bidirectional(&mut TokioIo::new(client_socket), &mut enc_broker_socket).await;
-        if let Err(e) = result {
+        if let Err(e) = &result {
             debug!("Relaying socket connection ended: {e}");
         }
+        if let Some((task, peer)) = task {
+            put_socket_result(&state, &sender, socket_result(task_id, &sender, peer, task, result)).await;
+        }
     });
 
     ([
@@ -241,6 +287,41 @@ async fn connect_socket(
     ], StatusCode::SWITCHING_PROTOCOLS).into_response()
 }
 
+/// The result that tells the other party of a socket belonging to a task how the socket ended for `sender`.
+/// A closed socket succeeds with the number of bytes `sender` sent and received, a broken one failed permanently.
+fn socket_result(socket: MsgId, sender: &AppId, peer: AppOrProxyId, task: MsgId, relayed: io::Result<(u64, u64)>) -> MsgTaskResult {
+    let (status, metadata) = match relayed {
+        Ok((bytes_sent, bytes_received)) => (WorkStatus::Succeeded, json!({ "socket": socket, "bytes_sent": bytes_sent, "bytes_received": bytes_received })),
+        Err(_) => (WorkStatus::PermFailed, json!({ "socket": socket })),
+    };
+    MsgTaskResult {
+        from: AppOrProxyId::App(sender.clone()),
+        to: vec![peer],
+        task,
+        status,
+        body: Plain::from(""),
+        metadata,
+        seq: None,
+    }
+}
+
+/// Records how the socket ended as the result of `sender` to the task, as neither app outlives the tunnel to report it.
+/// A result `sender` already put to the task is never replaced.
+async fn put_socket_result(state: &TasksState, sender: &AppId, result: MsgTaskResult) {
+    let task = result.task;
+    let req = Request::put(format!("/v1/tasks/{task}/results/{}", result.from))
+        .header(header::CONTENT_TYPE, "application/json")
+        .header(header::IF_NONE_MATCH, "*")
+        .body(axum::body::Body::from(serde_json::to_vec(&result).expect("Result serializes fine")))
+        .expect("Request is valid");
+    match forward_request(req, &state.config, sender, &state.client).await {
+        Ok(res) if res.status().is_success() => debug!("Recorded how the socket ended as result of {sender} to task {task}"),
+        Ok(res) if res.status() == StatusCode::PRECONDITION_FAILED => debug!("{sender} already put a result to task {task}, keeping it"),
+        Ok(res) => warn!("Failed to record how the socket ended as result to task {task}: {}", res.status()),
+        Err(err) => warn!("Failed to record how the socket ended as result to task {task}: {}", err.status()),
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 struct SocketEncKey(GenericArray<u8, U32>);
 
@@ -458,6 +539,7 @@ impl<S: AsyncWrite + AsyncRead + Unpin> AsyncWrite for EncryptedSocket<S> {
 #[cfg(test)]
 mod tests {
     use chacha20poly1305::aead::stream::{Decryptor, Encryptor, EncryptorLE31};
+    use shared::test_utils::app_id;
     use tokio::net::{TcpListener, TcpStream};
 
     use super::*;
@@ -522,4 +604,19 @@ mod tests {
         let a = decrypter.decrypt_next(cipher_text.as_slice()).unwrap();
         assert_eq!(test_data, a.as_slice());
     }
+
+    #[test]
+    fn socket_result_tells_how_socket_ended() {
+        let sender = app_id("app1", "proxy1");
+        let peer = AppOrProxyId::new("app2.proxy2.broker.samply.de").unwrap();
+        let (socket, task) = (MsgId::new(), MsgId::new());
+
+        let closed = socket_result(socket, &sender, peer.clone(), task, Ok((4, 2)));
+        assert_eq!((closed.task, closed.to, closed.status), (task, vec![peer.clone()], WorkStatus::Succeeded));
+        assert_eq!(closed.metadata, json!({ "socket": socket, "bytes_sent": 4, "bytes_received": 2 }));
+
+        let broken = socket_result(socket, &sender, peer, task, Err(io::ErrorKind::ConnectionReset.into()));
+        assert_eq!(broken.status, WorkStatus::PermFailed);
+        assert_eq!(broken.metadata, json!({ "socket": socket }));
+    }
 }
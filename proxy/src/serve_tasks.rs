@@ -1,47 +1,64 @@
 use std::{
+    collections::{HashMap, HashSet},
     convert::Infallible,
     str::FromStr,
-    time::{Duration, SystemTime},
+    time::Duration,
 };
 
 use axum::{
-    body::Bytes, extract::{FromRef, Request, State}, http::{header, request::Parts, HeaderMap, HeaderValue, StatusCode, Uri}, response::{sse::Event, IntoResponse, Response, Sse}, routing::{any, get, put}, Json, RequestExt, Router
+    body::Bytes, extract::{ws::{Message as WsMessage, WebSocket, WebSocketUpgrade}, FromRef, FromRequestParts, Request, State}, http::{header, request::Parts, HeaderMap, HeaderName, HeaderValue, Method, StatusCode, Uri}, response::{sse::Event, IntoResponse, Response, Sse}, routing::{any, get, post, put}, Json, RequestExt, Router
 };
 use futures::{
+    io::AsyncBufReadExt,
     stream::{StreamExt, TryStreamExt},
     Stream, TryFutureExt,
 };
-use httpdate::fmt_http_date;
-use rsa::{pkcs8::DecodePublicKey, RsaPublicKey};
+use bytes::BytesMut;
+use rsa::{pkcs8::DecodePublicKey, RsaPrivateKey, RsaPublicKey};
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
-use beam_lib::{AppId, AppOrProxyId, ProxyId};
+use beam_lib::{AppId, AppOrProxyId, FailureStrategy, ProxyId};
 use shared::{
-    config::{self, CONFIG_PROXY}, config_proxy, config_shared::ConfigCrypto, crypto::{self, CryptoPublicPortion}, crypto_jwt, errors::SamplyBeamError, http_client::SamplyHttpClient, reqwest, sse_event::SseEventType, DecryptableMsg, EncryptableMsg, EncryptedMessage, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, MessageType, Msg, MsgEmpty, MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, PlainMessage
+    config::{self, CONFIG_PROXY}, config_proxy, config_shared::ConfigCrypto, crypto::{self, CryptoPublicPortion}, errors::{DecryptErrorReason, SamplyBeamError}, http_client::SamplyHttpClient, reqwest, serde_helpers::ranged_response, sse_event::SseEventType, DecryptableMsg, EncryptableMsg, EncryptedMessage, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, MessageType, Msg, MsgEmpty, MsgId, MsgSigned, MsgTaskRequest, MsgTaskResult, PlainMessage, APPLICATION_NDJSON
 };
 use tokio::io::BufReader;
 use tracing::{debug, error, info, trace, warn};
 
-use crate::{auth::AuthenticatedApp, PROXY_TIMEOUT};
+use crate::{auth::AuthenticatedApp, brokers::BROKERS, cbor, crypto_pool::CRYPTO_POOL, json_array::JsonArraySplitter, metrics::{self, FailureReason}, response_cache::ResponseCache, PROXY_TIMEOUT};
 
 #[derive(Clone, FromRef)]
 pub(crate) struct TasksState {
     pub(crate) client: SamplyHttpClient,
     pub(crate) config: config_proxy::Config,
+    pub(crate) response_cache: ResponseCache,
 }
 
 pub(crate) fn router(client: &SamplyHttpClient) -> Router {
     let config = config::CONFIG_PROXY.clone();
+    let request_timeout = config.request_timeout;
     let state = TasksState {
         client: client.clone(),
+        response_cache: ResponseCache::new(config.response_cache_ttl),
         config,
     };
-    Router::new()
+    let router = Router::new()
         // We need both path variants so the server won't send us into a redirect loop (/tasks, /tasks/, ...)
         .route("/v1/tasks", get(handler_task).post(handler_task))
-        .route("/v1/tasks/:task_id/results", get(handler_task))
+        .route("/v1/tasks/:task_id/claim", post(handler_task))
+        .route("/v1/tasks/heartbeat", post(handler_unencrypted))
+        .route("/v1/tasks/search", get(handler_unencrypted))
+        .route("/v1/tasks/results/stream", get(handler_results_stream))
+        .route("/v1/tasks/:task_id/results", get(handler_results))
+        .route("/v1/tasks/:task_id/summary", get(handler_unencrypted))
         .route("/v1/tasks/:task_id/results/:app_id", put(handler_task))
-        .with_state(state)
+        .route("/v1/tasks/:task_id/results/:app_id/ack", get(handler_unencrypted).post(handler_unencrypted))
+        .route("/v1/tasks/:task_id/attachments", put(handler_task))
+        .route("/v1/tasks/:task_id/attachments/:digest", get(handler_task))
+        .route("/v1/capabilities", put(handler_unencrypted))
+        .route("/v1/capabilities/:capability", get(handler_unencrypted))
+        .with_state(state);
+    // The socket router is not limited, as creating a socket connection waits for the other party to connect
+    shared::middleware::with_request_timeout(router, request_timeout)
 }
 
 const ERR_BODY: (StatusCode, &str) = (StatusCode::BAD_REQUEST, "Invalid body");
@@ -49,7 +66,7 @@ const ERR_INTERNALCRYPTO: (StatusCode, &str) = (
     StatusCode::INTERNAL_SERVER_ERROR,
     "Cryptography failed; see server logs.",
 );
-const ERR_UPSTREAM: (StatusCode, &str) =
+pub(crate) const ERR_UPSTREAM: (StatusCode, &str) =
     (StatusCode::BAD_GATEWAY, "Unable to parse server's reply.");
 const ERR_VALIDATION: (StatusCode, &str) = (
     StatusCode::BAD_GATEWAY,
@@ -77,15 +94,18 @@ pub(crate) async fn forward_request(
         Uri::try_from(config.broker_uri.to_string() + path_query.trim_start_matches('/'))
             .map_err(|_| (StatusCode::BAD_REQUEST, "Invalid path queried.").into_response())?;
     *req.uri_mut() = target_uri;
+    // A range of the broker's response is a slice of signed and encrypted messages the proxy could not decrypt, so ranges are served by the proxy
+    req.headers_mut().remove(header::RANGE);
+    req.headers_mut().remove(header::IF_RANGE);
 
     req.headers_mut().append(
         header::VIA,
         HeaderValue::from_static(env!("SAMPLY_USER_AGENT")),
     );
-    let (encrypted_msg, parts) = encrypt_request(req, &sender).await?;
+    let (encrypted_msg, parts) = encrypt_request(req, sender, config, client).await?;
     let req = sign_request(encrypted_msg, parts, &config, None).await.map_err(IntoResponse::into_response)?;
     trace!("Requesting: {:?}", req);
-    let resp = client.execute(req).await.map_err(|e| {
+    let resp = BROKERS.execute(client, req).await.map_err(|e| {
         if e.is_timeout() {
             debug!("Request to broker timed out after set proxy timeout of {PROXY_TIMEOUT}s");
             (StatusCode::GATEWAY_TIMEOUT, "Request to broker timed out ")
@@ -100,6 +120,7 @@ pub(crate) async fn forward_request(
 pub(crate) async fn handler_task(
     State(client): State<SamplyHttpClient>,
     State(config): State<config_proxy::Config>,
+    State(response_cache): State<ResponseCache>,
     AuthenticatedApp(sender): AuthenticatedApp,
     headers: HeaderMap,
     req: Request,
@@ -114,14 +135,111 @@ pub(crate) async fn handler_task(
         .find(|part| *part == "text/event-stream")
         .is_some();
 
-    if *found {
+    let wants_websocket = headers
+        .get(header::UPGRADE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(b"websocket"));
+
+    if req.method() == Method::GET && wants_websocket {
+        handler_tasks_websocket(client, config, sender, req).await
+    } else if *found {
         handler_tasks_stream(client, config, sender, req)
             .await
             .into_response()
     } else {
-        handler_tasks_nostream(client, config, sender, req)
+        let res = if req.method() == Method::GET && headers.get(REPOLL_HEADER).is_some_and(|v| v == "true") {
+            handler_tasks_repoll(client, config, sender, req).await
+        } else {
+            let app = sender.clone();
+            response_cache.get_or_fetch(&sender, req, |req| async move {
+                handler_tasks_nostream(client, config, app, req)
+                    .await
+                    .into_response()
+            }).await
+        };
+        if cbor::accepts_cbor(&headers) {
+            cbor::encode_response(res).await
+        } else {
+            res
+        }
+    }
+}
+
+async fn handler_results(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    State(response_cache): State<ResponseCache>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    headers: HeaderMap,
+    req: Request,
+) -> Response {
+    // Like on the Broker, SSE takes precedence if the App accepts both
+    if shared::accepts(&headers, APPLICATION_NDJSON) && !shared::accepts(&headers, "text/event-stream") {
+        handler_results_ndjson(client, config, sender, req)
             .await
             .into_response()
+    } else {
+        handler_task(State(client), State(config), State(response_cache), AuthenticatedApp(sender), headers, req).await
+    }
+}
+
+/// Summaries, search hits, acknowledgements and socket outcomes only contain unencrypted data so they are passed through as is
+pub(crate) async fn handler_unencrypted(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    req: Request,
+) -> Result<Response, Response> {
+    let resp = forward_request(req, &config, &sender, &client).await?;
+    Ok(axum::http::Response::from(resp).map(axum::body::Body::new))
+}
+
+async fn handler_results_stream(
+    State(client): State<SamplyHttpClient>,
+    State(config): State<config_proxy::Config>,
+    AuthenticatedApp(sender): AuthenticatedApp,
+    req: Request,
+) -> Response {
+    handler_tasks_stream(client, config, sender, req)
+        .await
+        .into_response()
+}
+
+/// Lets the proxy re-poll the broker itself with a backoff if it is temporarily unavailable
+const REPOLL_HEADER: HeaderName = HeaderName::from_static("beam-repoll");
+const REPOLL_ATTEMPTS: u32 = 5;
+const REPOLL_BASE_DELAY: Duration = Duration::from_millis(500);
+const REPOLL_MAX_DELAY: Duration = Duration::from_secs(30);
+
+/// Exponential backoff with full jitter so that apps don't reconnect all at once after a broker hiccup
+fn repoll_delay(attempt: u32) -> Duration {
+    let ceiling = REPOLL_BASE_DELAY
+        .saturating_mul(2u32.saturating_pow(attempt))
+        .min(REPOLL_MAX_DELAY);
+    ceiling.mul_f64(rand::random::<f64>())
+}
+
+async fn handler_tasks_repoll(
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+    sender: AppId,
+    req: Request,
+) -> Response {
+    // GET requests have no body so they can be repeated from their parts
+    let (parts, _) = req.into_parts();
+    let mut attempt = 0;
+    loop {
+        let req = Request::from_parts(parts.clone(), axum::body::Body::empty());
+        let res = handler_tasks_nostream(client.clone(), config.clone(), sender.clone(), req)
+            .await
+            .into_response();
+        let unavailable = matches!(res.status(), StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT);
+        if !unavailable || attempt >= REPOLL_ATTEMPTS {
+            return res;
+        }
+        let delay = repoll_delay(attempt);
+        debug!("Got {} polling the broker; re-polling in {}ms", res.status(), delay.as_millis());
+        tokio::time::sleep(delay).await;
+        attempt += 1;
     }
 }
 
@@ -133,17 +251,58 @@ async fn handler_tasks_nostream(
 ) -> Result<Response, Response> {
     // Validate Query, forward to server, get response.
 
+    let range_headers: HeaderMap = [header::RANGE, header::IF_RANGE]
+        .into_iter()
+        .filter_map(|name| Some((name.clone(), req.headers().get(&name)?.clone())))
+        .collect();
     let resp = forward_request(req, &config, &sender, &client).await?;
-    let resp = axum::http::Response::from(resp);
+    if resp.status().is_client_error() || resp.status().is_server_error() {
+        // Error replies are not signed, e.g. the list of unknown recipients
+        return Ok(axum::http::Response::from(resp).map(axum::body::Body::new));
+    }
+    let (mut parts, body) = axum::http::Response::from(resp).into_parts();
+    let mut body = axum::body::Body::new(body).into_data_stream();
 
-    // Check reply's signature
+    // Read until we know whether the reply is a JSON array which we can decrypt element by element
+    let mut bytes = BytesMut::new();
+    while !bytes.iter().any(|b| !b.is_ascii_whitespace()) {
+        match body.next().await {
+            Some(Ok(chunk)) => bytes.extend_from_slice(&chunk),
+            Some(Err(e)) => {
+                error!("Error receiving reply from the broker: {}", e);
+                return Err(ERR_UPSTREAM.into_response());
+            },
+            None => break,
+        }
+    }
+    if bytes.trim_ascii_start().starts_with(b"[") {
+        // Once we start streaming we can no longer change the status code
+        to_server_error(CRYPTO_POOL.check_capacity())?;
+        // Remove content length header as it will change and is unknown until the whole body has been decrypted
+        parts.headers.remove(header::CONTENT_LENGTH);
+        let decrypted = validate_and_decrypt_array(bytes.freeze(), body);
+        // Only complete arrays are served in ranges, so a 206 without a Content-Range still means that fewer results than waited for arrived
+        if parts.status == StatusCode::OK && range_headers.contains_key(header::RANGE) {
+            let decrypted = to_server_error(decrypted.try_fold(BytesMut::new(), |mut all, chunk| async move {
+                all.extend_from_slice(&chunk);
+                Ok(all)
+            }).await)?;
+            let (ranged, body) = ranged_response(decrypted.freeze(), &range_headers).into_parts();
+            parts.status = ranged.status;
+            parts.headers.extend(ranged.headers);
+            return Ok(Response::from_parts(parts, body));
+        }
+        return Ok(Response::from_parts(parts, axum::body::Body::from_stream(decrypted)));
+    }
+    while let Some(chunk) = body.next().await {
+        bytes.extend_from_slice(&chunk.map_err(|e| {
+            error!("Error receiving reply from the broker: {}", e);
+            ERR_UPSTREAM.into_response()
+        })?);
+    }
+    let mut bytes = bytes.freeze();
 
-    let (mut parts, body) = resp.into_parts();
-    // Is this stupid? Yes. Is there an other way to do this? Yes by depending on hyper-body-util. Do you want to do that? No
-    let mut bytes = reqwest::Response::from(axum::http::Response::new(body)).bytes().await.map_err(|e| {
-        error!("Error receiving reply from the broker: {}", e);
-        ERR_UPSTREAM.into_response()
-    })?;
+    // Check reply's signature
 
     // TODO: Always return application/jwt from server.
     if !bytes.is_empty() {
@@ -171,12 +330,133 @@ async fn handler_tasks_nostream(
     Ok(Response::from_parts(parts, body))
 }
 
+/// Validates and decrypts a JSON array of signed messages element by element as it is received.
+/// As the response status has already been sent once we find an invalid element, the body stream is aborted in that case.
+fn validate_and_decrypt_array(
+    head: Bytes,
+    mut body: impl Stream<Item = Result<Bytes, axum::Error>> + Unpin + Send + 'static,
+) -> impl Stream<Item = Result<Bytes, SamplyBeamError>> + Send + 'static {
+    async_stream::try_stream! {
+        let mut splitter = JsonArraySplitter::default();
+        splitter.push(&head);
+        let mut length = 0;
+        yield Bytes::from_static(b"[");
+        loop {
+            while let Some(element) = splitter.next_element().map_err(|e| SamplyBeamError::JsonParseError(e.to_string()))? {
+                length += 1;
+                if length > CONFIG_PROXY.max_array_length {
+                    Err(array_too_long(CONFIG_PROXY.max_array_length)).inspect_err(|e| warn!("{e}"))?;
+                }
+                let json = serde_json::from_slice(&element)
+                    .map_err(|e| SamplyBeamError::JsonParseError(format!("Invalid array element in broker response: {e}")))?;
+                // The elements are nested in the streamed array
+                check_array_limits(&json, CONFIG_PROXY.max_array_depth.saturating_sub(1), CONFIG_PROXY.max_array_length)?;
+                let json = validate_and_decrypt_nested(json).await.inspect_err(|e| warn!("Failed to validate and decrypt array element: {e}"))?;
+                let mut out = if length == 1 { Vec::new() } else { vec![b','] };
+                serde_json::to_writer(&mut out, &json).expect("Should serialize fine");
+                yield Bytes::from(out);
+            }
+            match body.next().await {
+                Some(chunk) => splitter.push(&chunk.map_err(|e| {
+                    error!("Error receiving reply from the broker: {}", e);
+                    SamplyBeamError::JsonParseError(format!("Error receiving reply from the broker: {e}"))
+                })?),
+                None => break,
+            }
+        }
+        splitter.finish().map_err(|e| SamplyBeamError::JsonParseError(e.to_string()))?;
+        yield Bytes::from_static(b"]");
+    }
+}
+
 async fn handler_tasks_stream(
     client: SamplyHttpClient,
     config: config_proxy::Config,
     sender: AppId,
     req: Request,
 ) -> Result<Sse<impl Stream<Item = Result<Event, Infallible>>>, Response> {
+    let incoming = request_stream(client, config, sender, req).await?;
+    let outgoing = validate_and_decrypt_sse(incoming);
+    // TODO: Somehow return correct error code (not always possible since headers are sent before long request)
+    let sse = Sse::new(outgoing);
+    Ok(sse)
+}
+
+/// Streams the results as newline-delimited JSON, which the Broker is asked for as well
+async fn handler_results_ndjson(
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+    sender: AppId,
+    req: Request,
+) -> Result<Response, Response> {
+    let incoming = request_stream(client, config, sender, req).await?;
+    let body = axum::body::Body::from_stream(validate_and_decrypt_ndjson(incoming));
+    Ok(([(header::CONTENT_TYPE, APPLICATION_NDJSON)], body).into_response())
+}
+
+/// Validates and decrypts the Broker's results line by line as they are received.
+/// As the response status has already been sent once we find an invalid result, the body stream is aborted in that case.
+fn validate_and_decrypt_ndjson(
+    incoming: impl futures::AsyncBufRead + Unpin + Send + 'static,
+) -> impl Stream<Item = Result<Bytes, SamplyBeamError>> + Send + 'static {
+    async_stream::try_stream! {
+        let mut lines = incoming.lines();
+        while let Some(line) = lines.next().await {
+            let line = line.map_err(|e| {
+                error!("Error receiving reply from the broker: {e}");
+                SamplyBeamError::JsonParseError(format!("Error receiving reply from the broker: {e}"))
+            })?;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut decrypted = validate_and_decrypt_event(line.as_bytes()).await.map_err(|reason| {
+                warn!("Aborting result stream: {reason}");
+                SamplyBeamError::JsonParseError(reason)
+            })?;
+            decrypted.push(b'\n');
+            yield Bytes::from(decrypted);
+        }
+    }
+}
+
+/// Streams the results like [`handler_tasks_stream`] but as JSON text frames over a WebSocket.
+/// The Broker is still asked for an SSE stream.
+async fn handler_tasks_websocket(
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+    sender: AppId,
+    req: Request,
+) -> Response {
+    let (mut parts, body) = req.into_parts();
+    let ws = match WebSocketUpgrade::from_request_parts(&mut parts, &()).await {
+        Ok(ws) => ws,
+        Err(rejection) => return rejection.into_response(),
+    };
+    for name in [
+        header::CONNECTION,
+        header::UPGRADE,
+        header::SEC_WEBSOCKET_KEY,
+        header::SEC_WEBSOCKET_VERSION,
+        header::SEC_WEBSOCKET_EXTENSIONS,
+        header::SEC_WEBSOCKET_PROTOCOL,
+    ] {
+        parts.headers.remove(name);
+    }
+    parts.headers.insert(header::ACCEPT, HeaderValue::from_static("text/event-stream"));
+    let incoming = match request_stream(client, config, sender, Request::from_parts(parts, body)).await {
+        Ok(incoming) => incoming,
+        Err(resp) => return resp,
+    };
+    ws.on_upgrade(|socket| forward_events_to_websocket(validate_and_decrypt_events(incoming), socket))
+}
+
+/// Forwards the request to the Broker and returns its streamed reply, e.g. an SSE stream
+async fn request_stream(
+    client: SamplyHttpClient,
+    config: config_proxy::Config,
+    sender: AppId,
+    req: Request,
+) -> Result<impl futures::AsyncBufRead + Unpin + Send + 'static, Response> {
     // Validate Query, forward to server, get response.
 
     let resp = forward_request(req, &config, &sender, &client).await?;
@@ -188,29 +468,100 @@ async fn handler_tasks_stream(
         return Err((code, error_msg).into_response());
     }
 
-    let outgoing = async_stream::stream! {
-        let incoming = resp
-            .bytes_stream()
-            .map(|result| result.map_err(|error| {
-                let kind = error.is_timeout().then_some(std::io::ErrorKind::TimedOut).unwrap_or(std::io::ErrorKind::Other);
-                std::io::Error::new(kind, format!("IO Error: {error}"))
-            }))
-            .into_async_read();
+    Ok(resp
+        .bytes_stream()
+        .map(|result| result.map_err(|error| {
+            let kind = if error.is_timeout() { std::io::ErrorKind::TimedOut } else { std::io::ErrorKind::Other };
+            std::io::Error::new(kind, format!("IO Error: {error}"))
+        }))
+        .into_async_read())
+}
+
+/// An event of the Broker's SSE stream after it has been validated and decrypted for the App
+struct AppEvent {
+    event_type: SseEventType,
+    /// Passing on the Broker's event ids lets the App resume the stream with Last-Event-ID
+    id: Option<String>,
+    data: String,
+}
+
+impl From<AppEvent> for Event {
+    fn from(AppEvent { event_type, id, data }: AppEvent) -> Self {
+        let event = Event::default().event(event_type).data(data);
+        match id {
+            Some(id) => event.id(id),
+            None => event,
+        }
+    }
+}
+
+impl AppEvent {
+    /// WebSocket frames carry the fields of the SSE event as JSON. JSON data is embedded as is.
+    fn to_websocket_frame(&self) -> String {
+        let data = serde_json::from_str(&self.data).unwrap_or_else(|_| Value::String(self.data.clone()));
+        serde_json::json!({
+            "event": self.event_type.as_ref(),
+            "id": self.id,
+            "data": data,
+        }).to_string()
+    }
+}
+
+/// Sends the events to the App until the stream ends or the App closes the WebSocket
+async fn forward_events_to_websocket(events: impl Stream<Item = AppEvent>, mut socket: WebSocket) {
+    futures::pin_mut!(events);
+    loop {
+        tokio::select! {
+            event = events.next() => {
+                let Some(event) = event else {
+                    break;
+                };
+                if socket.send(WsMessage::Text(event.to_websocket_frame())).await.is_err() {
+                    debug!("WebSocket closed by App while sending");
+                    return;
+                }
+            },
+            msg = socket.recv() => match msg {
+                Some(Ok(WsMessage::Close(_))) | Some(Err(_)) | None => {
+                    debug!("WebSocket closed by App");
+                    return;
+                },
+                // Pings are answered by axum and Apps are not expected to send anything else
+                Some(Ok(_)) => {},
+            },
+        }
+    }
+    _ = socket.send(WsMessage::Close(None)).await;
+}
 
+/// Decodes the Broker's SSE stream and validates and decrypts the contained messages.
+/// Messages that have to be dropped are replaced by an error event so the App learns about them.
+fn validate_and_decrypt_sse(
+    incoming: impl futures::AsyncBufRead + Unpin + Send + 'static,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    validate_and_decrypt_events(incoming).map(|event| Ok(Event::from(event)))
+}
+
+fn validate_and_decrypt_events(
+    incoming: impl futures::AsyncBufRead + Unpin + Send + 'static,
+) -> impl Stream<Item = AppEvent> {
+    async_stream::stream! {
         let mut reader = async_sse::decode(incoming);
 
         while let Some(event) = reader.next().await {
             let event = match event {
                 Ok(event)=> event,
-                Err(e) if e.downcast_ref::<std::io::Error>().unwrap().kind() == std::io::ErrorKind::TimedOut => {
+                Err(e) if e.downcast_ref::<std::io::Error>().is_some_and(|e| e.kind() == std::io::ErrorKind::TimedOut) => {
                     debug!("SSE connection timed out");
                     break;
                 },
                 Err(err) => {
                     error!("Got error reading SSE stream: {err}");
-                    yield Ok(Event::default()
-                        .event(SseEventType::Error)
-                        .data("Error reading SSE stream from Broker (see Proxy logs for details)."));
+                    yield AppEvent {
+                        event_type: SseEventType::Error,
+                        id: None,
+                        data: "Error reading SSE stream from Broker (see Proxy logs for details).".to_string(),
+                    };
                     continue;
                 }
             };
@@ -221,22 +572,21 @@ async fn handler_tasks_stream(
                 async_sse::Event::Message(event) => {
                     // Check if this is a message or some control event
                     let event_type = SseEventType::from_str(event.name()).expect("Error in Infallible");
+                    let id = event.id().clone();
                     let mut event_as_bytes = event.into_bytes();
                     let event_as_str = std::str::from_utf8(&event_as_bytes).unwrap_or("(unable to parse)");
 
                     match &event_type {
-                        SseEventType::DeletedTask | SseEventType::WaitExpired => {
+                        SseEventType::DeletedTask | SseEventType::WaitExpired | SseEventType::Complete => {
                             debug!("SSE: Got {event_type} message, forwarding to App.");
-                            yield Ok(Event::default()
-                                .event(event_type)
-                                .data(event_as_str));
+                            let data = event_as_str.to_string();
+                            yield AppEvent { event_type, id, data };
                             continue;
                         },
                         SseEventType::Error => {
                             warn!("SSE: The Broker has reported an error: {event_as_str}");
-                            yield Ok(Event::default()
-                                .event(event_type)
-                                .data(event_as_str));
+                            let data = event_as_str.to_string();
+                            yield AppEvent { event_type, id, data };
                             continue;
                         },
                         SseEventType::Undefined => {
@@ -256,44 +606,42 @@ async fn handler_tasks_stream(
                     }
 
                     // Check reply's signature
-
                     if !event_as_bytes.is_empty() {
-                        let Ok(json) = serde_json::from_slice::<Value>(&event_as_bytes) else {
-                            warn!("Answer is no valid JSON; discarding: \"{event_as_str}\".");
-                            // TODO: For some reason, compiler won't accept the following lines, so we can't inform the App about the problem.
-                            //
-                            // warn!("Answer is no valid JSON; returning as-is to client: \"{event_as_str}\".");
-                            // yield Ok(Event::default()
-                            //     .event(SseEventType::Error)
-                            //     .data(format!("Broker sent invalid JSON: {event_as_str}")));
-                            continue;
-                        };
-                        let json = match validate_and_decrypt(json).await {
-                            Ok(json) => json,
-                            Err(err) => {
-                                warn!("Got an error decrypting Broker's reply: {err}");
+                        match validate_and_decrypt_event(&event_as_bytes).await {
+                            Ok(decrypted) => event_as_bytes = decrypted,
+                            Err(reason) => {
+                                warn!("SSE: Discarding {event_type} event: {reason}");
+                                yield AppEvent {
+                                    data: format!("Discarded {event_type} event from Broker: {reason}"),
+                                    event_type: SseEventType::Error,
+                                    id,
+                                };
                                 continue;
                             }
-                        };
-                        trace!("Decrypted Msg: {:#?}",json);
-                        event_as_bytes = serde_json::to_vec(&json).unwrap();
-                        trace!(
-                            "Validated and stripped signature: \"{}\"",
-                            std::str::from_utf8(&event_as_bytes).unwrap_or("Unable to parse string as UTF-8")
-                        );
+                        }
                     }
-                    let as_string = std::str::from_utf8(&event_as_bytes).unwrap_or("(garbled_utf8)");
-                    let event = Event::default()
-                        .event(event_type)
-                        .data(as_string);
-                    yield Ok(event);
+                    let data = std::str::from_utf8(&event_as_bytes).unwrap_or("(garbled_utf8)").to_string();
+                    yield AppEvent { event_type, id, data };
                 }
             }
         }
-    };
-    // TODO: Somehow return correct error code (not always possible since headers are sent before long request)
-    let sse = Sse::new(outgoing);
-    Ok(sse)
+    }
+}
+
+/// Returns the decrypted event data or a description of why the event has to be discarded
+async fn validate_and_decrypt_event(data: &[u8]) -> Result<Vec<u8>, String> {
+    let json = serde_json::from_slice::<Value>(data)
+        .map_err(|e| format!("Broker sent invalid JSON ({e}): {}", String::from_utf8_lossy(data)))?;
+    let json = validate_and_decrypt(json)
+        .await
+        .map_err(|e| format!("Unable to validate and decrypt Broker's reply: {e}"))?;
+    trace!("Decrypted Msg: {:#?}", json);
+    let decrypted = serde_json::to_vec(&json).expect("Serializing a Value does not fail");
+    trace!(
+        "Validated and stripped signature: \"{}\"",
+        std::str::from_utf8(&decrypted).unwrap_or("Unable to parse string as UTF-8")
+    );
+    Ok(decrypted)
 }
 
 pub(crate) fn to_server_error<T>(res: Result<T, SamplyBeamError>) -> Result<T, Response> {
@@ -307,6 +655,22 @@ pub(crate) fn to_server_error<T>(res: Result<T, SamplyBeamError>) -> Result<T, R
             ERR_VALIDATION
         },
         SamplyBeamError::SignEncryptError(_) => ERR_INTERNALCRYPTO,
+        SamplyBeamError::DecryptError(reason) => {
+            warn!("Unable to decrypt message: {reason}");
+            match reason {
+                DecryptErrorReason::NotARecipient => (StatusCode::UNPROCESSABLE_ENTITY, "This proxy is not a recipient of the message."),
+                DecryptErrorReason::KeyMismatch => (StatusCode::UNPROCESSABLE_ENTITY, "The message was not encrypted for this proxy's current key."),
+                DecryptErrorReason::MalformedCiphertext(_) => (StatusCode::BAD_GATEWAY, "The message's ciphertext is malformed."),
+            }
+        },
+        SamplyBeamError::Overloaded(e) => {
+            warn!("Rejecting request: {e}");
+            return (
+                StatusCode::SERVICE_UNAVAILABLE,
+                [(header::RETRY_AFTER, HeaderValue::from_static("1"))],
+                "Too many concurrent requests; please retry later.",
+            ).into_response();
+        },
         e => {
             warn!("Unhandled error {e}");
             (StatusCode::INTERNAL_SERVER_ERROR, "Unknown error")
@@ -317,63 +681,48 @@ pub(crate) fn to_server_error<T>(res: Result<T, SamplyBeamError>) -> Result<T, R
 // TODO: This could be a middleware
 pub async fn sign_request(
     body: EncryptedMessage,
-    mut parts: Parts,
+    parts: Parts,
     config: &config_proxy::Config,
     private_crypto: Option<&ConfigCrypto>,
 ) -> Result<reqwest::Request, (StatusCode, &'static str)> {
-    let from = body.get_from();
-
-    let token_without_extended_signature = crypto_jwt::sign_to_jwt(&body, private_crypto)
-        .await
-        .map_err(|e| {
-            error!("Crypto failed: {}", e);
-            ERR_INTERNALCRYPTO
-        })?;
-    let (_, sig) = token_without_extended_signature
-        .rsplit_once('.')
-        .ok_or_else(|| {
-            error!(
-                "Cannot get initial token's signature. Token: {}",
-                token_without_extended_signature
-            );
-            ERR_INTERNALCRYPTO
-        })?;
-    let mut headers_mut = parts.headers;
-    headers_mut.insert(
-        header::DATE,
-        HeaderValue::from_str(&fmt_http_date(SystemTime::now()))
-            .expect("Internal error: Unable to format system time"),
-    );
-    let digest =
-        crypto_jwt::make_extra_fields_digest(&parts.method, &parts.uri, &headers_mut, sig, &from)
-            .map_err(|_| ERR_INTERNALCRYPTO)?;
-    let token_with_extended_signature = crypto_jwt::sign_to_jwt(&digest, private_crypto)
+    shared::client::sign_request(body, parts, &config.broker_host_header, private_crypto)
         .await
         .map_err(|e| {
             error!("Crypto failed: {}", e);
             ERR_INTERNALCRYPTO
-        })?;
-    let body: reqwest::Body = token_without_extended_signature.into();
-    let mut auth_header = String::from("SamplyJWT ");
-    auth_header.push_str(&token_with_extended_signature);
-    headers_mut.insert(header::HOST, config.broker_host_header.clone());
-
-    headers_mut.remove(header::CONTENT_LENGTH);
-    headers_mut.insert(
-        header::CONTENT_TYPE,
-        HeaderValue::from_str("application/jwt").unwrap(),
-    ); // static input
-    headers_mut.insert(
-        header::AUTHORIZATION,
-        HeaderValue::from_str(&auth_header).map_err(|_| ERR_INTERNALCRYPTO)?,
-    );
-    parts.headers = headers_mut;
-    let req = Request::from_parts(parts, body);
-    Ok(req.try_into().expect("Uri to Url conversion should work"))
+        })
 }
 
-// This requires rustc 1.77
 pub(crate) async fn validate_and_decrypt(json: Value) -> Result<Value, SamplyBeamError> {
+    check_array_limits(&json, CONFIG_PROXY.max_array_depth, CONFIG_PROXY.max_array_length)?;
+    validate_and_decrypt_nested(json).await
+}
+
+/// Rejects arrays nested deeper than `max_depth` or longer than `max_length` before they are decrypted recursively.
+/// Only arrays count, as any object has to be a signed message.
+fn check_array_limits(json: &Value, max_depth: usize, max_length: usize) -> Result<(), SamplyBeamError> {
+    let mut arrays = vec![(json, 1)];
+    while let Some((value, depth)) = arrays.pop() {
+        let Value::Array(arr) = value else {
+            continue;
+        };
+        if depth > max_depth {
+            return Err(SamplyBeamError::JsonParseError(format!("Arrays of messages are nested deeper than {max_depth} levels")));
+        }
+        if arr.len() > max_length {
+            return Err(array_too_long(max_length));
+        }
+        arrays.extend(arr.iter().filter(|value| value.is_array()).map(|value| (value, depth + 1)));
+    }
+    Ok(())
+}
+
+fn array_too_long(max_length: usize) -> SamplyBeamError {
+    SamplyBeamError::JsonParseError(format!("Array holds more than {max_length} messages"))
+}
+
+// This requires rustc 1.77
+async fn validate_and_decrypt_nested(json: Value) -> Result<Value, SamplyBeamError> {
     // It might be possible to use MsgSigned directly instead but there are issues impl Deserialize for MsgSigned<EncryptedMessage>
     #[derive(Deserialize)]
     struct MsgSignedHelper {
@@ -382,16 +731,23 @@ pub(crate) async fn validate_and_decrypt(json: Value) -> Result<Value, SamplyBea
     if let Value::Array(arr) = json {
         let mut results = Vec::with_capacity(arr.len());
         for value in arr {
-            results.push(Box::pin(validate_and_decrypt(value)).await?);
+            results.push(Box::pin(validate_and_decrypt_nested(value)).await?);
         }
         Ok(Value::Array(results))
     } else if json.is_object() {
         match serde_json::from_value::<MsgSignedHelper>(json) {
             Ok(signed) => {
-                let msg = MsgSigned::<EncryptedMessage>::verify(&signed.jwt)
-                    .await?
-                    .msg;
-                Ok(serde_json::to_value(decrypt_msg(msg)?).expect("Should serialize fine"))
+                let msg = match MsgSigned::<EncryptedMessage>::verify(&signed.jwt).await {
+                    Ok(verified) => verified.msg,
+                    Err(e) => {
+                        let sender = shared::crypto_jwt::claimed_sender(&signed.jwt);
+                        warn!("Failed to verify message from {}: {e}", sender.as_ref().map_or("unknown sender".to_string(), ToString::to_string));
+                        metrics::record_decryption_failure(sender.as_ref(), FailureReason::Signature);
+                        return Err(e);
+                    }
+                };
+                let decrypted = CRYPTO_POOL.run(move || decrypt_msg(msg)).await??;
+                Ok(serde_json::to_value(decrypted).expect("Should serialize fine"))
             }
             Err(e) => Err(SamplyBeamError::JsonParseError(format!(
                 "Failed to parse broker response as a signed encrypted message. Err is {e}"
@@ -404,53 +760,92 @@ pub(crate) async fn validate_and_decrypt(json: Value) -> Result<Value, SamplyBea
     }
 }
 
-fn decrypt_msg<M: DecryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
-    msg.decrypt(
+pub(crate) fn decrypt_msg<M: DecryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
+    decrypt_msg_as(
+        msg,
         &AppOrProxyId::Proxy(CONFIG_PROXY.proxy_id.to_owned()),
-        &crypto::get_own_crypto_material().privkey_rsa,
+        crypto::get_own_crypto_material().decryption_keys(),
     )
 }
 
+fn decrypt_msg_as<'a, M: DecryptableMsg>(msg: M, me: &AppOrProxyId, privkeys: impl IntoIterator<Item = &'a RsaPrivateKey>) -> Result<M::Output, SamplyBeamError> {
+    let sender = msg.get_from().clone();
+    msg.decrypt_with_any_key(me, privkeys).inspect_err(|e| {
+        warn!("Failed to decrypt message from {sender}: {e}");
+        metrics::record_decryption_failure(Some(&sender), FailureReason::Decryption);
+    })
+}
+
 async fn encrypt_request(
     mut req: Request,
     sender: &AppId,
+    config: &config_proxy::Config,
+    client: &SamplyHttpClient,
 ) -> Result<(EncryptedMessage, Parts), Response> {
-    let parts = req.extract_parts().await.unwrap();
+    let parts: Parts = req.extract_parts().await.unwrap();
     let body: bytes::Bytes = req.extract().await.map_err(|e| {
         warn!("Unable to read message body: {e}");
         ERR_BODY.into_response()
     })?;
 
+    let mut pinned_keys = HashMap::new();
     let msg = if body.is_empty() {
         debug!("Body is empty, substituting MsgEmpty.");
         PlainMessage::MsgEmpty(MsgEmpty {
             from: sender.clone().into(),
         })
     } else {
-        match serde_json::from_slice(&body) {
-            Ok(val) => {
-                debug!("Body is valid json");
-                val
-            }
-            Err(e) => {
+        check_content_type(&parts.headers, config.require_content_type).map_err(IntoResponse::into_response)?;
+        let mut json: Value = if cbor::is_cbor(&parts.headers) {
+            cbor::to_json(&body).map_err(|e| {
+                warn!("Received Body is invalid CBOR: {e}");
+                ERR_BODY.into_response()
+            })?
+        } else {
+            serde_json::from_slice(&body).map_err(|e| {
                 warn!(
                     "Received Body is invalid json: {}. Body was {}",
                     e,
                     std::str::from_utf8(&body).unwrap_or("(not valid UTF-8)")
                 );
-                return Err(ERR_BODY.into_response());
+                ERR_BODY.into_response()
+            })?
+        };
+        debug!("Body is valid json");
+        expand_recipient_groups(&mut json, config, client).await?;
+        pinned_keys = take_pinned_keys(&mut json).map_err(IntoResponse::into_response)?;
+        if parts.method == Method::POST && parts.uri.path() == "/v1/tasks" {
+            if let Some(strategy) = &config.default_failure_strategy {
+                apply_default_failure_strategy(&mut json, strategy);
             }
+            check_task_fields(&json, config.deny_unknown_task_fields).map_err(IntoResponse::into_response)?;
         }
+        serde_json::from_value(json).map_err(|e| {
+            warn!("Received Body is no valid message: {e}");
+            ERR_BODY.into_response()
+        })?
     };
+    if let PlainMessage::MsgTaskRequest(task) = &msg {
+        task.failure_strategy.validate().map_err(|e| (StatusCode::BAD_REQUEST, e).into_response())?;
+    }
     // Sanity/security checks: From address sane?
     if msg.get_from() != sender {
         return Err(ERR_FAKED_FROM.into_response());
     }
-    let body = encrypt_msg(msg).await.map_err(|e| {
+    // Fail before looking up every recipient's certificate
+    if msg.get_to().len() > CONFIG_PROXY.max_task_recipients {
+        return Err((StatusCode::BAD_REQUEST, format!("Too many recipients; at most {} are allowed.", CONFIG_PROXY.max_task_recipients)).into_response());
+    }
+    check_allowed_recipients(sender, msg.get_to(), config.allowed_recipients.get(sender)).map_err(IntoResponse::into_response)?;
+    let body = encrypt_msg(msg, &pinned_keys).await.map_err(|e| {
         match e {
             SamplyBeamError::InvalidReceivers(proxies) => {
                 (StatusCode::FAILED_DEPENDENCY, Json(proxies)).into_response()
             }
+            SamplyBeamError::PinnedKeyMismatch(proxies) => {
+                warn!("Refusing to encrypt message of {sender} as the keys of {proxies:?} do not match their pinned fingerprints");
+                (StatusCode::CONFLICT, Json(proxies)).into_response()
+            }
             e => {
                 warn!("Encryption failed with: {e}");
                 ERR_INTERNALCRYPTO.into_response()
@@ -460,7 +855,498 @@ async fn encrypt_request(
     Ok((body, parts))
 }
 
-async fn encrypt_msg<M: EncryptableMsg>(msg: M) -> Result<M::Output, SamplyBeamError> {
+/// Field of a message pinning the public keys of recipient proxies to their fingerprints.
+/// It is only meant for this proxy and removed before the message is sent.
+const PINNED_KEYS_FIELD: &str = "pinned_keys";
+
+fn take_pinned_keys(msg: &mut Value) -> Result<HashMap<ProxyId, String>, (StatusCode, &'static str)> {
+    let Some(pinned_keys) = msg.as_object_mut().and_then(|msg| msg.remove(PINNED_KEYS_FIELD)) else {
+        return Ok(HashMap::new());
+    };
+    serde_json::from_value(pinned_keys).map_err(|e| {
+        warn!("Received invalid pinned keys: {e}");
+        (StatusCode::BAD_REQUEST, "pinned_keys has to map proxy ids to key fingerprints")
+    })
+}
+
+/// Tasks without a failure strategy or with `discard` get the default, as clients like beam-lib send `discard` unless told otherwise
+fn apply_default_failure_strategy(task: &mut Value, strategy: &FailureStrategy) {
+    let Value::Object(task) = task else {
+        return;
+    };
+    if task.get("failure_strategy").is_none_or(|current| current.is_null() || current == "discard") {
+        task.insert("failure_strategy".to_string(), serde_json::to_value(strategy).expect("Failure strategies serialize"));
+    }
+}
+
+/// Serde ignores unknown fields, so a misspelled field would silently get its default.
+/// In strict mode such tasks are rejected, naming the unknown fields.
+fn check_task_fields(task: &Value, strict: bool) -> Result<(), (StatusCode, String)> {
+    let (true, Value::Object(task)) = (strict, task) else {
+        return Ok(());
+    };
+    let unknown: Vec<&str> = task
+        .keys()
+        .map(String::as_str)
+        .filter(|field| !MsgTaskRequest::FIELDS.contains(field))
+        .collect();
+    if unknown.is_empty() {
+        return Ok(());
+    }
+    warn!("Rejected task with unknown fields {unknown:?}");
+    Err((StatusCode::BAD_REQUEST, format!("Unknown fields in task: {}", unknown.join(", "))))
+}
+
+/// Operators may restrict the proxies an app can address to keep tenants apart. Rejects messages naming the recipients on other proxies.
+fn check_allowed_recipients(sender: &AppId, to: &[AppOrProxyId], allowed: Option<&HashSet<ProxyId>>) -> Result<(), (StatusCode, Json<Vec<AppOrProxyId>>)> {
+    let Some(allowed) = allowed else {
+        return Ok(());
+    };
+    let disallowed: Vec<_> = to.iter().filter(|to| !allowed.contains(&to.proxy_id())).cloned().collect();
+    if disallowed.is_empty() {
+        return Ok(());
+    }
+    warn!("Refusing to send message of {sender} to {disallowed:?} as it may not address their proxies");
+    Err((StatusCode::FORBIDDEN, Json(disallowed)))
+}
+
+/// Bodies are parsed as JSON unless they are declared as CBOR, so e.g. form-encoded bodies fail as invalid JSON.
+/// In strict mode bodies have to be declared as either.
+fn check_content_type(headers: &HeaderMap, strict: bool) -> Result<(), (StatusCode, &'static str)> {
+    if !strict || cbor::is_cbor(headers) {
+        return Ok(());
+    }
+    let content_type = headers.get(header::CONTENT_TYPE).and_then(|v| v.to_str().ok());
+    if content_type.is_some_and(|v| v.split(';').next().unwrap_or_default().trim().eq_ignore_ascii_case("application/json")) {
+        return Ok(());
+    }
+    warn!("Rejected body with Content-Type {content_type:?}");
+    Err((StatusCode::UNSUPPORTED_MEDIA_TYPE, "Content-Type has to be application/json or application/cbor"))
+}
+
+/// Prefix of recipient groups defined at the broker in the `to` field of a message
+const RECIPIENT_GROUP_PREFIX: &str = "group:";
+/// Prefix of capabilities advertised by workers in the `to` field of a message
+const CAPABILITY_PREFIX: &str = "capability:";
+
+/// Recipients which the broker resolves: their prefix, the broker's endpoint and their name in error messages
+const RECIPIENT_GROUP_KINDS: [(&str, &str, &str); 2] = [
+    (RECIPIENT_GROUP_PREFIX, "groups", "recipient group"),
+    (CAPABILITY_PREFIX, "capabilities", "capability"),
+];
+
+/// Replaces the recipient groups and capabilities in the `to` field of a message with their members as known to the broker.
+/// This has to happen here as the message is encrypted for every member individually.
+async fn expand_recipient_groups(msg: &mut Value, config: &config_proxy::Config, client: &SamplyHttpClient) -> Result<(), Response> {
+    let mut members = HashMap::new();
+    for (prefix, endpoint, kind) in RECIPIENT_GROUP_KINDS {
+        for group in recipient_groups(msg, prefix) {
+            if !group.chars().all(|c| c.is_ascii_alphanumeric() || c == '-') {
+                return Err((StatusCode::BAD_REQUEST, format!("Invalid {kind} {group}")).into_response());
+            }
+            if config.resolved_recipients.is_empty() {
+                return Err((StatusCode::BAD_REQUEST, format!("This proxy does not resolve the {kind} {group} as RESOLVED_RECIPIENTS is not set")).into_response());
+            }
+            let group_members = get_group_members(endpoint, kind, &group, config, client).await?;
+            check_resolved_members(kind, &group, &group_members, &config.resolved_recipients).map_err(IntoResponse::into_response)?;
+            members.insert(format!("{prefix}{group}"), group_members);
+        }
+    }
+    if !members.is_empty() {
+        replace_recipient_groups(msg, &members);
+    }
+    Ok(())
+}
+
+/// The broker is not trusted with the contents of messages, so it may only resolve groups to proxies the operator allowed.
+/// Rejects groups with other members, naming them.
+fn check_resolved_members(kind: &str, group: &str, members: &[AppOrProxyId], allowed: &HashSet<ProxyId>) -> Result<(), (StatusCode, Json<Vec<AppOrProxyId>>)> {
+    let unexpected: Vec<_> = members.iter().filter(|member| !allowed.contains(&member.proxy_id())).cloned().collect();
+    if unexpected.is_empty() {
+        return Ok(());
+    }
+    warn!("The broker resolved the {kind} {group} to {unexpected:?} which are not on proxies in RESOLVED_RECIPIENTS");
+    Err((StatusCode::FORBIDDEN, Json(unexpected)))
+}
+
+fn recipient_groups(msg: &Value, prefix: &str) -> HashSet<String> {
+    let Some(Value::Array(to)) = msg.get("to") else {
+        return HashSet::new();
+    };
+    to.iter()
+        .filter_map(|recipient| recipient.as_str()?.strip_prefix(prefix))
+        .map(ToString::to_string)
+        .collect()
+}
+
+/// `members` maps the prefixed groups to their members
+fn replace_recipient_groups(msg: &mut Value, members: &HashMap<String, Vec<AppOrProxyId>>) {
+    let Some(Value::Array(to)) = msg.get_mut("to") else {
+        return;
+    };
+    let mut expanded: Vec<Value> = Vec::with_capacity(to.len());
+    for recipient in to.drain(..) {
+        let group_members = recipient
+            .as_str()
+            .and_then(|recipient| members.get(recipient));
+        let recipients = match group_members {
+            Some(group_members) => group_members.iter().map(|member| Value::String(member.to_string())).collect(),
+            None => vec![recipient],
+        };
+        // Recipients may be part of several groups
+        for recipient in recipients {
+            if !expanded.contains(&recipient) {
+                expanded.push(recipient);
+            }
+        }
+    }
+    *to = expanded;
+}
+
+async fn get_group_members(endpoint: &str, kind: &str, group: &str, config: &config_proxy::Config, client: &SamplyHttpClient) -> Result<Vec<AppOrProxyId>, Response> {
+    let uri = Uri::try_from(format!("{}v1/{endpoint}/{group}", config.broker_uri))
+        .map_err(|_| (StatusCode::BAD_REQUEST, format!("Invalid {kind}")).into_response())?;
+    let (parts, _) = Request::get(uri).body(()).expect("To build request successfully").into_parts();
+    let body = EncryptedMessage::MsgEmpty(MsgEmpty { from: AppOrProxyId::Proxy(config.proxy_id.clone()) });
+    let req = sign_request(body, parts, config, None).await.map_err(IntoResponse::into_response)?;
+    let res = BROKERS.execute(client, req).await.map_err(|e| {
+        warn!("Unable to resolve {kind} {group}: {e}");
+        (StatusCode::BAD_GATEWAY, "Upstream error; see server logs.").into_response()
+    })?;
+    match res.status() {
+        StatusCode::OK => res.json().await.map_err(|e| {
+            warn!("Invalid members of {kind} {group}: {e}");
+            ERR_UPSTREAM.into_response()
+        }),
+        StatusCode::NOT_FOUND => Err((StatusCode::BAD_REQUEST, format!("Unknown {kind} {group}")).into_response()),
+        code => {
+            warn!("Got unexpected response code {code} resolving {kind} {group}");
+            Err((StatusCode::BAD_GATEWAY, "Upstream error; see server logs.").into_response())
+        }
+    }
+}
+
+async fn encrypt_msg<M: EncryptableMsg>(msg: M, pinned_keys: &HashMap<ProxyId, String>) -> Result<M::Output, SamplyBeamError> {
     let receivers_keys = crypto::get_proxy_public_keys(msg.get_to()).await?;
+    encrypt_msg_with_pinned_keys(msg, receivers_keys, pinned_keys)
+}
+
+/// Encrypts the message unless the key of a recipient differs from the one pinned by the app, e.g. because the PKI was compromised.
+/// `receivers_keys` are the keys of the recipients in the order of the message's `to` field.
+fn encrypt_msg_with_pinned_keys<M: EncryptableMsg>(msg: M, receivers_keys: Vec<RsaPublicKey>, pinned_keys: &HashMap<ProxyId, String>) -> Result<M::Output, SamplyBeamError> {
+    let mut mismatches = Vec::new();
+    for (receiver, key) in msg.get_to().iter().zip(&receivers_keys) {
+        let proxy = receiver.proxy_id();
+        let Some(pinned) = pinned_keys.get(&proxy) else {
+            continue;
+        };
+        if !crypto::public_key_fingerprint(key)?.eq_ignore_ascii_case(pinned) && !mismatches.contains(&proxy) {
+            mismatches.push(proxy);
+        }
+    }
+    if !mismatches.is_empty() {
+        return Err(SamplyBeamError::PinnedKeyMismatch(mismatches));
+    }
     msg.encrypt(&receivers_keys)
 }
+
+#[cfg(test)]
+mod tests {
+    use shared::test_utils::{app, app_id, init_broker_id, proxy_id};
+
+    use super::*;
+
+    #[test]
+    fn decryption_failure_is_counted() {
+        init_broker_id();
+        let sender = AppOrProxyId::new("app1.proxy-undecryptable.broker.samply.de").unwrap();
+        let me = AppOrProxyId::new("proxy1.broker.samply.de").unwrap();
+        let failures = || metrics::DECRYPTION_FAILURES.with_label_values(&["proxy-undecryptable.broker.samply.de", "decryption"]).get();
+        let key = || RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let (my_key, other_key) = (key(), key());
+        let msg = MsgTaskRequest::new(sender, vec![me.clone()], "secret".to_string(), FailureStrategy::Discard, Value::Null);
+        let encrypted = msg.encrypt(&vec![RsaPublicKey::from(&other_key)]).unwrap();
+
+        assert_eq!(failures(), 0);
+        assert!(decrypt_msg_as(encrypted.clone(), &me, [&my_key]).is_err());
+        assert_eq!(failures(), 1);
+        assert!(decrypt_msg_as(encrypted, &me, [&other_key]).is_ok());
+        assert_eq!(failures(), 1);
+    }
+
+    #[test]
+    fn decrypt_with_previous_key_after_rotation() {
+        init_broker_id();
+        let sender = AppOrProxyId::new("app1.proxy2.broker.samply.de").unwrap();
+        let me = AppOrProxyId::new("proxy1.broker.samply.de").unwrap();
+        let key = || RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap();
+        let (old_key, new_key) = (key(), key());
+        let in_flight = MsgTaskRequest::new(sender.clone(), vec![me.clone()], "before".to_string(), FailureStrategy::Discard, Value::Null)
+            .encrypt(&vec![RsaPublicKey::from(&old_key)])
+            .unwrap();
+        let current = MsgTaskRequest::new(sender, vec![me.clone()], "after".to_string(), FailureStrategy::Discard, Value::Null)
+            .encrypt(&vec![RsaPublicKey::from(&new_key)])
+            .unwrap();
+
+        assert!(decrypt_msg_as(in_flight.clone(), &me, [&new_key]).is_err(), "The new key alone cannot decrypt messages encrypted before the rotation");
+        let rotated = [&new_key, &old_key];
+        assert_eq!(decrypt_msg_as(in_flight, &me, rotated).unwrap().body.body.as_deref(), Some("before"));
+        assert_eq!(decrypt_msg_as(current, &me, rotated).unwrap().body.body.as_deref(), Some("after"));
+    }
+
+    #[test]
+    fn pinned_key_mismatch_aborts_encryption() {
+        let sender = app("app1");
+        let (proxy2, proxy3) = (ProxyId::new("proxy2.broker.samply.de").unwrap(), ProxyId::new("proxy3.broker.samply.de").unwrap());
+        let to = vec![AppOrProxyId::new("app1.proxy2.broker.samply.de").unwrap(), AppOrProxyId::Proxy(proxy3.clone())];
+        let msg = MsgTaskRequest::new(sender, to, "secret".to_string(), FailureStrategy::Discard, Value::Null);
+        let key = || RsaPublicKey::from(&RsaPrivateKey::new(&mut rand::thread_rng(), 1024).unwrap());
+        let (key2, key3, forged) = (key(), key(), key());
+        let fingerprint = |key: &RsaPublicKey| crypto::public_key_fingerprint(key).unwrap();
+        assert_eq!(fingerprint(&key2).len(), 64);
+
+        let pins = HashMap::from([(proxy2.clone(), fingerprint(&key2))]);
+        assert!(encrypt_msg_with_pinned_keys(msg.clone(), vec![key2.clone(), key3.clone()], &pins).is_ok(), "Recipients without a pin are not checked");
+        let pins = HashMap::from([(proxy2.clone(), fingerprint(&key2).to_uppercase()), (proxy3.clone(), fingerprint(&key3))]);
+        assert!(encrypt_msg_with_pinned_keys(msg.clone(), vec![key2.clone(), key3.clone()], &pins).is_ok());
+
+        match encrypt_msg_with_pinned_keys(msg, vec![forged, key3], &pins) {
+            Err(SamplyBeamError::PinnedKeyMismatch(proxies)) => assert_eq!(proxies, [proxy2]),
+            other => panic!("Encryption should have been aborted, got {:?}", other.map(|_| ())),
+        }
+    }
+
+    #[test]
+    fn pinned_keys_are_removed() {
+        let mut msg = serde_json::json!({"body": "secret", "pinned_keys": {"proxy2.broker.samply.de": "ab12"}});
+        init_broker_id();
+        let pins = take_pinned_keys(&mut msg).unwrap();
+        assert_eq!(pins[&ProxyId::new("proxy2.broker.samply.de").unwrap()], "ab12");
+        assert_eq!(msg, serde_json::json!({"body": "secret"}));
+        assert!(take_pinned_keys(&mut serde_json::json!({"pinned_keys": ["ab12"]})).is_err());
+    }
+
+    #[test]
+    fn repoll_delays() {
+        for attempt in 0..10 {
+            let ceiling = REPOLL_BASE_DELAY * 2u32.pow(attempt);
+            assert!(repoll_delay(attempt) <= ceiling.min(REPOLL_MAX_DELAY));
+        }
+        assert!(repoll_delay(u32::MAX) <= REPOLL_MAX_DELAY);
+    }
+
+    #[test]
+    fn array_limits() {
+        let mut nested = Value::Array(Vec::new());
+        for _ in 0..1000 {
+            nested = Value::Array(vec![nested]);
+        }
+        assert!(check_array_limits(&nested, 8, 100).is_err());
+        let wide = Value::Array(vec![Value::Null; 100_001]);
+        assert!(check_array_limits(&wide, 8, 100_000).is_err());
+        let hidden = serde_json::json!([{"jwt": ""}, [[{"jwt": ""}], vec![0; 101]]]);
+        assert!(check_array_limits(&hidden, 8, 100).is_err(), "Nested arrays are limited as well");
+
+        let results = serde_json::json!([{"jwt": ""}, {"jwt": ""}]);
+        assert!(check_array_limits(&results, 1, 2).is_ok());
+        assert!(check_array_limits(&serde_json::json!([results]), 1, 2).is_err());
+        assert!(check_array_limits(&serde_json::json!({"jwt": ""}), 0, 0).is_ok());
+    }
+
+    #[test]
+    fn expand_groups() {
+        init_broker_id();
+        let member = |id: &str| AppOrProxyId::new(id).unwrap();
+        let mut msg = serde_json::json!({
+            "to": ["app1.proxy1.broker.samply.de", "group:hospitals", "group:labs"]
+        });
+        assert_eq!(recipient_groups(&msg, RECIPIENT_GROUP_PREFIX), HashSet::from(["hospitals".to_string(), "labs".to_string()]));
+        let members = HashMap::from([
+            ("group:hospitals".to_string(), vec![member("proxy2.broker.samply.de"), member("app1.proxy1.broker.samply.de")]),
+            ("group:labs".to_string(), vec![member("app1.proxy3.broker.samply.de")]),
+        ]);
+        replace_recipient_groups(&mut msg, &members);
+        assert_eq!(msg["to"], serde_json::json!(["app1.proxy1.broker.samply.de", "proxy2.broker.samply.de", "app1.proxy3.broker.samply.de"]));
+        assert!(recipient_groups(&msg, RECIPIENT_GROUP_PREFIX).is_empty());
+    }
+
+    #[test]
+    fn resolved_groups_stay_on_allowed_proxies() {
+        let allowed = HashSet::from([proxy_id("proxy1"), proxy_id("proxy2")]);
+        let members = [app("app1"), AppOrProxyId::from(app_id("app1", "proxy2")), AppOrProxyId::Proxy(proxy_id("proxy2"))];
+        assert!(check_resolved_members("recipient group", "hospitals", &members, &allowed).is_ok());
+
+        let planted = AppOrProxyId::from(app_id("app1", "proxy3"));
+        let (status, Json(unexpected)) = check_resolved_members("recipient group", "hospitals", &[members[0].clone(), planted.clone()], &allowed).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(unexpected, [planted]);
+    }
+
+    #[test]
+    fn expand_capabilities() {
+        init_broker_id();
+        let member = |id: &str| AppOrProxyId::new(id).unwrap();
+        let mut msg = serde_json::json!({
+            "to": ["capability:ocr", "group:ocr", "app2.proxy2.broker.samply.de"]
+        });
+        assert_eq!(recipient_groups(&msg, CAPABILITY_PREFIX), HashSet::from(["ocr".to_string()]));
+        let members = HashMap::from([
+            ("capability:ocr".to_string(), vec![member("app1.proxy1.broker.samply.de"), member("app2.proxy2.broker.samply.de")]),
+            ("group:ocr".to_string(), vec![member("proxy3.broker.samply.de")]),
+        ]);
+        replace_recipient_groups(&mut msg, &members);
+        assert_eq!(msg["to"], serde_json::json!(["app1.proxy1.broker.samply.de", "app2.proxy2.broker.samply.de", "proxy3.broker.samply.de"]));
+
+        // Any worker may advertise a capability, but only those on allowed proxies receive the task
+        let allowed = HashSet::from([proxy_id("proxy1"), proxy_id("proxy2")]);
+        let workers = &members["capability:ocr"];
+        assert!(check_resolved_members("capability", "ocr", workers, &allowed).is_ok());
+        let advertised = [workers.clone(), vec![member("app1.proxy3.broker.samply.de")]].concat();
+        let (status, Json(unexpected)) = check_resolved_members("capability", "ocr", &advertised, &allowed).unwrap_err();
+        assert_eq!((status, unexpected), (StatusCode::FORBIDDEN, vec![member("app1.proxy3.broker.samply.de")]));
+    }
+
+    #[test]
+    fn content_type() {
+        let headers = |content_type: Option<&'static str>| {
+            let mut headers = HeaderMap::new();
+            if let Some(content_type) = content_type {
+                headers.insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+            }
+            headers
+        };
+        for declared in [Some("application/json"), Some("application/json; charset=utf-8"), Some("application/cbor")] {
+            assert!(check_content_type(&headers(declared), true).is_ok(), "{declared:?} is accepted");
+        }
+        for wrong in [Some("application/x-www-form-urlencoded"), Some("text/plain"), None] {
+            assert!(check_content_type(&headers(wrong), false).is_ok(), "Lenient by default");
+            assert_eq!(check_content_type(&headers(wrong), true).unwrap_err().0, StatusCode::UNSUPPORTED_MEDIA_TYPE, "{wrong:?} is rejected");
+        }
+    }
+
+    #[test]
+    fn disallowed_recipients() {
+        let sender = app_id("app1", "proxy1");
+        let (own, allowed, other) = (
+            AppOrProxyId::new("app2.proxy1.broker.samply.de").unwrap(),
+            AppOrProxyId::new("app1.proxy2.broker.samply.de").unwrap(),
+            AppOrProxyId::new("proxy3.broker.samply.de").unwrap(),
+        );
+        let to = [own.clone(), allowed.clone(), other.clone()];
+        assert!(check_allowed_recipients(&sender, &to, None).is_ok(), "Apps without an allowlist may address anyone");
+
+        let allowlist = HashSet::from([own.proxy_id(), allowed.proxy_id()]);
+        assert!(check_allowed_recipients(&sender, &to[..2], Some(&allowlist)).is_ok());
+        let (status, Json(disallowed)) = check_allowed_recipients(&sender, &to, Some(&allowlist)).unwrap_err();
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        assert_eq!(disallowed, [other]);
+    }
+
+    #[test]
+    fn unknown_task_fields() {
+        let mut task = serde_json::to_value(MsgTaskRequest::new(
+            app("app1"),
+            vec![AppOrProxyId::new("app1.proxy2.broker.samply.de").unwrap()],
+            "body".to_string(),
+            FailureStrategy::Discard,
+            Value::Null,
+        )).unwrap();
+        task["completion_policy"] = serde_json::json!("all");
+        assert!(task.as_object().unwrap().keys().all(|field| MsgTaskRequest::FIELDS.contains(&field.as_str())));
+        assert!(check_task_fields(&task, true).is_ok());
+
+        task["recipients"] = serde_json::json!(["app1.proxy3.broker.samply.de"]);
+        task["ttl_secs"] = serde_json::json!(60);
+        assert!(check_task_fields(&task, false).is_ok());
+        assert!(serde_json::from_value::<MsgTaskRequest>(task.clone()).is_ok(), "Unknown fields are ignored by default");
+        let (status, message) = check_task_fields(&task, true).unwrap_err();
+        assert_eq!(status, StatusCode::BAD_REQUEST);
+        assert_eq!(message, "Unknown fields in task: recipients, ttl_secs");
+    }
+
+    #[test]
+    fn default_failure_strategy() {
+        let strategy = FailureStrategy::Retry { backoff_millisecs: 1000, max_tries: 5 };
+        let mut task = serde_json::json!({"id": "a"});
+        apply_default_failure_strategy(&mut task, &strategy);
+        assert_eq!(task["failure_strategy"], serde_json::json!({"retry": {"backoff_millisecs": 1000, "max_tries": 5}}));
+        let mut task = serde_json::json!({"failure_strategy": null});
+        apply_default_failure_strategy(&mut task, &strategy);
+        assert_eq!(task["failure_strategy"]["retry"]["max_tries"], 5);
+        let mut task = serde_json::json!({"failure_strategy": "discard"});
+        apply_default_failure_strategy(&mut task, &strategy);
+        assert_eq!(task["failure_strategy"]["retry"]["max_tries"], 5, "Discarding is what clients send by default");
+        let mut task = serde_json::json!({"failure_strategy": {"retry": {"backoff_millisecs": 10, "max_tries": 1}}});
+        apply_default_failure_strategy(&mut task, &strategy);
+        assert_eq!(task["failure_strategy"]["retry"]["max_tries"], 1);
+    }
+
+    #[tokio::test]
+    async fn malformed_sse_event_is_reported() {
+        let incoming = futures::io::Cursor::new(
+            "event: new_result\ndata: {\"jwt\": \"cut off\n\nevent: wait_expired\ndata: []\n\n"
+        );
+        let res = Sse::new(validate_and_decrypt_sse(incoming)).into_response();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        let (error, rest) = body.split_once("\n\n").unwrap();
+        assert!(error.starts_with("event: error\ndata: Discarded new_result event from Broker: Broker sent invalid JSON"), "{error}");
+        assert_eq!(rest, "event: wait_expired\ndata: []\n\n");
+    }
+
+    #[tokio::test]
+    async fn malformed_ndjson_result_aborts_stream() {
+        let incoming = futures::io::Cursor::new("\n{\"jwt\": \"cut off\n");
+        let mut stream = Box::pin(validate_and_decrypt_ndjson(incoming));
+        // Blank lines are skipped rather than reported
+        let Some(Err(SamplyBeamError::JsonParseError(reason))) = stream.next().await else {
+            panic!("Malformed result was not reported");
+        };
+        assert!(reason.starts_with("Broker sent invalid JSON"), "{reason}");
+        assert!(stream.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn results_over_websocket() {
+        use tokio_tungstenite::tungstenite::Message;
+
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        let app = Router::new().route("/results", get(|ws: WebSocketUpgrade| async move {
+            let incoming = futures::io::Cursor::new(
+                "event: new_result\nid: 3\ndata: {\"jwt\": \"cut off\n\nevent: wait_expired\ndata: []\n\n"
+            );
+            ws.on_upgrade(|socket| forward_events_to_websocket(validate_and_decrypt_events(incoming), socket))
+        }));
+        tokio::spawn(async move { axum::serve(listener, app).await });
+
+        let (mut socket, _) = tokio_tungstenite::connect_async(format!("ws://{addr}/results")).await.unwrap();
+        let mut frames = Vec::new();
+        while let Some(msg) = socket.next().await {
+            match msg.unwrap() {
+                Message::Text(frame) => frames.push(serde_json::from_str::<Value>(&frame).unwrap()),
+                Message::Close(_) => break,
+                other => panic!("Unexpected message {other:?}"),
+            }
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0]["event"], "error");
+        assert_eq!(frames[0]["id"], "3");
+        assert!(frames[0]["data"].as_str().unwrap().starts_with("Discarded new_result event from Broker"));
+        assert_eq!(frames[1], serde_json::json!({"event": "wait_expired", "id": "3", "data": []}));
+    }
+
+    #[tokio::test]
+    async fn sse_event_ids_are_forwarded() {
+        let incoming = futures::io::Cursor::new(
+            "event: new_result\nid: 3\ndata: {\"jwt\": \"cut off\n\nevent: wait_expired\ndata: []\n\n"
+        );
+        let res = Sse::new(validate_and_decrypt_sse(incoming)).into_response();
+        let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+        let body = std::str::from_utf8(&body).unwrap();
+        let (discarded, rest) = body.split_once("\n\n").unwrap();
+        assert!(discarded.ends_with("\nid: 3"), "{discarded}");
+        // Like in browsers, the last event id sticks until the Broker sends a new one
+        assert_eq!(rest, "event: wait_expired\ndata: []\nid: 3\n\n");
+    }
+}
@@ -0,0 +1,91 @@
+//! Measures the memory used to pass on a large JSON array of results.
+//! It is a separate test binary without the test harness, so its counting allocator does not affect other tests.
+
+use std::{alloc::{GlobalAlloc, Layout, System}, cell::Cell};
+
+use bytes::BytesMut;
+
+#[path = "../src/json_array.rs"]
+#[allow(dead_code)]
+mod json_array;
+
+use json_array::JsonArraySplitter;
+
+thread_local! {
+    static CURRENT: Cell<isize> = const { Cell::new(0) };
+    static PEAK: Cell<isize> = const { Cell::new(0) };
+}
+
+/// Counts the heap memory allocated by the current thread
+struct PeakAlloc;
+
+#[global_allocator]
+static ALLOCATOR: PeakAlloc = PeakAlloc;
+
+unsafe impl GlobalAlloc for PeakAlloc {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        track(layout.size() as isize);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        track(-(layout.size() as isize));
+        System.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        track(new_size as isize - layout.size() as isize);
+        System.realloc(ptr, layout, new_size)
+    }
+}
+
+fn track(delta: isize) {
+    // Thread locals are gone while a thread shuts down
+    _ = CURRENT.try_with(|current| {
+        current.set(current.get() + delta);
+        _ = PEAK.try_with(|peak| peak.set(peak.get().max(current.get())));
+    });
+}
+
+/// Runs `f` and returns the most bytes it had allocated at once on this thread
+fn peak_during(f: impl FnOnce()) -> usize {
+    let base = CURRENT.with(Cell::get);
+    PEAK.with(|peak| peak.set(base));
+    f();
+    (PEAK.with(Cell::get) - base) as usize
+}
+
+/// Compares reading a reply of 100 results of 64 KiB as a whole, as the proxy used to, with splitting it into its elements.
+/// Decryption is left out as it needs a PKI and costs the same for every element either way.
+fn main() {
+    const ELEMENT_SIZE: usize = 64 * 1024;
+    let element = serde_json::json!({ "jwt": "x".repeat(ELEMENT_SIZE) });
+    let reply = serde_json::to_vec(&vec![element; 100]).unwrap();
+    let chunks = || reply.chunks(16 * 1024);
+
+    let buffered = peak_during(|| {
+        let mut body = BytesMut::new();
+        for chunk in chunks() {
+            body.extend_from_slice(chunk);
+        }
+        let json: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        let out = serde_json::to_vec(&json).unwrap();
+        assert_eq!(out.len(), reply.len());
+    });
+    let split = peak_during(|| {
+        let mut splitter = JsonArraySplitter::default();
+        let mut sent = 2;
+        for chunk in chunks() {
+            splitter.push(chunk);
+            while let Some(element) = splitter.next_element().unwrap() {
+                let json: serde_json::Value = serde_json::from_slice(&element).unwrap();
+                // Handed to the response body, which sends it right away
+                sent += serde_json::to_vec(&json).unwrap().len() + 1;
+            }
+        }
+        splitter.finish().unwrap();
+        assert_eq!(sent - 1, reply.len());
+    });
+    assert!(buffered >= 2 * reply.len(), "Buffering holds the reply and its copy: {buffered} bytes for a {} byte reply", reply.len());
+    assert!(split < 8 * ELEMENT_SIZE, "Splitting only holds a few elements at once: {split} bytes");
+}
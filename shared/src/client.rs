@@ -0,0 +1,329 @@
+use std::{sync::Arc, time::SystemTime};
+
+use axum::http::{header, request::Parts, HeaderValue, Method, Request, StatusCode};
+use beam_lib::{AppOrProxyId, MsgId, ProxyId};
+use httpdate::fmt_http_date;
+use reqwest::Url;
+use serde::Deserialize;
+use tracing::debug;
+
+use crate::{
+    config_proxy::uri_to_host_header, config_shared::ConfigCrypto, crypto, crypto_jwt,
+    errors::SamplyBeamError, http_client::SamplyHttpClient, DecryptableMsg, EncryptableMsg,
+    EncryptedMessage, EncryptedMsgTaskResult, HowLongToBlock, Msg, MsgEmpty, MsgSigned,
+    MsgTaskRequest, MsgTaskResult,
+};
+
+/// Signs a request to the broker on behalf of the sender of `body`.
+/// The broker verifies the signature over the method, uri and date of the request in addition to the body.
+pub async fn sign_request(
+    body: EncryptedMessage,
+    parts: Parts,
+    broker_host_header: &HeaderValue,
+    private_crypto: Option<&ConfigCrypto>,
+) -> Result<reqwest::Request, SamplyBeamError> {
+    let token = crypto_jwt::sign_to_jwt(&body, private_crypto).await?;
+    sign_request_with_token(token, body.get_from(), parts, broker_host_header, private_crypto).await
+}
+
+/// Like [`sign_request`] for a body `from` signed before, so the very same message can be sent more than once
+pub async fn sign_request_with_token(
+    token_without_extended_signature: String,
+    from: &AppOrProxyId,
+    mut parts: Parts,
+    broker_host_header: &HeaderValue,
+    private_crypto: Option<&ConfigCrypto>,
+) -> Result<reqwest::Request, SamplyBeamError> {
+    let (_, sig) = token_without_extended_signature
+        .rsplit_once('.')
+        .ok_or_else(|| SamplyBeamError::SignEncryptError(format!(
+            "Cannot get initial token's signature. Token: {token_without_extended_signature}"
+        )))?;
+    parts.headers.insert(
+        header::DATE,
+        HeaderValue::from_str(&fmt_http_date(SystemTime::now()))
+            .expect("Internal error: Unable to format system time"),
+    );
+    let digest = crypto_jwt::make_extra_fields_digest(&parts.method, &parts.uri, &parts.headers, sig, from)?;
+    let token_with_extended_signature = crypto_jwt::sign_to_jwt(&digest, private_crypto).await?;
+    parts.headers.insert(header::HOST, broker_host_header.clone());
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/jwt"));
+    parts.headers.insert(
+        header::AUTHORIZATION,
+        HeaderValue::from_str(&format!("SamplyJWT {token_with_extended_signature}"))
+            .map_err(|e| SamplyBeamError::SignEncryptError(format!("Invalid authorization header: {e}")))?,
+    );
+    let body: reqwest::Body = token_without_extended_signature.into();
+    Ok(Request::from_parts(parts, body).try_into().expect("Uri to Url conversion should work"))
+}
+
+/// Talks to the broker directly as a proxy would, encrypting and signing outgoing messages and
+/// verifying and decrypting incoming ones. Recipients' certificates are looked up through [`crypto`].
+pub struct BrokerClient {
+    client: SamplyHttpClient,
+    broker_url: Url,
+    broker_host_header: HeaderValue,
+    proxy_id: ProxyId,
+    crypto: Arc<ConfigCrypto>,
+}
+
+impl BrokerClient {
+    pub fn new(client: SamplyHttpClient, broker_url: Url, proxy_id: ProxyId, crypto: Arc<ConfigCrypto>) -> Result<Self, SamplyBeamError> {
+        Ok(Self {
+            client,
+            broker_host_header: uri_to_host_header(&broker_url)?,
+            broker_url,
+            proxy_id,
+            crypto,
+        })
+    }
+
+    /// Creates a task, failing with [`SamplyBeamError::InvalidReceivers`] if some recipients have no valid certificate
+    pub async fn create_task(&self, task: MsgTaskRequest) -> Result<MsgId, SamplyBeamError> {
+        let id = task.id;
+        let keys = crypto::get_proxy_public_keys(task.get_to()).await?;
+        let res = self.send(Method::POST, "v1/tasks", EncryptedMessage::MsgTaskRequest(task.encrypt(&keys)?)).await?;
+        match res.status() {
+            StatusCode::CREATED => Ok(id),
+            _ => Err(Self::error_for(res).await),
+        }
+    }
+
+    /// Creates or updates a result. Returns true if the result was newly created.
+    pub async fn put_result(&self, result: MsgTaskResult) -> Result<bool, SamplyBeamError> {
+        let path = format!("v1/tasks/{}/results/{}", result.task, result.from);
+        let keys = crypto::get_proxy_public_keys(result.get_to()).await?;
+        let res = self.send(Method::PUT, &path, EncryptedMessage::MsgTaskResult(result.encrypt(&keys)?)).await?;
+        match res.status() {
+            StatusCode::CREATED => Ok(true),
+            StatusCode::NO_CONTENT => Ok(false),
+            _ => Err(Self::error_for(res).await),
+        }
+    }
+
+    /// Retrieves the results of a task created by `from`, waiting for them as specified by `block`
+    pub async fn get_results(&self, from: AppOrProxyId, task_id: &MsgId, block: &HowLongToBlock) -> Result<Vec<MsgTaskResult>, SamplyBeamError> {
+        let mut path = format!("v1/tasks/{task_id}/results?");
+        if let Some(wait_count) = block.wait_count {
+            path.push_str(&format!("wait_count={wait_count}&"));
+        }
+        if let Some(wait_time) = block.wait_time {
+            path.push_str(&format!("wait_time={}ms", wait_time.as_millis()));
+        }
+        let res = self.send(Method::GET, path.trim_end_matches(['?', '&']), EncryptedMessage::MsgEmpty(MsgEmpty { from })).await?;
+        if !res.status().is_success() {
+            return Err(Self::error_for(res).await);
+        }
+        #[derive(Deserialize)]
+        struct SignedResult {
+            jwt: String,
+        }
+        let me = AppOrProxyId::Proxy(self.proxy_id.clone());
+        let mut results = Vec::new();
+        for signed in res.json::<Vec<SignedResult>>().await? {
+            let result = MsgSigned::<EncryptedMsgTaskResult>::verify(&signed.jwt).await?.msg;
+            results.push(result.decrypt_with_any_key(&me, self.crypto.decryption_keys())?);
+        }
+        Ok(results)
+    }
+
+    /// Asks the recipients of the request to connect to the socket with its id, see [`Self::connect_socket`].
+    /// The secret is encrypted for the recipients and should be a key to encrypt the connection with,
+    /// as the broker relays whatever is sent through the socket.
+    #[cfg(feature = "sockets")]
+    pub async fn create_socket(&self, request: crate::MsgSocketRequest<crate::Plain>) -> Result<MsgId, SamplyBeamError> {
+        let id = request.id;
+        let keys = crypto::get_proxy_public_keys(request.get_to()).await?;
+        let res = self.send(Method::POST, "v1/sockets", EncryptedMessage::MsgSocketRequest(request.encrypt(&keys)?)).await?;
+        match res.status() {
+            StatusCode::CREATED => Ok(id),
+            _ => Err(Self::error_for(res).await),
+        }
+    }
+
+    /// Connects `from` to the socket with the given id. The broker relays the connection to the other party once both are connected.
+    #[cfg(feature = "sockets")]
+    pub async fn connect_socket(&self, from: AppOrProxyId, socket_id: &MsgId) -> Result<reqwest::Upgraded, SamplyBeamError> {
+        let req = Request::builder()
+            .method(Method::GET)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "tcp");
+        let res = self.send_with(req, &format!("v1/sockets/{socket_id}"), EncryptedMessage::MsgEmpty(MsgEmpty { from })).await?;
+        match res.status() {
+            StatusCode::SWITCHING_PROTOCOLS => Ok(res.upgrade().await?),
+            _ => Err(Self::error_for(res).await),
+        }
+    }
+
+    async fn send(&self, method: Method, path: &str, body: EncryptedMessage) -> Result<reqwest::Response, SamplyBeamError> {
+        self.send_with(Request::builder().method(method), path, body).await
+    }
+
+    async fn send_with(&self, req: axum::http::request::Builder, path: &str, body: EncryptedMessage) -> Result<reqwest::Response, SamplyBeamError> {
+        let url = self.broker_url
+            .join(path)
+            .map_err(|_| SamplyBeamError::InvalidPath)?;
+        let (parts, body) = req
+            .uri(url.as_str())
+            .body(body)
+            .expect("To build request successfully")
+            .into_parts();
+        let req = sign_request(body, parts, &self.broker_host_header, Some(&self.crypto)).await?;
+        debug!("Requesting {} {}", req.method(), req.url());
+        Ok(self.client.execute(req).await?)
+    }
+
+    async fn error_for(res: reqwest::Response) -> SamplyBeamError {
+        let status = res.status();
+        if status == StatusCode::UNPROCESSABLE_ENTITY {
+            if let Ok(proxies) = res.json().await {
+                return SamplyBeamError::InvalidReceivers(proxies);
+            }
+            return SamplyBeamError::InternalSynchronizationError(format!("Broker replied with status {status}"));
+        }
+        let reason = res.text().await.unwrap_or_default();
+        SamplyBeamError::InternalSynchronizationError(format!("Broker replied with status {status}: {reason}"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::HashMap, net::SocketAddr, sync::Mutex, time::Duration};
+
+    use axum::{extract::{Path, State}, http::{HeaderMap, Uri}, routing::{get, post, put}, Json, Router};
+    use beam_lib::{FailureStrategy, WorkStatus};
+    use jwt_simple::prelude::{RS256KeyPair, RSAKeyPairLike};
+    use serde_json::Value;
+    use tokio::net::TcpListener;
+
+    use super::*;
+    use crate::{test_utils::{app_id, trusted_proxy}, EncryptedMsgTaskRequest};
+
+    /// Stores what it is sent like the broker after verifying it the same way
+    #[derive(Clone, Default)]
+    struct MockBroker {
+        tasks: Arc<Mutex<Vec<MsgSigned<EncryptedMsgTaskRequest>>>>,
+        results: Arc<Mutex<HashMap<MsgId, Vec<MsgSigned<EncryptedMsgTaskResult>>>>>,
+        requests: Arc<Mutex<Vec<(Uri, HeaderMap)>>>,
+    }
+
+    async fn post_task(State(broker): State<MockBroker>, task: MsgSigned<EncryptedMsgTaskRequest>) -> StatusCode {
+        broker.tasks.lock().unwrap().push(task);
+        StatusCode::CREATED
+    }
+
+    async fn put_result(State(broker): State<MockBroker>, Path((task_id, _)): Path<(MsgId, AppOrProxyId)>, result: MsgSigned<EncryptedMsgTaskResult>) -> StatusCode {
+        broker.results.lock().unwrap().entry(task_id).or_default().push(result);
+        StatusCode::CREATED
+    }
+
+    async fn get_results(State(broker): State<MockBroker>, Path(task_id): Path<MsgId>, uri: Uri, headers: HeaderMap, _: MsgSigned<MsgEmpty>) -> Json<Vec<MsgSigned<EncryptedMsgTaskResult>>> {
+        broker.requests.lock().unwrap().push((uri, headers));
+        Json(broker.results.lock().unwrap().get(&task_id).cloned().unwrap_or_default())
+    }
+
+    async fn mock_broker() -> (Url, MockBroker) {
+        let broker = MockBroker::default();
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap()).parse().unwrap();
+        let app = Router::new()
+            .route("/v1/tasks", post(post_task))
+            .route("/v1/tasks/:task_id/results", get(get_results))
+            .route("/v1/tasks/:task_id/results/:app_id", put(put_result));
+        #[cfg(feature = "sockets")]
+        let app = app.route("/v1/sockets/:socket_id", get(echo_socket));
+        let app = app.with_state(broker.clone());
+        tokio::spawn(async move { axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>()).await });
+        (url, broker)
+    }
+
+    fn client(url: &Url, crypto: Arc<ConfigCrypto>) -> BrokerClient {
+        let proxy_id = crypto.public.as_ref().unwrap().beam_id.clone();
+        BrokerClient::new(SamplyHttpClient::new(), url.clone(), proxy_id, crypto).unwrap()
+    }
+
+    #[tokio::test]
+    async fn task_round_trip() {
+        let (url, broker) = mock_broker().await;
+        let creator = client(&url, trusted_proxy("proxy1").await);
+        let worker = client(&url, trusted_proxy("proxy2").await);
+        let (app1, app2): (AppOrProxyId, AppOrProxyId) = (app_id("app1", "proxy1").into(), app_id("app2", "proxy2").into());
+
+        let task = MsgTaskRequest::new(app1.clone(), vec![app2.clone()], "Do it".to_string(), FailureStrategy::Discard, Value::Null);
+        let task_id = creator.create_task(task).await.unwrap();
+        let stored = broker.tasks.lock().unwrap().pop().unwrap();
+        assert_eq!(stored.msg.id, task_id);
+        let task = MsgSigned::<EncryptedMsgTaskRequest>::verify(&stored.jwt).await.unwrap().msg;
+        assert!(task.clone().decrypt_with_any_key(&app2, creator.crypto.decryption_keys()).is_err(), "Only the recipient's proxy can read the task");
+        let task = task.decrypt_with_any_key(&app2, worker.crypto.decryption_keys()).unwrap();
+        assert_eq!(task.body.body.as_deref(), Some("Do it"));
+
+        let result = MsgTaskResult {
+            from: app2.clone(),
+            to: vec![app1.clone()],
+            task: task_id,
+            status: WorkStatus::Succeeded,
+            body: "Done".into(),
+            metadata: Value::Null,
+            seq: None,
+        };
+        assert!(worker.put_result(result).await.unwrap());
+
+        let block = HowLongToBlock { wait_count: Some(1), wait_time: Some(Duration::from_secs(1)) };
+        let results = creator.get_results(app1, &task_id, &block).await.unwrap();
+        assert_eq!(results.len(), 1);
+        assert_eq!((&results[0].from, results[0].status, results[0].body.body.as_deref()), (&app2, WorkStatus::Succeeded, Some("Done")));
+
+        let requests = broker.requests.lock().unwrap();
+        let (uri, headers) = &requests[0];
+        assert_eq!(uri.query(), Some("wait_count=1&wait_time=1000ms"));
+        assert_eq!(headers[header::HOST], url.authority());
+        assert_eq!(headers[header::CONTENT_TYPE], "application/jwt");
+    }
+
+    #[tokio::test]
+    async fn broker_errors() {
+        let (url, _broker) = mock_broker().await;
+        let trusted = trusted_proxy("proxy1").await;
+        // Signing with another key than the one of the certificate
+        let crypto = ConfigCrypto {
+            privkey_rs256: RS256KeyPair::generate(2048).unwrap().with_key_id(trusted.privkey_rs256.key_id().as_ref().unwrap()),
+            privkey_rsa: trusted.privkey_rsa.clone(),
+            previous_privkey_rsa: None,
+            public: trusted.public.clone(),
+        };
+        let client = client(&url, Arc::new(crypto));
+        let block = HowLongToBlock { wait_count: None, wait_time: None };
+        let err = client.get_results(app_id("app1", "proxy1").into(), &MsgId::new(), &block).await.unwrap_err();
+        assert!(err.to_string().contains("401"), "{err}");
+    }
+
+    /// Sends back whatever it receives through the socket
+    #[cfg(feature = "sockets")]
+    async fn echo_socket(mut req: axum::extract::Request) -> Result<StatusCode, (StatusCode, &'static str)> {
+        use axum::extract::FromRequest;
+
+        let upgrade = req.extensions_mut().remove::<hyper::upgrade::OnUpgrade>().ok_or((StatusCode::UPGRADE_REQUIRED, "Not upgradable"))?;
+        MsgSigned::<MsgEmpty>::from_request(req, &()).await?;
+        tokio::spawn(async move {
+            let (mut reader, mut writer) = tokio::io::split(hyper_util::rt::TokioIo::new(upgrade.await.unwrap()));
+            tokio::io::copy(&mut reader, &mut writer).await.unwrap();
+        });
+        Ok(StatusCode::SWITCHING_PROTOCOLS)
+    }
+
+    #[cfg(feature = "sockets")]
+    #[tokio::test]
+    async fn socket_connection() {
+        use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+        let (url, _broker) = mock_broker().await;
+        let client = client(&url, trusted_proxy("proxy1").await);
+        let mut socket = client.connect_socket(app_id("app1", "proxy1").into(), &MsgId::new()).await.unwrap();
+        socket.write_all(b"ping").await.unwrap();
+        let mut echo = [0; 4];
+        socket.read_exact(&mut echo).await.unwrap();
+        assert_eq!(&echo, b"ping");
+    }
+}
@@ -0,0 +1,209 @@
+use std::{
+    fmt,
+    net::SocketAddr,
+    str::FromStr,
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::http::{header, HeaderMap};
+use reqwest::Url;
+use tokio::net::UdpSocket;
+use tracing::{debug, warn};
+
+use crate::{errors::SamplyBeamError, http_client::SamplyHttpClient};
+
+/// Seconds from the NTP epoch (1900) to the Unix epoch (1970)
+const NTP_UNIX_OFFSET: u64 = 2_208_988_800;
+const NTP_DEFAULT_PORT: u16 = 123;
+const NTP_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Source of the wall-clock time, so that logic depending on it, like the expiry of tasks, can be tested without waiting.
+/// Timeouts and deadlines of waiting clients use tokio's monotonic clock instead, which tests can pause and advance.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> SystemTime;
+}
+
+/// The system clock
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> SystemTime {
+        SystemTime::now()
+    }
+}
+
+/// A clock that stands still until it is advanced, for tests
+#[derive(Debug)]
+pub struct MockClock(Mutex<SystemTime>);
+
+impl MockClock {
+    pub fn new(now: SystemTime) -> Self {
+        Self(Mutex::new(now))
+    }
+
+    pub fn advance(&self, by: Duration) {
+        *self.0.lock().unwrap() += by;
+    }
+}
+
+impl Clock for MockClock {
+    fn now(&self) -> SystemTime {
+        *self.0.lock().unwrap()
+    }
+}
+
+/// Trusted source of the current time to check the system clock against at startup
+#[derive(Debug, Clone, PartialEq)]
+pub enum TimeReference {
+    /// An NTP server given as ntp://host[:port]
+    Ntp(String),
+    /// The Date header of a response from an http(s) URL
+    Http(Url),
+}
+
+impl FromStr for TimeReference {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url = Url::parse(s).map_err(|e| format!("Invalid time reference {s}: {e}"))?;
+        match url.scheme() {
+            "ntp" => {
+                let host = url.host_str().ok_or_else(|| format!("Time reference {s} has no host"))?;
+                Ok(Self::Ntp(format!("{host}:{}", url.port().unwrap_or(NTP_DEFAULT_PORT))))
+            }
+            "http" | "https" => Ok(Self::Http(url)),
+            other => Err(format!("Unsupported time reference scheme {other}, expected ntp, http or https")),
+        }
+    }
+}
+
+impl fmt::Display for TimeReference {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Ntp(server) => write!(f, "ntp://{server}"),
+            Self::Http(url) => write!(f, "{url}"),
+        }
+    }
+}
+
+impl TimeReference {
+    /// Asks the reference for the current time
+    pub async fn now(&self, client: &SamplyHttpClient) -> Result<SystemTime, SamplyBeamError> {
+        match self {
+            Self::Ntp(server) => ntp_time(server).await,
+            Self::Http(url) => {
+                let resp = client.get(url.clone()).send().await?;
+                date_header(resp.headers()).ok_or_else(|| SamplyBeamError::InternalSynchronizationError(
+                    format!("{url} did not send a valid Date header")
+                ))
+            }
+        }
+    }
+}
+
+/// Parses the Date header of an HTTP response
+pub fn date_header(headers: &HeaderMap) -> Option<SystemTime> {
+    let date = headers.get(header::DATE)?.to_str().ok()?;
+    httpdate::parse_http_date(date).ok()
+}
+
+/// Absolute difference between the two points in time
+pub fn clock_skew(local: SystemTime, reference: SystemTime) -> Duration {
+    local
+        .duration_since(reference)
+        .unwrap_or_else(|e| e.duration())
+}
+
+/// Compares the system clock with the reference time. If they differ by more than `max_skew`,
+/// this is an error with `enforce` and a warning otherwise.
+pub fn check_clock(reference: SystemTime, max_skew: Duration, enforce: bool) -> Result<(), SamplyBeamError> {
+    let skew = clock_skew(SystemTime::now(), reference);
+    if skew <= max_skew {
+        debug!("System clock is within {}s of the time reference", skew.as_secs());
+        return Ok(());
+    }
+    let err = SamplyBeamError::ClockSkew(skew.as_secs());
+    if enforce {
+        return Err(err);
+    }
+    warn!("{err}. Signed messages from and to this component will likely be rejected. Please synchronize the system clock");
+    Ok(())
+}
+
+async fn ntp_time(server: &str) -> Result<SystemTime, SamplyBeamError> {
+    let ntp_error = |e: &dyn fmt::Display| SamplyBeamError::InternalSynchronizationError(format!("Unable to query NTP server {server}: {e}"));
+    let addr = tokio::net::lookup_host(server)
+        .await
+        .map_err(|e| ntp_error(&e))?
+        .next()
+        .ok_or_else(|| ntp_error(&"Host not found"))?;
+    let local_addr: SocketAddr = if addr.is_ipv4() { ([0, 0, 0, 0], 0).into() } else { ([0u16; 8], 0).into() };
+    let socket = UdpSocket::bind(local_addr).await.map_err(|e| ntp_error(&e))?;
+    let mut packet = [0u8; 48];
+    // Leap indicator 0, version 3, mode 3 (client)
+    packet[0] = 0x1b;
+    socket.send_to(&packet, addr).await.map_err(|e| ntp_error(&e))?;
+    let (len, _) = tokio::time::timeout(NTP_TIMEOUT, socket.recv_from(&mut packet))
+        .await
+        .map_err(|e| ntp_error(&e))?
+        .map_err(|e| ntp_error(&e))?;
+    if len < packet.len() {
+        return Err(ntp_error(&"Response too short"));
+    }
+    ntp_transmit_time(&packet).ok_or_else(|| ntp_error(&"Invalid transmit timestamp"))
+}
+
+/// Reads the server's transmit timestamp from an NTP response
+fn ntp_transmit_time(packet: &[u8; 48]) -> Option<SystemTime> {
+    let secs = u32::from_be_bytes(packet[40..44].try_into().expect("4 bytes")) as u64;
+    let fraction = u32::from_be_bytes(packet[44..48].try_into().expect("4 bytes")) as u64;
+    let unix_secs = secs.checked_sub(NTP_UNIX_OFFSET)?;
+    Some(UNIX_EPOCH + Duration::from_secs(unix_secs) + Duration::from_nanos((fraction * 1_000_000_000) >> 32))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_time_reference() {
+        assert_eq!("ntp://pool.ntp.org".parse(), Ok(TimeReference::Ntp("pool.ntp.org:123".to_string())));
+        assert_eq!("ntp://127.0.0.1:1123".parse(), Ok(TimeReference::Ntp("127.0.0.1:1123".to_string())));
+        assert_eq!(
+            "https://example.com/".parse(),
+            Ok(TimeReference::Http(Url::parse("https://example.com/").unwrap()))
+        );
+        assert!("ftp://example.com".parse::<TimeReference>().is_err());
+        assert!("pool.ntp.org".parse::<TimeReference>().is_err());
+    }
+
+    #[test]
+    fn skew() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(90);
+        assert_eq!(clock_skew(now, later), Duration::from_secs(90));
+        assert_eq!(clock_skew(later, now), Duration::from_secs(90));
+        assert!(check_clock(later, Duration::from_secs(60), true).is_err());
+        assert!(check_clock(later, Duration::from_secs(60), false).is_ok());
+        assert!(check_clock(now, Duration::from_secs(60), true).is_ok());
+    }
+
+    #[tokio::test]
+    async fn ntp_query() {
+        let server = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let addr = server.local_addr().unwrap();
+        let reference = UNIX_EPOCH + Duration::from_millis(1_700_000_000_500);
+        tokio::spawn(async move {
+            let mut packet = [0u8; 48];
+            let (_, client) = server.recv_from(&mut packet).await.unwrap();
+            assert_eq!(packet[0] & 0b111, 3, "Expected client mode");
+            packet[40..44].copy_from_slice(&(1_700_000_000 + NTP_UNIX_OFFSET as u32).to_be_bytes());
+            packet[44..48].copy_from_slice(&(1u32 << 31).to_be_bytes());
+            server.send_to(&packet, client).await.unwrap();
+        });
+        let time = TimeReference::Ntp(addr.to_string()).now(&SamplyHttpClient::new()).await.unwrap();
+        assert_eq!(time, reference);
+    }
+}
@@ -1,10 +1,14 @@
-use std::{fs::read_to_string, net::SocketAddr, path::PathBuf};
+use std::{collections::HashMap, fs::read_to_string, net::SocketAddr, path::PathBuf, time::Duration};
 
 use crate::{
+    clock::TimeReference,
     errors::SamplyBeamError,
 };
 use axum::http::Uri;
+use beam_lib::{AppOrProxyId, ProxyId};
 use clap::Parser;
+use ipnet::IpNet;
+use regex::Regex;
 use reqwest::Url;
 use std::str::FromStr;
 use tracing::info;
@@ -21,6 +25,10 @@ struct CliArgs {
     #[clap(long, env, value_parser, default_value_t = SocketAddr::from_str("0.0.0.0:8080").unwrap())]
     bind_addr: SocketAddr,
 
+    /// Separate bind address for the metrics and monitoring endpoints, e.g. 127.0.0.1:8082. If unset they are served on the local bind address
+    #[clap(long, env, value_parser)]
+    admin_bind_addr: Option<SocketAddr>,
+
     /// Outgoing HTTP proxy: Directory with CA certificates to trust for TLS connections (e.g. /etc/samply/cacerts/)
     #[clap(long, env, value_parser)]
     pub tls_ca_certificates_dir: Option<PathBuf>,
@@ -53,6 +61,114 @@ struct CliArgs {
     #[clap(long, env, value_parser)]
     monitoring_api_key: Option<String>,
 
+    /// The API key for the admin endpoints of the broker, which can remove any task. The admin endpoints are disabled if unset
+    #[clap(long, env, value_parser)]
+    admin_api_key: Option<String>,
+
+    /// Number of failed vault requests in a row after which certificate lookups fail fast instead of waiting for the vault
+    #[clap(long, env, value_parser, default_value_t = 5)]
+    vault_failure_threshold: u32,
+
+    /// Seconds to fail certificate lookups fast before probing whether the vault has recovered
+    #[clap(long, env, value_parser, default_value_t = 30)]
+    vault_cooldown: u64,
+
+    /// Maximum number of recipients of a single task
+    #[clap(long, env, value_parser, default_value_t = 1000)]
+    max_task_recipients: usize,
+
+    /// Maximum number of tries a task's retry failure strategy may ask for. The broker retries tasks asking for more only this often. 0 disables the limit
+    #[clap(long, env, value_parser, default_value_t = 100)]
+    max_retry_tries: usize,
+
+    /// Maximum pause in milliseconds between tries a task's retry failure strategy may ask for. The broker waits only this long for tasks asking for longer pauses. 0 disables the limit
+    #[clap(long, env, value_parser, default_value_t = 60 * 60 * 1000)]
+    max_retry_backoff_millisecs: usize,
+
+    /// Reject tasks addressed to proxies without a valid certificate. This costs a certificate lookup per recipient
+    #[clap(long, env, value_parser, default_value_t = false)]
+    validate_recipients: bool,
+
+    /// Number of new task notifications buffered for waiting clients. A client missing more of them while waiting has to recount all tasks
+    #[clap(long, env, value_parser, default_value_t = 256)]
+    task_broadcast_capacity: usize,
+
+    /// Minimum number of new result notifications buffered per task. Tasks with more recipients buffer one per recipient. Each slot costs memory for every open task
+    #[clap(long, env, value_parser, default_value_t = 16)]
+    result_broadcast_capacity: usize,
+
+    /// Maximum number of concurrent long-polling requests for tasks and results. Further ones are rejected with 503 Service Unavailable
+    #[clap(long, env, value_parser, default_value_t = 10_000)]
+    max_long_polls: usize,
+
+    /// Maximum number of concurrent streams and long-polling requests per app. Further ones are rejected with 429 Too Many Requests. 0 disables the limit
+    #[clap(long, env, value_parser, default_value_t = 1000)]
+    max_streams_per_app: usize,
+
+    /// Record which recipients have fetched a task and report their number as delivered in the task summary. Costs memory for every recipient of an open task
+    #[clap(long, env, value_parser, default_value_t = false)]
+    delivery_receipts: bool,
+
+    /// Directory to keep large tasks in instead of memory. Stored tasks are deleted some minutes after they expire. S3-compatible storage is not supported
+    #[clap(long, env, value_parser)]
+    blob_store_dir: Option<PathBuf>,
+
+    /// File to append a line of JSON to for every accepted result, recording its worker, status and digest. Entries are also kept in memory for the admin API as long as their task
+    #[clap(long, env, value_parser)]
+    audit_log: Option<PathBuf>,
+
+    /// Maximum size in bytes of a signed result or attachment. Larger ones are rejected with 413 Payload Too Large. 0 disables the limit
+    #[clap(long, env, value_parser, default_value_t = 10 * 1024 * 1024)]
+    max_result_size: usize,
+
+    /// Minimum size in bytes of a signed task to move it to the blob store
+    #[clap(long, env, value_parser, default_value_t = 1024 * 1024)]
+    blob_store_min_size: usize,
+
+    /// Keep tasks of at least BLOB_STORE_MIN_SIZE bytes compressed in memory. Ignored if BLOB_STORE_DIR is set
+    #[clap(long, env, value_parser, default_value_t = false)]
+    compress_tasks: bool,
+
+    /// Trusted time source to check the system clock against at startup: ntp://host[:port] or an http(s) URL whose Date header is used.
+    #[clap(long, env, value_parser)]
+    time_reference: Option<TimeReference>,
+
+    /// Maximum difference in seconds between the system clock and the time reference. 0 disables the check
+    #[clap(long, env, value_parser, default_value_t = 60)]
+    max_clock_skew: u64,
+
+    /// Refuse to start if the system clock exceeds the maximum clock skew instead of only warning
+    #[clap(long, env, value_parser, default_value_t = false)]
+    enforce_clock_skew: bool,
+
+    /// Log the startup information as a single structured event instead of the banner
+    #[clap(long, env, value_parser, default_value_t = false)]
+    no_banner: bool,
+
+    /// Seconds after which requests other than GET requests, e.g. creating tasks or results, are answered with 408 Request Timeout. Long polls and streams are not limited. 0 disables the timeout
+    #[clap(long, env, value_parser, default_value_t = 0)]
+    request_timeout: u64,
+
+    /// Reject requests that did not reach the broker over https, as told by the X-Forwarded-Proto or Forwarded header of a TLS-terminating reverse proxy. Requires an https broker URL and TRUSTED_PROXIES or PROXY_PROTOCOL
+    #[clap(long, env, value_parser, default_value_t = false)]
+    require_tls: bool,
+
+    /// Answer requests signed by proxies with 503 Service Unavailable until the CA chain has been loaded from the PKI, instead of accepting them while their signatures cannot be verified yet
+    #[clap(long, env, value_parser, default_value_t = false)]
+    reject_until_pki_ready: bool,
+
+    /// Expect a PROXY protocol (v1 or v2) header on every connection to the bind address, as sent by L4 load balancers, and use the client address from it. Connections without the header are closed
+    #[clap(long, env, value_parser, default_value_t = false)]
+    proxy_protocol: bool,
+
+    /// Seconds after which socket connections are closed even if they are still in use. 0 lets them last as long as both parties want
+    #[clap(long, env, value_parser, default_value_t = 0)]
+    socket_max_lifetime: u64,
+
+    /// Comma separated networks of reverse proxies, e.g. 10.0.0.0/8, whose X-Forwarded-For header is trusted to name the client. Requests from other addresses are logged with their own address. Disabled by default
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    trusted_proxies: Vec<IpNet>,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -60,11 +176,106 @@ struct CliArgs {
 
 pub struct Config {
     pub bind_addr: SocketAddr,
+    pub admin_bind_addr: Option<SocketAddr>,
     pub pki_address: Url,
     pub pki_realm: String,
     pub pki_token: String,
     pub tls_ca_certificates_dir: Option<PathBuf>,
     pub monitoring_api_key: Option<String>,
+    pub admin_api_key: Option<String>,
+    pub vault_failure_threshold: u32,
+    pub vault_cooldown: Duration,
+    pub max_task_recipients: usize,
+    pub max_retry_tries: usize,
+    pub max_retry_backoff_millisecs: usize,
+    pub validate_recipients: bool,
+    pub task_broadcast_capacity: usize,
+    pub result_broadcast_capacity: usize,
+    pub max_long_polls: usize,
+    pub max_streams_per_app: usize,
+    pub delivery_receipts: bool,
+    pub blob_store_dir: Option<PathBuf>,
+    pub blob_store_min_size: usize,
+    pub compress_tasks: bool,
+    pub max_result_size: usize,
+    pub audit_log: Option<PathBuf>,
+    pub time_reference: Option<TimeReference>,
+    pub max_clock_skew: Duration,
+    pub enforce_clock_skew: bool,
+    pub no_banner: bool,
+    pub require_tls: bool,
+    pub reject_until_pki_ready: bool,
+    pub proxy_protocol: bool,
+    pub trusted_proxies: Vec<IpNet>,
+    pub socket_max_lifetime: Option<Duration>,
+    pub request_timeout: Option<Duration>,
+    pub recipient_groups: HashMap<String, Vec<AppOrProxyId>>,
+    pub webhooks: HashMap<ProxyId, Webhook>,
+}
+
+/// Where the broker notifies a proxy about results and completed tasks of its apps
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    pub url: Url,
+    /// Key of the HMAC the notifications are signed with
+    pub secret: String,
+}
+
+pub const GROUP_PREFIX: &str = "GROUP";
+pub const WEBHOOK_PREFIX: &str = "WEBHOOK";
+
+/// Parses recipient groups from the environment like:
+/// GROUP_hospitals_MEMBERS=proxy1.broker.samply.de,app1.proxy2.broker.samply.de
+fn parse_recipient_groups() -> Result<HashMap<String, Vec<AppOrProxyId>>, SamplyBeamError> {
+    let pattern = Regex::new(&format!("^{GROUP_PREFIX}_([A-Za-z0-9-]+)_MEMBERS$")).expect("This is a valid regex");
+    let mut groups = HashMap::new();
+    for (env_var_name, members) in std::env::vars() {
+        let Some(group) = pattern.captures(&env_var_name).and_then(|cap| cap.get(1)) else {
+            continue;
+        };
+        let members = members
+            .split(',')
+            .map(str::trim)
+            .filter(|member| !member.is_empty())
+            .map(|member| AppOrProxyId::new(member).map_err(|e| SamplyBeamError::ConfigurationFailed(format!(
+                "Invalid member \"{member}\" of recipient group {}: {e}", group.as_str()
+            ))))
+            .collect::<Result<Vec<_>, _>>()?;
+        if members.is_empty() {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "Please supply at least one member for recipient group {}", group.as_str()
+            )));
+        }
+        groups.insert(group.as_str().to_string(), members);
+    }
+    Ok(groups)
+}
+
+/// Parses the webhooks of proxies from the environment like:
+/// WEBHOOK_proxy1_URL=https://proxy1.example.com/beam-webhook
+/// WEBHOOK_proxy1_SECRET=<shared secret>
+/// Only proxies configured here are ever notified, so apps can't make the broker send requests to arbitrary URLs.
+fn parse_webhooks() -> Result<HashMap<ProxyId, Webhook>, SamplyBeamError> {
+    let pattern = Regex::new(&format!("^{WEBHOOK_PREFIX}_([A-Za-z0-9-]+)_URL$")).expect("This is a valid regex");
+    let mut webhooks = HashMap::new();
+    for (env_var_name, url) in std::env::vars() {
+        let Some(proxy) = pattern.captures(&env_var_name).and_then(|cap| cap.get(1)) else {
+            continue;
+        };
+        let proxy = proxy.as_str();
+        let url = Url::parse(&url)
+            .ok()
+            .filter(|url| matches!(url.scheme(), "http" | "https"))
+            .ok_or_else(|| SamplyBeamError::ConfigurationFailed(format!("Invalid webhook URL of proxy {proxy}: {url}")))?;
+        let secret = std::env::var(format!("{WEBHOOK_PREFIX}_{proxy}_SECRET"))
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| SamplyBeamError::ConfigurationFailed(format!("Please supply {WEBHOOK_PREFIX}_{proxy}_SECRET to sign the notifications of proxy {proxy}")))?;
+        let proxy_id = ProxyId::new(format!("{proxy}.{}", beam_lib::get_broker_id()))
+            .map_err(|e| SamplyBeamError::ConfigurationFailed(format!("Invalid proxy {proxy} of webhook: {e}")))?;
+        webhooks.insert(proxy_id, Webhook { url, secret });
+    }
+    Ok(webhooks)
 }
 
 impl crate::config::Config for Config {
@@ -81,16 +292,98 @@ impl crate::config::Config for Config {
             })?
             .trim()
             .to_string();
+        if cli_args.require_tls && cli_args.broker_url.scheme_str() != Some("https") {
+            return Err(SamplyBeamError::ConfigurationFailed(format!(
+                "REQUIRE_TLS is set but the broker URL {} is not an https URL",
+                cli_args.broker_url
+            )));
+        }
+        if cli_args.require_tls && cli_args.trusted_proxies.is_empty() && !cli_args.proxy_protocol {
+            return Err(SamplyBeamError::ConfigurationFailed(
+                "REQUIRE_TLS is set but neither TRUSTED_PROXIES nor PROXY_PROTOCOL names the reverse proxy that reports the protocol".into()
+            ));
+        }
 
         info!("Successfully read config and API keys from CLI and secrets files.");
         let config = Config {
             bind_addr: cli_args.bind_addr,
+            admin_bind_addr: cli_args.admin_bind_addr,
             pki_address: cli_args.pki_address,
             pki_realm: cli_args.pki_realm,
             pki_token,
             tls_ca_certificates_dir: cli_args.tls_ca_certificates_dir,
             monitoring_api_key: cli_args.monitoring_api_key,
+            admin_api_key: cli_args.admin_api_key,
+            vault_failure_threshold: cli_args.vault_failure_threshold,
+            vault_cooldown: Duration::from_secs(cli_args.vault_cooldown),
+            max_task_recipients: cli_args.max_task_recipients,
+            max_retry_tries: cli_args.max_retry_tries,
+            max_retry_backoff_millisecs: cli_args.max_retry_backoff_millisecs,
+            validate_recipients: cli_args.validate_recipients,
+            task_broadcast_capacity: cli_args.task_broadcast_capacity.max(1),
+            result_broadcast_capacity: cli_args.result_broadcast_capacity.max(1),
+            max_long_polls: cli_args.max_long_polls,
+            max_streams_per_app: cli_args.max_streams_per_app,
+            delivery_receipts: cli_args.delivery_receipts,
+            blob_store_dir: cli_args.blob_store_dir,
+            blob_store_min_size: cli_args.blob_store_min_size,
+            compress_tasks: cli_args.compress_tasks,
+            max_result_size: cli_args.max_result_size,
+            audit_log: cli_args.audit_log,
+            time_reference: cli_args.time_reference,
+            max_clock_skew: Duration::from_secs(cli_args.max_clock_skew),
+            enforce_clock_skew: cli_args.enforce_clock_skew,
+            no_banner: cli_args.no_banner,
+            require_tls: cli_args.require_tls,
+            reject_until_pki_ready: cli_args.reject_until_pki_ready,
+            proxy_protocol: cli_args.proxy_protocol,
+            trusted_proxies: cli_args.trusted_proxies,
+            socket_max_lifetime: (cli_args.socket_max_lifetime != 0).then(|| Duration::from_secs(cli_args.socket_max_lifetime)),
+            request_timeout: (cli_args.request_timeout != 0).then(|| Duration::from_secs(cli_args.request_timeout)),
+            recipient_groups: parse_recipient_groups()?,
+            webhooks: parse_webhooks()?,
         };
         Ok(config)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_utils::init_broker_id;
+
+    #[test]
+    fn test_parse_recipient_groups() {
+        init_broker_id();
+        std::env::set_var("GROUP_hospitals_MEMBERS", "proxy1.broker.samply.de, app1.proxy2.broker.samply.de");
+        std::env::set_var("NOT_A_GROUP_MEMBERS", "invalid");
+        let groups = parse_recipient_groups().unwrap();
+        assert_eq!(groups["hospitals"], [
+            AppOrProxyId::new("proxy1.broker.samply.de").unwrap(),
+            AppOrProxyId::new("app1.proxy2.broker.samply.de").unwrap(),
+        ]);
+        assert!(!groups.contains_key("A"));
+        std::env::set_var("GROUP_invalid_MEMBERS", "proxy1.otherbroker.samply.de");
+        assert!(parse_recipient_groups().is_err());
+        std::env::remove_var("GROUP_invalid_MEMBERS");
+    }
+
+    #[test]
+    fn test_parse_webhooks() {
+        init_broker_id();
+        std::env::set_var("WEBHOOK_proxy3_URL", "https://proxy3.example.com/webhook");
+        std::env::set_var("WEBHOOK_proxy3_SECRET", "secret");
+        let webhooks = parse_webhooks().unwrap();
+        let webhook = &webhooks[&ProxyId::new("proxy3.broker.samply.de").unwrap()];
+        assert_eq!(webhook.url.as_str(), "https://proxy3.example.com/webhook");
+        assert_eq!(webhook.secret, "secret");
+        std::env::set_var("WEBHOOK_proxy4_URL", "https://proxy4.example.com/webhook");
+        assert!(parse_webhooks().is_err(), "Notifications must be signed");
+        std::env::set_var("WEBHOOK_proxy4_SECRET", "secret");
+        std::env::set_var("WEBHOOK_proxy4_URL", "file:///etc/passwd");
+        assert!(parse_webhooks().is_err());
+        for var in ["WEBHOOK_proxy3_URL", "WEBHOOK_proxy3_SECRET", "WEBHOOK_proxy4_URL", "WEBHOOK_proxy4_SECRET"] {
+            std::env::remove_var(var);
+        }
+    }
+}
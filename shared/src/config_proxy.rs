@@ -4,32 +4,189 @@ use regex::Regex;
 use reqwest::Url;
 
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
+    fmt,
     fs::read_to_string,
     net::SocketAddr,
     path::{Path, PathBuf},
     process::exit,
     str::FromStr,
+    time::Duration,
 };
 
 use axum::http::HeaderValue;
 use serde::Deserialize;
 use tracing::{debug, info, warn};
 
-use beam_lib::{AppId, ProxyId};
-use crate::errors::SamplyBeamError;
+use beam_lib::{AppId, FailureStrategy, ProxyId, ProxyMetadata};
+use crate::{clock::TimeReference, crypto::constant_time_eq, errors::SamplyBeamError, http_client::ConnectionPool};
 
 #[derive(Clone, Debug)]
 pub struct Config {
     pub broker_uri: Url,
     pub broker_host_header: HeaderValue,
+    pub fallback_broker_uris: Vec<Url>,
+    pub broker_connection_pool: ConnectionPool,
     pub bind_addr: SocketAddr,
     pub proxy_id: ProxyId,
     pub api_keys: HashMap<AppId, ApiKey>,
+    /// Proxies an app may address. Apps without an entry may address any proxy
+    pub allowed_recipients: HashMap<AppId, HashSet<ProxyId>>,
+    /// Proxies whose apps recipient groups and capabilities may be resolved to by the broker. Without any, they are not resolved
+    pub resolved_recipients: HashSet<ProxyId>,
+    pub auth_url: Option<Url>,
     pub tls_ca_certificates: Vec<reqwest::Certificate>,
+    pub crypto_concurrency: usize,
+    pub response_cache_ttl: Option<Duration>,
+    pub max_task_recipients: usize,
+    pub max_array_depth: usize,
+    pub max_array_length: usize,
+    pub cors_allowed_origins: Vec<HeaderValue>,
+    pub default_failure_strategy: Option<FailureStrategy>,
+    pub time_reference: Option<TimeReference>,
+    pub max_clock_skew: Duration,
+    pub enforce_clock_skew: bool,
+    pub no_banner: bool,
+    pub deny_unknown_task_fields: bool,
+    pub require_content_type: bool,
+    pub request_timeout: Option<Duration>,
+    pub monitoring_api_key: Option<String>,
+    pub metadata: ProxyMetadata,
 }
 
-pub type ApiKey = String;
+/// An app's API key, either in plain text or as a salted scrypt hash like
+/// `$scrypt$ln=14,r=8,p=1$<base64 salt>$<base64 hash>`
+#[derive(Clone, PartialEq)]
+pub enum ApiKey {
+    /// Deprecated as a memory dump of the proxy reveals the key
+    Plain(String),
+    Hashed(HashedApiKey),
+}
+
+impl fmt::Debug for ApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Plain(_) => f.write_str("Plain(<redacted>)"),
+            Self::Hashed(_) => f.write_str("Hashed(<redacted>)"),
+        }
+    }
+}
+
+impl ApiKey {
+    /// Compares the presented key with this one in constant time.
+    /// Checking a hashed key is CPU and memory heavy.
+    pub fn verify(&self, presented: &str) -> bool {
+        match self {
+            Self::Plain(key) => constant_time_eq(key.as_bytes(), presented.as_bytes()),
+            Self::Hashed(hashed) => hashed.verify(presented),
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+pub struct HashedApiKey {
+    log_n: u8,
+    r: u32,
+    p: u32,
+    salt: Vec<u8>,
+    hash: Vec<u8>,
+}
+
+impl HashedApiKey {
+    const PREFIX: &'static str = "$scrypt$";
+    /// Guards against hashes that take more than 1 GiB to check
+    const MAX_MEMORY: u64 = 1 << 30;
+    /// Supported cost parameters `log2 N`, well below where the needed memory overflows
+    const LOG_N: std::ops::RangeInclusive<u8> = 1..=30;
+
+    /// Hashes the key with a random salt
+    pub fn new(key: &str, log_n: u8, r: u32, p: u32) -> Result<Self, SamplyBeamError> {
+        let mut salt = vec![0; 16];
+        openssl::rand::rand_bytes(&mut salt).map_err(|e| SamplyBeamError::SignEncryptError(e.to_string()))?;
+        let mut hashed = Self { log_n, r, p, salt, hash: vec![0; 32] };
+        hashed.hash = hashed.hash_key(key)?;
+        Ok(hashed)
+    }
+
+    /// Memory scrypt needs to check the hash, or `None` if it does not even fit into a `u64`
+    fn memory(&self) -> Option<u64> {
+        (1u64 << self.log_n)
+            .checked_add(self.p as u64 + 2)?
+            .checked_mul(self.r as u64)?
+            .checked_mul(128)
+    }
+
+    fn hash_key(&self, key: &str) -> Result<Vec<u8>, SamplyBeamError> {
+        let mut hash = vec![0; self.hash.len()];
+        openssl::pkcs5::scrypt(
+            key.as_bytes(),
+            &self.salt,
+            1 << self.log_n,
+            self.r as u64,
+            self.p as u64,
+            self.memory().and_then(|memory| memory.checked_add(1 << 20)).unwrap_or(u64::MAX),
+            &mut hash,
+        ).map_err(|e| SamplyBeamError::SignEncryptError(format!("Unable to hash API key: {e}")))?;
+        Ok(hash)
+    }
+
+    pub fn verify(&self, presented: &str) -> bool {
+        self.hash_key(presented).is_ok_and(|hash| constant_time_eq(&hash, &self.hash))
+    }
+}
+
+impl FromStr for HashedApiKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("Invalid hashed API key. Expected {}ln=<log2 N>,r=<r>,p=<p>$<base64 salt>$<base64 hash>", Self::PREFIX);
+        let rest = s.strip_prefix(Self::PREFIX).ok_or_else(invalid)?;
+        let [params, salt, hash] = rest.split('$').collect::<Vec<_>>()[..] else {
+            return Err(invalid());
+        };
+        let (mut log_n, mut r, mut p) = (None, None, None);
+        for param in params.split(',') {
+            match param.split_once('=').ok_or_else(invalid)? {
+                ("ln", v) => log_n = v.parse().ok(),
+                ("r", v) => r = v.parse().ok(),
+                ("p", v) => p = v.parse().ok(),
+                _ => return Err(invalid()),
+            }
+        }
+        let decode = |b64: &str| openssl::base64::decode_block(b64).map_err(|_| invalid());
+        let hashed = Self {
+            log_n: log_n.filter(|ln| Self::LOG_N.contains(ln)).ok_or_else(invalid)?,
+            r: r.filter(|r| *r > 0).ok_or_else(invalid)?,
+            p: p.filter(|p| *p > 0).ok_or_else(invalid)?,
+            salt: decode(salt)?,
+            hash: decode(hash)?,
+        };
+        if hashed.hash.is_empty() {
+            return Err(invalid());
+        }
+        match hashed.memory() {
+            Some(memory) if memory <= Self::MAX_MEMORY => {}
+            Some(memory) => return Err(format!("Hashed API key needs {} MiB to be checked, at most {} MiB are allowed", memory >> 20, Self::MAX_MEMORY >> 20)),
+            None => return Err(format!("Hashed API key needs more than {} MiB to be checked", Self::MAX_MEMORY >> 20)),
+        }
+        Ok(hashed)
+    }
+}
+
+impl fmt::Display for HashedApiKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{}ln={},r={},p={}${}${}",
+            Self::PREFIX,
+            self.log_n,
+            self.r,
+            self.p,
+            openssl::base64::encode_block(&self.salt),
+            openssl::base64::encode_block(&self.hash)
+        )
+    }
+}
 
 #[derive(Parser, Debug)]
 #[clap(
@@ -51,6 +208,19 @@ pub struct CliArgs {
     #[clap(long, env, value_parser)]
     pub broker_url: Url,
 
+    /// Comma separated base URLs of further instances of the same broker, tried in order if the broker can't be reached.
+    /// They may only differ from the broker URL in scheme, host and port.
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    pub fallback_broker_urls: Vec<Url>,
+
+    /// Seconds after which idle connections to the broker are closed. Should be shorter than the idle timeout of any firewall or reverse proxy in between. 0 keeps them open
+    #[clap(long, env, value_parser, default_value_t = 50)]
+    pub broker_pool_idle_timeout: u64,
+
+    /// Maximum number of idle connections kept open per broker. Unlimited by default
+    #[clap(long, env, value_parser)]
+    pub broker_pool_max_idle: Option<usize>,
+
     /// This proxy's beam id, e.g. proxy42.broker23.beam.samply.de
     #[clap(long, env, value_parser)]
     pub proxy_id: String,
@@ -63,6 +233,84 @@ pub struct CliArgs {
     #[clap(long, env, value_parser, default_value = "/run/secrets/root.crt.pem")]
     rootcert_file: PathBuf,
 
+    /// Maximum number of messages decrypted concurrently. Defaults to the number of available CPUs
+    #[clap(long, env, value_parser)]
+    pub crypto_concurrency: Option<usize>,
+
+    /// Milliseconds for which replies to non-blocking GET requests are cached, e.g. for apps polling the status of their tasks. 0 disables the cache
+    #[clap(long, env, value_parser, default_value_t = 0)]
+    pub response_cache_ttl: u64,
+
+    /// Maximum number of recipients of a single message. Messages to more recipients are rejected before looking up their certificates
+    #[clap(long, env, value_parser, default_value_t = 1000)]
+    pub max_task_recipients: usize,
+
+    /// Maximum nesting depth of arrays of signed messages the proxy decrypts, e.g. in replies of the broker. Deeper nested replies are rejected before decrypting them
+    #[clap(long, env, value_parser, default_value_t = 8)]
+    pub max_array_depth: usize,
+
+    /// Maximum number of signed messages in a single array the proxy decrypts, e.g. in a task listing of the broker
+    #[clap(long, env, value_parser, default_value_t = 100_000)]
+    pub max_array_length: usize,
+
+    /// Comma separated origins of browser apps allowed to call this proxy, e.g. https://app.example.com, or * for any origin.
+    /// Browsers hold the API key of any app allowed here, so only list origins you trust. Disabled by default.
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    pub cors_allowed_origins: Vec<String>,
+
+    /// Comma separated proxies, e.g. proxy2,proxy3, that recipient groups and capabilities may resolve to.
+    /// The broker resolves them but must not be able to add readers to encrypted messages, so they are refused unless set.
+    #[clap(long, env, value_parser, value_delimiter = ',')]
+    pub resolved_recipients: Vec<String>,
+
+    /// Failure strategy of tasks created without one or with discard, in the format of the task, e.g. discard or {"retry":{"backoff_millisecs":1000,"max_tries":5}}
+    #[clap(long, env, value_parser = parse_failure_strategy)]
+    pub default_failure_strategy: Option<FailureStrategy>,
+
+    /// URL of a service that decides about the API keys of apps without an APP_<name>_KEY. The proxy POSTs {"app_id": ..., "api_key": ...} and accepts the key if the service answers with a 2xx status
+    #[clap(long, env, value_parser)]
+    pub auth_url: Option<Url>,
+
+    /// Trusted time source to check the system clock against at startup: ntp://host[:port] or an http(s) URL whose Date header is used. Defaults to the broker
+    #[clap(long, env, value_parser)]
+    pub time_reference: Option<TimeReference>,
+
+    /// Maximum difference in seconds between the system clock and the time reference. 0 disables the check
+    #[clap(long, env, value_parser, default_value_t = 60)]
+    pub max_clock_skew: u64,
+
+    /// Refuse to start if the system clock exceeds the maximum clock skew instead of only warning
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pub enforce_clock_skew: bool,
+
+    /// Log the startup information as a single structured event instead of the banner
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pub no_banner: bool,
+
+    /// Reject new tasks with fields a task does not have, e.g. misspelled ones, instead of ignoring these fields
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pub deny_unknown_task_fields: bool,
+
+    /// Reject message bodies of apps with a Content-Type other than application/json or application/cbor instead of parsing them as JSON anyway
+    #[clap(long, env, value_parser, default_value_t = false)]
+    pub require_content_type: bool,
+
+    /// Seconds after which requests to the task API other than GET requests, e.g. creating tasks or results, are answered with 408 Request Timeout. Long polls, streams and socket connections are not limited. 0 disables the timeout
+    #[clap(long, env, value_parser, default_value_t = 0)]
+    pub request_timeout: u64,
+
+    /// The API key for accessing the metrics endpoint of the proxy. The endpoint is disabled if unset
+    #[clap(long, env, value_parser)]
+    pub monitoring_api_key: Option<String>,
+
+    /// Zone this proxy runs in, e.g. eu-west. The broker lists it so apps can address the proxies of a zone
+    #[clap(long, env, value_parser)]
+    pub zone: Option<String>,
+
+    /// Region this proxy runs in, e.g. de-bw. The broker lists it so apps can choose a nearby proxy
+    #[clap(long, env, value_parser)]
+    pub region: Option<String>,
+
     /// (included for technical reasons)
     #[clap(long, hide(true))]
     test_threads: Option<String>,
@@ -70,12 +318,40 @@ pub struct CliArgs {
 
 pub const APP_PREFIX: &str = "APP";
 
+/// Parses the proxies apps may address from the environment like:
+/// APP_app1_RECIPIENTS=proxy2,proxy3
+fn parse_allowed_recipients(proxy_id: &ProxyId) -> Result<HashMap<AppId, HashSet<ProxyId>>, SamplyBeamError> {
+    let pattern = Regex::new(&format!("^{APP_PREFIX}_([A-Za-z0-9-]+)_RECIPIENTS$")).expect("This is a valid regex");
+    let mut allowed = HashMap::new();
+    for (env_var_name, proxies) in std::env::vars() {
+        let Some(app_name) = pattern.captures(&env_var_name).and_then(|cap| cap.get(1)) else {
+            continue;
+        };
+        let app_id = AppId::new(format!("{}.{proxy_id}", app_name.as_str()))
+            .map_err(|e| SamplyBeamError::ConfigurationFailed(format!("{env_var_name}: {e}")))?;
+        allowed.insert(app_id, parse_proxy_names(&env_var_name, proxies.split(','), proxy_id)?);
+    }
+    Ok(allowed)
+}
+
+/// Parses proxy names like proxy2 of the broker of `proxy_id`, skipping empty ones
+fn parse_proxy_names<'a>(setting: &str, names: impl IntoIterator<Item = &'a str>, proxy_id: &ProxyId) -> Result<HashSet<ProxyId>, SamplyBeamError> {
+    let (_, broker_id) = proxy_id.as_ref().split_once('.').expect("Proxy ids contain the broker id");
+    names
+        .into_iter()
+        .map(str::trim)
+        .filter(|proxy| !proxy.is_empty())
+        .map(|proxy| ProxyId::new(format!("{proxy}.{broker_id}")).map_err(|e| SamplyBeamError::ConfigurationFailed(format!("{setting}: {e}"))))
+        .collect()
+}
+
 /// Parses API-Keys from the environment like:
 /// APP_app1_KEY=App1Secret
-/// APP_app2_KEY=App2Secret
+/// APP_app2_KEY=$scrypt$ln=14,r=8,p=1$<base64 salt>$<base64 hash>
 fn parse_apikeys(proxy_id: &ProxyId) -> Result<HashMap<AppId, ApiKey>, SamplyBeamError> {
-    let env_vars = std::env::vars().collect::<HashMap<String, ApiKey>>();
+    let env_vars = std::env::vars().collect::<HashMap<String, String>>();
     let mut api_keys = HashMap::new();
+    let mut plain_keys = Vec::new();
     let pattern = Regex::new(&format!("{APP_PREFIX}_([A-Za-z0-9-]+)_KEY")).expect("This is a valid regex");
     for (env_var_name, secret) in env_vars {
         if let Some(app_name) = pattern.captures_iter(&env_var_name).next().and_then(|cap| cap.get(1)) {
@@ -89,9 +365,19 @@ fn parse_apikeys(proxy_id: &ProxyId) -> Result<HashMap<AppId, ApiKey>, SamplyBea
                     "Please supply a non empty API key for client {app_id}",
                 )));
             }
-            api_keys.insert(app_id, secret);
+            let api_key = if secret.starts_with(HashedApiKey::PREFIX) {
+                ApiKey::Hashed(secret.parse().map_err(|e| SamplyBeamError::ConfigurationFailed(format!("{env_var_name}: {e}")))?)
+            } else {
+                plain_keys.push(app_id.to_string());
+                ApiKey::Plain(secret)
+            };
+            api_keys.insert(app_id, api_key);
         }
     }
+    if !plain_keys.is_empty() {
+        plain_keys.sort();
+        warn!("Plain text API keys are deprecated. Please replace the keys of {} with their hashes", plain_keys.join(", "));
+    }
     Ok(api_keys)
 }
 
@@ -106,8 +392,10 @@ impl crate::config::Config for Config {
             ))
         })?;
         let api_keys = parse_apikeys(&proxy_id)?;
-        if api_keys.is_empty() {
-            return Err(SamplyBeamError::ConfigurationFailed(format!("No API keys have been defined. Please set environment vars à la {0}_<clientname>_KEY=<key>", APP_PREFIX)));
+        let allowed_recipients = parse_allowed_recipients(&proxy_id)?;
+        let resolved_recipients = parse_proxy_names("RESOLVED_RECIPIENTS", cli_args.resolved_recipients.iter().map(String::as_str), &proxy_id)?;
+        if api_keys.is_empty() && cli_args.auth_url.is_none() {
+            return Err(SamplyBeamError::ConfigurationFailed(format!("No API keys have been defined. Please set environment vars à la {0}_<clientname>_KEY=<key> or an AUTH_URL", APP_PREFIX)));
         }
         let tls_ca_certificates = crate::crypto::load_certificates_from_dir(
             cli_args.tls_ca_certificates_dir,
@@ -118,20 +406,48 @@ impl crate::config::Config for Config {
                 e
             ))
         })?;
+        check_fallback_brokers(&cli_args.broker_url, &cli_args.fallback_broker_urls)?;
+        let cors_allowed_origins = parse_cors_origins(&cli_args.cors_allowed_origins)?;
         let config = Config {
             broker_host_header: uri_to_host_header(&cli_args.broker_url)?,
+            fallback_broker_uris: cli_args.fallback_broker_urls,
+            broker_connection_pool: ConnectionPool {
+                idle_timeout: (cli_args.broker_pool_idle_timeout != 0).then(|| Duration::from_secs(cli_args.broker_pool_idle_timeout)),
+                max_idle_per_host: cli_args.broker_pool_max_idle.unwrap_or(usize::MAX),
+            },
             broker_uri: cli_args.broker_url,
             bind_addr: cli_args.bind_addr,
             proxy_id,
             api_keys,
+            allowed_recipients,
+            resolved_recipients,
+            auth_url: cli_args.auth_url,
             tls_ca_certificates,
+            crypto_concurrency: cli_args.crypto_concurrency
+                .or_else(|| std::thread::available_parallelism().ok().map(usize::from))
+                .unwrap_or(1),
+            response_cache_ttl: (cli_args.response_cache_ttl != 0).then(|| Duration::from_millis(cli_args.response_cache_ttl)),
+            max_task_recipients: cli_args.max_task_recipients,
+            max_array_depth: cli_args.max_array_depth,
+            max_array_length: cli_args.max_array_length,
+            cors_allowed_origins,
+            default_failure_strategy: cli_args.default_failure_strategy,
+            time_reference: cli_args.time_reference,
+            max_clock_skew: Duration::from_secs(cli_args.max_clock_skew),
+            enforce_clock_skew: cli_args.enforce_clock_skew,
+            no_banner: cli_args.no_banner,
+            deny_unknown_task_fields: cli_args.deny_unknown_task_fields,
+            require_content_type: cli_args.require_content_type,
+            request_timeout: (cli_args.request_timeout != 0).then(|| Duration::from_secs(cli_args.request_timeout)),
+            monitoring_api_key: cli_args.monitoring_api_key,
+            metadata: ProxyMetadata { zone: cli_args.zone, region: cli_args.region },
         };
         info!("Successfully read config and API keys from CLI and secrets file.");
         Ok(config)
     }
 }
 
-fn uri_to_host_header(uri: &Url) -> Result<HeaderValue, SamplyBeamError> {
+pub(crate) fn uri_to_host_header(uri: &Url) -> Result<HeaderValue, SamplyBeamError> {
     let hostname: String = uri
         .host()
         .ok_or(SamplyBeamError::WrongBrokerUri("URI's host is empty."))?
@@ -146,10 +462,54 @@ fn uri_to_host_header(uri: &Url) -> Result<HeaderValue, SamplyBeamError> {
     Ok(host_header)
 }
 
+/// Fallback brokers are addressed with the primary broker's id and the paths signed for it,
+/// so they have to serve the same broker under the same path.
+fn check_fallback_brokers(primary: &Url, fallbacks: &[Url]) -> Result<(), SamplyBeamError> {
+    for fallback in fallbacks {
+        if !matches!(fallback.scheme(), "http" | "https") || fallback.host().is_none() {
+            return Err(SamplyBeamError::WrongBrokerUri("Fallback broker URLs need to be http(s) URLs with a host."));
+        }
+        if fallback.path() != primary.path() || fallback.query().is_some() {
+            return Err(SamplyBeamError::WrongBrokerUri("Fallback broker URLs may only differ from the broker URL in scheme, host and port."));
+        }
+    }
+    Ok(())
+}
+
+/// Origins are compared verbatim by browsers, so they must not carry a path or trailing slash
+fn parse_cors_origins(origins: &[String]) -> Result<Vec<HeaderValue>, SamplyBeamError> {
+    origins
+        .iter()
+        .map(|origin| origin.trim())
+        .filter(|origin| !origin.is_empty())
+        .map(|origin| {
+            let valid = origin == "*" || Url::parse(origin).is_ok_and(|url| {
+                matches!(url.scheme(), "http" | "https") && url.origin().ascii_serialization() == origin
+            });
+            if !valid {
+                return Err(SamplyBeamError::ConfigurationFailed(format!(
+                    "Invalid CORS origin \"{origin}\". Please use the form https://app.example.com or *."
+                )));
+            }
+            Ok(HeaderValue::from_str(origin).expect("Valid origins are valid header values"))
+        })
+        .collect()
+}
+
+/// Accepts the strategy as in a task, with or without the quotes around a bare strategy like discard
+fn parse_failure_strategy(strategy: &str) -> Result<FailureStrategy, String> {
+    let strategy: FailureStrategy = serde_json::from_str(strategy)
+        .or_else(|_| serde_json::from_value(serde_json::Value::String(strategy.trim().to_string())))
+        .map_err(|_| format!("Invalid failure strategy \"{strategy}\". Please use discard or {{\"retry\":{{\"backoff_millisecs\":1000,\"max_tries\":5}}}}."))?;
+    strategy.validate()?;
+    Ok(strategy)
+}
+
 #[cfg(test)]
 mod tests {
     // Note this useful idiom: importing names from outer (for mod tests) scope.
     use super::*;
+    use crate::test_utils::{init_broker_id, BROKER_ID};
 
     #[test]
     fn test_parse_apikeys() {
@@ -163,9 +523,84 @@ mod tests {
             std::env::set_var(format!("APP_{i}_KEY"), key);
             std::env::set_var(format!("APP_{app}_KEY"), key);
         }
-        const BROKER_ID: &str = "broker.samply.de";
-        beam_lib::set_broker_id(BROKER_ID.to_string());
+        init_broker_id();
         let parsed = parse_apikeys(&ProxyId::new(&format!("proxy.{BROKER_ID}")).unwrap()).unwrap();
         assert_eq!(parsed.len(), apps.len() * 2);
     }
+
+    #[test]
+    fn test_parse_allowed_recipients() {
+        init_broker_id();
+        std::env::set_var("APP_tenant1_RECIPIENTS", "proxy2, proxy3,");
+        std::env::set_var("APP_tenant2_RECIPIENTS", "");
+        let proxy_id = ProxyId::new(format!("proxy1.{BROKER_ID}")).unwrap();
+        let parsed = parse_allowed_recipients(&proxy_id).unwrap();
+        let allowed = |app: &str| &parsed[&AppId::new(format!("{app}.{proxy_id}")).unwrap()];
+        assert_eq!(allowed("tenant1"), &HashSet::from([ProxyId::new(format!("proxy2.{BROKER_ID}")).unwrap(), ProxyId::new(format!("proxy3.{BROKER_ID}")).unwrap()]));
+        assert!(allowed("tenant2").is_empty(), "An empty list allows no proxy at all");
+    }
+
+    #[test]
+    fn test_hashed_api_keys() {
+        let hashed = HashedApiKey::new("App1Secret", 10, 8, 1).unwrap();
+        assert!(hashed.verify("App1Secret"));
+        assert!(!hashed.verify("App1Secret "));
+        assert!(!hashed.verify(""));
+        let parsed: HashedApiKey = hashed.to_string().parse().unwrap();
+        assert!(parsed == hashed);
+        // Generated with Python's hashlib.scrypt
+        let python: HashedApiKey = "$scrypt$ln=10,r=8,p=1$c2FsdHNhbHRzYWx0c2FsdA==$pnhtb1yePT5dz73ZafkwO85O6DLXYd66iaW4DlApYKI=".parse().unwrap();
+        assert!(python.verify("App1Secret"));
+        for invalid in [
+            "$scrypt$ln=10,r=8$c2FsdA==$aGFzaA==",
+            "$scrypt$ln=10,r=8,p=1$c2FsdA==",
+            "$scrypt$ln=10,r=8,p=1$c2FsdA==$",
+            "$scrypt$ln=10,r=8,p=1$not base64$aGFzaA==",
+            "$scrypt$ln=30,r=8,p=1$c2FsdA==$aGFzaA==",
+            "$scrypt$ln=31,r=1,p=1$c2FsdA==$aGFzaA==",
+            "$scrypt$ln=63,r=8,p=1$c2FsdA==$aGFzaA==",
+            "$scrypt$ln=20,r=4294967295,p=4294967295$c2FsdA==$aGFzaA==",
+        ] {
+            assert!(invalid.parse::<HashedApiKey>().is_err(), "{invalid} should be rejected");
+        }
+
+        let plain = ApiKey::Plain("App1Secret".to_string());
+        assert!(plain.verify("App1Secret"));
+        assert!(!plain.verify("App2Secret"));
+        assert!(!plain.verify("App1"));
+        assert!(!plain.verify("App1SecretApp1Secret"));
+        assert_eq!(format!("{:?}", ApiKey::Plain("App1Secret".to_string())), "Plain(<redacted>)");
+    }
+
+    #[test]
+    fn test_check_fallback_brokers() {
+        let primary: Url = "https://broker.samply.de/".parse().unwrap();
+        let fallbacks: Vec<Url> = ["http://10.0.0.2:8080/", "https://broker-b.samply.de"].iter().map(|u| u.parse().unwrap()).collect();
+        assert!(check_fallback_brokers(&primary, &fallbacks).is_ok());
+        for invalid in ["https://broker-b.samply.de/beam/", "https://broker-b.samply.de/?a=b", "file:///tmp/broker"] {
+            assert!(check_fallback_brokers(&primary, &[invalid.parse().unwrap()]).is_err(), "{invalid} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_cors_origins() {
+        let origins = ["https://app.samply.de", " http://localhost:3000", "*", ""].map(String::from);
+        assert_eq!(parse_cors_origins(&origins).unwrap(), ["https://app.samply.de", "http://localhost:3000", "*"]);
+        for invalid in ["https://app.samply.de/", "app.samply.de", "https://app.samply.de/path", "file:///tmp"] {
+            assert!(parse_cors_origins(&[invalid.to_string()]).is_err(), "{invalid} should be rejected");
+        }
+    }
+
+    #[test]
+    fn test_parse_failure_strategy() {
+        assert_eq!(parse_failure_strategy("discard").unwrap(), FailureStrategy::Discard);
+        assert_eq!(parse_failure_strategy("\"discard\"").unwrap(), FailureStrategy::Discard);
+        assert_eq!(
+            parse_failure_strategy(r#"{"retry":{"backoff_millisecs":1000,"max_tries":5}}"#).unwrap(),
+            FailureStrategy::Retry { backoff_millisecs: 1000, max_tries: 5 }
+        );
+        for invalid in ["retry", r#"{"retry":{"backoff_millisecs":1000,"max_tries":0}}"#, r#"{"retry":{"backoff_millisecs":0,"max_tries":5}}"#] {
+            assert!(parse_failure_strategy(invalid).is_err(), "{invalid} should be rejected");
+        }
+    }
 }
@@ -37,6 +37,10 @@ struct CliArgs {
     #[clap(long, env, value_parser, default_value = "/run/secrets/privkey.pem")]
     privkey_file: PathBuf,
 
+    /// samply.pki: Path to the secret key used before the last key rotation. Messages encrypted to it are still decrypted until it is removed
+    #[clap(long, env, value_parser)]
+    previous_privkey_file: Option<PathBuf>,
+
     /// samply.pki: Path to CA Root certificate
     #[clap(long, env, value_parser, default_value = "/run/secrets/root.crt.pem")]
     rootcert_file: PathBuf,
@@ -71,9 +75,18 @@ pub struct Config {
 pub struct ConfigCrypto {
     pub privkey_rs256: RS256KeyPair,
     pub privkey_rsa: RsaPrivateKey,
+    /// Key replaced by `privkey_rsa` in a key rotation, kept to decrypt messages encrypted to it before
+    pub previous_privkey_rsa: Option<RsaPrivateKey>,
     pub public: Option<CryptoPublicPortion>,
 }
 
+impl ConfigCrypto {
+    /// Keys to decrypt messages with, the current one first
+    pub fn decryption_keys(&self) -> impl Iterator<Item = &RsaPrivateKey> {
+        std::iter::once(&self.privkey_rsa).chain(&self.previous_privkey_rsa)
+    }
+}
+
 impl crate::config::Config for Config {
     fn load() -> Result<Self, SamplyBeamError> {
         let cli_args = CliArgs::parse();
@@ -136,25 +149,25 @@ pub async fn init_public_crypto_for_proxy(
 
 pub fn load_private_crypto_for_proxy() -> Result<ConfigCrypto, SamplyBeamError> {
     let cli_args = CliArgs::parse();
-    let privkey_pem = read_to_string(&cli_args.privkey_file)
+    let read_privkey = |file: &PathBuf| read_to_string(file)
         .map_err(|e| {
             SamplyBeamError::ConfigurationFailed(format!(
                 "Unable to load private key from file {}: {}\n{}",
-                cli_args.privkey_file.to_string_lossy(),
+                file.to_string_lossy(),
                 e,
                 get_enrollment_msg(&cli_args.proxy_id)
             ))
-        })?
-        .trim()
-        .to_string();
-    let privkey_rsa = RsaPrivateKey::from_pkcs1_pem(&privkey_pem)
-        .or_else(|_| RsaPrivateKey::from_pkcs8_pem(&privkey_pem))
-        .map_err(|e| {
-            SamplyBeamError::ConfigurationFailed(format!(
-                "Unable to interpret private key PEM as PKCS#1 or PKCS#8: {}",
-                e
-            ))
-        })?;
+        })
+        .map(|pem| pem.trim().to_string());
+    let privkey_pem = read_privkey(&cli_args.privkey_file)?;
+    let privkey_rsa = parse_privkey_rsa(&privkey_pem)?;
+    let previous_privkey_rsa = cli_args.previous_privkey_file
+        .as_ref()
+        .map(|file| {
+            info!("Also decrypting messages with the previous private key from {}", file.to_string_lossy());
+            parse_privkey_rsa(&read_privkey(file)?)
+        })
+        .transpose()?;
     let privkey_rs256 = RS256KeyPair::from_pem(&privkey_pem).map_err(|e| {
         SamplyBeamError::ConfigurationFailed(format!(
             "Unable to interpret private key PEM as PKCS#1 or PKCS#8: {}",
@@ -164,10 +177,22 @@ pub fn load_private_crypto_for_proxy() -> Result<ConfigCrypto, SamplyBeamError>
     Ok(ConfigCrypto {
         privkey_rs256,
         privkey_rsa,
+        previous_privkey_rsa,
         public: None,
     })
 }
 
+fn parse_privkey_rsa(privkey_pem: &str) -> Result<RsaPrivateKey, SamplyBeamError> {
+    RsaPrivateKey::from_pkcs1_pem(privkey_pem)
+        .or_else(|_| RsaPrivateKey::from_pkcs8_pem(privkey_pem))
+        .map_err(|e| {
+            SamplyBeamError::ConfigurationFailed(format!(
+                "Unable to interpret private key PEM as PKCS#1 or PKCS#8: {}",
+                e
+            ))
+        })
+}
+
 async fn load_public_crypto_for_proxy(
     cli_args: &CliArgs,
     mut config: ConfigCrypto,
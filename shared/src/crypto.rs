@@ -10,7 +10,7 @@ use openssl::{
     x509::{X509, X509Crl, CrlStatus},
 };
 use rsa::{
-    pkcs1::DecodeRsaPublicKey, pkcs8::DecodePublicKey, RsaPrivateKey, RsaPublicKey, traits::PublicKeyParts,
+    pkcs1::DecodeRsaPublicKey, pkcs8::{DecodePublicKey, EncodePublicKey}, RsaPrivateKey, RsaPublicKey, traits::PublicKeyParts,
 };
 use sha2::{Digest, Sha256};
 use std::{
@@ -509,6 +509,11 @@ pub async fn get_serial_list() -> Vec<String> {
         .collect()
 }
 
+/// Number of cached certificates including invalid ones, for diagnostics
+pub async fn certificate_cache_size() -> usize {
+    CERT_CACHE.read().await.serial_to_x509.len()
+}
+
 pub async fn get_im_cert() -> Result<String, SamplyBeamError> {
     CERT_GETTER.get().unwrap().im_certificate_as_pem().await
 }
@@ -569,6 +574,15 @@ pub(crate) static CERT_CACHE: Lazy<Arc<RwLock<CertificateCache>>> = Lazy::new(||
     cc
 });
 
+/// Adds a certificate to the cache without checking it against the CA, so tests can sign, verify and encrypt messages without a PKI
+#[cfg(any(test, feature = "test-utils"))]
+pub(crate) async fn insert_unverified_certificate(serial: &str, cert: X509) {
+    let cname = extract_x509(&cert).expect("Test certificates are valid").beam_id;
+    let mut cache = CERT_CACHE.write().await;
+    cache.serial_to_x509.insert(serial.to_string(), CertificateCacheEntry::Valid(cert));
+    cache.cn_to_serial.entry(cname).or_default().push(serial.to_string());
+}
+
 async fn get_cert_by_serial(serial: &str) -> Option<X509> {
     CertificateCache::get_by_serial(serial).await
 }
@@ -898,6 +912,20 @@ pub async fn get_proxy_public_keys(
     }
 }
 
+/// Hex encoded SHA-256 digest of the DER encoded public key, as printed by
+/// `openssl x509 -in cert.pem -pubkey -noout | openssl pkey -pubin -outform der | sha256sum`
+pub fn public_key_fingerprint(key: &RsaPublicKey) -> Result<String, SamplyBeamError> {
+    let der = key.to_public_key_der()
+        .map_err(|e| SamplyBeamError::SignEncryptError(format!("Unable to encode public key: {e}")))?;
+    Ok(Sha256::digest(der.as_bytes()).iter().map(|byte| format!("{byte:02x}")).collect())
+}
+
+/// Compares secrets like API keys without short-circuiting on the first differing byte.
+/// Only their length is revealed by timing.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && openssl::memcmp::eq(a, b)
+}
+
 #[cfg(test)]
 mod tests {
     use openssl::{x509::{X509NameBuilder, extension::{BasicConstraints, KeyUsage, SubjectKeyIdentifier}}, bn::{BigNum, MsbOption}, pkey::PKey, rsa::Rsa, hash::MessageDigest};
@@ -973,4 +1001,17 @@ mod tests {
         assert!(matches!(cache.serial_to_x509.get("3"), Some(&CertificateCacheEntry::Invalid(CertificateInvalidReason::Revoked))), "Certificate was not revoked");
         assert_eq!(cache.serial_to_x509.values().filter(|cert| matches!(cert, CertificateCacheEntry::Valid(..))).count(), 3, "No other certs have been invalidated");
     }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"App1Secret", b"App1Secret"));
+        assert!(constant_time_eq(b"", b""));
+        // Equal length, differing in the first or last byte
+        assert!(!constant_time_eq(b"App1Secret", b"Bpp1Secret"));
+        assert!(!constant_time_eq(b"App1Secret", b"App1Secreu"));
+        // Differing length, including prefixes
+        assert!(!constant_time_eq(b"App1Secret", b"App1"));
+        assert!(!constant_time_eq(b"App1", b"App1Secret"));
+        assert!(!constant_time_eq(b"App1Secret", b""));
+    }
 }
@@ -1,4 +1,4 @@
-use std::net::{SocketAddr, IpAddr};
+use std::{net::{SocketAddr, IpAddr}, num::NonZeroUsize, sync::Mutex, time::Instant};
 
 use beam_lib::{AppOrProxyId, ProxyId};
 use crate::{
@@ -6,21 +6,23 @@ use crate::{
     config_shared::ConfigCrypto,
     crypto::{self, CryptoPublicPortion},
     errors::{CertificateInvalidReason, SamplyBeamError},
+    metrics,
     Msg, MsgEmpty, MsgId, MsgSigned,
 };
 use axum::{async_trait, body::HttpBody, extract::{{FromRequest, ConnectInfo, FromRequestParts}, Request}, http::{header, request::Parts, uri::PathAndQuery, HeaderMap, HeaderName, Method, StatusCode, Uri}, BoxError, RequestExt};
 use jwt_simple::{
     claims::JWTClaims,
     prelude::{
-        Base64, Base64UrlSafeNoPadding, Claims, Duration, KeyMetadata, RS256KeyPair,
+        Base64, Base64UrlSafeNoPadding, Claims, Clock, Duration, KeyMetadata, RS256KeyPair,
         RS256PublicKey, RSAKeyPairLike, RSAPublicKeyLike, Token, VerificationOptions,
     },
     reexports::ct_codecs::Decoder,
 };
-use once_cell::unsync::Lazy;
+use lru::LruCache;
 use openssl::base64;
 use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use serde_json::Value;
+use sha2::{Digest, Sha256};
 use tracing::{debug, error, warn, Span, info_span};
 
 const ERR_SIG: (StatusCode, &str) = (StatusCode::UNAUTHORIZED, "Signature could not be verified");
@@ -56,7 +58,7 @@ where
 pub type Authorized = MsgSigned<MsgEmpty>;
 
 #[tracing::instrument]
-pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
+pub async fn extract_jwt<T: DeserializeOwned + Serialize + Send + 'static>(
     token: &str,
 ) -> Result<
     (
@@ -66,11 +68,11 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
     ),
     SamplyBeamError,
 > {
-    let metadata = Token::decode_metadata(token).map_err(|e| {
-        SamplyBeamError::RequestValidationFailed(format!("Unable to decode JWT metadata: {}", e))
-    })?;
-    let public = if let Some(serial) = metadata.key_id() {
-        crypto::get_cert_and_client_by_serial_as_pemstr(serial)
+    if let Some(VerifiedToken { public, pubkey }) = VERIFIED_TOKENS.get(token) {
+        return Ok((public, pubkey, decode_claims_unverified(token)?));
+    }
+    let public = match claimed_signer(token)? {
+        Signer::Serial(serial) => crypto::get_cert_and_client_by_serial_as_pemstr(&serial)
             .await
             .ok_or_else(|| {
                 SamplyBeamError::VaultOtherError(format!(
@@ -78,35 +80,67 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
                     serial
                 ))
             })?
-            .map_err(|e| SamplyBeamError::CertificateError(e))?
-    } else {
-        // if it does not have a serial in the metadata try to get it by reading the from field in the body
-        // this happens, e.g. during proxy initialization before a certificate (serial) is received
-        let data = token
-            .splitn(3, ".")
-            .nth(1)
-            .ok_or(SamplyBeamError::RequestValidationFailed(
-                "Invalid JWT in header".to_string(),
-            ))?;
-        let data = Base64UrlSafeNoPadding::decode_to_vec(data, None).map_err(|e| {
-            warn!("Failed to b64decode {data:?}. Err: {e}");
-            SamplyBeamError::RequestValidationFailed("Invalid JWT in header".to_string())
-        })?;
-        let json = serde_json::from_slice::<JWTClaims<HeaderClaim>>(&data).map_err(|e| {
-            warn!("Failed to decode {data:?} to JwtClaims<HeaderClaims>. Err: {e}");
-            SamplyBeamError::RequestValidationFailed("Invalid JWT body in header".to_string())
-        })?;
-        let proxy_id: ProxyId = json.custom.from.proxy_id();
-        let mut certs = crypto::get_all_certs_and_clients_by_cname_as_pemstr(&proxy_id)
+            .map_err(SamplyBeamError::CertificateError)?,
+        Signer::Proxy(proxy_id) => {
+            let mut certs = crypto::get_all_certs_and_clients_by_cname_as_pemstr(&proxy_id)
+                .await
+                .into_iter()
+                .flatten()
+                .collect::<Vec<_>>();
+            // Get newest Certificate
+            crypto::get_newest_cert(&mut certs).ok_or(SamplyBeamError::CertificateError(
+                CertificateInvalidReason::NoCommonName,
+            ))?
+        }
+    };
+    // Checking the RSA signature takes long enough to stall the other tasks on this worker
+    let (public, pubkey, content) = {
+        let token = token.to_string();
+        tokio::task::spawn_blocking(move || verify_jwt(&token, &public).map(|(pubkey, content)| (public, pubkey, content)))
             .await
-            .into_iter()
-            .flatten()
-            .collect::<Vec<_>>();
-        // Get newest Certificate
-        crypto::get_newest_cert(&mut certs).ok_or(SamplyBeamError::CertificateError(
-            CertificateInvalidReason::NoCommonName,
-        ))?
+            .map_err(|e| SamplyBeamError::InternalSynchronizationError(format!("Signature verification failed: {e}")))??
     };
+    if outlives_cache(&content) {
+        VERIFIED_TOKENS.insert(token, VerifiedToken { public: public.clone(), pubkey: pubkey.clone() });
+    }
+    Ok((public, pubkey, content))
+}
+
+/// Unlike [`AppOrProxyId::can_be_signed_by`], this requires the signer to be exactly the proxy of the sender
+fn check_signer(from: &AppOrProxyId, signer: &CryptoPublicPortion) -> Result<(), SamplyBeamError> {
+    if from.proxy_id() != signer.beam_id {
+        return Err(SamplyBeamError::RequestValidationFailed(format!(
+            "Message from {from} is signed by {}",
+            signer.beam_id
+        )));
+    }
+    Ok(())
+}
+
+/// How to find the certificate a token claims to be signed with
+enum Signer {
+    Serial(String),
+    Proxy(ProxyId),
+}
+
+/// Reads which certificate the token claims to be signed with without verifying anything
+fn claimed_signer(token: &str) -> Result<Signer, SamplyBeamError> {
+    let metadata = Token::decode_metadata(token).map_err(|e| {
+        SamplyBeamError::RequestValidationFailed(format!("Unable to decode JWT metadata: {}", e))
+    })?;
+    if let Some(serial) = metadata.key_id() {
+        return Ok(Signer::Serial(serial.to_string()));
+    }
+    // if it does not have a serial in the metadata try to get it by reading the from field in the body
+    // this happens, e.g. during proxy initialization before a certificate (serial) is received
+    let json = decode_claims_unverified::<HeaderClaim>(token)?;
+    Ok(Signer::Proxy(json.custom.from.proxy_id()))
+}
+
+fn verify_jwt<T: DeserializeOwned + Serialize>(
+    token: &str,
+    public: &CryptoPublicPortion,
+) -> Result<(RS256PublicKey, JWTClaims<T>), SamplyBeamError> {
     let pubkey = RS256PublicKey::from_pem(&public.pubkey).map_err(|e| {
         SamplyBeamError::SignEncryptError(format!("Unable to initialize public key: {}", e))
     })?;
@@ -118,10 +152,106 @@ pub async fn extract_jwt<T: DeserializeOwned + Serialize>(
                 e
             ))
         })?;
-    Ok((public, pubkey, content))
+    Ok((pubkey, content))
+}
+
+/// Decodes the claims of a JWT without verifying its signature
+fn decode_claims_unverified<T: DeserializeOwned>(token: &str) -> Result<JWTClaims<T>, SamplyBeamError> {
+    let data = token
+        .split('.')
+        .nth(1)
+        .ok_or(SamplyBeamError::RequestValidationFailed(
+            "Invalid JWT in header".to_string(),
+        ))?;
+    let data = Base64UrlSafeNoPadding::decode_to_vec(data, None).map_err(|e| {
+        warn!("Failed to b64decode {data:?}. Err: {e}");
+        SamplyBeamError::RequestValidationFailed("Invalid JWT in header".to_string())
+    })?;
+    serde_json::from_slice::<JWTClaims<T>>(&data).map_err(|e| {
+        warn!("Failed to decode {data:?} to JwtClaims. Err: {e}");
+        SamplyBeamError::RequestValidationFailed("Invalid JWT body in header".to_string())
+    })
+}
+
+/// Reads the sender a signed message claims to be from without verifying anything,
+/// e.g. to attribute messages that failed verification
+pub fn claimed_sender(token: &str) -> Option<AppOrProxyId> {
+    #[derive(Deserialize)]
+    struct Sender {
+        from: AppOrProxyId,
+    }
+    decode_claims_unverified::<Sender>(token).ok().map(|claims| claims.custom.from)
+}
+
+/// How long a verified token is trusted without verifying it again.
+/// This is short so that revoked or renewed certificates take effect long before the tokens signed with them expire.
+const VERIFIED_TOKEN_TTL: std::time::Duration = std::time::Duration::from_secs(60);
+const VERIFIED_TOKEN_CAPACITY: usize = 10_000;
+
+#[derive(Clone)]
+struct VerifiedToken {
+    public: CryptoPublicPortion,
+    pubkey: RS256PublicKey,
+}
+
+/// Tokens verified by [`extract_jwt`] with the certificate they were signed with
+static VERIFIED_TOKENS: once_cell::sync::Lazy<TokenCache<VerifiedToken>> =
+    once_cell::sync::Lazy::new(|| TokenCache::new("token", VERIFIED_TOKEN_TTL, VERIFIED_TOKEN_CAPACITY));
+/// Body tokens verified by [`verify_with_extended_header`] with the PEM of the public key they were signed with
+static VERIFIED_BODY_TOKENS: once_cell::sync::Lazy<TokenCache<String>> =
+    once_cell::sync::Lazy::new(|| TokenCache::new("body", VERIFIED_TOKEN_TTL, VERIFIED_TOKEN_CAPACITY));
+
+/// Number of cached verified header and body tokens, for diagnostics
+pub fn verified_token_cache_sizes() -> (usize, usize) {
+    (VERIFIED_TOKENS.len(), VERIFIED_BODY_TOKENS.len())
+}
+
+/// Only tokens which are still valid once their cache entry expires may be cached as the expiry is not checked on cache hits
+fn outlives_cache<T>(claims: &JWTClaims<T>) -> bool {
+    claims.expires_at.is_some_and(|exp| exp > Clock::now_since_epoch() + Duration::from_secs(VERIFIED_TOKEN_TTL.as_secs()))
+}
+
+/// A bounded cache of recently verified tokens keyed by their hash.
+/// Retried requests carry identical tokens so their signatures don't need to be verified again.
+/// Once full, each new token evicts the least recently used one.
+struct TokenCache<V> {
+    /// Label of the cache's lookups in [`metrics::VERIFIED_TOKEN_CACHE_LOOKUPS`]
+    name: &'static str,
+    entries: Mutex<LruCache<[u8; 32], (Instant, V)>>,
+    ttl: std::time::Duration,
+}
+
+impl<V: Clone> TokenCache<V> {
+    fn new(name: &'static str, ttl: std::time::Duration, capacity: usize) -> Self {
+        let capacity = NonZeroUsize::new(capacity).expect("Token caches hold at least one token");
+        Self { name, entries: Mutex::new(LruCache::new(capacity)), ttl }
+    }
+
+    fn get(&self, token: &str) -> Option<V> {
+        let mut entries = self.entries.lock().unwrap();
+        let value = entries
+            .get(&Self::key(token))
+            .filter(|(verified_at, _)| verified_at.elapsed() < self.ttl)
+            .map(|(_, value)| value.clone());
+        let result = if value.is_some() { "hit" } else { "miss" };
+        metrics::VERIFIED_TOKEN_CACHE_LOOKUPS.with_label_values(&[self.name, result]).inc();
+        value
+    }
+
+    fn insert(&self, token: &str, value: V) {
+        self.entries.lock().unwrap().put(Self::key(token), (Instant::now(), value));
+    }
+
+    fn len(&self) -> usize {
+        self.entries.lock().unwrap().len()
+    }
+
+    fn key(token: &str) -> [u8; 32] {
+        Sha256::digest(token.as_bytes()).into()
+    }
 }
 
-pub const JWT_VERIFICATION_OPTIONS: Lazy<VerificationOptions> = Lazy::new(|| VerificationOptions {
+pub static JWT_VERIFICATION_OPTIONS: once_cell::sync::Lazy<VerificationOptions> = once_cell::sync::Lazy::new(|| VerificationOptions {
     accept_future: true,
     max_token_length: Some(1024 * 1024 * 100), //100MB
     ..Default::default()
@@ -135,6 +265,7 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
     req: &mut Parts,
     token_without_extended_signature: &str,
 ) -> Result<MsgSigned<M>, (StatusCode, &'static str)> {
+    let _timer = metrics::SIGNATURE_VERIFICATION_SECONDS.start_timer();
     let ip = get_ip(req).await;
     let token_with_extended_signature = req.headers
         .get(header::AUTHORIZATION)
@@ -159,27 +290,47 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
             })?;
 
     Span::current().record("from", header_claims.custom.from.hide_broker());
+    verify_body_token(req, token_without_extended_signature, &proxy_public_info, &pubkey, header_claims.custom)
+}
 
+/// Verifies the body token of a request whose header token has been verified with `pubkey` and checks that both belong together.
+/// Everything in here is attacker controlled except for the signer's certificate.
+fn verify_body_token<M: Msg + DeserializeOwned>(
+    req: &Parts,
+    token_without_extended_signature: &str,
+    proxy_public_info: &CryptoPublicPortion,
+    pubkey: &RS256PublicKey,
+    header_claims: HeaderClaim,
+) -> Result<MsgSigned<M>, (StatusCode, &'static str)> {
     // Check extra digest
 
-    let custom = header_claims.custom;
+    let custom = header_claims;
     let digest_claimed = custom.sig;
     let sender_claimed = custom.from;
 
     // Check if short token matches the long token
-    let msg = pubkey
-        .verify_token::<M>(
-            token_without_extended_signature,
-            Some(JWT_VERIFICATION_OPTIONS.clone()),
-        )
-        .map_err(|e| {
-            warn!(
-                "Unable to verify short token {}: {}",
-                token_without_extended_signature, e
-            );
-            ERR_SIG
-        })?
-        .custom;
+    let msg = if VERIFIED_BODY_TOKENS.get(token_without_extended_signature).is_some_and(|key| key == proxy_public_info.pubkey) {
+        decode_claims_unverified::<M>(token_without_extended_signature)
+            .map_err(|_| ERR_SIG)?
+            .custom
+    } else {
+        let claims = pubkey
+            .verify_token::<M>(
+                token_without_extended_signature,
+                Some(JWT_VERIFICATION_OPTIONS.clone()),
+            )
+            .map_err(|e| {
+                warn!(
+                    "Unable to verify short token {}: {}",
+                    token_without_extended_signature, e
+                );
+                ERR_SIG
+            })?;
+        if outlives_cache(&claims) {
+            VERIFIED_BODY_TOKENS.insert(token_without_extended_signature, proxy_public_info.pubkey.clone());
+        }
+        claims.custom
+    };
 
     let Some((_, sig)) = token_without_extended_signature.rsplit_once('.') else {
         warn!("Cannot split signature from body token");
@@ -212,11 +363,9 @@ pub async fn verify_with_extended_header<M: Msg + DeserializeOwned>(
         return Err(ERR_SIG);
     }
 
-    // Check if Messages' "from" attribute can be signed by the proxy
-    if !msg.get_from().can_be_signed_by(&proxy_public_info.beam_id) {
-        warn!(
-            "Received messages' \"from\" attribute which should not have been signed by the proxy."
-        );
+    // Every endpoint taking a signed message relies on this check, so nobody can act on behalf of another proxy's apps
+    if let Err(e) = check_signer(msg.get_from(), proxy_public_info) {
+        warn!("Rejecting message: {e}");
         return Err(ERR_FROM);
     }
     // TODO: Check if Date header makes sense (replay attacks)
@@ -302,6 +451,34 @@ pub fn make_extra_fields_digest(
     })
 }
 
+/// Entry points for the fuzz targets in `fuzz/`. They verify tokens like [`extract_jwt`] and [`verify_with_extended_header`],
+/// but with the given certificate instead of looking it up.
+#[cfg(feature = "fuzzing")]
+pub mod fuzzing {
+    use super::*;
+
+    pub fn verify_token<M: Msg + DeserializeOwned + Serialize>(token: &str, public: &CryptoPublicPortion) -> Result<MsgSigned<M>, SamplyBeamError> {
+        claimed_signer(token)?;
+        let msg = verify_jwt::<M>(token, public)?.1.custom;
+        Ok(MsgSigned { msg, jwt: token.to_string() })
+    }
+
+    pub fn verify_request<M: Msg + DeserializeOwned>(
+        req: &Parts,
+        token_without_extended_signature: &str,
+        public: &CryptoPublicPortion,
+    ) -> Result<MsgSigned<M>, (StatusCode, &'static str)> {
+        let token_with_extended_signature = req.headers
+            .get(header::AUTHORIZATION)
+            .and_then(|auth| auth.to_str().ok())
+            .ok_or(ERR_SIG)?
+            .trim_start_matches("SamplyJWT ");
+        claimed_signer(token_with_extended_signature).map_err(|_| ERR_SIG)?;
+        let (pubkey, header_claims) = verify_jwt::<HeaderClaim>(token_with_extended_signature, public).map_err(|_| ERR_SIG)?;
+        verify_body_token(req, token_without_extended_signature, public, &pubkey, header_claims.custom)
+    }
+}
+
 async fn get_ip(parts: &mut Parts) -> IpAddr {
     let source_ip = ConnectInfo::<SocketAddr>::from_request_parts(parts, &()).await.expect("The server is configured to keep connect info").0.ip();
     const X_FORWARDED_FOR: HeaderName = HeaderName::from_static("x-forwarded-for");
@@ -312,3 +489,94 @@ async fn get_ip(parts: &mut Parts) -> IpAddr {
         .and_then(|v| v.parse().ok())
         .unwrap_or(source_ip)
 }
+
+#[cfg(test)]
+mod tests {
+    use beam_lib::WorkStatus;
+
+    use super::*;
+    use crate::{test_utils::{app, proxy_crypto, result}, EncryptedMsgTaskResult, MsgEmpty};
+
+    /// A self-signed certificate of the proxy and the key to sign with
+    fn proxy(name: &str) -> (CryptoPublicPortion, RS256KeyPair) {
+        let crypto = proxy_crypto(name);
+        (crypto.public.unwrap(), crypto.privkey_rs256)
+    }
+
+    #[test]
+    fn result_signed_by_wrong_key() {
+        let (worker_proxy, worker_key) = proxy("proxy1");
+        // Its id is a suffix of the worker's proxy id
+        let (forger, forger_key) = proxy("oxy1");
+        let from = app("app1");
+        let result = result(MsgId::new(), &from, &app("app2"), WorkStatus::Succeeded);
+        let sign = |key: &RS256KeyPair| key.sign(Claims::with_custom_claims(serde_json::to_value(&result).unwrap(), Duration::from_hours(1))).unwrap();
+
+        let genuine = sign(&worker_key);
+        let verified = verify_jwt::<EncryptedMsgTaskResult>(&genuine, &worker_proxy).unwrap().1.custom;
+        assert!(check_signer(verified.get_from(), &worker_proxy).is_ok());
+
+        let forged = sign(&forger_key);
+        assert!(verify_jwt::<EncryptedMsgTaskResult>(&forged, &worker_proxy).is_err(), "The forger's key does not match the worker's certificate");
+        let verified = verify_jwt::<EncryptedMsgTaskResult>(&forged, &forger).unwrap().1.custom;
+        assert!(from.can_be_signed_by(&forger.beam_id));
+        assert!(check_signer(verified.get_from(), &forger).is_err(), "The forger's certificate is not the worker's");
+    }
+
+    /// Signs the message like a proxy sending it to the broker and verifies it like the [`MsgSigned`] extractor
+    fn send<M: Msg + Serialize + DeserializeOwned>(method: Method, uri: &str, msg: &M, (public, key): &(CryptoPublicPortion, RS256KeyPair)) -> Result<(), (StatusCode, &'static str)> {
+        let token = key.sign(Claims::with_custom_claims(serde_json::to_value(msg).unwrap(), Duration::from_hours(1))).unwrap();
+        let (parts, ()) = axum::http::Request::builder()
+            .method(method)
+            .uri(uri)
+            .header(header::DATE, "Tue, 01 Sep 2026 12:00:00 GMT")
+            .body(())
+            .unwrap()
+            .into_parts();
+        let (_, sig) = token.rsplit_once('.').unwrap();
+        let header_claims = make_extra_fields_digest(&parts.method, &parts.uri, &parts.headers, sig, msg.get_from()).unwrap();
+        verify_body_token::<M>(&parts, &token, public, &key.public_key(), header_claims).map(|_| ())
+    }
+
+    #[tokio::test]
+    async fn verification_is_measured() {
+        let observations = || metrics::SIGNATURE_VERIFICATION_SECONDS.get_sample_count();
+        let lookups = |cache, result| metrics::VERIFIED_TOKEN_CACHE_LOOKUPS.with_label_values(&[cache, result]).get();
+        let (observed, token_misses) = (observations(), lookups("token", "miss"));
+        let (mut parts, ()) = axum::http::Request::builder().uri("/v1/tasks").body(()).unwrap().into_parts();
+        parts.extensions.insert(ConnectInfo(SocketAddr::from(([127, 0, 0, 1], 0))));
+        assert!(verify_with_extended_header::<MsgEmpty>(&mut parts, "not.a.token").await.is_err());
+        parts.headers.insert(header::AUTHORIZATION, "SamplyJWT not.a.token".parse().unwrap());
+        assert!(verify_with_extended_header::<MsgEmpty>(&mut parts, "not.a.token").await.is_err());
+        assert!(MsgSigned::<MsgEmpty>::verify("not.a.token").await.is_err());
+        // Other tests may verify messages concurrently
+        assert!(observations() >= observed + 3, "Failed verifications are observed as well");
+        assert!(lookups("token", "miss") >= token_misses + 2);
+
+        let signer = proxy("proxy1");
+        let body_misses = lookups("body", "miss");
+        let from = app("app1");
+        assert!(send(Method::GET, "/v1/tasks", &MsgEmpty { from }, &signer).is_ok());
+        assert!(lookups("body", "miss") > body_misses);
+    }
+
+    #[test]
+    fn token_cache() {
+        let cache = TokenCache::new("test", std::time::Duration::from_millis(50), 2);
+        assert_eq!(cache.get("a.b.c"), None);
+        cache.insert("a.b.c", "app1");
+        assert_eq!(cache.get("a.b.c"), Some("app1"));
+        assert_eq!(cache.get("a.b.d"), None);
+
+        // Full caches evict the least recently used token
+        cache.insert("a.b.d", "app2");
+        assert_eq!(cache.get("a.b.c"), Some("app1"));
+        cache.insert("a.b.e", "app3");
+        assert_eq!(cache.len(), 2);
+        assert_eq!(cache.get("a.b.d"), None);
+        assert_eq!((cache.get("a.b.c"), cache.get("a.b.e")), (Some("app1"), Some("app3")));
+
+        std::thread::sleep(std::time::Duration::from_millis(60));
+        assert_eq!(cache.get("a.b.c"), None, "Tokens expire");
+    }
+}
@@ -20,13 +20,15 @@ pub enum SamplyBeamError {
     #[error("Unable to parse JSON: {0}")]
     JsonParseError(String),
     #[error("Decryption error: {0}")]
-    DecryptError(&'static str),
+    DecryptError(#[from] DecryptErrorReason),
     #[error("Signing / encryption failed: {0}")]
     SignEncryptError(String),
     #[error("Samply.PKI error: Vault is still sealed.")]
     VaultSealed,
     #[error("Samply.PKI error: Unable to connect to Vault: {0}")]
     VaultUnreachable(reqwest::Error),
+    #[error("Samply.PKI error: Vault keeps failing, not contacting it until it has recovered.")]
+    VaultCircuitOpen,
     #[error("Samply.PKI error: Vault has not been initialized, yet.")]
     VaultNotInitialized,
     #[error("Samply.PKI error: Vault has asked with code {0} to redirect to {1}; this should not happen.")]
@@ -52,7 +54,13 @@ pub enum SamplyBeamError {
     #[error("Timeout executing HTTP request: {0}")]
     HttpTimeoutError(Elapsed),
     #[error("Invalid receivers: {0:?}")]
-    InvalidReceivers(Vec<ProxyId>)
+    InvalidReceivers(Vec<ProxyId>),
+    #[error("Public keys of receivers do not match their pinned fingerprints: {0:?}")]
+    PinnedKeyMismatch(Vec<ProxyId>),
+    #[error("Overloaded: {0}")]
+    Overloaded(&'static str),
+    #[error("System clock differs from the time reference by {0}s")]
+    ClockSkew(u64),
 }
 
 impl From<AddrParseError> for SamplyBeamError {
@@ -75,6 +83,17 @@ impl From<rsa::errors::Error> for SamplyBeamError {
     }
 }
 
+/// Distinguishes messages not meant for this client from broken ones
+#[derive(thiserror::Error, Debug, Clone, PartialEq)]
+pub enum DecryptErrorReason {
+    #[error("This client cannot be found in the message's recipients")]
+    NotARecipient,
+    #[error("The message's key was not encrypted with this client's public key")]
+    KeyMismatch,
+    #[error("Malformed ciphertext: {0}")]
+    MalformedCiphertext(String),
+}
+
 #[derive(thiserror::Error, Debug, Clone)]
 pub enum CertificateInvalidReason {
     #[error("Cannot find common name in certificate")]
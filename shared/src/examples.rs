@@ -78,6 +78,7 @@ pub fn generate_example_tasks(
         status: beam_lib::WorkStatus::Succeeded,
         body: "All done!".into(),
         metadata: json!("A normal string works, too!"),
+        seq: None,
     };
     let response_by_app2 = MsgTaskResult {
         from: app2.into(),
@@ -86,6 +87,7 @@ pub fn generate_example_tasks(
         status: beam_lib::WorkStatus::PermFailed,
         body: "Unable to complete".into(),
         metadata: json!({ "I": { "like": [ "results", "cake" ] } }),
+        seq: None,
     };
     let mut tasks = Vec::new();
     for task in [task_for_apps_1_2] {
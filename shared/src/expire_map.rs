@@ -43,7 +43,56 @@ impl<K: Hash + Eq, V> LazyExpireMap<K, V> {
         self.map.insert(key, (value, instant.into())).map(|(v, _)| v)
     }
 
+    /// Removes all expired entries
     pub fn retain_expired(&self) {
-        self.map.retain(|_, v| v.1 < Instant::now())
+        let now = Instant::now();
+        self.map.retain(|_, v| v.1 > now)
+    }
+}
+
+impl<K: Hash + Eq + Clone, V> LazyExpireMap<K, V> {
+    /// Makes room for a new entry if the map holds `capacity` or more entries
+    /// by removing expired entries and, if that is not enough, the entry expiring the soonest.
+    pub fn evict_to(&self, capacity: usize) {
+        if self.map.len() < capacity {
+            return;
+        }
+        self.retain_expired();
+        while self.map.len() >= capacity.max(1) {
+            let Some(soonest) = self.map.iter().min_by_key(|entry| entry.1).map(|entry| entry.key().clone()) else {
+                break;
+            };
+            self.map.remove(&soonest);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retain_expired() {
+        let map = LazyExpireMap::default();
+        map.insert_for(Duration::ZERO, 1, ());
+        map.insert_for(Duration::from_secs(60), 2, ());
+        map.retain_expired();
+        assert!(!map.contains_key(&1));
+        assert!(map.get(&2).is_some());
+    }
+
+    #[test]
+    fn evict_to() {
+        let map = LazyExpireMap::default();
+        map.insert_for(Duration::from_secs(60), 1, ());
+        map.insert_for(Duration::from_secs(30), 2, ());
+        map.insert_for(Duration::ZERO, 3, ());
+        map.evict_to(4);
+        assert_eq!(map.len(), 3);
+        map.evict_to(3);
+        assert_eq!(map.len(), 2, "Only the expired entry should be evicted");
+        map.evict_to(2);
+        assert!(map.contains_key(&1));
+        assert!(!map.contains_key(&2), "The entry expiring the soonest should be evicted");
     }
 }
@@ -12,12 +12,39 @@ use crate::{config, errors::SamplyBeamError};
 
 pub type SamplyHttpClient = reqwest::Client;
 
+/// How many idle connections are kept open for reuse and for how long
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ConnectionPool {
+    /// Idle connections are closed after this time, never if `None`
+    pub idle_timeout: Option<Duration>,
+    pub max_idle_per_host: usize,
+}
+
+impl Default for ConnectionPool {
+    /// The defaults of reqwest
+    fn default() -> Self {
+        Self { idle_timeout: Some(Duration::from_secs(90)), max_idle_per_host: usize::MAX }
+    }
+}
+
 pub fn build(
     ca_certificates: &Vec<Certificate>,
     timeout: Option<Duration>,
     keepalive: Option<Duration>,
 ) -> Result<SamplyHttpClient, SamplyBeamError> {
-    let mut builder = Client::builder().tcp_keepalive(keepalive);
+    build_with_pool(ca_certificates, timeout, keepalive, ConnectionPool::default())
+}
+
+pub fn build_with_pool(
+    ca_certificates: &Vec<Certificate>,
+    timeout: Option<Duration>,
+    keepalive: Option<Duration>,
+    pool: ConnectionPool,
+) -> Result<SamplyHttpClient, SamplyBeamError> {
+    let mut builder = Client::builder()
+        .tcp_keepalive(keepalive)
+        .pool_idle_timeout(pool.idle_timeout)
+        .pool_max_idle_per_host(pool.max_idle_per_host);
     if let Some(to) = timeout {
         builder = builder.connect_timeout(to);
     }
@@ -1,13 +1,15 @@
 #![allow(unused_imports)]
 
 use axum::async_trait;
-use beam_lib::{AppId, AppOrProxyId, ProxyId, FailureStrategy, WorkStatus};
+use bytes::Bytes;
+use futures_util::Stream;
+use beam_lib::{AppId, AppOrProxyId, ProxyId, FailureStrategy, CompletionPolicy, WorkStatus};
 use chacha20poly1305::{
     aead::{Aead, AeadCore, KeyInit, OsRng},
     XChaCha20Poly1305, XNonce,
 };
 use crypto_jwt::extract_jwt;
-use errors::SamplyBeamError;
+use errors::{DecryptErrorReason, SamplyBeamError};
 use itertools::Itertools;
 use jwt_simple::prelude::{RS256PublicKey, RSAPublicKeyLike};
 use openssl::base64;
@@ -30,7 +32,7 @@ use serde::{
 use std::{collections::HashMap, str::FromStr};
 use uuid::Uuid;
 
-use crate::{crypto_jwt::JWT_VERIFICATION_OPTIONS, serde_helpers::*};
+use crate::{crypto_jwt::JWT_VERIFICATION_OPTIONS, serde_helpers::*, stream_crypto::StreamNonce};
 // Reexport b64 implementation
 pub use jwt_simple::reexports::ct_codecs;
 pub use reqwest;
@@ -39,11 +41,30 @@ pub type MsgId = beam_lib::MsgId;
 pub type MsgType = String;
 pub type TaskResponse = String;
 
+/// Set on paginated listings, e.g. of socket requests or task searches, if there are more entries after the returned ones.
+/// Its value is meant to be passed as the `after` query parameter to get the next page.
+pub const NEXT_CURSOR_HEADER: axum::http::HeaderName = axum::http::HeaderName::from_static("next-cursor");
+
+/// Content type of results streamed as one JSON object per line, for clients that would rather not parse Server-sent Events
+pub const APPLICATION_NDJSON: &str = "application/x-ndjson";
+
+/// Whether the `Accept` header lists the media type, ignoring its parameters
+pub fn accepts(headers: &axum::http::HeaderMap, media_type: &str) -> bool {
+    headers
+        .get_all(axum::http::header::ACCEPT)
+        .iter()
+        .filter_map(|v| v.to_str().ok())
+        .flat_map(|v| v.split(','))
+        .any(|part| part.split(';').next().unwrap_or_default().trim() == media_type)
+}
+
+pub mod clock;
 pub mod crypto;
 pub mod crypto_jwt;
 pub mod errors;
 pub mod serde_helpers;
 pub mod logger;
+pub mod metrics;
 mod traits;
 #[cfg(test)]
 mod serializing_compatibility_test;
@@ -62,15 +83,22 @@ mod sockets;
 pub use sockets::*;
 // pub mod beam_id;
 pub mod graceful_shutdown;
+pub mod client;
 pub mod http_client;
 pub mod middleware;
+pub mod sharding;
 
 pub mod examples;
 
 pub mod sse_event;
+pub mod stream_crypto;
+
+#[cfg(any(test, feature = "test-utils"))]
+pub mod test_utils;
 
 // Reexports
 pub use openssl;
+pub use ipnet;
 
 
 #[derive(Serialize, Deserialize, Debug, Clone, Copy)]
@@ -86,8 +114,9 @@ pub struct MsgSigned<M: Msg> {
     pub jwt: String,
 }
 
-impl<M: Msg + DeserializeOwned> MsgSigned<M> {
+impl<M: Msg + DeserializeOwned + Send + 'static> MsgSigned<M> {
     pub async fn verify(token: &str) -> Result<Self, SamplyBeamError> {
+        let _timer = metrics::SIGNATURE_VERIFICATION_SECONDS.start_timer();
         let msg = extract_jwt(token).await?.2.custom;
 
         debug!("Message has been verified successfully.");
@@ -227,73 +256,132 @@ pub trait DecryptableMsg: Msg + Serialize + Sized {
     fn get_encryption(&self) -> Option<&Encrypted>;
     fn convert_self(self, body: String) -> Self::Output;
 
-    /// Decrypts an encrypted message. Caution: can panic.
-    #[allow(clippy::or_fun_call)]
+    /// Decrypts an encrypted message
     fn decrypt(
         self,
         my_id: &AppOrProxyId,
         my_priv_key: &RsaPrivateKey,
+    ) -> Result<Self::Output, SamplyBeamError> {
+        self.decrypt_with_any_key(my_id, [my_priv_key])
+    }
+
+    /// Decrypts an encrypted message with the first of the keys that fits, e.g. with the previous key after a key rotation
+    #[allow(clippy::or_fun_call)]
+    fn decrypt_with_any_key<'a>(
+        self,
+        my_id: &AppOrProxyId,
+        my_priv_keys: impl IntoIterator<Item = &'a RsaPrivateKey>,
     ) -> Result<Self::Output, SamplyBeamError> {
         let Some(Encrypted {
             encrypted,
             encryption_keys,
+            streamed,
         }) = self.get_encryption() else {
             // We have something that is not encryptable
             return Ok(self.convert_self(String::new()));
         };
+        if *streamed {
+            return Err(DecryptErrorReason::MalformedCiphertext("Payload is streamed separately".into()).into());
+        }
 
-        let to_array_index: usize = self
-            .get_to()
-            .iter()
-            .position(|entry| {
-                let entry_str = entry.to_string();
-
-                let mut matched = entry_str.ends_with(&my_id.to_string());
-                matched &= match entry_str.find(&my_id.to_string()) {
-                    Some(0) => true,                                      // Begins with id
-                    Some(i) => entry_str.chars().nth(i - 1) == Some('.'), // Ends with id, but before is a separator (e.g. appId)
-                    None => false,
-                };
-                matched
-            }) // TODO remove expect!
-            .ok_or(SamplyBeamError::SignEncryptError(
-                "Decryption error: This client cannot be found in 'to' list".into(),
-            ))?;
-        let encrypted_decryption_key = &encryption_keys[to_array_index];
-
-        // Cryptographic Operations
-        let cipher_engine = XChaCha20Poly1305::new_from_slice(&my_priv_key.decrypt(
-            Oaep::new::<sha2::Sha256>(),
-            &encrypted_decryption_key,
-        )?)
-        .map_err(|e| {
-            SamplyBeamError::SignEncryptError(format!(
-                "Decryption error: Cannot initialize stream cipher because {}",
-                e
-            ))
-        })?;
+        let cipher_engine = content_cipher(self.get_to(), encryption_keys, my_id, my_priv_keys)?;
+        if encrypted.len() < 24 {
+            return Err(DecryptErrorReason::MalformedCiphertext("Payload is too short to contain a nonce".into()).into());
+        }
         let nonce: XNonce = XNonce::clone_from_slice(&encrypted[0..24]);
         let ciphertext = &encrypted[24..];
         let plaintext = String::from_utf8(
             cipher_engine
                 .decrypt(&nonce, ciphertext.as_ref())
                 .map_err(|e| {
-                    SamplyBeamError::SignEncryptError(format!(
-                        "Decryption error: Cannot decrypt payload because {}",
-                        e
-                    ))
+                    DecryptErrorReason::MalformedCiphertext(format!("Cannot decrypt payload because {e}"))
                 })?,
         )
         .map_err(|e| {
-            SamplyBeamError::SignEncryptError(format!(
-                "Decryption error: Invalid UTF8 text in decrypted ciphertext {}",
-                e
-            ))
+            DecryptErrorReason::MalformedCiphertext(format!("Invalid UTF8 text in decrypted ciphertext {e}"))
         })?;
 
         // self.set_body(plaintext);
         Ok(self.convert_self(plaintext))
     }
+
+    /// Decrypts a message encrypted with [`EncryptableMsg::encrypt_streamed`] and returns it with an empty body,
+    /// along with the decrypted body, which is read from `frames` one chunk at a time.
+    fn decrypt_streamed<'a>(
+        self,
+        my_id: &AppOrProxyId,
+        my_priv_keys: impl IntoIterator<Item = &'a RsaPrivateKey>,
+        frames: impl Stream<Item = Result<Bytes, SamplyBeamError>>,
+    ) -> Result<(Self::Output, impl Stream<Item = Result<Bytes, SamplyBeamError>>), SamplyBeamError> {
+        let Some(Encrypted { encrypted, encryption_keys, streamed: true }) = self.get_encryption() else {
+            return Err(DecryptErrorReason::MalformedCiphertext("Payload is not streamed".into()).into());
+        };
+        let cipher = content_cipher(self.get_to(), encryption_keys, my_id, my_priv_keys)?;
+        if encrypted.len() != stream_crypto::NONCE_LEN {
+            return Err(DecryptErrorReason::MalformedCiphertext("Invalid nonce of streamed payload".into()).into());
+        }
+        let body = stream_crypto::decrypt_chunks(cipher, &StreamNonce::clone_from_slice(encrypted), frames);
+        Ok((self.convert_self(String::new()), body))
+    }
+}
+
+/// Decrypts the message's symmetric key that was encrypted for us
+fn content_cipher<'a>(
+    to: &[AppOrProxyId],
+    encryption_keys: &[Vec<u8>],
+    my_id: &AppOrProxyId,
+    my_priv_keys: impl IntoIterator<Item = &'a RsaPrivateKey>,
+) -> Result<XChaCha20Poly1305, SamplyBeamError> {
+    let to_array_index: usize = to
+        .iter()
+        .position(|entry| {
+            let entry_str = entry.to_string();
+
+            let mut matched = entry_str.ends_with(&my_id.to_string());
+            matched &= match entry_str.find(&my_id.to_string()) {
+                Some(0) => true,                                      // Begins with id
+                Some(i) => entry_str.chars().nth(i - 1) == Some('.'), // Ends with id, but before is a separator (e.g. appId)
+                None => false,
+            };
+            matched
+        })
+        .ok_or(DecryptErrorReason::NotARecipient)?;
+    let encrypted_decryption_key = encryption_keys
+        .get(to_array_index)
+        .ok_or_else(|| DecryptErrorReason::MalformedCiphertext("Missing key for this client".into()))?;
+
+    // Cryptographic Operations
+    let decryption_key = my_priv_keys
+        .into_iter()
+        .find_map(|key| key.decrypt(Oaep::new::<sha2::Sha256>(), encrypted_decryption_key).ok())
+        .ok_or(DecryptErrorReason::KeyMismatch)?;
+    Ok(XChaCha20Poly1305::new_from_slice(&decryption_key).map_err(|e| {
+        DecryptErrorReason::MalformedCiphertext(format!("Cannot initialize stream cipher because {e}"))
+    })?)
+}
+
+/// Generates a symmetric key and encrypts it with each of the receivers' public keys
+fn new_content_cipher(receivers_public_keys: &[RsaPublicKey]) -> Result<(XChaCha20Poly1305, Vec<Vec<u8>>), SamplyBeamError> {
+    let mut rng = rand::thread_rng();
+    let symmetric_key = XChaCha20Poly1305::generate_key(&mut rng);
+
+    // Encrypt symmetric key with receivers' public keys
+    let Ok(encrypted_keys) = receivers_public_keys
+        .iter()
+        .map(|key| {
+            key.encrypt(
+                &mut rng,
+                Oaep::new::<sha2::Sha256>(),
+                symmetric_key.as_slice(),
+            )
+        })
+        .collect()
+    else {
+        return Err(SamplyBeamError::SignEncryptError(
+            "Encryption error: Cannot encrypt symmetric key".into(),
+        ));
+    };
+    Ok((XChaCha20Poly1305::new(&symmetric_key), encrypted_keys))
 }
 
 pub trait EncryptableMsg: Msg + Serialize + Sized {
@@ -308,29 +396,8 @@ pub trait EncryptableMsg: Msg + Serialize + Sized {
         receivers_public_keys: &Vec<RsaPublicKey>,
     ) -> Result<Self::Output, SamplyBeamError> {
         // Generate Symmetric Key and Nonce
-        let mut rng = rand::thread_rng();
-        let symmetric_key = XChaCha20Poly1305::generate_key(&mut rng);
-        let nonce = XChaCha20Poly1305::generate_nonce(&mut rng);
-
-        // Encrypt symmetric key with receivers' public keys
-        let Ok(encrypted_keys) = receivers_public_keys
-            .iter()
-            .map(|key| {
-                key.encrypt(
-                    &mut rng,
-                    Oaep::new::<sha2::Sha256>(),
-                    symmetric_key.as_slice(),
-                )
-            })
-            .collect()
-        else {
-            return Err(SamplyBeamError::SignEncryptError(
-                "Encryption error: Cannot encrypt symmetric key".into(),
-            ));
-        };
-
-        // Encrypt fields content
-        let cipher = XChaCha20Poly1305::new(&symmetric_key);
+        let (cipher, encrypted_keys) = new_content_cipher(receivers_public_keys)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut rand::thread_rng());
 
         // I cant believe there is no better way
         let default = String::new();
@@ -347,8 +414,31 @@ pub trait EncryptableMsg: Msg + Serialize + Sized {
         Ok(self.convert_self(Encrypted {
             encrypted: nonce_and_ciphertext,
             encryption_keys: encrypted_keys,
+            streamed: false,
         }))
     }
+
+    /// Encrypts a message whose body is too large to be held in memory and is read from `body` instead.
+    /// The message keeps only the nonce in place of its ciphertext; the returned frames of the encrypted body are to be sent along with it.
+    fn encrypt_streamed(
+        self,
+        receivers_public_keys: &[RsaPublicKey],
+        body: impl Stream<Item = Result<Bytes, SamplyBeamError>>,
+    ) -> Result<(Self::Output, impl Stream<Item = Result<Bytes, SamplyBeamError>>), SamplyBeamError> {
+        if self.get_plain().body.is_some() {
+            return Err(SamplyBeamError::SignEncryptError("Encryption error: Message with a streamed body has a body of its own".into()));
+        }
+        let (cipher, encrypted_keys) = new_content_cipher(receivers_public_keys)?;
+        let mut nonce = StreamNonce::default();
+        rand::thread_rng().fill(nonce.as_mut_slice());
+        let frames = stream_crypto::encrypt_chunks(cipher, &nonce, body);
+        let msg = self.convert_self(Encrypted {
+            encrypted: nonce.to_vec(),
+            encryption_keys: encrypted_keys,
+            streamed: true,
+        });
+        Ok((msg, frames))
+    }
 }
 
 pub trait Msg: Serialize {
@@ -412,6 +502,9 @@ pub struct Encrypted {
     pub encrypted: Vec<u8>,
     #[serde(with = "serde_base64::nested" )]
     pub encryption_keys: Vec<Vec<u8>>,
+    /// The body is sent separately in encrypted chunks and `encrypted` only holds their nonce
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub streamed: bool,
 }
 
 impl Debug for Encrypted {
@@ -419,6 +512,7 @@ impl Debug for Encrypted {
         f.debug_struct("Encrypted")
             .field("encrypted len", &self.encrypted.len())
             .field("encryption_key_count", &self.encryption_keys.len())
+            .field("streamed", &self.streamed)
             .finish()
     }
 }
@@ -468,6 +562,14 @@ where
     #[serde(with = "serialize_time", rename = "ttl")]
     pub expire: SystemTime,
     pub failure_strategy: FailureStrategy,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub completion_policy: Option<CompletionPolicy>,
+    /// Results submitted after this point in time are rejected, even if the task has not expired yet
+    #[serde(default, with = "serialize_deadline", skip_serializing_if = "Option::is_none")]
+    pub deadline: Option<SystemTime>,
+    /// Earlier attempt of the same logical task that this task retries, e.g. because it failed permanently
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub parent_task: Option<MsgId>,
     #[serde(skip)]
     pub results: HashMap<AppOrProxyId, MsgSigned<MsgTaskResult<State>>>,
     pub metadata: Value,
@@ -484,6 +586,9 @@ impl EncryptableMsg for MsgTaskRequest {
             to,
             expire,
             failure_strategy,
+            completion_policy,
+            deadline,
+            parent_task,
             metadata,
             ..
         } = self;
@@ -494,6 +599,9 @@ impl EncryptableMsg for MsgTaskRequest {
             to,
             expire,
             failure_strategy,
+            completion_policy,
+            deadline,
+            parent_task,
             metadata,
             results: Default::default(),
         }
@@ -514,6 +622,9 @@ impl DecryptableMsg for MsgTaskRequest<Encrypted> {
             to,
             expire,
             failure_strategy,
+            completion_policy,
+            deadline,
+            parent_task,
             metadata,
             ..
         } = self;
@@ -524,6 +635,9 @@ impl DecryptableMsg for MsgTaskRequest<Encrypted> {
             to,
             expire,
             failure_strategy,
+            completion_policy,
+            deadline,
+            parent_task,
             metadata,
             results: Default::default(),
         }
@@ -549,6 +663,9 @@ where
     #[serde(flatten)]
     pub body: State,
     pub metadata: Value,
+    /// Increasing number of the sender's updates to this result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub seq: Option<u64>,
 }
 
 impl DecryptableMsg for MsgTaskResult<Encrypted> {
@@ -561,6 +678,7 @@ impl DecryptableMsg for MsgTaskResult<Encrypted> {
             task,
             status,
             metadata,
+            seq,
             ..
         } = self;
         Self::Output {
@@ -570,6 +688,7 @@ impl DecryptableMsg for MsgTaskResult<Encrypted> {
             task,
             status,
             metadata,
+            seq,
         }
     }
 
@@ -592,6 +711,7 @@ impl EncryptableMsg for MsgTaskResult<Plain> {
             task,
             status,
             metadata,
+            seq,
             ..
         } = self;
         Self::Output {
@@ -601,6 +721,7 @@ impl EncryptableMsg for MsgTaskResult<Plain> {
             task,
             status,
             metadata,
+            seq,
         }
     }
 }
@@ -647,8 +768,27 @@ impl<T: MsgState> MsgTaskRequest<T> {
     pub fn id(&self) -> &MsgId {
         &self.id
     }
+
+    /// Whether the task is complete according to its completion policy.
+    /// Tasks without recipients expect no results and are complete right away, other tasks without a completion policy are never complete.
+    pub fn is_complete(&self) -> bool {
+        if self.to.is_empty() {
+            return true;
+        }
+        let succeeded = |to: &AppOrProxyId| self.results
+            .get(to)
+            .is_some_and(|res| res.msg.status == WorkStatus::Succeeded);
+        match self.completion_policy {
+            None => false,
+            Some(CompletionPolicy::All) => self.to.iter().all(succeeded),
+            Some(CompletionPolicy::Any) => self.to.iter().any(succeeded),
+        }
+    }
 }
 impl MsgTaskRequest {
+    /// Names of the fields of a plain task in JSON. Needed to detect unknown fields, as `deny_unknown_fields` does not work with the flattened body
+    pub const FIELDS: &'static [&'static str] = &["id", "from", "to", "body", "ttl", "failure_strategy", "completion_policy", "deadline", "parent_task", "metadata"];
+
     pub fn new(
         from: AppOrProxyId,
         to: Vec<AppOrProxyId>,
@@ -662,6 +802,9 @@ impl MsgTaskRequest {
             to,
             body: body.into(),
             failure_strategy,
+            completion_policy: None,
+            deadline: None,
+            parent_task: None,
             results: HashMap::new(),
             metadata,
             expire: SystemTime::now() + Duration::from_secs(3600),
@@ -678,6 +821,9 @@ impl<T: MsgState> PartialEq for MsgTaskRequest<T> {
             && self.to == other.to
             && self.body == other.body
             && self.failure_strategy == other.failure_strategy
+            && self.completion_policy == other.completion_policy
+            && self.deadline == other.deadline
+            && self.parent_task == other.parent_task
             && self.results == other.results
             && self.metadata == other.metadata
     }
@@ -736,11 +882,12 @@ where
 mod tests {
 
     use super::*;
+    use crate::test_utils::{app, app_id, init_broker_id};
 
     #[test]
     fn encrypt_decrypt_task() {
         //Create Task
-        beam_lib::set_broker_id("broker.samply.de".to_string());
+        init_broker_id();
         let p1_id = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
         let p2_id = AppOrProxyId::App(AppId::new("app.proxy2.broker.samply.de").unwrap());
         let from = p1_id.clone();
@@ -754,6 +901,9 @@ mod tests {
             body: "Testbody".into(),
             expire: expiry,
             failure_strategy: failure,
+            completion_policy: None,
+            deadline: None,
+            parent_task: None,
             results: HashMap::new(),
             metadata: "".into(),
         };
@@ -789,7 +939,7 @@ mod tests {
 
     #[test]
     fn encrypt_decrypt_result() {
-        beam_lib::set_broker_id("broker.samply.de".to_string());
+        init_broker_id();
         let p1_id = AppOrProxyId::App(AppId::new("app.proxy1.broker.samply.de").unwrap());
         let p2_id = AppOrProxyId::App(AppId::new("app.proxy2.broker.samply.de").unwrap());
         let from = p1_id.clone();
@@ -802,6 +952,7 @@ mod tests {
             status,
             body: "The result is 55!".into(),
             metadata: "".into(),
+            seq: None,
         };
 
         //Setup Keypairs
@@ -833,4 +984,93 @@ mod tests {
         assert_eq!(msg_p1_decr, msg_p2_decr);
         assert_eq!(msg, msg_p1_decr);
     }
+
+    #[test]
+    fn decrypt_errors() {
+        let (p1_id, p2_id) = (app("app"), AppOrProxyId::from(app_id("app", "proxy2")));
+        let msg = MsgTaskResult {
+            from: p1_id.clone(),
+            to: vec![p1_id.clone()],
+            task: MsgId::new(),
+            status: WorkStatus::Succeeded,
+            body: "The result is 55!".into(),
+            metadata: "".into(),
+            seq: None,
+        };
+        let mut rng = rand::thread_rng();
+        let p1_private = RsaPrivateKey::new(&mut rng, 2048).expect("Failed to generate private key for proxy 1");
+        let p2_private = RsaPrivateKey::new(&mut rng, 2048).expect("Failed to generate private key for proxy 2");
+        let msg_encr = msg.encrypt(&vec![RsaPublicKey::from(&p1_private)]).expect("Could not encrypt message");
+
+        let reason = |res: Result<MsgTaskResult, SamplyBeamError>| match res {
+            Err(SamplyBeamError::DecryptError(reason)) => reason,
+            other => panic!("Expected a decryption error, got {other:?}"),
+        };
+        assert_eq!(reason(msg_encr.clone().decrypt(&p2_id, &p2_private)), DecryptErrorReason::NotARecipient);
+        assert_eq!(reason(msg_encr.clone().decrypt(&p1_id, &p2_private)), DecryptErrorReason::KeyMismatch);
+        let mut truncated = msg_encr.clone();
+        truncated.body.encrypted.truncate(10);
+        assert!(matches!(reason(truncated.decrypt(&p1_id, &p1_private)), DecryptErrorReason::MalformedCiphertext(_)));
+        let mut tampered = msg_encr;
+        *tampered.body.encrypted.last_mut().unwrap() ^= 1;
+        assert!(matches!(reason(tampered.decrypt(&p1_id, &p1_private)), DecryptErrorReason::MalformedCiphertext(_)));
+    }
+
+    #[tokio::test]
+    async fn encrypt_decrypt_streamed_result() {
+        use futures_util::{stream, StreamExt, TryStreamExt};
+        use stream_crypto::CHUNK_SIZE;
+
+        let (p1_id, p2_id) = (app("app"), AppOrProxyId::from(app_id("app", "proxy2")));
+        let msg = MsgTaskResult {
+            from: p1_id.clone(),
+            to: vec![p1_id.clone(), p2_id.clone()],
+            task: MsgId::new(),
+            status: WorkStatus::Succeeded,
+            body: Plain { body: None },
+            metadata: "".into(),
+            seq: None,
+        };
+        let mut rng = rand::thread_rng();
+        let p1_private = RsaPrivateKey::new(&mut rng, 2048).expect("Failed to generate private key for proxy 1");
+        let p2_private = RsaPrivateKey::new(&mut rng, 2048).expect("Failed to generate private key for proxy 2");
+        let keys = [RsaPublicKey::from(&p1_private), RsaPublicKey::from(&p2_private)];
+        // 16 MiB made up on the fly, each MiB filled with its index
+        const MIB: usize = 1 << 20;
+        let body = || stream::iter((0..16u8).map(|i| Ok(Bytes::from(vec![i; MIB]))));
+
+        let (msg_encr, frames) = msg.clone().encrypt_streamed(&keys, body()).expect("Could not encrypt message");
+        assert!(msg_encr.body.encrypted.len() < 32, "The body is not part of the message");
+        // Frames are passed on as they are encrypted, so no more than a chunk is held at a time
+        let frames = frames.inspect_ok(|frame| assert!(frame.len() <= CHUNK_SIZE + 20));
+        let (msg_decr, plain) = msg_encr.clone().decrypt_streamed(&p2_id, [&p2_private], frames).expect("Cannot decrypt message");
+        assert_eq!(msg_decr.body, Plain::from(""));
+        let len = plain
+            .try_fold(0, |offset, chunk| async move {
+                assert!(chunk.len() <= CHUNK_SIZE);
+                for (i, byte) in chunk.iter().enumerate() {
+                    assert_eq!(*byte as usize, (offset + i) / MIB);
+                }
+                Ok(offset + chunk.len())
+            })
+            .await
+            .expect("Cannot decrypt body");
+        assert_eq!(len, 16 * MIB);
+
+        // Inline decryption and decryption of tampered or cut off bodies fail
+        assert!(msg_encr.clone().decrypt(&p1_id, &p1_private).is_err());
+        let (encr, frames) = msg.encrypt_streamed(&keys[..1], body().take(1)).unwrap();
+        let mut frames: Vec<Bytes> = frames.try_collect().await.unwrap();
+        let decrypt = |frames: Vec<Bytes>| {
+            let (_, plain) = encr.clone().decrypt_streamed(&p1_id, [&p1_private], stream::iter(frames.into_iter().map(Ok))).unwrap();
+            plain.try_collect::<Vec<_>>()
+        };
+        let mut tampered = frames.clone();
+        let mut frame = tampered[3].to_vec();
+        frame[100] ^= 1;
+        tampered[3] = frame.into();
+        assert!(decrypt(tampered).await.is_err());
+        frames.pop();
+        assert!(decrypt(frames).await.is_err());
+    }
 }
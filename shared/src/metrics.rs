@@ -0,0 +1,21 @@
+use once_cell::sync::Lazy;
+use prometheus::{exponential_buckets, register_histogram, register_int_counter_vec, Histogram, IntCounterVec};
+
+/// Starts at 100µs as most verifications are answered from a cache
+pub static SIGNATURE_VERIFICATION_SECONDS: Lazy<Histogram> = Lazy::new(|| {
+    register_histogram!(
+        "beam_signature_verification_seconds",
+        "Time taken to verify a signed message, including certificate lookups and failed verifications",
+        exponential_buckets(0.0001, 2.0, 15).expect("Buckets are valid")
+    )
+    .expect("Metric is only registered once")
+});
+
+pub static VERIFIED_TOKEN_CACHE_LOOKUPS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "beam_verified_token_cache_lookups_total",
+        "Number of lookups in the caches of recently verified tokens by cache and whether the token was found",
+        &["cache", "result"]
+    )
+    .expect("Metric is only registered once")
+});
@@ -1,9 +1,13 @@
+use std::{sync::Arc, time::Duration};
+
 use axum::{
-    extract::Request,
-    http::StatusCode,
+    extract::{Request, State},
+    http::{StatusCode, Uri},
     middleware::Next,
-    response::Response,
+    response::{IntoResponse, Response},
+    Json, Router,
 };
+use serde_json::json;
 use tracing::{info, warn, info_span, field, Instrument, Span};
 
 pub async fn log(
@@ -26,3 +30,98 @@ pub async fn log(
         resp
     }.instrument(span).await
 }
+
+/// Answers requests with 408 Request Timeout if the router takes longer than `timeout` to respond, e.g. because of a stalled upload.
+/// Only requests changing something are limited, as GET requests may long poll or stream on purpose.
+pub fn with_request_timeout(router: Router, timeout: Option<Duration>) -> Router {
+    match timeout {
+        Some(timeout) => router.layer(axum::middleware::from_fn_with_state(timeout, request_timeout)),
+        None => router,
+    }
+}
+
+async fn request_timeout(State(timeout): State<Duration>, req: Request, next: Next) -> Response {
+    if req.method().is_safe() {
+        return next.run(req).await;
+    }
+    let (method, uri) = (req.method().clone(), req.uri().clone());
+    match tokio::time::timeout(timeout, next.run(req)).await {
+        Ok(res) => res,
+        Err(_) => {
+            warn!("{method} {uri} took longer than {timeout:?}");
+            StatusCode::REQUEST_TIMEOUT.into_response()
+        }
+    }
+}
+
+/// Answers requests to unknown paths with a JSON error naming this server and the routes it serves, e.g. `/v1/tasks`,
+/// instead of an empty 404, so integrators can tell that they got the base URL wrong.
+/// Routes meant for operators only should not be listed, and servers only reachable by operators may list no routes at all.
+/// Has to be added after all routers are merged, as merged routers can only have one fallback.
+pub fn with_not_found_fallback(router: Router, server: &'static str, routes: Vec<&'static str>) -> Router {
+    let routes: Arc<[&'static str]> = routes.into();
+    router.fallback(move |uri: Uri| async move {
+        let mut body = json!({
+            "error": format!("No route for {}", uri.path()),
+            "server": server,
+        });
+        if !routes.is_empty() {
+            body["routes"] = json!(*routes);
+        }
+        (StatusCode::NOT_FOUND, Json(body))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use axum::{http::Method, routing::get};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    async fn status(method: Method, timeout: Option<Duration>) -> StatusCode {
+        let slow = || async {
+            tokio::time::sleep(Duration::from_millis(200)).await;
+            "done"
+        };
+        let app = with_request_timeout(Router::new().route("/v1/tasks", get(slow).post(slow)), timeout);
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        reqwest::Client::new()
+            .request(method, format!("http://{addr}/v1/tasks"))
+            .send()
+            .await
+            .unwrap()
+            .status()
+    }
+
+    #[tokio::test]
+    async fn timeout_only_mutating_requests() {
+        let timeout = Some(Duration::from_millis(50));
+        assert_eq!(status(Method::POST, timeout).await, StatusCode::REQUEST_TIMEOUT);
+        assert_eq!(status(Method::GET, timeout).await, StatusCode::OK, "Long polls are not limited");
+        assert_eq!(status(Method::POST, None).await, StatusCode::OK);
+    }
+
+    async fn get_json(app: Router, path: &str) -> (StatusCode, serde_json::Value) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let addr = listener.local_addr().unwrap();
+        tokio::spawn(async move { axum::serve(listener, app).await });
+        let res = reqwest::get(format!("http://{addr}{path}")).await.unwrap();
+        (res.status(), res.json().await.unwrap_or_default())
+    }
+
+    #[tokio::test]
+    async fn unknown_routes() {
+        let app = || Router::new().route("/v1/tasks", get(|| async { "tasks" }));
+        let (status, body) = get_json(with_not_found_fallback(app(), "Samply.Beam.Broker/0.8.0", vec!["/v1/tasks"]), "/beam/v1/tasks?filter=todo").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert_eq!(body, json!({"error": "No route for /beam/v1/tasks", "server": "Samply.Beam.Broker/0.8.0", "routes": ["/v1/tasks"]}));
+        assert_eq!(get_json(with_not_found_fallback(app(), "Samply.Beam.Broker/0.8.0", vec!["/v1/tasks"]), "/v1/tasks").await.0, StatusCode::OK);
+
+        let (status, body) = get_json(with_not_found_fallback(app(), "Samply.Beam.Broker/0.8.0", Vec::new()), "/v1/admin").await;
+        assert_eq!(status, StatusCode::NOT_FOUND);
+        assert!(body.get("routes").is_none(), "Servers for operators don't list their routes");
+    }
+}
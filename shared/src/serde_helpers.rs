@@ -1,7 +1,9 @@
-use std::ops::Deref;
+use std::ops::{Deref, Range};
 
-use axum::{http::{header, HeaderValue, StatusCode}, response::{IntoResponse, Response}};
-use bytes::BufMut;
+use axum::{body::Body, http::{header, HeaderMap, HeaderValue, StatusCode}, response::{IntoResponse, Response}};
+use bytes::{BufMut, Bytes};
+use sha2::{Digest, Sha256};
+use tracing::warn;
 use serde::{Serialize, Serializer, ser::SerializeSeq};
 
 
@@ -38,6 +40,32 @@ pub mod serialize_time {
     }
 }
 
+/// Points in time given as RFC 3339 timestamps, e.g. `2024-01-01T12:00:00Z`, unlike relative TTLs they stay the same when a message is passed on
+pub mod serialize_deadline {
+    use std::time::SystemTime;
+
+    use serde::{self, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(time: &Option<SystemTime>, s: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match time {
+            Some(time) => s.serialize_str(&humantime::format_rfc3339_millis(*time).to_string()),
+            None => s.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<SystemTime>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Option::<String>::deserialize(deserializer)?
+            .map(|time| humantime::parse_rfc3339_weak(&time).map_err(serde::de::Error::custom))
+            .transpose()
+    }
+}
+
 // https://github.com/serde-rs/json/issues/360#issuecomment-330095360
 pub mod serde_base64 {
     use serde::{Serializer, de, ser, Deserialize, Deserializer};
@@ -109,6 +137,59 @@ impl DerefSerializer {
     }
 }
 
+/// Answers a single satisfiable `Range` request for a JSON `body` with `206 Partial Content` and the requested bytes, so clients can resume an interrupted download.
+/// The body's hash serves as `ETag`. A range is only served if the `If-Range` header, if any, names it, as the elements may have changed since the download started.
+/// Other requests are answered with the whole body and `200 OK`.
+pub fn ranged_response(body: Bytes, request_headers: &HeaderMap) -> Response {
+    let digest = Sha256::digest(&body);
+    let etag = format!("\"{}\"", digest[..16].iter().map(|byte| format!("{byte:02x}")).collect::<String>());
+    let unchanged = request_headers.get(header::IF_RANGE).is_none_or(|if_range| if_range.as_bytes() == etag.as_bytes());
+    let range = request_headers
+        .get(header::RANGE)
+        .filter(|_| unchanged)
+        .and_then(|range| range.to_str().ok())
+        .and_then(|range| parse_byte_range(range, body.len()));
+    let len = body.len();
+    let json = (header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+    let mut resp = match range {
+        None => ([json], body).into_response(),
+        Some(None) => (
+            StatusCode::RANGE_NOT_SATISFIABLE,
+            [(header::CONTENT_RANGE, format!("bytes */{len}"))],
+        ).into_response(),
+        Some(Some(range)) => (
+            StatusCode::PARTIAL_CONTENT,
+            [json],
+            [(header::CONTENT_RANGE, format!("bytes {}-{}/{len}", range.start, range.end - 1))],
+            body.slice(range),
+        ).into_response(),
+    };
+    resp.headers_mut().insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    resp.headers_mut().insert(header::ETAG, HeaderValue::from_str(&etag).expect("Hex digits are a valid header value"));
+    resp
+}
+
+/// Parses a single byte range like `bytes=0-499`, `bytes=500-` or the suffix `bytes=-500` of a body of `len` bytes.
+/// Returns `None` for headers to ignore, like invalid ones or several ranges, and `Some(None)` for ranges outside of the body.
+fn parse_byte_range(value: &str, len: usize) -> Option<Option<Range<usize>>> {
+    let (start, end) = value.trim().strip_prefix("bytes=")?.split_once('-')?;
+    if end.contains(',') {
+        return None;
+    }
+    let range = match (start.trim(), end.trim()) {
+        ("", suffix) => len.saturating_sub(suffix.parse().ok()?)..len,
+        (start, "") => start.parse().ok()?..len,
+        (start, end) => {
+            let (start, end): (usize, usize) = (start.parse().ok()?, end.parse().ok()?);
+            if end < start {
+                return None;
+            }
+            start..end.saturating_add(1).min(len)
+        }
+    };
+    Some((range.start < range.end).then_some(range))
+}
+
 impl IntoResponse for DerefSerializer {
     fn into_response(self) -> Response {
         let Self { buffer, read_expected } = self;
@@ -122,3 +203,177 @@ impl IntoResponse for DerefSerializer {
         resp
     }
 }
+
+/// Streams a JSON array into the response body, serializing the elements only as the body is sent so large listings are never held in memory as a whole.
+/// The elements are looked up by their keys while streaming, so they are not borrowed for longer than it takes to serialize them.
+pub struct JsonArrayStream {
+    pub read_expected: bool,
+    body: Body,
+}
+
+/// Chunks of a [`JsonArrayStream`] are passed to the body once they reach this size
+const JSON_CHUNK_SIZE: usize = 64 * 1024;
+
+impl JsonArrayStream {
+    /// `write` serializes the element with the given key into the buffer and returns `false` if it no longer exists.
+    /// Whether the expected number of elements was read is decided by the number of keys, as elements rarely vanish in between.
+    pub fn new<K, F>(keys: Vec<K>, expected_len: Option<u16>, write: F) -> Self
+    where
+        K: Send + 'static,
+        F: FnMut(K, &mut Vec<u8>) -> Result<bool, serde_json::Error> + Send + 'static,
+    {
+        let read_expected = keys.len() >= expected_len.map(usize::from).unwrap_or(0);
+        let chunks = JsonArrayChunks { keys: keys.into_iter(), write, started: false, finished: false };
+        Self { read_expected, body: Body::from_stream(futures_util::stream::iter(chunks)) }
+    }
+
+    /// Like [`Self::new`], but serializes each chunk on a thread for blocking operations, for elements that `write` reads from disk
+    pub fn new_blocking<K, F>(keys: Vec<K>, expected_len: Option<u16>, write: F) -> Self
+    where
+        K: Send + 'static,
+        F: FnMut(K, &mut Vec<u8>) -> Result<bool, serde_json::Error> + Send + 'static,
+    {
+        let read_expected = keys.len() >= expected_len.map(usize::from).unwrap_or(0);
+        let chunks = JsonArrayChunks { keys: keys.into_iter(), write, started: false, finished: false };
+        let body = futures_util::stream::unfold(Some(chunks), |chunks| async move {
+            let mut chunks = chunks?;
+            match tokio::task::spawn_blocking(move || (chunks.next(), chunks)).await {
+                Ok((chunk, chunks)) => Some((chunk?, Some(chunks))),
+                Err(e) => Some((Err(serde::ser::Error::custom(format!("Serializing JSON array failed: {e}"))), None)),
+            }
+        });
+        Self { read_expected, body: Body::from_stream(body) }
+    }
+}
+
+struct JsonArrayChunks<I, F> {
+    keys: I,
+    write: F,
+    started: bool,
+    finished: bool,
+}
+
+impl<K, I, F> Iterator for JsonArrayChunks<I, F>
+where
+    I: Iterator<Item = K>,
+    F: FnMut(K, &mut Vec<u8>) -> Result<bool, serde_json::Error>,
+{
+    type Item = Result<Bytes, serde_json::Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let mut chunk = Vec::new();
+        if !self.started {
+            chunk.push(b'[');
+        }
+        while chunk.len() < JSON_CHUNK_SIZE {
+            let Some(key) = self.keys.next() else {
+                chunk.push(b']');
+                self.finished = true;
+                break;
+            };
+            let start = chunk.len();
+            if self.started {
+                chunk.push(b',');
+            }
+            match (self.write)(key, &mut chunk) {
+                Ok(true) => self.started = true,
+                Ok(false) => chunk.truncate(start),
+                Err(e) => {
+                    // The status has already been sent, so all we can do is to abort the body
+                    warn!("Failed to serialize element of JSON array: {e}");
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+        Some(Ok(chunk.into()))
+    }
+}
+
+impl IntoResponse for JsonArrayStream {
+    fn into_response(self) -> Response {
+        let Self { body, read_expected } = self;
+        let status = if read_expected {
+            StatusCode::OK
+        } else {
+            StatusCode::PARTIAL_CONTENT
+        };
+        (status, [(header::CONTENT_TYPE, HeaderValue::from_static("application/json"))], body).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn byte_ranges() {
+        assert_eq!(parse_byte_range("bytes=0-9", 100), Some(Some(0..10)));
+        assert_eq!(parse_byte_range("bytes=90-", 100), Some(Some(90..100)));
+        assert_eq!(parse_byte_range("bytes=-10", 100), Some(Some(90..100)));
+        assert_eq!(parse_byte_range("bytes=-1000", 100), Some(Some(0..100)));
+        assert_eq!(parse_byte_range("bytes=50-1000", 100), Some(Some(50..100)));
+        assert_eq!(parse_byte_range("bytes=100-", 100), Some(None));
+        assert_eq!(parse_byte_range("bytes=-0", 100), Some(None));
+        assert_eq!(parse_byte_range("bytes=0-9, 20-29", 100), None, "Several ranges are answered with the whole body");
+        assert_eq!(parse_byte_range("bytes=9-0", 100), None);
+        assert_eq!(parse_byte_range("items=0-9", 100), None);
+    }
+
+    #[tokio::test]
+    async fn ranges_resume_downloads() {
+        let body = Bytes::from_static(br#"[{"from":"app2"},{"from":"app3"}]"#);
+        let len = body.len();
+        let request = |headers: &[(header::HeaderName, &str)]| {
+            ranged_response(body.clone(), &HeaderMap::from_iter(headers.iter().map(|(name, value)| (name.clone(), HeaderValue::from_str(value).unwrap()))))
+        };
+        let bytes = |res: Response| async { axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap() };
+
+        let whole = request(&[]);
+        assert_eq!(whole.status(), StatusCode::OK);
+        assert_eq!(whole.headers()[header::ACCEPT_RANGES], "bytes");
+        let etag = whole.headers()[header::ETAG].to_str().unwrap().to_string();
+        assert_eq!(bytes(whole).await, body);
+
+        let rest = request(&[(header::RANGE, "bytes=10-"), (header::IF_RANGE, &etag)]);
+        assert_eq!(rest.status(), StatusCode::PARTIAL_CONTENT);
+        assert_eq!(rest.headers()[header::CONTENT_RANGE], format!("bytes 10-{}/{len}", len - 1));
+        assert_eq!(rest.headers()[header::ETAG], etag.as_str());
+        assert_eq!(bytes(rest).await, body.slice(10..));
+        let beyond = request(&[(header::RANGE, &format!("bytes={len}-"))]);
+        assert_eq!(beyond.status(), StatusCode::RANGE_NOT_SATISFIABLE);
+        assert_eq!(beyond.headers()[header::CONTENT_RANGE], format!("bytes */{len}"));
+        // The body changed since the download started, so the client gets all of it again
+        let changed = request(&[(header::RANGE, "bytes=10-"), (header::IF_RANGE, "\"0123\"")]);
+        assert_eq!(changed.status(), StatusCode::OK);
+        assert_eq!(bytes(changed).await, body);
+    }
+
+    #[tokio::test]
+    async fn json_array_stream() {
+        async fn stream(keys: Vec<usize>, expected: Option<u16>) -> (StatusCode, Vec<String>) {
+            // Odd keys stand for elements removed in the meantime
+            let write = |key: usize, buf: &mut Vec<u8>| {
+                (key % 2 == 0).then(|| serde_json::to_writer(buf, &"x".repeat(key))).transpose().map(|written| written.is_some())
+            };
+            let res = JsonArrayStream::new(keys.clone(), expected, write).into_response();
+            let blocking = JsonArrayStream::new_blocking(keys, expected, write).into_response();
+            assert_eq!(res.status(), blocking.status());
+            let blocking = axum::body::to_bytes(blocking.into_body(), usize::MAX).await.unwrap();
+            let status = res.status();
+            let body = axum::body::to_bytes(res.into_body(), usize::MAX).await.unwrap();
+            assert_eq!(body, blocking);
+            (status, serde_json::from_slice(&body).unwrap())
+        }
+        assert_eq!(stream(vec![], None).await, (StatusCode::OK, vec![]));
+        assert_eq!(stream(vec![1, 2, 3, 4], Some(3)).await, (StatusCode::OK, vec!["xx".to_string(), "xxxx".to_string()]));
+        assert_eq!(stream(vec![1, 2], Some(3)).await.0, StatusCode::PARTIAL_CONTENT);
+        // Spans several chunks
+        let keys: Vec<usize> = (0..100).map(|i| i * 2_000).collect();
+        let (_, elements) = stream(keys.clone(), None).await;
+        assert_eq!(elements.iter().map(String::len).collect::<Vec<_>>(), keys);
+    }
+}
@@ -3,11 +3,11 @@ use std::{
     time::{Duration, SystemTime},
 };
 
-use beam_lib::{set_broker_id, AppOrProxyId, MsgId};
+use beam_lib::{AppOrProxyId, MsgId};
 use serde::{de::DeserializeOwned, Serialize};
 use serde_json::json;
 
-use crate::Plain;
+use crate::{test_utils::init_broker_id, Plain};
 
 fn assert_json_eq<A, B>(a: A, b: B)
 where
@@ -23,7 +23,7 @@ where
 
 #[test]
 fn test_msg_empty() {
-    set_broker_id("broker.samply.de".to_string());
+    init_broker_id();
     let internal = crate::MsgEmpty {
         from: AppOrProxyId::new("app1.proxy1.broker.samply.de").unwrap(),
     };
@@ -35,12 +35,12 @@ fn test_msg_empty() {
 
 #[test]
 fn test_msg_task() {
-    set_broker_id("broker.samply.de".to_string());
+    init_broker_id();
     let json_data = json!({
         "foo": 1,
         "bar": true,
     });
-    let id = MsgId::new();
+    let (id, parent) = (MsgId::new(), MsgId::new());
     let internal = crate::MsgTaskRequest {
         from: AppOrProxyId::new("app1.proxy1.broker.samply.de").unwrap(),
         to: vec![],
@@ -51,27 +51,33 @@ fn test_msg_task() {
             backoff_millisecs: 100,
             max_tries: 10,
         },
+        completion_policy: Some(crate::CompletionPolicy::Any),
+        deadline: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(2_000_000_000)),
+        parent_task: Some(parent),
         results: Default::default(),
         metadata: json_data.clone(),
     };
-    let lib = beam_lib::TaskRequest {
-        from: AppOrProxyId::new("app1.proxy1.broker.samply.de").unwrap(),
+    let mut lib = beam_lib::TaskRequest::new(
         id,
-        to: vec![],
-        body: json_data.clone(),
-        ttl: "9".to_string(),
-        failure_strategy: beam_lib::FailureStrategy::Retry {
+        AppOrProxyId::new("app1.proxy1.broker.samply.de").unwrap(),
+        vec![],
+        json_data.clone(),
+        "9".to_string(),
+        beam_lib::FailureStrategy::Retry {
             backoff_millisecs: 100,
             max_tries: 10,
         },
-        metadata: json_data,
-    };
+        json_data,
+    );
+    lib.completion_policy = Some(beam_lib::CompletionPolicy::Any);
+    lib.deadline = Some("2033-05-18T03:33:20.000Z".to_string());
+    lib.parent_task = Some(parent);
     assert_json_eq(lib, internal);
 }
 
 #[test]
 fn test_task_result() {
-    set_broker_id("broker.samply.de".to_string());
+    init_broker_id();
     let json_data = json!({
         "foo": 1,
         "bar": true,
@@ -85,23 +91,18 @@ fn test_task_result() {
         metadata: json_data.clone(),
         task,
         status: crate::WorkStatus::Succeeded,
+        seq: Some(3),
     };
-    let lib = beam_lib::TaskResult {
-        from,
-        to: vec![],
-        task,
-        status: beam_lib::WorkStatus::Succeeded,
-        body: json_data.clone(),
-        metadata: json_data,
-    };
+    let mut lib = beam_lib::TaskResult::new(from, vec![], task, beam_lib::WorkStatus::Succeeded, json_data.clone(), json_data);
+    lib.seq = Some(3);
     assert_json_eq(lib, internal);
 }
 
 #[cfg(feature = "sockets")]
 #[test]
 fn test_socket_task() {
-    set_broker_id("broker.samply.de".to_string());
-    let id = MsgId::new();
+    init_broker_id();
+    let (id, task_id) = (MsgId::new(), MsgId::new());
     let from = AppOrProxyId::new("app1.proxy1.broker.samply.de").unwrap();
     let internal = crate::MsgSocketRequest {
         from: from.clone(),
@@ -109,15 +110,11 @@ fn test_socket_task() {
         secret: Plain { body: None },
         expire: SystemTime::now() + Duration::from_secs(10),
         id,
-        metadata: serde_json::Value::Null
-    };
-    let lib = beam_lib::SocketTask {
-        from,
-        to: vec![],
-        ttl: "9".to_string(),
-        id,
-        metadata: serde_json::Value::Null
+        metadata: serde_json::Value::Null,
+        task: Some(task_id),
     };
+    let mut lib = beam_lib::SocketTask::new(from, vec![], "9".to_string(), id, serde_json::Value::Null);
+    lib.task = Some(task_id);
     let a_str = serde_json::to_string(&lib).unwrap();
     let b_str = serde_json::to_string(&internal).unwrap();
     assert_eq!(a_str, b_str);
@@ -0,0 +1,76 @@
+//! Deterministic assignment of tasks to broker instances, so that several brokers or proxies agree on
+//! which broker is responsible for a task without talking to each other.
+//!
+//! Uses rendezvous (highest random weight) hashing: every broker gets a score per task and the highest score wins.
+//! Adding a broker only moves the tasks it wins to it, removing a broker only moves its own tasks,
+//! and the order in which the brokers are listed does not matter.
+
+use beam_lib::MsgId;
+use sha2::{Digest, Sha256};
+
+/// Returns the broker owning the task, or `None` if there are no brokers.
+/// Brokers are identified by any stable name, e.g. their id or URL.
+pub fn owner_for<'a, B: AsRef<str>>(task_id: &MsgId, brokers: &'a [B]) -> Option<&'a B> {
+    let task_id = task_id.to_string();
+    brokers
+        .iter()
+        .map(|broker| (score(&task_id, broker.as_ref()), broker))
+        // Ties are practically impossible but have to be broken independent of the order of the brokers
+        .max_by(|(score1, broker1), (score2, broker2)| score1.cmp(score2).then_with(|| broker1.as_ref().cmp(broker2.as_ref())))
+        .map(|(_, broker)| broker)
+}
+
+/// Stable across processes and releases unlike [`std::hash::DefaultHasher`]
+fn score(task_id: &str, broker: &str) -> u64 {
+    let digest = Sha256::new()
+        .chain_update(broker.as_bytes())
+        // Keeps "ab" + "c" and "a" + "bc" apart
+        .chain_update([0])
+        .chain_update(task_id.as_bytes())
+        .finalize();
+    u64::from_be_bytes(digest[..8].try_into().expect("SHA-256 digests are 32 bytes long"))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+
+    fn owners<'a>(task_ids: &[MsgId], brokers: &'a [&'a str]) -> Vec<&'a str> {
+        task_ids.iter().map(|id| *owner_for(id, brokers).unwrap()).collect()
+    }
+
+    #[test]
+    fn distribution() {
+        let task_ids: Vec<MsgId> = (0..10_000).map(|_| MsgId::new()).collect();
+        let brokers = ["broker1", "broker2", "broker3", "broker4"];
+        let mut counts: HashMap<&str, usize> = HashMap::new();
+        for owner in owners(&task_ids, &brokers) {
+            *counts.entry(owner).or_default() += 1;
+        }
+        for broker in brokers {
+            assert!((2000..3000).contains(&counts[broker]), "{broker} owns {} of 10000 tasks", counts[broker]);
+        }
+        assert_eq!(owner_for::<&str>(&task_ids[0], &[]), None);
+    }
+
+    #[test]
+    fn stability() {
+        let task_ids: Vec<MsgId> = (0..10_000).map(|_| MsgId::new()).collect();
+        let before = owners(&task_ids, &["broker1", "broker2", "broker3", "broker4"]);
+        assert_eq!(before, owners(&task_ids, &["broker4", "broker2", "broker1", "broker3"]), "Order does not matter");
+
+        let added = owners(&task_ids, &["broker1", "broker2", "broker3", "broker4", "broker5"]);
+        let moved: Vec<_> = before.iter().zip(&added).filter(|(before, after)| before != after).collect();
+        assert!(moved.iter().all(|(_, after)| **after == "broker5"), "Tasks only move to the new broker");
+        assert!((1500..2500).contains(&moved.len()), "{} tasks moved", moved.len());
+
+        let removed = owners(&task_ids, &["broker1", "broker3", "broker4"]);
+        for (before, after) in before.iter().zip(&removed) {
+            if *before != "broker2" {
+                assert_eq!(before, after, "Only tasks of the removed broker move");
+            }
+        }
+    }
+}
@@ -6,7 +6,6 @@ use serde_json::Value;
 use crate::{MsgState, serialize_time, MsgId, Msg, DecryptableMsg, Plain, Encrypted, EncryptableMsg, HasWaitId};
 use beam_lib::AppOrProxyId;
 
-
 #[derive(Debug, Deserialize, Serialize, Clone)]
 pub struct MsgSocketRequest<State>
 where State: MsgState {
@@ -19,7 +18,10 @@ where State: MsgState {
     #[serde(skip_serializing_if = "MsgState::is_empty")]
     pub secret: State,
     #[serde(default)]
-    pub metadata: Value
+    pub metadata: Value,
+    /// The task of the socket's creator the socket belongs to. The proxy of the worker on the other end records how the socket ended as its result
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub task: Option<MsgId>,
 }
 
 impl<State: MsgState> Msg for MsgSocketRequest<State> {
@@ -44,8 +46,8 @@ impl DecryptableMsg for MsgSocketRequest<Encrypted> {
     }
 
     fn convert_self(self, body: String) -> Self::Output {
-        let Self { from, to, expire, id, metadata, .. } = self;
-        Self::Output { from, to, expire, secret: body.into(), id, metadata }
+        let Self { from, to, expire, id, metadata, task, .. } = self;
+        Self::Output { from, to, expire, secret: body.into(), id, metadata, task }
     }
 }
 
@@ -53,8 +55,8 @@ impl EncryptableMsg for MsgSocketRequest<Plain> {
     type Output = MsgSocketRequest<Encrypted>;
 
     fn convert_self(self, body: Encrypted) -> Self::Output {
-        let Self { from, to, expire, id, metadata, .. } = self;
-        Self::Output { from, to, expire, secret: body, id, metadata }
+        let Self { from, to, expire, id, metadata, task, .. } = self;
+        Self::Output { from, to, expire, secret: body, id, metadata, task }
     }
 
     fn get_plain(&self) -> &Plain {
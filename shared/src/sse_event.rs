@@ -7,6 +7,9 @@ pub enum SseEventType {
     UpdatedResult,
     WaitExpired,
     DeletedTask,
+    ExpiredTask,
+    Acknowledged,
+    Complete,
     Error,
     Undefined,
     Unknown(String),
@@ -21,6 +24,9 @@ impl AsRef<str> for SseEventType {
             SseEventType::UpdatedResult => "updated_result",
             SseEventType::WaitExpired => "wait_expired",
             SseEventType::DeletedTask => "deleted_task",
+            SseEventType::ExpiredTask => "expired_task",
+            SseEventType::Acknowledged => "acknowledged",
+            SseEventType::Complete => "complete",
             SseEventType::Error => "error",
             SseEventType::Undefined => "", // Make this "message"?
             SseEventType::Unknown(e) => e.as_str(),
@@ -45,6 +51,9 @@ impl FromStr for SseEventType {
             "updated_result" => Self::UpdatedResult,
             "wait_expired" => Self::WaitExpired,
             "deleted_task" => Self::DeletedTask,
+            "expired_task" => Self::ExpiredTask,
+            "acknowledged" => Self::Acknowledged,
+            "complete" => Self::Complete,
             "error" => Self::Error,
             "message" => Self::Undefined,
             unknown => Self::Unknown(unknown.to_string()),
@@ -0,0 +1,104 @@
+//! Encryption of message bodies too large to be held in memory.
+//! The body is encrypted in chunks with the STREAM construction under the message's symmetric key,
+//! so every chunk is authenticated and chunks can neither be reordered nor cut off unnoticed.
+//! Each frame starts with the length of its ciphertext as a little endian `u32`, whose top bit marks the last frame.
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use chacha20poly1305::{
+    aead::stream::{DecryptorBE32, EncryptorBE32, Nonce, StreamBE32},
+    XChaCha20Poly1305,
+};
+use futures_util::{Stream, StreamExt};
+
+use crate::errors::{DecryptErrorReason, SamplyBeamError};
+
+/// Plaintext bytes per chunk
+pub const CHUNK_SIZE: usize = 64 * 1024;
+/// The nonce of the STREAM construction leaves 5 of XChaCha20's 24 bytes for the chunk counter and the last chunk flag
+pub(crate) const NONCE_LEN: usize = 19;
+const HEADER_LEN: usize = 4;
+const TAG_LEN: usize = 16;
+const LAST_FRAME: u32 = 1 << 31;
+
+pub(crate) type StreamNonce = Nonce<XChaCha20Poly1305, StreamBE32<XChaCha20Poly1305>>;
+
+fn frame(last: bool, ciphertext: &[u8]) -> Bytes {
+    let header = ciphertext.len() as u32 | if last { LAST_FRAME } else { 0 };
+    let mut frame = BytesMut::with_capacity(HEADER_LEN + ciphertext.len());
+    frame.put_u32_le(header);
+    frame.put_slice(ciphertext);
+    frame.freeze()
+}
+
+fn malformed(reason: &str) -> SamplyBeamError {
+    DecryptErrorReason::MalformedCiphertext(reason.into()).into()
+}
+
+/// Encrypts `body` into frames of at most [`CHUNK_SIZE`] bytes of plaintext, holding no more than one chunk and one item of `body` at a time
+pub(crate) fn encrypt_chunks(
+    cipher: XChaCha20Poly1305,
+    nonce: &StreamNonce,
+    body: impl Stream<Item = Result<Bytes, SamplyBeamError>>,
+) -> impl Stream<Item = Result<Bytes, SamplyBeamError>> {
+    let encryptor = EncryptorBE32::from_aead(cipher, nonce);
+    futures_util::stream::unfold((Some(encryptor), Box::pin(body), BytesMut::new()), |(encryptor, mut body, mut buf)| async move {
+        let mut encryptor = encryptor?;
+        let failed = || SamplyBeamError::SignEncryptError("Encryption error: Can not encrypt data.".into());
+        // Read past the chunk to know whether it is the last one
+        while buf.len() <= CHUNK_SIZE {
+            match body.next().await {
+                Some(Ok(bytes)) => buf.extend_from_slice(&bytes),
+                Some(Err(e)) => return Some((Err(e), (None, body, buf))),
+                None => {
+                    let frame = encryptor.encrypt_last(&buf[..]).map(|ciphertext| frame(true, &ciphertext)).map_err(|_| failed());
+                    return Some((frame, (None, body, BytesMut::new())));
+                }
+            }
+        }
+        let chunk = buf.split_to(CHUNK_SIZE);
+        match encryptor.encrypt_next(&chunk[..]) {
+            Ok(ciphertext) => Some((Ok(frame(false, &ciphertext)), (Some(encryptor), body, buf))),
+            Err(_) => Some((Err(failed()), (None, body, buf))),
+        }
+    })
+}
+
+/// Decrypts the frames written by [`encrypt_chunks`] one chunk at a time.
+/// Fails if a frame was tampered with or the frames end before the last one.
+pub(crate) fn decrypt_chunks(
+    cipher: XChaCha20Poly1305,
+    nonce: &StreamNonce,
+    frames: impl Stream<Item = Result<Bytes, SamplyBeamError>>,
+) -> impl Stream<Item = Result<Bytes, SamplyBeamError>> {
+    let decryptor = DecryptorBE32::from_aead(cipher, nonce);
+    futures_util::stream::unfold((Some(decryptor), Box::pin(frames), BytesMut::new()), |(decryptor, mut frames, mut buf)| async move {
+        let mut decryptor = decryptor?;
+        loop {
+            if buf.len() >= HEADER_LEN {
+                let header = u32::from_le_bytes(buf[..HEADER_LEN].try_into().expect("Checked length"));
+                let (last, len) = (header & LAST_FRAME != 0, (header & !LAST_FRAME) as usize);
+                if len > CHUNK_SIZE + TAG_LEN {
+                    return Some((Err(malformed("Frame is larger than a chunk")), (None, frames, buf)));
+                }
+                if buf.len() >= HEADER_LEN + len {
+                    buf.advance(HEADER_LEN);
+                    let ciphertext = buf.split_to(len);
+                    let failed = |_| malformed("Cannot decrypt chunk of payload");
+                    if last {
+                        let plaintext = decryptor.decrypt_last(&ciphertext[..]).map(Bytes::from).map_err(failed);
+                        return Some((plaintext, (None, frames, buf)));
+                    }
+                    return match decryptor.decrypt_next(&ciphertext[..]) {
+                        Ok(plaintext) => Some((Ok(plaintext.into()), (Some(decryptor), frames, buf))),
+                        Err(e) => Some((Err(failed(e)), (None, frames, buf))),
+                    };
+                }
+            }
+            match frames.next().await {
+                Some(Ok(bytes)) => buf.extend_from_slice(&bytes),
+                Some(Err(e)) => return Some((Err(e), (None, frames, buf))),
+                None => return Some((Err(malformed("Payload ended before its last chunk")), (None, frames, buf))),
+            }
+        }
+    })
+}
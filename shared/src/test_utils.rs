@@ -0,0 +1,129 @@
+//! Fixtures shared by the tests of this crate, the broker and the proxy
+
+use std::{collections::HashMap, sync::Arc, time::{Duration, SystemTime}};
+
+use beam_lib::{AppId, AppOrProxyId, FailureStrategy, MsgId, ProxyId, WorkStatus};
+use once_cell::sync::Lazy;
+use jwt_simple::prelude::RS256KeyPair;
+use openssl::{asn1::{Asn1Integer, Asn1Time}, bn::{BigNum, MsbOption}, hash::MessageDigest, pkey::PKey, rsa::Rsa, x509::{X509NameBuilder, X509}};
+use rsa::{pkcs8::DecodePrivateKey, RsaPrivateKey};
+use tokio::sync::Mutex;
+
+use crate::{config_shared::ConfigCrypto, crypto::{self, CryptoPublicPortion}, Encrypted, EncryptedMsgTaskRequest, EncryptedMsgTaskResult, MsgTaskRequest, MsgTaskResult};
+
+pub const BROKER_ID: &str = "broker.samply.de";
+
+/// Sets the broker id all ids in tests belong to
+pub fn init_broker_id() {
+    beam_lib::set_broker_id(BROKER_ID.to_string());
+}
+
+pub fn proxy_id(proxy: &str) -> ProxyId {
+    init_broker_id();
+    ProxyId::new(format!("{proxy}.{BROKER_ID}")).unwrap()
+}
+
+pub fn app_id(app: &str, proxy: &str) -> AppId {
+    init_broker_id();
+    AppId::new(format!("{app}.{proxy}.{BROKER_ID}")).unwrap()
+}
+
+/// An app of the first proxy
+pub fn app(app: &str) -> AppOrProxyId {
+    app_id(app, "proxy1").into()
+}
+
+/// Tests that don't decrypt messages leave their bodies empty
+pub fn encrypted() -> Encrypted {
+    Encrypted::default()
+}
+
+/// A task that expires in a minute and is not retried
+pub fn task(from: &AppOrProxyId, to: Vec<AppOrProxyId>) -> EncryptedMsgTaskRequest {
+    MsgTaskRequest {
+        id: MsgId::new(),
+        from: from.clone(),
+        to,
+        body: encrypted(),
+        expire: SystemTime::now() + Duration::from_secs(60),
+        failure_strategy: FailureStrategy::Discard,
+        completion_policy: None,
+        deadline: None,
+        parent_task: None,
+        results: HashMap::new(),
+        metadata: serde_json::Value::Null,
+    }
+}
+
+pub fn result(task_id: MsgId, from: &AppOrProxyId, to: &AppOrProxyId, status: WorkStatus) -> EncryptedMsgTaskResult {
+    MsgTaskResult {
+        from: from.clone(),
+        to: vec![to.clone()],
+        task: task_id,
+        status,
+        body: encrypted(),
+        metadata: serde_json::Value::Null,
+        seq: None,
+    }
+}
+
+/// A socket request that expires in a minute
+#[cfg(feature = "sockets")]
+pub fn socket_request(from: &AppOrProxyId, to: Vec<AppOrProxyId>) -> crate::MsgSocketRequest<Encrypted> {
+    crate::MsgSocketRequest {
+        from: from.clone(),
+        to,
+        expire: SystemTime::now() + Duration::from_secs(60),
+        id: MsgId::new(),
+        secret: encrypted(),
+        metadata: serde_json::Value::Null,
+        task: None,
+    }
+}
+
+/// Key material of a proxy with a self-signed certificate that is only known to the proxy itself.
+/// Use [`trusted_proxy`] for proxies whose messages other parties in the test verify or encrypt messages to.
+pub fn proxy_crypto(proxy: &str) -> ConfigCrypto {
+    let beam_id = proxy_id(proxy);
+    let key = PKey::from_rsa(Rsa::generate(2048).unwrap()).unwrap();
+    let mut serial = BigNum::new().unwrap();
+    serial.rand(64, MsbOption::MAYBE_ZERO, false).unwrap();
+    let mut subject = X509NameBuilder::new().unwrap();
+    subject.append_entry_by_text("CN", beam_id.as_ref()).unwrap();
+    let subject = subject.build();
+    let mut cert = X509::builder().unwrap();
+    cert.set_serial_number(&Asn1Integer::from_bn(&serial).unwrap()).unwrap();
+    cert.set_subject_name(&subject).unwrap();
+    cert.set_issuer_name(&subject).unwrap();
+    cert.set_pubkey(&key).unwrap();
+    cert.set_not_before(&Asn1Time::days_from_now(0).unwrap()).unwrap();
+    cert.set_not_after(&Asn1Time::days_from_now(1).unwrap()).unwrap();
+    cert.sign(&key, MessageDigest::sha256()).unwrap();
+    let private = String::from_utf8(key.private_key_to_pem_pkcs8().unwrap()).unwrap();
+    ConfigCrypto {
+        privkey_rs256: RS256KeyPair::from_pem(&private).unwrap().with_key_id(&serial.to_hex_str().unwrap()),
+        privkey_rsa: RsaPrivateKey::from_pkcs8_pem(&private).unwrap(),
+        previous_privkey_rsa: None,
+        public: Some(CryptoPublicPortion {
+            beam_id,
+            cert: cert.build(),
+            pubkey: String::from_utf8(key.public_key_to_pem().unwrap()).unwrap(),
+        }),
+    }
+}
+
+/// Key material of a proxy whose certificate is known to everyone in this process, like one issued by the PKI.
+/// Every proxy has a single certificate, so tests running concurrently encrypt messages for the same key.
+pub async fn trusted_proxy(proxy: &str) -> Arc<ConfigCrypto> {
+    static TRUSTED: Lazy<Mutex<HashMap<String, Arc<ConfigCrypto>>>> = Lazy::new(Default::default);
+    let mut trusted = TRUSTED.lock().await;
+    if let Some(crypto) = trusted.get(proxy) {
+        return crypto.clone();
+    }
+    let crypto = Arc::new(proxy_crypto(proxy));
+    let public = crypto.public.as_ref().unwrap();
+    let serial = public.cert.serial_number().to_bn().unwrap().to_hex_str().unwrap();
+    crypto::insert_unverified_certificate(&serial, public.cert.clone()).await;
+    trusted.insert(proxy.to_string(), crypto.clone());
+    crypto
+}
@@ -50,17 +50,47 @@ async fn test_task_claiming() -> Result<()> {
     Ok(())
 }
 
+#[tokio::test]
+async fn test_range_served_by_proxy() -> Result<()> {
+    use reqwest::{header, StatusCode};
+
+    let id = post_task(()).await?;
+    put_result(id, (), None).await?;
+    let request = |range: String, if_range: Option<String>| {
+        let mut req = reqwest::Client::new()
+            .get(format!("{}v1/tasks/{id}/results", crate::PROXY1.parse::<reqwest::Url>().unwrap()))
+            .header(header::AUTHORIZATION, format!("ApiKey {} {}", APP1.clone(), crate::APP_KEY))
+            .header(header::RANGE, range);
+        if let Some(if_range) = if_range {
+            req = req.header(header::IF_RANGE, if_range);
+        }
+        req.send()
+    };
+    // The proxy serves ranges of the decrypted results
+    let whole = request("bytes=0-".to_string(), None).await?;
+    assert_eq!(whole.status(), StatusCode::PARTIAL_CONTENT);
+    let etag = whole.headers()[header::ETAG].to_str()?.to_string();
+    let whole = whole.bytes().await?;
+    let results: Vec<TaskResult<()>> = serde_json::from_slice(&whole)?;
+    assert_eq!(results.len(), 1);
+    let rest = request("bytes=10-".to_string(), Some(etag)).await?;
+    assert_eq!(rest.status(), StatusCode::PARTIAL_CONTENT);
+    assert_eq!(rest.headers()[header::CONTENT_RANGE], format!("bytes 10-{}/{}", whole.len() - 1, whole.len()).as_str());
+    assert_eq!(rest.bytes().await?, whole.slice(10..));
+    Ok(())
+}
+
 pub async fn post_task<T: Serialize + 'static>(body: T) -> Result<MsgId> {
     let id = MsgId::new();
-    client1().post_task(&TaskRequest {
+    client1().post_task(&TaskRequest::new(
         id,
-        from: APP1.clone(),
-        to: vec![APP2.clone()],
+        APP1.clone(),
+        vec![APP2.clone()],
         body,
-        ttl: "10s".to_string(),
-        failure_strategy: beam_lib::FailureStrategy::Discard,
-        metadata: serde_json::Value::Null,
-    }).await?;
+        "10s".to_string(),
+        beam_lib::FailureStrategy::Discard,
+        serde_json::Value::Null,
+    )).await?;
     Ok(id)
 }
 
@@ -70,10 +100,13 @@ pub async fn poll_task<T: DeserializeOwned + 'static>(expected_id: MsgId) -> Res
         .into_iter()
         .find(|t| t.id == expected_id)
         .ok_or(anyhow::anyhow!("Did not find expected task"))
-        .and_then(|TaskRequest { id, from, to, body, ttl, failure_strategy, metadata }| Ok(TaskRequest {
-            id, from, to, ttl, failure_strategy, metadata,
-            body: serde_json::from_value(body)?
-        }))
+        .and_then(|task| {
+            let mut typed = TaskRequest::new(task.id, task.from, task.to, serde_json::from_value(task.body)?, task.ttl, task.failure_strategy, task.metadata);
+            typed.completion_policy = task.completion_policy;
+            typed.deadline = task.deadline;
+            typed.parent_task = task.parent_task;
+            Ok(typed)
+        })
 }
 
 pub async fn poll_result<T: DeserializeOwned + 'static>(task_id: MsgId, block: &BlockingOptions) -> Result<TaskResult<T>> {
@@ -84,13 +117,13 @@ pub async fn poll_result<T: DeserializeOwned + 'static>(task_id: MsgId, block: &
 }
 
 pub async fn put_result<T: Serialize + 'static>(task_id: MsgId, body: T, status: Option<beam_lib::WorkStatus>) -> Result<()> {
-    client2().put_result(&TaskResult {
-        from: APP2.clone(),
-        to: vec![APP1.clone()],
-        task: task_id,
-        status: status.unwrap_or(beam_lib::WorkStatus::Succeeded),
+    client2().put_result(&TaskResult::new(
+        APP2.clone(),
+        vec![APP1.clone()],
+        task_id,
+        status.unwrap_or(beam_lib::WorkStatus::Succeeded),
         body,
-        metadata: serde_json::Value::Null,
-    }, &task_id).await?;
+        serde_json::Value::Null,
+    ), &task_id).await?;
     Ok(())
 }